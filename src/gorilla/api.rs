@@ -1,15 +1,57 @@
 use crate::gorilla::*;
+use crate::gorilla::reader_mv::resample_entries;
 use chrono::{Duration, TimeZone, NaiveDate};
 
 pub fn compress_values(mv_entries: Vec<MVEntry>, header: GorillaDateTime, dim: usize) -> GorillaBlock {
-    let mut writer = GorillaWriterMV::with_vec(header, dim);
-
-    for i in 0..mv_entries.len() {
-        assert!(writer.append_entry(mv_entries[i].clone()).is_ok());
+    let mut writer = GorillaWriterMV::with_vec(header, dim).unwrap_or_else(|e| panic!("{}", e));
+    if let Err(e) = writer.append_entries(&mv_entries) {
+        panic!("{}", e);
     }
     writer.close()
 }
 
+// same as 'compress_values' but a new block, with the time of the entry as
+// its header, is started whenever an entry would take the current one past
+// 'max_bytes', a block always takes its first entry even if it ends up larger
+pub fn compress_values_capped(mv_entries: Vec<MVEntry>, header: GorillaDateTime, dim: usize, max_bytes: usize)
+    -> Vec<GorillaBlock>
+{
+    let mut blocks = Vec::new();
+    let mut writer = GorillaWriterMV::with_vec(header, dim).unwrap_or_else(|e| panic!("{}", e));
+    let mut num_entries = 0;
+    for entry in mv_entries {
+        // an entry that fits even at its largest is appended right away
+        if num_entries == 0 || writer.body.length() + writer.max_entry_bits() <= 8 * max_bytes {
+            if let Err(e) = writer.append_entry(entry) {
+                panic!("{}", e);
+            }
+            num_entries += 1;
+            continue;
+        }
+
+        // close to the cap the entry is appended to a copy first, as its size
+        // is only known once encoded
+        let mut grown = writer.fork();
+        let time = entry.time;
+        if let Err(e) = grown.append_entry(entry.clone()) {
+            panic!("{}", e);
+        }
+        if grown.body.length() > 8 * max_bytes {
+            let next = GorillaWriterMV::with_vec(time, dim).unwrap_or_else(|e| panic!("{}", e));
+            blocks.push(std::mem::replace(&mut writer, next).close());
+            if let Err(e) = writer.append_entry(entry) {
+                panic!("{}", e);
+            }
+            num_entries = 1;
+        } else {
+            writer = grown;
+            num_entries += 1;
+        }
+    }
+    blocks.push(writer.close());
+    blocks
+}
+
 pub fn retrieve_values(block: GorillaBlock, dim: usize, num_entries: usize) -> Vec<MVEntry> {
     let mut reader = GorillaReaderMV::from_block(block, dim);
     let mut result = Vec::new();
@@ -21,6 +63,45 @@ pub fn retrieve_values(block: GorillaBlock, dim: usize, num_entries: usize) -> V
     result
 }
 
+// the entries among the first 'num_entries' with a time in [start, end],
+// decoding stops at the first entry past 'end'
+pub fn retrieve_values_range(block: GorillaBlock, dim: usize, num_entries: usize,
+                             start: GorillaDateTime, end: GorillaDateTime) -> Vec<MVEntry> {
+    let mut reader = GorillaReaderMV::from_block(block, dim);
+    let mut result = Vec::new();
+    for _ in 0..num_entries {
+        let ts = reader.get_next_time();
+        if ts > end {
+            break;
+        }
+        // values are XORed against the previous ones, so they are decoded
+        // even for entries before 'start'
+        let values = reader.get_next_values();
+        if ts >= start {
+            result.push(MVEntry { time: ts, values });
+        }
+    }
+    result
+}
+
+// every entry of blocks following one another, such as the ones of
+// 'compress_values_capped', in order
+pub fn retrieve_chained(blocks: Vec<GorillaBlock>, dim: usize) -> Vec<MVEntry> {
+    blocks.into_iter().flat_map(|block| GorillaReaderMV::from_block(block, dim)).collect()
+}
+
+// the combinations 'aggregate' applies per column, Mean is the average
+pub type Agg = DownsampleMethod;
+
+// the first 'num_entries' entries of a block combined per 'bucket' by 'agg',
+// buckets are aligned like in 'GorillaReaderMV::window_resample', the ones
+// without entries are skipped and a trailing bucket the block ends inside of
+// is kept, panics on a bucket shorter than a second
+pub fn aggregate(block: &GorillaBlock, dim: usize, num_entries: usize, bucket: Duration, agg: Agg) -> Vec<MVEntry> {
+    let entries = retrieve_values(block.clone(), dim, num_entries).into_iter().map(Ok);
+    resample_entries(entries, bucket, agg).unwrap_or_else(|e| panic!("{}", e))
+}
+
 #[cfg(test)]
 
 mod test {
@@ -58,6 +139,93 @@ mod test {
       assert!(is_all_same(&vec2, &read_entry[1].values));
   }
 
+  #[test]
+  pub fn retrieve_range() {
+      let entries: Vec<MVEntry> = (0..20)
+          .map(|i| MVEntry::new(dt(1970, 1, 1, 1, 0, 0) + Duration::seconds(10 * i), vec![i as f64, 0.5 * i as f64]))
+          .collect();
+      let block = compress_values(entries.clone(), dt(1970, 1, 1, 0, 0, 0), 2);
+
+      // both ends are inclusive, the first and last entries are left out
+      let window = retrieve_values_range(block.clone(), 2, entries.len(), entries[3].time, entries[15].time);
+      assert_eq!(window.len(), 13);
+      for (entry, expected) in window.iter().zip(&entries[3..=15]) {
+        assert!(entry.time == expected.time);
+        assert!(is_all_same(&entry.values, &expected.values));
+      }
+
+      // bounds between entries, and windows missing the block
+      let window = retrieve_values_range(block.clone(), 2, entries.len(),
+                                         entries[3].time + Duration::seconds(1), entries[15].time - Duration::seconds(1));
+      assert_eq!(window.len(), 11);
+      assert!(window[0].time == entries[4].time);
+      assert!(retrieve_values_range(block.clone(), 2, entries.len(), dt(1970, 1, 1, 0, 0, 0), dt(1970, 1, 1, 0, 59, 59)).is_empty());
+      assert!(retrieve_values_range(block.clone(), 2, entries.len(), dt(1970, 1, 1, 2, 0, 0), dt(1970, 1, 1, 3, 0, 0)).is_empty());
+
+      // only the first 'num_entries' are considered
+      assert_eq!(retrieve_values_range(block, 2, 10, entries[3].time, entries[15].time).len(), 7);
+  }
+
+  #[test]
+  pub fn compress_capped() {
+      let entries: Vec<MVEntry> = (0..2000)
+          .map(|i| MVEntry::new(dt(1970, 1, 1, 1, 0, 0) + Duration::seconds(10 * i + i % 7), vec![(i as f64).sin(), i as f64]))
+          .collect();
+      let whole = compress_values(entries.clone(), dt(1970, 1, 1, 0, 0, 0), 2);
+      let blocks = compress_values_capped(entries.clone(), dt(1970, 1, 1, 0, 0, 0), 2, 1024);
+      let bits = |block: &GorillaBlock| GorillaReaderMV::from_block(block.clone(), 2).get_reader().length();
+      assert!(blocks.len() > 1);
+      assert!(blocks.iter().all(|b| bits(b) <= 8 * 1024));
+      assert!(blocks.len() >= bits(&whole) / (8 * 1024));
+
+      // every block after the first starts at its first entry
+      let decoded = retrieve_chained(blocks.clone(), 2);
+      assert_eq!(decoded.len(), entries.len());
+      for (entry, expected) in decoded.iter().zip(&entries) {
+        assert!(entry.time == expected.time);
+        assert!(is_all_same(&entry.values, &expected.values));
+      }
+      let mut first = blocks[0].num_entries(2).unwrap();
+      for block in &blocks[1..] {
+        assert!(block.header().unwrap() == entries[first].time);
+        first += block.num_entries(2).unwrap();
+      }
+
+      // a cap below a single entry leaves one entry per block
+      let blocks = compress_values_capped(entries[..5].to_vec(), dt(1970, 1, 1, 0, 0, 0), 2, 1);
+      assert_eq!(blocks.len(), 5);
+      assert_eq!(retrieve_chained(blocks, 2).len(), 5);
+  }
+
+  #[test]
+  pub fn aggregate_avg() {
+      // an entry every 10 seconds but for the third minute
+      let entries: Vec<MVEntry> = (0..32).filter(|i| !(12..18).contains(i))
+          .map(|i| MVEntry::new(dt(1970, 1, 1, 1, 0, 0) + Duration::seconds(10 * i), vec![i as f64, -2.0 * i as f64]))
+          .collect();
+      let block = compress_values(entries.clone(), dt(1970, 1, 1, 0, 0, 0), 2);
+
+      let avg = aggregate(&block, 2, entries.len(), Duration::minutes(1), Agg::Mean);
+      let times: Vec<GorillaDateTime> = avg.iter().map(|e| e.time).collect();
+      let expected: Vec<GorillaDateTime> = [0, 1, 3, 4, 5].iter().map(|m| dt(1970, 1, 1, 1, *m, 0)).collect();
+      assert_eq!(times, expected);
+      // the last minute only has two entries
+      let means = [2.5, 8.5, 20.5, 26.5, 30.5];
+      for (entry, mean) in avg.iter().zip(&means) {
+        assert!(is_all_same(&entry.values, &vec![*mean, -2.0 * mean]));
+      }
+
+      // the other combinations over the first entries only
+      let max = aggregate(&block, 2, 8, Duration::minutes(1), Agg::Max);
+      assert_eq!(max.len(), 2);
+      assert!(is_all_same(&max[1].values, &vec![7.0, -12.0]));
+      let min = aggregate(&block, 2, 8, Duration::minutes(1), Agg::Min);
+      assert!(is_all_same(&min[1].values, &vec![6.0, -14.0]));
+      let last = aggregate(&block, 2, entries.len(), Duration::hours(1), Agg::Last);
+      assert_eq!(last.len(), 1);
+      assert!(is_all_same(&last[0].values, &vec![31.0, -62.0]));
+  }
+
   #[test]
   pub fn complex_compress_get() {
       let mut vec: Vec<MVEntry> = Vec::new();
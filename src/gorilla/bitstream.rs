@@ -4,58 +4,397 @@ use std::str;
 use byteorder::*;
 use crate::gorilla::*;
 
+// the string form starts with the bit count n, streams that know their
+// number of entries set this flag on n and store the count right after it
+const ENTRIES_FLAG: u32 = 1 << 31;
+// set on n for streams holding 32 bit values
+const F32_FLAG: u32 = 1 << 30;
+// set on n for streams with a wide first delta field
+const WIDE_DELTA_FLAG: u32 = 1 << 29;
+// set on n for streams holding delta-of-delta encoded integer values
+const INT_VALUES_FLAG: u32 = 1 << 28;
+// set on n for streams that know their number of values per entry, the
+// count is stored after the entry count
+const DIM_FLAG: u32 = 1 << 27;
+// set on n for streams carrying a CRC32 of their bits, stored after the dim
+const CRC_FLAG: u32 = 1 << 26;
+// set on n for streams whose timestamps are in milliseconds
+const MILLIS_FLAG: u32 = 1 << 25;
+// every flag set on n, n itself has to fit below the lowest of them
+const FLAGS: u32 = ENTRIES_FLAG | F32_FLAG | WIDE_DELTA_FLAG | INT_VALUES_FLAG | DIM_FLAG | CRC_FLAG | MILLIS_FLAG;
+// n as stored for streams too long for the bits below the flags, their bit
+// count is stored as a u64 after the crc
+const LONG_N: u32 = !FLAGS;
+
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct BitStream {
     n: usize,
+    // number of entries written, when the writer recorded it
+    entries: Option<usize>,
+    // values are stored as f32 rather than f64
+    f32_values: bool,
+    // the first entry's delta is stored in the wide field
+    wide_first_delta: bool,
+    // values are integers stored as the delta of their deltas
+    int_values: bool,
+    // number of values per entry, when the writer recorded it
+    dim: Option<usize>,
+    // CRC32 of the bytes holding the bits, when the writer recorded it
+    crc: Option<u32>,
+    // timestamps are stored in milliseconds rather than seconds
+    millis: bool,
+    bitstream: Vec<u8>,
+}
+
+// the fields of a stream serialized by 'GorillaBlock::to_bytes' before the
+// bytes carried a version, from before the dim and crc were recorded
+#[derive(Deserialize)]
+pub(crate) struct BitStreamV0 {
+    n: usize,
+    entries: Option<usize>,
+    f32_values: bool,
+    wide_first_delta: bool,
+    int_values: bool,
+    bitstream: Vec<u8>,
+}
+
+impl From<BitStreamV0> for BitStream {
+    fn from(v0: BitStreamV0) -> Self {
+        Self {
+            n: v0.n,
+            entries: v0.entries,
+            f32_values: v0.f32_values,
+            wide_first_delta: v0.wide_first_delta,
+            int_values: v0.int_values,
+            dim: None,
+            crc: None,
+            millis: false,
+            bitstream: v0.bitstream,
+        }
+    }
+}
+
+// the same once the dim was recorded, from before the crc was
+#[derive(Deserialize)]
+pub(crate) struct BitStreamV1 {
+    n: usize,
+    entries: Option<usize>,
+    f32_values: bool,
+    wide_first_delta: bool,
+    int_values: bool,
+    dim: Option<usize>,
     bitstream: Vec<u8>,
 }
 
+impl From<BitStreamV1> for BitStream {
+    fn from(v1: BitStreamV1) -> Self {
+        Self {
+            n: v1.n,
+            entries: v1.entries,
+            f32_values: v1.f32_values,
+            wide_first_delta: v1.wide_first_delta,
+            int_values: v1.int_values,
+            dim: v1.dim,
+            crc: None,
+            millis: false,
+            bitstream: v1.bitstream,
+        }
+    }
+}
+
+// the same once the crc was recorded, serialized as version 1 of the block
+// bytes and before they carried a version, from before a stream recorded
+// its timestamps being in milliseconds
+#[derive(Deserialize)]
+pub(crate) struct BitStreamV2 {
+    n: usize,
+    entries: Option<usize>,
+    f32_values: bool,
+    wide_first_delta: bool,
+    int_values: bool,
+    dim: Option<usize>,
+    crc: Option<u32>,
+    bitstream: Vec<u8>,
+}
+
+impl From<BitStreamV2> for BitStream {
+    fn from(v2: BitStreamV2) -> Self {
+        Self {
+            n: v2.n,
+            entries: v2.entries,
+            f32_values: v2.f32_values,
+            wide_first_delta: v2.wide_first_delta,
+            int_values: v2.int_values,
+            dim: v2.dim,
+            crc: v2.crc,
+            millis: false,
+            bitstream: v2.bitstream,
+        }
+    }
+}
+
 impl BitStream {
     pub fn to_string(&self) -> String {
-        let mut buf = Vec::new();
-        buf.write_u32::<LittleEndian>(self.n as u32);
-        buf.extend(&self.bitstream);
-
+        let buf = self.to_bytes();
         let stream = unsafe {
             str::from_utf8_unchecked(&buf)
         };
         String::from(stream)
     }
 
+    // the bytes of the string form
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut buf = Vec::new();
+        let long = self.n >= LONG_N as usize;
+        let mut nval = if long { LONG_N } else { self.n as u32 };
+        if self.f32_values {
+            nval |= F32_FLAG;
+        }
+        if self.wide_first_delta {
+            nval |= WIDE_DELTA_FLAG;
+        }
+        if self.int_values {
+            nval |= INT_VALUES_FLAG;
+        }
+        if self.dim.is_some() {
+            nval |= DIM_FLAG;
+        }
+        if self.crc.is_some() {
+            nval |= CRC_FLAG;
+        }
+        if self.millis {
+            nval |= MILLIS_FLAG;
+        }
+        match self.entries {
+            Some(entries) => {
+                buf.write_u32::<LittleEndian>(nval | ENTRIES_FLAG).unwrap();
+                buf.write_u32::<LittleEndian>(entries as u32).unwrap();
+            }
+            None => {
+                buf.write_u32::<LittleEndian>(nval).unwrap();
+            }
+        }
+        if let Some(dim) = self.dim {
+            buf.write_u32::<LittleEndian>(dim as u32).unwrap();
+        }
+        if let Some(crc) = self.crc {
+            buf.write_u32::<LittleEndian>(crc).unwrap();
+        }
+        if long {
+            buf.write_u64::<LittleEndian>(self.n as u64).unwrap();
+        }
+        buf.extend(&self.bitstream);
+        buf
+    }
+
+    // read n, the entry count, dim and crc if there are any and the flags from the
+    // front of 'buf' into a stream without any bits yet
+    fn read_header(buf: &mut &[u8]) -> Result<Self, Error> {
+        let nval = buf.read_u32::<LittleEndian>()?;
+        let entries = if nval & ENTRIES_FLAG != 0 {
+            Some(buf.read_u32::<LittleEndian>()? as usize)
+        } else {
+            None
+        };
+        let dim = if nval & DIM_FLAG != 0 {
+            Some(buf.read_u32::<LittleEndian>()? as usize)
+        } else {
+            None
+        };
+        let crc = if nval & CRC_FLAG != 0 {
+            Some(buf.read_u32::<LittleEndian>()?)
+        } else {
+            None
+        };
+        let n = match nval & !FLAGS {
+            LONG_N => buf.read_u64::<LittleEndian>()? as usize,
+            n => n as usize,
+        };
+        Ok(Self {
+            n,
+            entries,
+            f32_values: nval & F32_FLAG != 0,
+            wide_first_delta: nval & WIDE_DELTA_FLAG != 0,
+            int_values: nval & INT_VALUES_FLAG != 0,
+            dim,
+            crc,
+            millis: nval & MILLIS_FLAG != 0,
+            bitstream: Vec::new(),
+        })
+    }
+
     pub fn new(block: &str) -> Self {
         // the first 4 bytes should be n
-        let blockbytes = block.as_bytes();
+        let mut buf = block.as_bytes();
+        let mut stream = Self::read_header(&mut buf).unwrap();
+        stream.bitstream = buf.to_vec();
+        stream
+    }
 
-        let (mut nbuf, streambuf) = blockbytes.split_at(4);
-        let nval = nbuf.read_u32::<LittleEndian>().unwrap();
+    // same as 'new', but fails on input too short for the stream it announces
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, Error> {
+        let mut buf = bytes;
+        let mut stream = Self::read_header(&mut buf)
+            .map_err(|_| Error::BitReaderError("Missing bitstream length"))?;
+        stream.bitstream = buf.to_vec();
+        stream.check_len()?;
+        Ok(stream)
+    }
 
-        Self {
-            n: nval as usize,
-            bitstream: streambuf.to_vec(),
+    // fails if the stream holds fewer bytes than its bit count needs
+    pub fn check_len(&self) -> Result<(), Error> {
+        if self.n.div_ceil(8) > self.bitstream.len() {
+            return Err(Error::BitReaderError("Bitstream shorter than its length"));
+        }
+        Ok(())
+    }
+
+    // record the number of entries written into the stream
+    pub fn with_entries(mut self, entries: usize) -> Self {
+        self.entries = Some(entries);
+        self
+    }
+
+    pub fn entries(&self) -> Option<usize> {
+        self.entries
+    }
+
+    // mark the stream as holding 32 bit values
+    pub fn with_f32_values(mut self) -> Self {
+        self.f32_values = true;
+        self
+    }
+
+    pub fn f32_values(&self) -> bool {
+        self.f32_values
+    }
+
+    // mark the stream as storing the first delta in the wide field
+    pub fn with_wide_first_delta(mut self) -> Self {
+        self.wide_first_delta = true;
+        self
+    }
+
+    pub fn wide_first_delta(&self) -> bool {
+        self.wide_first_delta
+    }
+
+    // mark the stream as holding delta-of-delta encoded integer values
+    pub fn with_int_values(mut self) -> Self {
+        self.int_values = true;
+        self
+    }
+
+    pub fn int_values(&self) -> bool {
+        self.int_values
+    }
+
+    // record the number of values per entry
+    pub fn with_dim(mut self, dim: usize) -> Self {
+        self.dim = Some(dim);
+        self
+    }
+
+    pub fn dim(&self) -> Option<usize> {
+        self.dim
+    }
+
+    // mark the stream as holding millisecond timestamps
+    pub fn with_millis(mut self) -> Self {
+        self.millis = true;
+        self
+    }
+
+    pub fn millis(&self) -> bool {
+        self.millis
+    }
+
+    // record a CRC32 of the bits written so far
+    pub fn with_crc(mut self) -> Self {
+        self.crc = Some(self.payload_crc());
+        self
+    }
+
+    pub fn crc(&self) -> Option<u32> {
+        self.crc
+    }
+
+    fn payload_crc(&self) -> u32 {
+        crc32fast::hash(&self.bitstream[..self.n.div_ceil(8)])
+    }
+
+    // fails if the stream records a CRC32 its bits don't match, streams
+    // without one pass, as do truncated ones: the reader reports running out
+    // of bits after decoding what is left
+    pub fn verify_crc(&self) -> Result<(), Error> {
+        match self.crc {
+            Some(crc) if self.check_len().is_ok() && crc != self.payload_crc() => Err(Error::BlockChecksumMismatch),
+            _ => Ok(()),
+        }
+    }
+
+    // size of the encoded bits in bytes
+    pub fn num_bytes(&self) -> usize {
+        self.bitstream.len()
+    }
+
+    // detach any bytes stored after the last byte of the stream
+    pub fn split_trailer(&mut self) -> Vec<u8> {
+        let nbytes = self.n.div_ceil(8);
+        if self.bitstream.len() > nbytes {
+            self.bitstream.split_off(nbytes)
+        } else {
+            Vec::new()
         }
     }
+
+    // concatenate the bits of two closed streams
+    pub fn merge(a: BitStream, b: BitStream) -> BitStream {
+        let entries = match (a.entries, b.entries) {
+            (Some(x), Some(y)) => Some(x + y),
+            _ => None,
+        };
+        let f32_values = a.f32_values && b.f32_values;
+        let int_values = a.int_values && b.int_values;
+        let dim = if a.dim == b.dim { a.dim } else { None };
+        // the first entry is a's
+        let wide_first_delta = a.wide_first_delta;
+        let mut merged = BitWriter::from_stream(a).merge(BitWriter::from_stream(b)).unwrap().close();
+        merged.entries = entries;
+        merged.f32_values = f32_values;
+        merged.wide_first_delta = wide_first_delta;
+        merged.int_values = int_values;
+        merged.dim = dim;
+        merged
+    }
 }
 
+// bits are packed least significant bit first, the same order as
+// bit_io::LittleEndian, so the output can be read back with BitReader
+#[derive(Clone, Debug)]
 pub struct BitWriter {
     n: usize,
-    bitstream: bit_io::BitWriter<Vec<u8>, bit_io::LittleEndian>,
+    bitstream: Vec<u8>,
 }
 
 impl BitWriter {
     pub fn new() -> Self {
         BitWriter {
             n: 0,
-            bitstream: bit_io::BitWriter::endian(Vec::new(), bit_io::LittleEndian),
+            bitstream: Vec::new(),
         }
     }
 
     pub fn write_bit(&mut self, bit: bool) -> Result<(), Error> {
-        self.bitstream.write_bit(bit)?;
-        self.n += 1;
-        Ok(())
+        self.write(1, bit as u64)
     }
 
+    // write the low 'nbits' bits of 'val', writing 0 bits leaves the stream
+    // as it is whatever 'val' is
     pub fn write(&mut self, nbits: u32, val: u64) -> Result<(), Error> {
+        if nbits == 0 {
+            return Ok(());
+        }
         let mask = {
             if nbits < 64 {
                 (1 << nbits) - 1
@@ -64,28 +403,100 @@ impl BitWriter {
             }
         };
 
-        self.bitstream.write(nbits, val & mask)?;
-        self.n += nbits as usize;
+        let mut val = val & mask;
+        let mut remaining = nbits as usize;
+        while remaining > 0 {
+            let offset = self.n % 8;
+            if offset == 0 {
+                self.bitstream.push(0);
+            }
+            let nfit = std::cmp::min(8 - offset, remaining);
+            let last = self.bitstream.len() - 1;
+            self.bitstream[last] |= ((val & ((1 << nfit) - 1)) as u8) << offset;
+            val >>= nfit;
+            remaining -= nfit;
+            self.n += nfit;
+        }
         Ok(())
     }
 
+    // the low 'nbits' bits of the two's complement of 'val', read back by
+    // 'BitReader::read_signed' as long as 'val' fits
+    pub fn write_signed(&mut self, nbits: u32, val: i64) -> Result<(), Error> {
+        self.write(nbits, val as u64)
+    }
+
+    // 'val' in groups of 7 bits, least significant first, each followed by a
+    // bit telling whether another group comes
+    pub fn write_varint(&mut self, mut val: u64) -> Result<(), Error> {
+        loop {
+            self.write(7, val & 0x7f)?;
+            val >>= 7;
+            self.write_bit(val != 0)?;
+            if val == 0 {
+                return Ok(());
+            }
+        }
+    }
+
     pub fn length(&self) -> usize {
         self.n
     }
 
-    pub fn close(mut self) -> BitStream {
-        let fill_bits: usize = {
-            if self.n % 8 == 0 {
-                0
-            } else {
-                ((1 + (self.n / 8)) * 8) - self.n
-            }
-        };
-        self.bitstream.write(fill_bits as u32, 0).unwrap();
-        let v = self.bitstream.into_writer();
+    // drop everything written so far but keep the allocated buffer
+    pub fn reset(&mut self) {
+        self.n = 0;
+        self.bitstream.clear();
+    }
+
+    // continue writing at the end of a closed stream
+    pub fn from_stream(stream: BitStream) -> Self {
+        let mut bitstream = stream.bitstream;
+        bitstream.truncate(stream.n.div_ceil(8));
+
+        // clear whatever follows the last valid bit
+        if !stream.n.is_multiple_of(8) {
+            let last = bitstream.len() - 1;
+            bitstream[last] &= (1 << (stream.n % 8)) - 1;
+        }
+
+        BitWriter {
+            n: stream.n,
+            bitstream,
+        }
+    }
+
+    // append all bits of 'other' after the bits of this writer
+    pub fn merge(mut self, other: BitWriter) -> Result<BitWriter, Error> {
+        if self.n.is_multiple_of(8) {
+            // byte aligned, the bytes of 'other' can be taken as they are
+            self.bitstream.extend(other.bitstream);
+            self.n += other.n;
+            return Ok(self);
+        }
+
+        let mut remaining = other.n;
+        for byte in other.bitstream {
+            let nbits = std::cmp::min(8, remaining);
+            self.write(nbits as u32, byte as u64)?;
+            remaining -= nbits;
+        }
+        Ok(self)
+    }
+
+    // the unused bits of the last byte are always zero, so closing is
+    // just handing over the buffer
+    pub fn close(self) -> BitStream {
         BitStream {
             n: self.n,
-            bitstream: v,
+            entries: None,
+            f32_values: false,
+            wide_first_delta: false,
+            int_values: false,
+            dim: None,
+            crc: None,
+            millis: false,
+            bitstream: self.bitstream,
         }
     }
 }
@@ -98,8 +509,9 @@ pub struct BitReader {
 
 impl BitReader {
     pub fn new(stream: BitStream) -> Self {
+        // a truncated stream is only readable up to its last byte
         BitReader {
-            n: stream.n,
+            n: stream.n.min(8 * stream.bitstream.len()),
             c: 0,
             bitstream: bit_io::BitReader::endian(
                 Cursor::new(stream.bitstream),
@@ -117,7 +529,7 @@ impl BitReader {
     }
 
     pub fn read_bit(&mut self) -> Result<bool, Error> {
-        if self.c <= self.n {
+        if self.c < self.n {
             let x = self.bitstream.read_bit()?;
             self.c += 1;
             Ok(x)
@@ -135,6 +547,44 @@ impl BitReader {
             Err(Error::BitReaderError("Exceeds bitstream contents"))
         }
     }
+
+    // read n bits written by 'BitWriter::write_signed', sign extending the
+    // highest one
+    pub fn read_signed(&mut self, n: usize) -> Result<i64, Error> {
+        let x = self.read(n)?;
+        if n == 0 || n >= 64 {
+            return Ok(x as i64);
+        }
+        let shift = 64 - n as u32;
+        Ok(((x << shift) as i64) >> shift)
+    }
+
+    // move the cursor past the next n bits without decoding them
+    pub fn skip(&mut self, n: usize) -> Result<(), Error> {
+        if self.c + n <= self.n {
+            self.bitstream.skip(n as u32)?;
+            self.c += n;
+            Ok(())
+        } else {
+            Err(Error::BitReaderError("Exceeds bitstream contents"))
+        }
+    }
+
+    // read a value written by 'BitWriter::write_varint'
+    pub fn read_varint(&mut self) -> Result<u64, Error> {
+        let mut val = 0;
+        let mut shift = 0;
+        loop {
+            val |= self.read(7)? << shift;
+            shift += 7;
+            if !self.read_bit()? {
+                return Ok(val);
+            }
+            if shift >= 64 {
+                return Err(Error::BitReaderError("Varint longer than 64 bits"));
+            }
+        }
+    }
 }
 
 #[cfg(test)]
@@ -150,6 +600,22 @@ mod test {
         assert!(reader.read_bit().unwrap());
     }
 
+    #[test]
+    fn read_past_length() {
+        let mut writer = BitWriter::new();
+        assert!(writer.write(3, 0b101).is_ok());
+        let mut reader = BitReader::new(writer.close());
+        assert!(reader.read_bit().unwrap());
+        assert!(!reader.read_bit().unwrap());
+        assert!(reader.read_bit().unwrap());
+
+        // the padding bits of the last byte aren't readable
+        assert!(reader.read_bit().is_err());
+        assert!(reader.read(1).is_err());
+        assert!(reader.skip(1).is_err());
+        assert_eq!(reader.cursor(), 3);
+    }
+
     #[test]
     fn read_write() {
         let mut writer = BitWriter::new();
@@ -161,6 +627,31 @@ mod test {
         assert!(reader.read(6).unwrap() == 0b101011);
     }
 
+    #[test]
+    fn write_widths() {
+        let mut writer = BitWriter::new();
+        assert!(writer.write(0, u64::MAX).is_ok());
+        assert_eq!(writer.n, 0);
+        assert!(writer.bitstream.is_empty());
+
+        // only the low bits of the value are written
+        assert!(writer.write(1, u64::MAX).is_ok());
+        assert!(writer.write(0, u64::MAX).is_ok());
+        assert!(writer.write(63, u64::MAX).is_ok());
+        assert!(writer.write(64, 0x8000_0000_0000_0001).is_ok());
+        assert!(writer.write(63, 1 << 63).is_ok());
+        assert!(writer.write(1, 0b10).is_ok());
+        assert_eq!(writer.n, 1 + 63 + 64 + 63 + 1);
+
+        let mut reader = BitReader::new(writer.close());
+        assert_eq!(reader.read(1).unwrap(), 1);
+        assert_eq!(reader.read(63).unwrap(), u64::MAX >> 1);
+        assert_eq!(reader.read(64).unwrap(), 0x8000_0000_0000_0001);
+        assert_eq!(reader.read(63).unwrap(), 0);
+        assert_eq!(reader.read(1).unwrap(), 0);
+        assert!(reader.read(1).is_err());
+    }
+
     #[test]
     fn read_write_mix() {
         let mut writer = BitWriter::new();
@@ -198,6 +689,73 @@ mod test {
         assert!(!reader.read_bit().unwrap());
     }
 
+    #[test]
+    fn merge() {
+        // unaligned
+        let mut a = BitWriter::new();
+        assert!(a.write(7, 0b1011001).is_ok());
+        let mut b = BitWriter::new();
+        assert!(b.write(13, 0b1_0110_1110_0101).is_ok());
+        let merged = a.merge(b).unwrap();
+        assert_eq!(merged.length(), 20);
+        let mut reader = BitReader::new(merged.close());
+        assert!(reader.read(7).unwrap() == 0b1011001);
+        assert!(reader.read(13).unwrap() == 0b1_0110_1110_0101);
+
+        // byte aligned
+        let mut a = BitWriter::new();
+        assert!(a.write(8, 0b1011_0011).is_ok());
+        let mut b = BitWriter::new();
+        assert!(b.write(13, 0b1_0110_1110_0101).is_ok());
+        let merged = a.merge(b).unwrap();
+        assert_eq!(merged.length(), 21);
+        let mut reader = BitReader::new(merged.close());
+        assert!(reader.read(8).unwrap() == 0b1011_0011);
+        assert!(reader.read(13).unwrap() == 0b1_0110_1110_0101);
+
+        // closed streams
+        let mut a = BitWriter::new();
+        assert!(a.write(7, 0b1011001).is_ok());
+        let mut b = BitWriter::new();
+        assert!(b.write(13, 0b1_0110_1110_0101).is_ok());
+        let merged = BitStream::merge(a.close(), b.close());
+        let mut reader = BitReader::new(merged);
+        assert!(reader.length() == 20);
+        assert!(reader.read(7).unwrap() == 0b1011001);
+        assert!(reader.read(13).unwrap() == 0b1_0110_1110_0101);
+    }
+
+    #[test]
+    fn skip() {
+        let mut writer = BitWriter::new();
+        assert!(writer.write(6, 0b101011).is_ok());
+        assert!(writer.write(64, 42).is_ok());
+        assert!(writer.write(3, 0b101).is_ok());
+        let mut reader = BitReader::new(writer.close());
+        assert!(reader.skip(70).is_ok());
+        assert!(reader.cursor() == 70);
+        assert!(reader.read(3).unwrap() == 0b101);
+        assert!(reader.skip(1).is_err());
+    }
+
+    #[test]
+    fn reset() {
+        let mut writer = BitWriter::new();
+        for i in 0..100 {
+            assert!(writer.write(64, i).is_ok());
+        }
+        let capacity = writer.bitstream.capacity();
+
+        writer.reset();
+        assert_eq!(writer.length(), 0);
+        assert_eq!(writer.bitstream.capacity(), capacity);
+
+        assert!(writer.write(6, 0b101011).is_ok());
+        let mut reader = BitReader::new(writer.close());
+        assert!(reader.length() == 6);
+        assert!(reader.read(6).unwrap() == 0b101011);
+    }
+
     #[test]
     fn read_write_i64() {
         let mut writer = BitWriter::new();
@@ -214,4 +772,82 @@ mod test {
         assert!(reader.read_bit().unwrap());
         assert!(reader.read_bit().unwrap());
     }
+
+    #[test]
+    fn read_write_signed() {
+        let fields: [(u32, i64); 12] = [
+            (7, -64), (7, 63), (7, -1), (7, 0),
+            (12, -2048), (12, 2047), (12, 1),
+            (32, i32::MIN as i64), (32, i32::MAX as i64),
+            (64, i64::MIN), (64, i64::MAX), (1, -1),
+        ];
+        let mut writer = BitWriter::new();
+        for (nbits, val) in fields.iter() {
+            assert!(writer.write_signed(*nbits, *val).is_ok());
+        }
+        // values that don't fit are truncated to their low bits
+        assert!(writer.write_signed(7, 64).is_ok());
+        assert!(writer.write_signed(12, 2048).is_ok());
+        assert!(writer.write_signed(12, -2049).is_ok());
+
+        let mut reader = BitReader::new(writer.close());
+        for (nbits, val) in fields.iter() {
+            assert_eq!(reader.read_signed(*nbits as usize).unwrap(), *val);
+        }
+        assert_eq!(reader.read_signed(7).unwrap(), -64);
+        assert_eq!(reader.read_signed(12).unwrap(), -2048);
+        assert_eq!(reader.read_signed(12).unwrap(), 2047);
+        assert!(reader.read_signed(1).is_err());
+    }
+
+    fn long_stream(n: usize) -> BitStream {
+        BitStream {
+            n,
+            entries: Some(1),
+            f32_values: false,
+            wide_first_delta: false,
+            int_values: false,
+            dim: Some(1),
+            crc: None,
+            millis: false,
+            bitstream: vec![0xa5; n.div_ceil(8)],
+        }.with_crc()
+    }
+
+    #[test]
+    fn longest_stream() {
+        // 25 bits are left for n next to the flags
+        let stream = long_stream((1 << 25) - 2).with_millis();
+        let bytes = stream.to_bytes();
+        assert_eq!(bytes.len(), 16 + stream.bitstream.len());
+        let read = BitStream::from_bytes(&bytes).unwrap();
+        assert_eq!(read.n, (1 << 25) - 2);
+        assert!(read.millis());
+        assert_eq!(read.entries(), Some(1));
+        assert_eq!(read.dim(), Some(1));
+        assert!(read.verify_crc().is_ok());
+    }
+
+    #[test]
+    fn over_long_stream() {
+        // longer streams store their bit count after the crc
+        for n in [(1 << 25) - 1, 1 << 25, (1 << 26) + 3] {
+            let stream = long_stream(n);
+            let bytes = stream.to_bytes();
+            assert_eq!(bytes.len(), 24 + stream.bitstream.len());
+            let read = BitStream::from_bytes(&bytes).unwrap();
+            assert_eq!(read.n, n);
+            assert!(!read.millis());
+            assert_eq!(read.entries(), Some(1));
+            assert_eq!(read.dim(), Some(1));
+            assert!(read.verify_crc().is_ok());
+        }
+        assert!(BitStream::from_bytes(&long_stream(1 << 25).to_bytes()[..20]).is_err());
+
+        // the serialized block keeps n in a field of its own
+        let block = GorillaBlock { data: long_stream(1 << 25).with_millis(), checksum: None };
+        let read = GorillaBlock::from_bytes(&block.to_bytes()).unwrap();
+        assert_eq!(read.data.n, 1 << 25);
+        assert!(read.data.millis());
+    }
 }
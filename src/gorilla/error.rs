@@ -9,6 +9,16 @@ pub enum Error {
     AppendOrderError,
     AppendDurationError,
     BadDimensionError,
+    BadHeaderError,
+    BadWindowError,
+    // a block with millisecond timestamps opened by a reader of seconds
+    BadPrecisionError,
+    NonIntegerValueError,
+    NonFiniteValue,
+    // the bits of a block don't match the CRC32 stored with them
+    BlockChecksumMismatch,
+    // the error of the entry at the index of a batch that was appended
+    BatchEntryError(usize, Box<Error>),
 }
 
 impl fmt::Display for Error {
@@ -19,6 +29,13 @@ impl fmt::Display for Error {
             Error::AppendDurationError => f.write_str("Appending item with excessive duration"),
             Error::BitReaderError(_) => f.write_str("BitStreamReader error"),
             Error::BadDimensionError => f.write_str("Entry dimension must match that of writer"),
+            Error::BadHeaderError => f.write_str("Block header must be between 1970 and the year 9999"),
+            Error::BadWindowError => f.write_str("Resampling window must be at least one second"),
+            Error::BadPrecisionError => f.write_str("Millisecond blocks are only read by GorillaReader"),
+            Error::NonIntegerValueError => f.write_str("Integer blocks only hold integers up to 2^53"),
+            Error::NonFiniteValue => f.write_str("Appending NaN or infinite value"),
+            Error::BlockChecksumMismatch => f.write_str("Block bits don't match their checksum"),
+            Error::BatchEntryError(index, e) => write!(f, "Entry {} of the batch: {}", index, e),
         }
     }
 }
@@ -31,6 +48,13 @@ impl StdError for Error {
             Error::AppendDurationError => "Append excess duration",
             Error::BitReaderError(_) => "BitStreamReader error",
             Error::BadDimensionError => "Bad Dimension error",
+            Error::BadHeaderError => "Bad header error",
+            Error::BadWindowError => "Bad window error",
+            Error::BadPrecisionError => "Bad precision error",
+            Error::NonIntegerValueError => "Non-integer value error",
+            Error::NonFiniteValue => "Non-finite value",
+            Error::BlockChecksumMismatch => "Block checksum mismatch",
+            Error::BatchEntryError(..) => "Batch entry error",
         }
     }
 }
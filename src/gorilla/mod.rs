@@ -6,13 +6,19 @@ pub mod writer;
 pub mod writer_mv;
 pub mod api;
 
+use std::mem;
+use std::str;
+
+use bincode::Options;
+
 pub use serde::{Serialize, Deserialize};
 pub use bitstream::{BitReader, BitStream, BitWriter};
+use bitstream::{BitStreamV0, BitStreamV1, BitStreamV2};
 pub use error::Error;
 pub use reader::GorillaReader;
-pub use reader_mv::GorillaReaderMV;
+pub use reader_mv::{DownsampleMethod, GorillaReaderMV, GorillaTimestampIter};
 pub use writer::GorillaWriter;
-pub use writer_mv::GorillaWriterMV;
+pub use writer_mv::{GorillaWriterMV, GorillaWriterMVSafe};
 
 pub type GorillaDateTime = chrono::DateTime<chrono::Utc>;
 
@@ -24,38 +30,615 @@ lazy_static! {
       chrono::Utc,
     )
   };
+  // the latest header a block may start at, far enough from the limits of
+  // the seconds and milliseconds math on timestamps
+  static ref MAX_HEADER: GorillaDateTime = {
+    chrono::TimeZone::with_ymd_and_hms(&chrono::Utc, 9999, 12, 31, 23, 59, 59).unwrap()
+  };
+}
+
+// block headers are stored as an unsigned count since the epoch, so one
+// before it would decode as a date far in the future
+fn validate_header(header: GorillaDateTime) -> Result<(), Error> {
+  if header < *EPOCH || header > *MAX_HEADER {
+    return Err(Error::BadHeaderError);
+  }
+  Ok(())
 }
 
 pub fn new_gorilla_date_time(n: chrono::NaiveDateTime) -> GorillaDateTime {
   chrono::DateTime::<chrono::Utc>::from_utc(n, chrono::Utc)
 }
 
+// the unit timestamps of a block are stored in, the header, the first delta
+// and every delta of delta count in this unit
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TimePrecision {
+  Seconds,
+  Millis,
+}
+
+impl TimePrecision {
+  // width of the first delta field
+  fn first_delta_bits(self) -> u32 {
+    match self {
+      TimePrecision::Seconds => 14,
+      TimePrecision::Millis => 24,
+    }
+  }
+
+  // the largest delta between two entries
+  fn max_delta(self) -> i64 {
+    match self {
+      TimePrecision::Seconds => 16384,
+      TimePrecision::Millis => (1 << 24) - 1,
+    }
+  }
+
+  // widths of the three small delta of delta buckets, a bucket of width b
+  // holds values in [-(2^(b-1) - 1), 2^(b-1)], anything else takes 32 bits
+  fn dod_bucket_bits(self) -> [u32; 3] {
+    match self {
+      TimePrecision::Seconds => [7, 9, 12],
+      TimePrecision::Millis => [10, 14, 17],
+    }
+  }
+
+  fn units(self, d: chrono::Duration) -> i64 {
+    match self {
+      TimePrecision::Seconds => d.num_seconds(),
+      TimePrecision::Millis => d.num_milliseconds(),
+    }
+  }
+
+  fn duration(self, units: i64) -> chrono::Duration {
+    match self {
+      TimePrecision::Seconds => chrono::Duration::seconds(units),
+      TimePrecision::Millis => chrono::Duration::milliseconds(units),
+    }
+  }
+
+  fn of_stream(stream: &BitStream) -> Self {
+    if stream.millis() {
+      TimePrecision::Millis
+    } else {
+      TimePrecision::Seconds
+    }
+  }
+}
+
+// how values are stored, f32 values are XORed as 32 bit patterns and their
+// zero windows take fewer bits
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum ValueWidth {
+  F64,
+  F32,
+}
+
+impl ValueWidth {
+  fn bits(self) -> u8 {
+    match self {
+      ValueWidth::F64 => 64,
+      ValueWidth::F32 => 32,
+    }
+  }
+
+  // width of the leading zero count, larger counts are stored as the maximum
+  fn leading_bits(self) -> u32 {
+    match self {
+      ValueWidth::F64 => 5,
+      ValueWidth::F32 => 4,
+    }
+  }
+
+  // width of the meaningful bit count, a count of 'bits()' is stored as 0
+  fn nbits_bits(self) -> u32 {
+    match self {
+      ValueWidth::F64 => 6,
+      ValueWidth::F32 => 5,
+    }
+  }
+
+  fn to_bits(self, value: f64) -> u64 {
+    match self {
+      ValueWidth::F64 => value.to_bits(),
+      ValueWidth::F32 => (value as f32).to_bits() as u64,
+    }
+  }
+
+  fn value_of(self, bits: u64) -> f64 {
+    match self {
+      ValueWidth::F64 => f64::from_bits(bits),
+      ValueWidth::F32 => f32::from_bits(bits as u32) as f64,
+    }
+  }
+
+  // the zero window before the first value, the same width either way
+  fn initial_zeros(self) -> Zeros {
+    Zeros {
+      leading: self.bits() / 2,
+      trailing: self.bits() / 2,
+    }
+  }
+
+  // the values as they read back
+  fn round(self, values: Vec<f64>) -> Vec<f64> {
+    match self {
+      ValueWidth::F64 => values,
+      ValueWidth::F32 => values.into_iter().map(|v| v as f32 as f64).collect(),
+    }
+  }
+
+  fn of_stream(stream: &BitStream) -> Self {
+    if stream.f32_values() {
+      ValueWidth::F32
+    } else {
+      ValueWidth::F64
+    }
+  }
+}
+
+// width of the first delta field of a multi-valued block, the largest delta
+// between any two entries is bounded by it
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum DeltaWidth {
+  Narrow,
+  // up to ~4 years, for blocks spanning a day or more
+  Wide,
+}
+
+impl DeltaWidth {
+  fn first_delta_bits(self) -> u32 {
+    match self {
+      DeltaWidth::Narrow => 14,
+      DeltaWidth::Wide => 27,
+    }
+  }
+
+  fn max_delta(self) -> i64 {
+    match self {
+      DeltaWidth::Narrow => 16384,
+      DeltaWidth::Wide => (1 << 27) - 1,
+    }
+  }
+
+  fn of_stream(stream: &BitStream) -> Self {
+    if stream.wide_first_delta() {
+      DeltaWidth::Wide
+    } else {
+      DeltaWidth::Narrow
+    }
+  }
+}
+
+// how values after the first entry are stored: XORed against the previous
+// ones, or for integer series such as counters as the delta of their deltas
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum ValueEncoding {
+  Xor,
+  DeltaOfDelta,
+}
+
+// the largest magnitude of an integer value, any integer up to it is an
+// exact f64
+const MAX_INT_VALUE: f64 = (1u64 << 53) as f64;
+
+impl ValueEncoding {
+  fn accepts(self, value: f64) -> bool {
+    match self {
+      ValueEncoding::Xor => true,
+      ValueEncoding::DeltaOfDelta => value.fract() == 0.0 && value.abs() <= MAX_INT_VALUE,
+    }
+  }
+
+  // the values as they read back, integers lose the sign of -0.0
+  fn round(self, values: Vec<f64>) -> Vec<f64> {
+    match self {
+      ValueEncoding::Xor => values,
+      ValueEncoding::DeltaOfDelta => values.into_iter().map(|v| v as i64 as f64).collect(),
+    }
+  }
+
+  fn of_stream(stream: &BitStream) -> Self {
+    if stream.int_values() {
+      ValueEncoding::DeltaOfDelta
+    } else {
+      ValueEncoding::Xor
+    }
+  }
+}
+
+fn zigzag(x: i64) -> u64 {
+  ((x << 1) ^ (x >> 63)) as u64
+}
+
+fn unzigzag(x: u64) -> i64 {
+  (x >> 1) as i64 ^ -((x & 1) as i64)
+}
+
+// the buckets narrower than 32 bits hold delta of deltas in
+// [-(2^(bits-1) - 1), 2^(bits-1)] like in the Gorilla paper, so the pattern
+// two's complement reads as -2^(bits-1) is 2^(bits-1)
+fn read_delta_of_delta(reader: &mut BitReader, bits: u32) -> Result<i64, Error> {
+  let dod = reader.read_signed(bits as usize)?;
+  if bits < 32 && dod == -(1 << (bits - 1)) {
+    Ok(-dod)
+  } else {
+    Ok(dod)
+  }
+}
+
+// FNV-1a, used to checksum the decoded contents of a block
+const FNV_OFFSET_BASIS: u64 = 0xcbf2_9ce4_8422_2325;
+const FNV_PRIME: u64 = 0x0000_0100_0000_01b3;
+
+fn fnv1a(mut hash: u64, bytes: &[u8]) -> u64 {
+    for byte in bytes {
+        hash ^= *byte as u64;
+        hash = hash.wrapping_mul(FNV_PRIME);
+    }
+    hash
+}
+
+fn fnv1a_time(hash: u64, time: GorillaDateTime) -> u64 {
+    fnv1a(hash, &time.timestamp().to_le_bytes())
+}
+
+fn fnv1a_values(mut hash: u64, values: &[f64]) -> u64 {
+    for value in values {
+        hash = fnv1a(hash, &value.to_bits().to_le_bytes());
+    }
+    hash
+}
+
+// how well a block compressed, each entry is 8 bytes of timestamp and 8
+// bytes per value uncompressed
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct BlockStats {
+  pub entry_count: usize,
+  pub uncompressed_bytes: usize,
+  pub compressed_bytes: usize,
+  // uncompressed over compressed bytes
+  pub ratio: f64,
+}
+
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct GorillaBlock {
   data: BitStream,
+  // FNV-1a hash over every appended timestamp and value
+  checksum: Option<u64>,
+}
+
+// a block serialized by 'to_bytes' starts with the magic and the version of
+// the bincode layout that follows, bincode has no defaults for fields missing
+// from older layouts so each one is decoded as it was written
+const BLOCK_MAGIC: &[u8; 4] = b"GBLK";
+const BLOCK_VERSION: u8 = 2;
+
+// the layout serialized before the bytes carried a version and the stream
+// recorded its dim and crc
+#[derive(Deserialize)]
+struct GorillaBlockV0 {
+  data: BitStreamV0,
+  checksum: Option<u64>,
+}
+
+impl From<GorillaBlockV0> for GorillaBlock {
+  fn from(v0: GorillaBlockV0) -> Self {
+    Self { data: v0.data.into(), checksum: v0.checksum }
+  }
+}
+
+// the layout serialized before the bytes carried a version, once the stream
+// recorded its dim but not yet its crc
+#[derive(Deserialize)]
+struct GorillaBlockV1 {
+  data: BitStreamV1,
+  checksum: Option<u64>,
+}
+
+impl From<GorillaBlockV1> for GorillaBlock {
+  fn from(v1: GorillaBlockV1) -> Self {
+    Self { data: v1.data.into(), checksum: v1.checksum }
+  }
+}
+
+// the layout of version 1 and of the last blocks serialized before the bytes
+// carried a version, once the stream recorded its crc but not yet whether
+// its timestamps are in milliseconds
+#[derive(Deserialize)]
+struct GorillaBlockV2 {
+  data: BitStreamV2,
+  checksum: Option<u64>,
+}
+
+impl From<GorillaBlockV2> for GorillaBlock {
+  fn from(v2: GorillaBlockV2) -> Self {
+    Self { data: v2.data.into(), checksum: v2.checksum }
+  }
+}
+
+// the fixint bincode encoding 'bincode::serialize' writes, with nothing left
+// after the value
+fn deserialize_block<T: serde::de::DeserializeOwned>(bytes: &[u8]) -> Result<T, Error> {
+  bincode::options()
+    .with_fixint_encoding()
+    .reject_trailing_bytes()
+    .deserialize(bytes)
+    .map_err(|_| Error::BitReaderError("Malformed serialized block"))
 }
 
 impl GorillaBlock {
+    // the string form is the bitstream optionally followed by the 8 byte checksum
     pub fn new(datastr: &str) -> Self {
-        Self {
-            data: BitStream::new(datastr),
-        }
+        let mut data = BitStream::new(datastr);
+        let trailer = data.split_trailer();
+        let checksum = if trailer.len() == 8 {
+            let mut buf = [0u8; 8];
+            buf.copy_from_slice(&trailer);
+            Some(u64::from_le_bytes(buf))
+        } else {
+            None
+        };
+
+        Self { data, checksum }
     }
     
+    // same as 'new' for the bytes of a block string, but fails on malformed input
+    pub fn from_raw_bytes(bytes: &[u8]) -> Result<Self, Error> {
+        let mut data = BitStream::from_bytes(bytes)?;
+        let trailer = data.split_trailer();
+        let checksum = match trailer.len() {
+            0 => None,
+            8 => {
+                let mut buf = [0u8; 8];
+                buf.copy_from_slice(&trailer);
+                Some(u64::from_le_bytes(buf))
+            },
+            _ => return Err(Error::BitReaderError("Unexpected bytes after the bitstream")),
+        };
+
+        Ok(Self { data, checksum })
+    }
+
     pub fn to_string(&self) -> String {
-        self.data.to_string()
+        let mut datastr = self.data.to_string();
+        if let Some(checksum) = self.checksum {
+            let checksum_bytes = checksum.to_le_bytes();
+            datastr.push_str(unsafe { str::from_utf8_unchecked(&checksum_bytes) });
+        }
+        datastr
+    }
+
+    // the bincode serialized block behind its magic and version, stored as
+    // is rather than as a string
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = BLOCK_MAGIC.to_vec();
+        bytes.push(BLOCK_VERSION);
+        bincode::serialize_into(&mut bytes, self).unwrap();
+        bytes
+    }
+
+    // the block serialized by 'to_bytes', fails on malformed input or an
+    // unknown version
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, Error> {
+        let block = match bytes.strip_prefix(BLOCK_MAGIC) {
+            Some([BLOCK_VERSION, rest @ ..]) => deserialize_block(rest)?,
+            Some([1, rest @ ..]) => deserialize_block::<GorillaBlockV2>(rest)?.into(),
+            Some(_) => return Err(Error::BitReaderError("Unsupported serialized block version")),
+            None => Self::from_unversioned_bytes(bytes)?,
+        };
+        block.data.check_len()?;
+        Ok(block)
+    }
+
+    // a block serialized before the bytes carried a version, tried in each
+    // layout it was written in, newest first
+    fn from_unversioned_bytes(bytes: &[u8]) -> Result<Self, Error> {
+        deserialize_block::<GorillaBlockV2>(bytes).map(Self::from)
+            .or_else(|_| deserialize_block::<GorillaBlockV1>(bytes).map(Self::from))
+            .or_else(|_| deserialize_block::<GorillaBlockV0>(bytes).map(Self::from))
+    }
+
+    // a stored block value, serialized by 'to_bytes' or the bytes of a block
+    // string written before blocks were stored as bytes
+    pub fn from_stored_bytes(bytes: &[u8]) -> Result<Self, Error> {
+        Self::from_bytes(bytes).or_else(|_| Self::from_raw_bytes(bytes))
+    }
+
+    pub fn checksum(&self) -> Option<u64> {
+        self.checksum
+    }
+
+    // the number of values per entry recorded when the block was closed,
+    // None for blocks stored before it was recorded
+    pub fn dim(&self) -> Option<usize> {
+        self.data.dim()
+    }
+
+    // size of the compressed entries in bytes, header included
+    pub fn compressed_bytes(&self) -> usize {
+        self.data.num_bytes()
+    }
+
+    // the number of entries recorded when the block was closed, blocks stored
+    // before the count was recorded report 0, 'num_entries' decodes those
+    pub fn entry_count(&self) -> usize {
+        self.data.entries().unwrap_or(0)
+    }
+
+    // compression statistics for a block of 'dim' values per entry, blocks
+    // without a recorded entry count are decoded to count them
+    pub fn stats(&self, dim: usize) -> BlockStats {
+        let entry_count = match self.data.entries() {
+            Some(entries) => entries,
+            None => self.num_entries(dim).unwrap_or(0),
+        };
+        let uncompressed_bytes = entry_count * (8 + dim * 8);
+        let compressed_bytes = self.compressed_bytes();
+        let ratio = if compressed_bytes == 0 {
+            0.0
+        } else {
+            uncompressed_bytes as f64 / compressed_bytes as f64
+        };
+        BlockStats { entry_count, uncompressed_bytes, compressed_bytes, ratio }
+    }
+
+    // the start time every entry in the block is relative to
+    pub fn header(&self) -> Result<GorillaDateTime, Error> {
+        let mut reader = BitReader::new(self.data.clone());
+        let secs = reader.read(64)? as i64;
+        Ok(*EPOCH + chrono::Duration::seconds(secs))
+    }
+
+    // re-encode the entries of time ordered blocks into as few blocks as
+    // possible, a new block is started whenever an entry would fall
+    // BLOCK_DURATION or more after the header of the current one
+    pub fn concatenate(blocks: &[GorillaBlock], dim: usize) -> Result<Vec<GorillaBlock>, Error> {
+        let mut result = Vec::new();
+        let mut header = match blocks.first() {
+            Some(block) => block.header()?,
+            None => return Ok(result),
+        };
+        let mut writer = GorillaWriterMV::with_vec(header, dim)?;
+
+        for block in blocks {
+            let mut reader = GorillaReaderMV::from_block(block.clone(), dim);
+            while reader.has_next() {
+                let entry = reader.decode_nth(0)?;
+                if entry.time - header >= *BLOCK_DURATION {
+                    header = entry.time;
+                    let full = mem::replace(&mut writer, GorillaWriterMV::with_vec(header, dim)?);
+                    result.push(full.close());
+                }
+                writer.append_entry(entry)?;
+            }
+        }
+
+        result.push(writer.close());
+        Ok(result)
+    }
+
+    // decode 'num_entries' entries and compare their checksum with 'expected_checksum'
+    pub fn validate_checksum(&self, expected_checksum: u64, dim: usize, num_entries: usize) -> Result<bool, Error> {
+        let mut reader = GorillaReaderMV::from_block(self.clone(), dim);
+        let mut hash = FNV_OFFSET_BASIS;
+        for _ in 0..num_entries {
+            let entry = reader.decode_nth(0)?;
+            hash = fnv1a_time(hash, entry.time);
+            hash = fnv1a_values(hash, &entry.values);
+        }
+        Ok(hash == expected_checksum)
+    }
+
+    // export the first 'num_entries' entries in InfluxDB line protocol, one line per entry:
+    // 'measurement,tag_set field_names[0]=values[0],... timestamp_ns'
+    // 'tag_set' is used as given, NaN and infinite values have no line protocol
+    // representation and are left out
+    pub fn to_influx_line_protocol(&self, measurement: &str, tag_set: &str, field_names: &[&str],
+                                   dim: usize, num_entries: usize) -> Result<String, Error> {
+        if field_names.len() != dim {
+            return Err(Error::BadDimensionError);
+        }
+
+        // measurements escape commas and spaces, field keys also escape '='
+        let escape = |s: &str, special: &[char]| -> String {
+            let mut escaped = String::with_capacity(s.len());
+            for c in s.chars() {
+                if special.contains(&c) {
+                    escaped.push('\\');
+                }
+                escaped.push(c);
+            }
+            escaped
+        };
+        let series = if tag_set.is_empty() {
+            escape(measurement, &[',', ' '])
+        } else {
+            format!("{},{}", escape(measurement, &[',', ' ']), tag_set)
+        };
+        let fields: Vec<String> = field_names.iter().map(|f| escape(f, &[',', '=', ' '])).collect();
+
+        let mut reader = GorillaReaderMV::from_block(self.clone(), dim);
+        let mut lines = String::new();
+        for _ in 0..num_entries {
+            let entry = reader.decode_nth(0)?;
+            let field_set: Vec<String> = fields.iter().zip(entry.values.iter())
+                .filter(|(_, v)| v.is_finite())
+                .map(|(f, v)| format!("{}={}", f, v))
+                .collect();
+            if field_set.is_empty() {
+                continue;
+            }
+
+            let nanos = entry.time.timestamp() as i128 * 1_000_000_000
+                + entry.time.timestamp_subsec_nanos() as i128;
+            lines.push_str(&format!("{} {} {}\n", series, field_set.join(","), nanos));
+        }
+        Ok(lines)
+    }
+
+    // the number of entries, fails if the block doesn't decode with 'dim' values per entry
+    pub fn num_entries(&self, dim: usize) -> Result<usize, Error> {
+        let mut reader = GorillaReaderMV::from_block(self.clone(), dim);
+        let mut num_entries = 0;
+        while reader.has_next() {
+            reader.skip_entries(1)?;
+            num_entries += 1;
+        }
+        Ok(num_entries)
+    }
+
+    // the timestamps of every entry in order, without decoding any values
+    pub fn iter_timestamps(&self, dim: usize) -> GorillaTimestampIter {
+        GorillaTimestampIter::new(self.clone(), dim)
+    }
+
+    // decode every timestamp in the block and keep them in entry order
+    pub fn build_timestamp_index(&self, dim: usize) -> Result<GorillaTimestampIndex, Error> {
+        let entry_timestamps = self.iter_timestamps(dim).collect();
+        Ok(GorillaTimestampIndex { entry_timestamps })
+    }
+}
+
+// maps entry numbers to timestamps, timestamps are non-decreasing so an
+// entry number can be found by binary search in O(log n)
+#[derive(Clone, Debug)]
+pub struct GorillaTimestampIndex {
+    entry_timestamps: Vec<GorillaDateTime>,
+}
+
+impl GorillaTimestampIndex {
+    pub fn len(&self) -> usize {
+        self.entry_timestamps.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entry_timestamps.is_empty()
+    }
+
+    pub fn timestamp(&self, entry: usize) -> Option<GorillaDateTime> {
+        self.entry_timestamps.get(entry).copied()
+    }
+
+    // Ok(n) if entry n has exactly this timestamp, otherwise Err(n) where n is
+    // the first entry after the timestamp (same contract as slice::binary_search)
+    pub fn search(&self, time: GorillaDateTime) -> Result<usize, usize> {
+        self.entry_timestamps.binary_search(&time)
     }
 }
 
 const BLOCK_SIZE: usize = 4096;
 
-#[derive(Clone, Copy, Debug)]
+// entries serialize with their time in RFC 3339, which keeps the nanoseconds
+// and any time a block header may hold
+#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
 pub struct Entry {
   time: GorillaDateTime,
   value: f64,
 }
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct MVEntry {
   time: GorillaDateTime,
   values: Vec<f64>,
@@ -65,6 +648,14 @@ impl Entry {
   pub fn new(time: GorillaDateTime, value: f64) -> Self {
     Entry { time, value }
   }
+
+  pub fn time(&self) -> GorillaDateTime {
+    self.time
+  }
+
+  pub fn value(&self) -> f64 {
+    self.value
+  }
 }
 
 impl MVEntry {
@@ -80,6 +671,23 @@ impl MVEntry {
       self.values.clone()
   }
 
+  // the values without cloning them
+  pub fn values_ref(&self) -> &[f64] {
+      &self.values
+  }
+
+  pub fn into_values(self) -> Vec<f64> {
+      self.values
+  }
+
+  // an entry without values yet, with room for 'dim' of them
+  pub fn with_capacity(time: GorillaDateTime, dim: usize) -> Self {
+      MVEntry { time, values: Vec::with_capacity(dim) }
+  }
+
+  pub fn push_value(&mut self, value: f64) {
+      self.values.push(value);
+  }
 }
 
 #[derive(Clone, Serialize, Deserialize, Debug)]
@@ -87,3 +695,418 @@ struct Zeros {
   leading: u8,
   trailing: u8,
 }
+
+#[cfg(test)]
+mod test {
+  use super::*;
+  use crate::gorilla::api::compress_values;
+  use chrono::Duration;
+
+  fn make_block(num_entries: usize, dim: usize) -> GorillaBlock {
+    let mut entries = Vec::new();
+    for i in 0..num_entries {
+      let ts = *EPOCH + Duration::seconds(10 * (i as i64 + 1));
+      entries.push(MVEntry::new(ts, vec![i as f64; dim]));
+    }
+    compress_values(entries, *EPOCH, dim)
+  }
+
+  #[test]
+  fn timestamp_index() {
+    let index = make_block(500, 3).build_timestamp_index(3).unwrap();
+    assert_eq!(index.len(), 500);
+
+    // exact hits
+    assert_eq!(index.search(*EPOCH + Duration::seconds(10)), Ok(0));
+    assert_eq!(index.search(*EPOCH + Duration::seconds(2500)), Ok(249));
+    assert_eq!(index.search(*EPOCH + Duration::seconds(5000)), Ok(499));
+
+    // between two entries, before the first and after the last
+    assert_eq!(index.search(*EPOCH + Duration::seconds(1234)), Err(123));
+    assert_eq!(index.search(*EPOCH), Err(0));
+    assert_eq!(index.search(*EPOCH + Duration::seconds(6000)), Err(500));
+
+    assert_eq!(index.timestamp(249), Some(*EPOCH + Duration::seconds(2500)));
+    assert_eq!(index.timestamp(500), None);
+  }
+
+  #[test]
+  fn influx_line_protocol() {
+    let t0 = *EPOCH + Duration::seconds(1_500_000_000);
+    let mut writer = GorillaWriterMV::with_vec(t0, 2).unwrap().allow_non_finite();
+    assert!(writer.append_entry(MVEntry::new(t0, vec![58.5, 2.0])).is_ok());
+    assert!(writer.append_entry(MVEntry::new(t0 + Duration::seconds(10), vec![-0.25, f64::NAN])).is_ok());
+    let block = writer.close();
+
+    let lines = block.to_influx_line_protocol("cpu", "host=host_0,region=eu-west-1",
+                                              &["usage_user", "usage system"], 2, 2).unwrap();
+    assert_eq!(lines, "cpu,host=host_0,region=eu-west-1 usage_user=58.5,usage\\ system=2 1500000000000000000\n\
+                       cpu,host=host_0,region=eu-west-1 usage_user=-0.25 1500000010000000000\n");
+
+    // every line is '<measurement>[,<tags>] <fields> <timestamp>' with float fields
+    for line in lines.lines() {
+      let parts: Vec<&str> = line.split(' ').collect();
+      let (series, fields, timestamp) = (parts[0], &parts[1..parts.len() - 1].join(" "), parts[parts.len() - 1]);
+      assert!(series.starts_with("cpu,"));
+      assert!(timestamp.parse::<i64>().is_ok());
+      for field in fields.split(',') {
+        let value = field.rsplit('=').next().unwrap();
+        assert!(value.parse::<f64>().unwrap().is_finite());
+      }
+    }
+
+    let no_tags = block.to_influx_line_protocol("cpu", "", &["a", "b"], 2, 1).unwrap();
+    assert_eq!(no_tags, "cpu a=58.5,b=2 1500000000000000000\n");
+    assert!(block.to_influx_line_protocol("cpu", "", &["a"], 2, 2).is_err());
+  }
+
+  #[test]
+  fn iter_timestamps() {
+    let dim = 10;
+    let mut writer = GorillaWriterMV::with_vec(*EPOCH, dim).unwrap();
+    for i in 0..100i64 {
+      // irregular intervals and values with changing leading/trailing zeros
+      let ts = *EPOCH + Duration::seconds(10 * (i + 1) + (i * i) % 7);
+      let values = (0..dim).map(|d| (i as f64 * 1.37).powi(d as i32 % 4) - d as f64).collect();
+      assert!(writer.append_entry(MVEntry::new(ts, values)).is_ok());
+    }
+    let block = writer.close();
+
+    let timestamps: Vec<GorillaDateTime> = block.iter_timestamps(dim).collect();
+    assert_eq!(timestamps.len(), 100);
+    assert!(timestamps.windows(2).all(|w| w[0] < w[1]));
+    for (i, ts) in timestamps.iter().enumerate() {
+      let i = i as i64;
+      assert!(*ts == *EPOCH + Duration::seconds(10 * (i + 1) + (i * i) % 7));
+    }
+  }
+
+  #[test]
+  fn concatenate() {
+    // 10 blocks of 50 entries a minute apart, each covering 3000 seconds
+    let mut blocks = Vec::new();
+    let mut expected = Vec::new();
+    for b in 0..10 {
+      let header = *EPOCH + Duration::seconds(3000 * b);
+      let mut writer = GorillaWriterMV::with_vec(header, 2).unwrap();
+      for i in 0..50 {
+        let entry = MVEntry::new(header + Duration::seconds(60 * i), vec![b as f64, i as f64 * 0.5]);
+        assert!(writer.append_entry(entry.clone()).is_ok());
+        expected.push(entry);
+      }
+      blocks.push(writer.close());
+    }
+
+    // 30000 seconds need 5 blocks of at most 2 hours each
+    let merged = GorillaBlock::concatenate(&blocks, 2).unwrap();
+    assert_eq!(merged.len(), 5);
+    assert_eq!(merged[0].header().unwrap(), *EPOCH);
+
+    let mut entries = Vec::new();
+    for block in &merged {
+      let header = block.header().unwrap();
+      let mut reader = GorillaReaderMV::from_block(block.clone(), 2);
+      while reader.has_next() {
+        let entry = reader.decode_nth(0).unwrap();
+        assert!(entry.time - header < *BLOCK_DURATION);
+        entries.push(entry);
+      }
+    }
+    assert_eq!(entries.len(), expected.len());
+    for (entry, expected) in entries.iter().zip(expected.iter()) {
+      assert_eq!(entry.time, expected.time);
+      assert_eq!(entry.values, expected.values);
+    }
+
+    assert!(GorillaBlock::concatenate(&[], 2).unwrap().is_empty());
+  }
+
+  #[test]
+  fn from_raw_bytes() {
+    let block = make_block(20, 2);
+    let parsed = GorillaBlock::from_raw_bytes(block.to_string().as_bytes()).unwrap();
+    assert_eq!(parsed.to_string(), block.to_string());
+    assert_eq!(parsed.checksum(), None);
+    assert_eq!(parsed.num_entries(2).unwrap(), 20);
+
+    // with a checksum trailer
+    let mut writer = GorillaWriterMV::with_vec(*EPOCH, 1).unwrap();
+    assert!(writer.append_entry(MVEntry::new(*EPOCH + Duration::seconds(10), vec![1.5])).is_ok());
+    assert!(writer.append_entry(MVEntry::new(*EPOCH + Duration::seconds(20), vec![2.5])).is_ok());
+    let block = writer.close_with_checksum();
+    let blockstr = block.to_string();
+    let parsed = GorillaBlock::from_raw_bytes(blockstr.as_bytes()).unwrap();
+    assert_eq!(parsed.to_string(), blockstr);
+    assert_eq!(parsed.checksum(), block.checksum());
+    assert_eq!(parsed.num_entries(1).unwrap(), 2);
+
+    assert!(GorillaBlock::from_raw_bytes(b"").is_err());
+    assert!(GorillaBlock::from_raw_bytes(&blockstr.as_bytes()[..blockstr.len() - 12]).is_err());
+    assert!(GorillaBlock::from_raw_bytes(&blockstr.as_bytes()[..blockstr.len() - 3]).is_err());
+  }
+
+  #[test]
+  fn to_bytes() {
+    // negative values set the high bit of their first byte
+    let mut writer = GorillaWriterMV::with_vec(*EPOCH, 2).unwrap();
+    for i in 0..10 {
+      let entry = MVEntry::new(*EPOCH + Duration::seconds(10 * (i + 1)), vec![-1.5 * i as f64, 1e300]);
+      assert!(writer.append_entry(entry).is_ok());
+    }
+    let block = writer.close_with_checksum();
+    let bytes = block.to_bytes();
+    assert!(bytes.iter().any(|b| *b >= 0x80));
+
+    let parsed = GorillaBlock::from_bytes(&bytes).unwrap();
+    assert_eq!(parsed.to_bytes(), bytes);
+    assert_eq!(parsed.checksum(), block.checksum());
+    assert_eq!(parsed.num_entries(2).unwrap(), 10);
+    assert!(parsed.validate_checksum(block.checksum().unwrap(), 2, 10).unwrap());
+
+    assert!(GorillaBlock::from_bytes(b"").is_err());
+    assert!(GorillaBlock::from_bytes(&bytes[..bytes.len() - 1]).is_err());
+    let mut trailing = bytes.clone();
+    trailing.push(0);
+    assert!(GorillaBlock::from_bytes(&trailing).is_err());
+
+    // stored values read either way, the string form isn't a serialized block
+    let blockstr = block.to_string();
+    assert!(GorillaBlock::from_bytes(blockstr.as_bytes()).is_err());
+    assert_eq!(GorillaBlock::from_stored_bytes(blockstr.as_bytes()).unwrap().to_bytes(), bytes);
+    assert_eq!(GorillaBlock::from_stored_bytes(&bytes).unwrap().to_string(), blockstr);
+  }
+
+  fn expect_versioned_entries(block: GorillaBlock) {
+    assert!(block.validate_checksum(block.checksum().unwrap(), 2, 3).unwrap());
+    let read: Vec<_> = GorillaReaderMV::from_block(block, 2).map(|e| (e.time, e.values)).collect();
+    let expected: Vec<_> = (0..3)
+      .map(|i| (*EPOCH + Duration::seconds(10 * (i + 1)), vec![1.5 * i as f64, -2.0]))
+      .collect();
+    assert_eq!(read, expected);
+  }
+
+  #[test]
+  fn to_bytes_versions() {
+    let mut writer = GorillaWriterMV::with_vec(*EPOCH, 2).unwrap();
+    for i in 0..3 {
+      let entry = MVEntry::new(*EPOCH + Duration::seconds(10 * (i + 1)), vec![1.5 * i as f64, -2.0]);
+      assert!(writer.append_entry(entry).is_ok());
+    }
+    let block = writer.close_with_checksum();
+    let bytes = block.to_bytes();
+    assert_eq!(&bytes[..5], b"GBLK\x02");
+
+    // a version this build doesn't know
+    let mut unknown = bytes.clone();
+    unknown[4] = 3;
+    assert!(GorillaBlock::from_bytes(&unknown).is_err());
+
+    // version 1, from before the stream recorded whether its timestamps are
+    // in milliseconds
+    let v2 = [
+      71, 66, 76, 75, 1, 2, 1, 0, 0, 0, 0, 0, 0, 1, 3, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1, 2, 0, 0, 0, 0, 0,
+      0, 0, 1, 150, 229, 28, 52, 33, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 10, 0, 0, 0, 0, 0, 0, 0,
+      0, 0, 0, 0, 0, 0, 0, 0, 0, 176, 197, 242, 127, 14, 203, 255, 1, 1, 236, 13, 49, 45, 172, 126, 118,
+      92,
+    ];
+    let versioned = GorillaBlock::from_bytes(&v2).unwrap();
+    assert_eq!(versioned.to_bytes(), bytes);
+    expect_versioned_entries(versioned);
+
+    // the same layout written before it carried a version
+    let unversioned = GorillaBlock::from_bytes(&v2[5..]).unwrap();
+    assert_eq!(unversioned.to_bytes(), bytes);
+
+    // written before the stream recorded its dim and crc
+    let v0 = [
+      2, 1, 0, 0, 0, 0, 0, 0, 1, 3, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 33, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
+      0, 0, 0, 10, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 176, 197, 242, 127, 14, 203, 255, 1, 1,
+      236, 13, 49, 45, 172, 126, 118, 92,
+    ];
+    let block = GorillaBlock::from_stored_bytes(&v0).unwrap();
+    assert_eq!(block.dim(), None);
+    expect_versioned_entries(block);
+
+    // and once the dim was recorded, before the crc was
+    let v1 = [
+      2, 1, 0, 0, 0, 0, 0, 0, 1, 3, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1, 2, 0, 0, 0, 0, 0, 0, 0, 33, 0, 0, 0,
+      0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 10, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 176, 197,
+      242, 127, 14, 203, 255, 1, 1, 236, 13, 49, 45, 172, 126, 118, 92,
+    ];
+    let block = GorillaBlock::from_stored_bytes(&v1).unwrap();
+    assert_eq!(block.dim(), Some(2));
+    expect_versioned_entries(block);
+  }
+
+  #[test]
+  fn checksum() {
+    let mut writer = GorillaWriterMV::with_vec(*EPOCH, 1).unwrap();
+    assert!(writer.append_entry(MVEntry::new(*EPOCH + Duration::seconds(10), vec![1.5])).is_ok());
+    assert!(writer.append_entry(MVEntry::new(*EPOCH + Duration::seconds(20), vec![2.5])).is_ok());
+    let block = writer.close_with_checksum();
+    let checksum = block.checksum().unwrap();
+    assert!(block.validate_checksum(checksum, 1, 2).unwrap());
+
+    // the checksum survives the string round trip
+    let block = GorillaBlock::new(&block.to_string());
+    assert_eq!(block.checksum(), Some(checksum));
+    assert!(block.validate_checksum(checksum, 1, 2).unwrap());
+
+    // flip a bit in the first value: after the 16 bytes of bit count, entry
+    // count, dim and crc, 64 header bits and a 14 bit delta come the 64 raw bits of 1.5
+    let mut bytes = block.to_string().into_bytes();
+    bytes[16 + 90 / 8] ^= 1 << (90 % 8);
+    let corrupted = GorillaBlock::new(unsafe { str::from_utf8_unchecked(&bytes) });
+    assert!(matches!(corrupted.validate_checksum(checksum, 1, 2), Err(Error::BlockChecksumMismatch)));
+    // the same goes for blocks without the crc of their bits
+    bytes[3] &= !(1 << 2);
+    bytes.drain(12..16);
+    let corrupted = GorillaBlock::new(unsafe { str::from_utf8_unchecked(&bytes) });
+    assert!(!corrupted.validate_checksum(checksum, 1, 2).unwrap());
+
+    // blocks closed without a checksum don't carry one
+    let mut writer = GorillaWriterMV::with_vec(*EPOCH, 1).unwrap();
+    assert!(writer.append_entry(MVEntry::new(*EPOCH + Duration::seconds(10), vec![1.5])).is_ok());
+    let block = GorillaBlock::new(&writer.close().to_string());
+    assert_eq!(block.checksum(), None);
+  }
+
+  #[test]
+  fn entry_count() {
+    let mut writer = GorillaWriterMV::with_vec(*EPOCH, 2).unwrap();
+    for i in 0..5 {
+      let entry = MVEntry::new(*EPOCH + Duration::seconds(10 * (i + 1)), vec![i as f64, 1.0]);
+      assert!(writer.append_entry(entry).is_ok());
+    }
+    let block = writer.close();
+    assert_eq!(block.entry_count(), 5);
+    assert_eq!(block.compressed_bytes(), block.data.num_bytes());
+
+    // the count is part of the string form
+    let block = GorillaBlock::new(&block.to_string());
+    assert_eq!(block.entry_count(), 5);
+    assert_eq!(block.num_entries(2).unwrap(), 5);
+
+    // the scalar writer counts its entries as well
+    let mut writer = GorillaWriter::with_vec(*EPOCH).unwrap();
+    assert!(writer.append_first(Entry { time: *EPOCH + Duration::seconds(10), value: 1.0 }).is_ok());
+    assert!(writer.append_entry(Entry { time: *EPOCH + Duration::seconds(20), value: 2.0 }).is_ok());
+    assert_eq!(writer.close().entry_count(), 2);
+
+    // strings without a count or dim still read, the count is just unknown
+    let mut writer = GorillaWriterMV::with_vec(*EPOCH, 1).unwrap();
+    assert!(writer.append_entry(MVEntry::new(*EPOCH + Duration::seconds(10), vec![1.5])).is_ok());
+    let mut bytes = writer.body.length().to_le_bytes()[..4].to_vec();
+    bytes.extend(writer.close().to_string().as_bytes()[16..].iter());
+    let block = GorillaBlock::from_raw_bytes(&bytes).unwrap();
+    assert_eq!(block.entry_count(), 0);
+    assert_eq!(block.dim(), None);
+    assert_eq!(block.num_entries(1).unwrap(), 1);
+  }
+
+  #[test]
+  fn block_stats() {
+    // a slowly changing series every 10 seconds
+    let mut entries = Vec::new();
+    for i in 0..500 {
+      let ts = *EPOCH + Duration::seconds(10 * (i + 1));
+      entries.push(MVEntry::new(ts, vec![20.0 + (i / 50) as f64 * 0.5, 100.0]));
+    }
+    let block = compress_values(entries, *EPOCH, 2);
+    let stats = block.stats(2);
+    assert_eq!(stats.entry_count, 500);
+    assert_eq!(stats.uncompressed_bytes, 500 * 24);
+    assert_eq!(stats.compressed_bytes, block.compressed_bytes());
+    assert!(stats.ratio > 1.0);
+
+    // the count is decoded for blocks that didn't record it
+    let mut bytes = block.to_string().into_bytes();
+    bytes[3] &= 0x7f;
+    bytes.drain(4..8);
+    let block = GorillaBlock::from_raw_bytes(&bytes).unwrap();
+    assert_eq!(block.stats(2), stats);
+  }
+
+  #[test]
+  fn entry_accessors() {
+    let time = *EPOCH + Duration::seconds(10);
+    let entry = Entry::new(time, 1.5);
+    assert_eq!((entry.time(), entry.value()), (time, 1.5));
+
+    let mut entry = MVEntry::with_capacity(time, 3);
+    assert!(entry.values_ref().is_empty());
+    for i in 0..3 {
+      entry.push_value(i as f64);
+    }
+    assert_eq!(entry.time(), time);
+    assert_eq!(entry.values_ref(), &[0.0, 1.0, 2.0]);
+    assert_eq!(entry.values(), entry.values_ref());
+    assert_eq!(entry.into_values(), vec![0.0, 1.0, 2.0]);
+  }
+
+  #[test]
+  fn entry_serde() {
+    let times = [*EPOCH, *EPOCH + Duration::nanoseconds(1_500_000_001), *MAX_HEADER];
+    let entries: Vec<MVEntry> = times.iter().enumerate()
+      .map(|(i, time)| MVEntry::new(*time, vec![i as f64, -0.1, f64::MAX]))
+      .collect();
+    let bytes = bincode::serialize(&entries).unwrap();
+    let decoded: Vec<MVEntry> = bincode::deserialize(&bytes).unwrap();
+    assert_eq!(decoded.len(), entries.len());
+    for (entry, expected) in decoded.iter().zip(&entries) {
+      assert_eq!(entry.time(), expected.time());
+      assert_eq!(entry.values_ref(), expected.values_ref());
+    }
+    assert!(bincode::deserialize::<Vec<MVEntry>>(&bytes[..bytes.len() - 1]).is_err());
+
+    let entry = Entry::new(times[1], 2.5);
+    let decoded: Entry = bincode::deserialize(&bincode::serialize(&entry).unwrap()).unwrap();
+    assert_eq!((decoded.time(), decoded.value()), (entry.time(), entry.value()));
+  }
+
+  #[test]
+  fn delta_of_delta_boundaries() {
+    // the edges of every bucket, -64 and -256 only fit the next wider one
+    let dods = [64, -63, -64, 65, 256, -255, -256, 257, 2048, -2047, -2048, 2049, 0];
+    let mut delta = 3000;
+    let mut time = *EPOCH + Duration::seconds(delta);
+    let mut times = vec![time];
+    for dod in dods.iter() {
+      delta += dod;
+      time += Duration::seconds(delta);
+      times.push(time);
+    }
+
+    let mut writer = GorillaWriterMV::with_vec(*EPOCH, 1).unwrap();
+    for (i, time) in times.iter().enumerate() {
+      assert!(writer.append_entry(MVEntry::new(*time, vec![i as f64])).is_ok());
+    }
+    let mut reader = GorillaReaderMV::from_block_auto(writer.close());
+    for time in times.iter() {
+      assert!(reader.try_next_entry().unwrap().time == *time);
+    }
+    assert!(reader.try_next_entry().is_none());
+  }
+
+  #[test]
+  fn header_range() {
+    let before = *EPOCH - Duration::seconds(1);
+    assert!(matches!(GorillaWriter::with_vec(before), Err(Error::BadHeaderError)));
+    assert!(matches!(GorillaWriter::with_vec_millis(before), Err(Error::BadHeaderError)));
+    assert!(matches!(GorillaWriterMV::with_vec(before, 2), Err(Error::BadHeaderError)));
+    assert!(matches!(GorillaWriterMV::with_vec_int(before, 2), Err(Error::BadHeaderError)));
+
+    let after = *MAX_HEADER + Duration::seconds(1);
+    assert!(matches!(GorillaWriter::with_vec(after), Err(Error::BadHeaderError)));
+    assert!(matches!(GorillaWriterMV::with_vec(after, 2), Err(Error::BadHeaderError)));
+    assert!(GorillaWriter::with_vec(*EPOCH).is_ok());
+    assert!(GorillaWriterMV::with_vec(*MAX_HEADER, 2).is_ok());
+
+    // a bad header for the next block leaves the current one as it was
+    let mut writer = GorillaWriterMV::with_vec(*EPOCH, 1).unwrap();
+    assert!(writer.append_entry(MVEntry::new(*EPOCH + Duration::seconds(10), vec![1.0])).is_ok());
+    assert!(matches!(writer.close_and_reset(before), Err(Error::BadHeaderError)));
+    assert!(matches!(writer.reset(before), Err(Error::BadHeaderError)));
+    assert_eq!(writer.close().num_entries(1).unwrap(), 1);
+  }
+}
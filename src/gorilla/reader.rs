@@ -2,6 +2,7 @@ use crate::gorilla::*;
 use chrono::{Duration, TimeZone};
 
 pub struct GorillaReader {
+  precision: TimePrecision,
   entry: Entry,
   prev_entry: Entry,
   prev_diff: Duration,
@@ -10,18 +11,36 @@ pub struct GorillaReader {
 }
 
 impl GorillaReader {
-  fn from_writer(writer: GorillaWriter) -> Self {
-    let block = writer.close();
+  pub fn from_writer(writer: GorillaWriter) -> Self {
+    let precision = writer.precision();
+    Self::with_precision(writer.close(), precision)
+  }
+
+  // read a block in the precision it records, seconds for blocks from
+  // before it was recorded
+  pub fn from_block(block: GorillaBlock) -> Self {
+    let precision = TimePrecision::of_stream(&block.data);
+    Self::with_precision(block, precision)
+  }
+
+  // read a block written by GorillaWriter::with_vec_millis, including one
+  // from before the precision was recorded
+  pub fn from_block_millis(block: GorillaBlock) -> Self {
+    Self::with_precision(block, TimePrecision::Millis)
+  }
+
+  // the block starts with the header, the first delta and the raw first value
+  fn with_precision(block: GorillaBlock, precision: TimePrecision) -> Self {
     let mut reader = BitReader::new(block.data);
 
     let header = {
-      let ts = Duration::seconds(reader.read(64).unwrap() as i64);
+      let ts = precision.duration(reader.read(64).unwrap() as i64);
       chrono::Utc.ymd(1970, 1, 1).and_hms(0, 0, 0) + ts
     };
 
     let time = {
       // always positive diff so should be OK to cast to i64 w/o masking
-      let diff = Duration::seconds(reader.read(14).unwrap() as i64);
+      let diff = precision.duration(reader.read(precision.first_delta_bits() as usize).unwrap() as i64);
       header + diff
     };
 
@@ -33,6 +52,7 @@ impl GorillaReader {
     };
 
     GorillaReader {
+      precision,
       entry: Entry { time, value },
       prev_entry,
       prev_diff: Duration::seconds(0),
@@ -71,7 +91,11 @@ impl GorillaReader {
     // 0b11
     else {
       let leading = self.reader.read(5).unwrap() as u8;
-      let nbits = self.reader.read(6).unwrap() as u8;
+      // 64 meaningful bits do not fit in 6 bits and are stored as 0
+      let nbits = match self.reader.read(6).unwrap() as u8 {
+        0 => 64,
+        n => n,
+      };
       let trailing = 64 - leading - nbits;
       self.prev_zeros = Zeros { leading, trailing };
       let xored = self.reader.read(nbits as usize).unwrap() << trailing;
@@ -82,33 +106,23 @@ impl GorillaReader {
   }
 
   pub fn get_next_time(&mut self) -> GorillaDateTime {
-    let to_dod = |x: u64, shift: u32, max: u64| -> Duration {
-      let d = {
-        if x > max {
-          (x | std::u64::MAX << shift) as i64
-        } else {
-          x as i64
-        }
-      };
-      Duration::seconds(d)
-    };
-
-    let (bits, max) = {
+    let [bits1, bits2, bits3] = self.precision.dod_bucket_bits();
+    let bits = {
       if !self.reader.read_bit().unwrap() {
-        return self.prev_entry.time + self.prev_diff;
+        self.prev_entry.time += self.prev_diff;
+        return self.prev_entry.time;
       } else if !self.reader.read_bit().unwrap() {
-        (7, 64)
+        bits1
       } else if !self.reader.read_bit().unwrap() {
-        (9, 256)
+        bits2
       } else if !self.reader.read_bit().unwrap() {
-        (12, 2048)
+        bits3
       } else {
-        (32, std::i32::MAX as u64)
+        32
       }
     };
 
-    let x = self.reader.read(bits).unwrap();
-    let dod = to_dod(x, bits as u32, max);
+    let dod = self.precision.duration(read_delta_of_delta(&mut self.reader, bits).unwrap());
     let diff = dod + self.prev_diff;
     let time = self.prev_entry.time + diff;
     self.prev_entry.time = time;
@@ -118,7 +132,7 @@ impl GorillaReader {
 }
 
 fn compress_values(mv_entries: Vec<MVEntry>, header: GorillaDateTime, dim: usize) -> GorillaBlock {
-    let mut writer = GorillaWriterMV::with_vec(header, dim);
+    let mut writer = GorillaWriterMV::with_vec(header, dim).unwrap();
     for i in 0..mv_entries.len() {
         assert!(writer.append_entry(mv_entries[i].clone()).is_ok());
     }
@@ -128,7 +142,7 @@ fn compress_values(mv_entries: Vec<MVEntry>, header: GorillaDateTime, dim: usize
 fn retrieve_values(block: GorillaBlock, dim: usize) -> Vec<MVEntry> {
     let mut reader = GorillaReaderMV::from_block(block, dim);
     let mut result = Vec::new();
-    while reader.get_reader().cursor() <= reader.get_reader().length() {
+    while reader.has_next() {
         let ts = reader.get_next_time();
         let values = reader.get_next_values();
         result.push(MVEntry{time: ts, values: values.clone()});
@@ -141,7 +155,7 @@ mod test {
   use super::*;
 
   fn setup_writer() -> GorillaWriter {
-    let mut block = GorillaWriter::with_vec(*EPOCH);
+    let mut block = GorillaWriter::with_vec(*EPOCH).unwrap();
 
     // make first delta 50 minutes (delta of 3000 seconds)
     let ts = *EPOCH + Duration::minutes(50);
@@ -266,4 +280,85 @@ mod test {
       assert!(reader.get_next_value() == 12.0);
     }
   }
+
+  #[test]
+  pub fn millis_round_trip() {
+    // a 200ms series with some jitter, the header itself is sub-second
+    let header = *EPOCH + Duration::seconds(1_600_000_000) + Duration::milliseconds(250);
+    let offsets: Vec<i64> = (0..500).map(|i| 200 * i + [0, 3, -2, 17, 0, 150][i as usize % 6]).collect();
+    let entries: Vec<Entry> = offsets.iter()
+      .map(|ms| Entry::new(header + Duration::milliseconds(100 + ms), *ms as f64 * 0.5))
+      .collect();
+
+    let mut writer = GorillaWriter::with_vec_millis(header).unwrap();
+    assert!(writer.append_first(entries[0]).is_ok());
+    for entry in &entries[1..] {
+      assert!(writer.append_entry(*entry).is_ok());
+    }
+
+    let mut reader = GorillaReader::from_writer(writer);
+    let first = reader.next();
+    assert!(first.time == entries[0].time);
+    assert!(first.value == entries[0].value);
+    for entry in &entries[1..] {
+      assert!(reader.get_next_time() == entry.time);
+      assert!(reader.get_next_value() == entry.value);
+    }
+
+    // seconds precision blocks still truncate
+    let mut writer = GorillaWriter::with_vec(header).unwrap();
+    assert!(writer.append_first(entries[0]).is_ok());
+    let mut reader = GorillaReader::from_writer(writer);
+    assert!(reader.next().time == *EPOCH + Duration::seconds(1_600_000_000));
+  }
+
+  #[test]
+  pub fn millis_from_block() {
+    let header = *EPOCH + Duration::seconds(1_600_000_000) + Duration::milliseconds(250);
+    let entries: Vec<Entry> = (0..50)
+      .map(|i| Entry::new(header + Duration::milliseconds(7 * i + 3), i as f64))
+      .collect();
+    let mut writer = GorillaWriter::with_vec_millis(header).unwrap();
+    assert!(writer.append_first(entries[0]).is_ok());
+    for entry in &entries[1..] {
+      assert!(writer.append_entry(*entry).is_ok());
+    }
+
+    // the stored block records its precision
+    let block = GorillaBlock::from_bytes(&writer.close().to_bytes()).unwrap();
+    let mut reader = GorillaReader::from_block(block.clone());
+    let first = reader.next();
+    assert!(first.time == entries[0].time);
+    for entry in &entries[1..] {
+      assert!(reader.get_next_time() == entry.time);
+      assert!(reader.get_next_value() == entry.value);
+    }
+
+    // which the multi-valued reader doesn't decode
+    assert!(matches!(GorillaReaderMV::try_from_block(block.clone(), 1), Err(Error::BadPrecisionError)));
+    let mut reader = GorillaReaderMV::from_block(block, 1);
+    assert!(matches!(reader.try_get_next_time(), Err(Error::BadPrecisionError)));
+  }
+
+  #[test]
+  pub fn from_block() {
+    let mut writer = setup_writer();
+    let times = [2937, 3000, 3064, 5049];
+    let values = [24.0, 15.0, 12.0, -0.5];
+    for (t, v) in times.iter().zip(values.iter()) {
+      let entry = Entry::new(*EPOCH + Duration::minutes(50) + Duration::seconds(*t), *v);
+      assert!(writer.append_entry(entry).is_ok());
+    }
+
+    // block -> string -> block
+    let blockstr = writer.close().to_string();
+    let mut reader = GorillaReader::from_block(GorillaBlock::new(&blockstr));
+    let first = reader.next();
+    assert!(first.time == *EPOCH + Duration::minutes(50));
+    assert!(first.value == 12.0);
+    for (t, v) in times.iter().zip(values.iter()) {
+      assert!(reader.get_next_time() == *EPOCH + Duration::minutes(50) + Duration::seconds(*t));
+      assert!(reader.get_next_value() == *v);
+    }
+  }
 }
@@ -1,5 +1,54 @@
 use crate::gorilla::*;
-use chrono::{Duration, TimeZone};
+use chrono::Duration;
+
+// how the entries within one resampling window are combined
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum DownsampleMethod {
+  Mean,
+  Min,
+  Max,
+  Last,
+}
+
+impl DownsampleMethod {
+  // fold one more entry's values into the window accumulator
+  fn fold(self, acc: &mut [f64], values: &[f64]) {
+    for (a, v) in acc.iter_mut().zip(values) {
+      *a = match self {
+        DownsampleMethod::Mean => *a + v,
+        DownsampleMethod::Min => a.min(*v),
+        DownsampleMethod::Max => a.max(*v),
+        DownsampleMethod::Last => *v,
+      };
+    }
+  }
+
+  fn finish(self, mut acc: Vec<f64>, count: usize) -> Vec<f64> {
+    if self == DownsampleMethod::Mean {
+      for a in acc.iter_mut() {
+        *a /= count as f64;
+      }
+    }
+    acc
+  }
+}
+
+// where the reader is with the raw first entry of a block, which is decoded
+// as soon as the block is opened
+#[derive(Clone, Copy, PartialEq)]
+enum FirstEntry {
+  Pending,
+  TimeRead,
+  Read,
+  Failed,
+  // the block records a different dim than the reader was opened with
+  WrongDim,
+  // the bits of the block don't match the crc it records
+  BadChecksum,
+  // the block records millisecond timestamps, which this reader doesn't
+  // decode
+  Millis,
+}
 
 pub struct GorillaReaderMV {
   dim: usize,
@@ -7,181 +56,320 @@ pub struct GorillaReaderMV {
   prev_entry: MVEntry,
   prev_diff: Duration,
   prev_zeros: Vec<Zeros>,
+  first: FirstEntry,
+  width: ValueWidth,
+  delta_width: DeltaWidth,
+  encoding: ValueEncoding,
+  // the last delta of every value, only used by DeltaOfDelta
+  prev_value_deltas: Vec<i64>,
   reader: BitReader,
 }
 
 impl GorillaReaderMV {
   pub fn from_writer(writer: GorillaWriterMV) -> Self {
     let dim = writer.dim();
-    let block = writer.close();
-    let mut reader = BitReader::new(block.data);
-
-    let header = {
-      let ts = Duration::seconds(reader.read(64).unwrap() as i64);
-      chrono::Utc.ymd(1970, 1, 1).and_hms(0, 0, 0) + ts
-    };
+    GorillaReaderMV::from_block(writer.close(), dim)
+  }
 
-    let time = {
-      // always positive diff so should be OK to cast to i64 w/o masking
-      let diff = Duration::seconds(reader.read(14).unwrap() as i64);
-      header + diff
-    };
+  // a block that records its dim is read with that dim, one without reads
+  // as unreadable
+  pub fn from_block_auto(block: GorillaBlock) -> Self {
+    match block.dim() {
+      Some(dim) => GorillaReaderMV::from_block(block, dim),
+      None => {
+        let mut reader = GorillaReaderMV::from_block(block, 0);
+        reader.first = FirstEntry::Failed;
+        reader
+      }
+    }
+  }
 
-    let mut values: Vec<f64> = Vec::new();
-    for _i in 0..dim {
-      values.push(f64::from_le_bytes(reader.read(64).unwrap().to_le_bytes()))
+  // same as 'from_block', but fails if 'dim' conflicts with the one the
+  // block records
+  // or if its bits don't match the crc it records, or if its timestamps are
+  // in milliseconds
+  pub fn try_from_block(block: GorillaBlock, dim: usize) -> Result<Self, Error> {
+    match block.dim() {
+      Some(stored) if stored != dim => Err(Error::BadDimensionError),
+      _ if block.data.millis() => Err(Error::BadPrecisionError),
+      _ => {
+        block.data.verify_crc()?;
+        Ok(GorillaReaderMV::from_block(block, dim))
+      }
     }
+  }
+
+  // a 'dim' that conflicts with the one the block records gives a reader
+  // that reports BadDimensionError rather than decoding garbage, as do bits
+  // that don't match the block's crc with BlockChecksumMismatch and
+  // millisecond timestamps with BadPrecisionError
+  pub fn from_block(block: GorillaBlock, dim: usize) -> Self {
+    let wrong_dim = matches!(block.dim(), Some(stored) if stored != dim);
+    let bad_checksum = block.data.verify_crc().is_err();
+    let millis = block.data.millis();
+    let width = ValueWidth::of_stream(&block.data);
+    let delta_width = DeltaWidth::of_stream(&block.data);
+    let encoding = ValueEncoding::of_stream(&block.data);
+    let mut reader = BitReader::new(block.data);
+
+    // a stream too short for the header has no readable entries
+    let (header, first) = match reader.read(64) {
+      Ok(ts) => (*EPOCH + Duration::seconds(ts as i64), FirstEntry::Read),
+      Err(_) => (*EPOCH, FirstEntry::Failed),
+    };
 
     let prev_entry = MVEntry {
       time: header,
       values: vec![0.0; dim],
     };
 
-    GorillaReaderMV {
+    let mut mv_reader = GorillaReaderMV {
       dim: dim,
-      entry: MVEntry { time, values },
+      entry: prev_entry.clone(),
       prev_entry,
       prev_diff: Duration::seconds(0),
-      prev_zeros: vec![
-        Zeros {
-          leading: 32,
-          trailing: 32,
-        };
-        dim
-      ],
+      prev_zeros: vec![width.initial_zeros(); dim],
+      first,
+      width,
+      delta_width,
+      encoding,
+      prev_value_deltas: vec![0; dim],
       reader,
-    }
-  }
-
-  pub fn from_block(block: GorillaBlock, dim: usize) -> Self {
-      let mut reader = BitReader::new(block.data);
-
-      let header = {
-        let ts = Duration::seconds(reader.read(64).unwrap() as i64);
-        chrono::Utc.ymd(1970, 1, 1).and_hms(0, 0, 0) + ts
-      };
+    };
 
-/*
-      let time = {
-        // always positive diff so should be OK to cast to i64 w/o masking
-        let diff = Duration::seconds(reader.read(14).unwrap() as i64);
-        header + diff
+    // a block without entries is only a header
+    if wrong_dim {
+      mv_reader.first = FirstEntry::WrongDim;
+    } else if bad_checksum {
+      mv_reader.first = FirstEntry::BadChecksum;
+    } else if millis {
+      mv_reader.first = FirstEntry::Millis;
+    } else if first == FirstEntry::Read && mv_reader.reader.cursor() < mv_reader.reader.length() {
+      mv_reader.first = match mv_reader.read_first() {
+        Ok(()) => FirstEntry::Pending,
+        Err(_) => FirstEntry::Failed,
       };
+    }
+    mv_reader
+  }
 
+  // the first entry is written by 'append_first': a 14 bit (27 bit in wide
+  // blocks) delta from the header followed by the raw values
+  fn read_first(&mut self) -> Result<(), Error> {
+    // always positive diff so should be OK to cast to i64 w/o masking
+    let diff = Duration::seconds(self.reader.read(self.delta_width.first_delta_bits() as usize)? as i64);
 
-      let mut values: Vec<f64> = Vec::new();
-      for _i in 0..dim {
-        values.push(f64::from_le_bytes(reader.read(64).unwrap().to_le_bytes()));
-        println!("Read value");
-      }
-      */
+    let mut values: Vec<f64> = Vec::with_capacity(self.dim);
+    for _i in 0..self.dim {
+      let bits = self.reader.read(self.width.bits() as usize)?;
+      values.push(self.width.value_of(bits));
+    }
 
-      let prev_entry = MVEntry {
-        time: header,
-        values: vec![0.0; dim],
-      };
+    self.prev_diff = diff;
+    self.prev_entry = MVEntry {
+      time: self.prev_entry.time + diff,
+      values,
+    };
+    self.entry = self.prev_entry.clone();
+    Ok(())
+  }
 
-      GorillaReaderMV {
-        dim: dim,
-        entry: MVEntry { time: header, values: vec![0.0; dim] },
-        prev_entry,
-        prev_diff: Duration::seconds(0),
-        prev_zeros: vec![
-          Zeros {
-            leading: 32,
-            trailing: 32,
-          };
-          dim
-        ],
-        reader,
-      }
+  // whether there is an entry left to read, a block with an unreadable first
+  // entry has one so that reading it reports the error
+  pub fn has_next(&self) -> bool {
+    match self.first {
+      FirstEntry::Read => self.reader.cursor() < self.reader.length(),
+      _ => true,
+    }
   }
 
   pub fn get_reader(&self) -> &BitReader {
       &self.reader
   }
 
+  // the leading/trailing zero windows after the entries read so far, the
+  // writer that produced them ends up in the same state
+  pub(super) fn prev_zeros(&self) -> &[Zeros] {
+    &self.prev_zeros
+  }
+
+  // the last delta of every value for integer blocks
+  pub(super) fn prev_value_deltas(&self) -> &[i64] {
+    &self.prev_value_deltas
+  }
+
+  // the first entry of the block, or the last one decoded once reading has
+  // moved past it
   pub fn next(&mut self) -> MVEntry {
-    let entry_time = self.entry.time;
-    self.prev_diff = entry_time - self.prev_entry.time;
-    self.prev_entry = MVEntry {
-      time: entry_time,
-      values: self.entry.values.clone(),
-    };
-    MVEntry {
-      time: entry_time,
-      values: self.entry.values.clone(),
+    if !matches!(self.first, FirstEntry::Failed | FirstEntry::WrongDim | FirstEntry::BadChecksum | FirstEntry::Millis) {
+      self.first = FirstEntry::Read;
     }
+    self.entry.clone()
   }
 
   pub fn get_next_values(&mut self) -> Vec<f64> {
-    let to_f64 = |x: u64| -> f64 { f64::from_le_bytes(x.to_le_bytes()) };
-    let to_u64 = |x: f64| -> u64 { u64::from_le_bytes(x.to_le_bytes()) };
+    self.try_get_next_values().unwrap()
+  }
 
-    let mut values: Vec<f64> = vec![0.0; self.dim];
+  // same as 'get_next_values', but a truncated or corrupt block is reported
+  // rather than panicking
+  pub fn try_get_next_values(&mut self) -> Result<Vec<f64>, Error> {
+    self.read_next_values()?;
+    Ok(self.prev_entry.values.clone())
+  }
+
+  // decode the next set of values into prev_entry.values in place
+  fn read_next_values(&mut self) -> Result<(), Error> {
+    if self.take_first_values()? {
+      return Ok(());
+    }
+    if self.encoding == ValueEncoding::DeltaOfDelta {
+      return self.read_int_values();
+    }
+    let width = self.width;
+    let to_f64 = |x: u64| -> f64 { width.value_of(x) };
+    let to_u64 = |x: f64| -> u64 { width.to_bits(x) };
 
     for i in 0..self.dim {
-      // 0b0
-      if !self.reader.read_bit().unwrap() {
-        values[i] = self.prev_entry.values[i]
+      // 0b0, same as the previous value
+      if !self.reader.read_bit()? {
+        continue;
       }
       // 0b10
-      else if !self.reader.read_bit().unwrap() {
+      else if !self.reader.read_bit()? {
         let Zeros { leading, trailing } = self.prev_zeros[i];
-        let nbits = 64 - leading - trailing;
-        let xored = self.reader.read(nbits as usize).unwrap() << trailing;
-        let val = to_f64(to_u64(self.prev_entry.values[i]) ^ xored);
-        self.prev_entry.values[i] = val;
-        values[i] = val;
+        let nbits = width.bits() - leading - trailing;
+        let xored = self.reader.read(nbits as usize)? << trailing;
+        self.prev_entry.values[i] = to_f64(to_u64(self.prev_entry.values[i]) ^ xored);
       }
       // 0b11
       else {
-        let leading = self.reader.read(5).unwrap() as u8;
-        let nbits = self.reader.read(6).unwrap() as u8;
-        let trailing = 64 - leading - nbits;
+        let Zeros { leading, trailing } = self.read_window()?;
+        let nbits = width.bits() - leading - trailing;
         self.prev_zeros[i] = Zeros { leading, trailing };
-        let xored = self.reader.read(nbits as usize).unwrap() << trailing;
-        let val = to_f64(to_u64(self.prev_entry.values[i]) ^ xored);
-        self.prev_entry.values[i] = val;
-        values[i] = val
+        let xored = self.reader.read(nbits as usize)? << trailing;
+        self.prev_entry.values[i] = to_f64(to_u64(self.prev_entry.values[i]) ^ xored);
       }
     }
-    values
+    Ok(())
   }
 
-  pub fn get_next_time(&mut self) -> GorillaDateTime {
-    let to_dod = |x: u64, shift: u32, max: u64| -> Duration {
-      let d = {
-        if x > max {
-          (x | std::u64::MAX << shift) as i64
-        } else {
-          x as i64
-        }
-      };
-      Duration::seconds(d)
+  // values of integer blocks, see 'GorillaWriterMV::append_int_values'
+  fn read_int_values(&mut self) -> Result<(), Error> {
+    for i in 0..self.dim {
+      if self.reader.read_bit()? {
+        let delta_of_delta = unzigzag(self.reader.read_varint()?);
+        self.prev_value_deltas[i] = self.prev_value_deltas[i].wrapping_add(delta_of_delta);
+      }
+      let value = (self.prev_entry.values[i] as i64).wrapping_add(self.prev_value_deltas[i]);
+      self.prev_entry.values[i] = value as f64;
+    }
+    Ok(())
+  }
+
+  // move past the next set of values without decoding them, only the
+  // leading/trailing zero state is kept up to date: timestamps can still be
+  // read afterwards but values decoded after this are meaningless. Integer
+  // blocks are decoded anyway, their state is the values themselves
+  pub fn skip_values(&mut self) -> Result<(), Error> {
+    if self.take_first_values()? {
+      return Ok(());
+    }
+    if self.encoding == ValueEncoding::DeltaOfDelta {
+      return self.read_int_values();
+    }
+    for i in 0..self.dim {
+      // 0b0, same as the previous value
+      if !self.reader.read_bit()? {
+        continue;
+      }
+      // 0b10
+      else if !self.reader.read_bit()? {
+        let Zeros { leading, trailing } = self.prev_zeros[i];
+        self.reader.skip((self.width.bits() - leading - trailing) as usize)?;
+      }
+      // 0b11
+      else {
+        let Zeros { leading, trailing } = self.read_window()?;
+        self.prev_zeros[i] = Zeros { leading, trailing };
+        self.reader.skip((self.width.bits() - leading - trailing) as usize)?;
+      }
+    }
+    Ok(())
+  }
+
+  // the leading zeros and meaningful bit count following a 0b11 control
+  // word, a corrupt block can hold a window wider than the value
+  fn read_window(&mut self) -> Result<Zeros, Error> {
+    let leading = self.reader.read(self.width.leading_bits() as usize)? as u8;
+    // a full width of meaningful bits doesn't fit and is stored as 0
+    let nbits = match self.reader.read(self.width.nbits_bits() as usize)? as u8 {
+      0 => self.width.bits(),
+      n => n,
     };
+    if leading as u32 + nbits as u32 > self.width.bits() as u32 {
+      return Err(Error::BitReaderError("Value window wider than the value"));
+    }
+    Ok(Zeros { leading, trailing: self.width.bits() - leading - nbits })
+  }
 
-    let (bits, max) = {
-      if !self.reader.read_bit().unwrap() {
-        return self.prev_entry.time + self.prev_diff;
-      } else if !self.reader.read_bit().unwrap() {
-        (7, 64)
-      } else if !self.reader.read_bit().unwrap() {
-        (9, 256)
-      } else if !self.reader.read_bit().unwrap() {
-        (12, 2048)
+  // the values of the first entry are already in prev_entry, true if they
+  // were not handed out yet
+  fn take_first_values(&mut self) -> Result<bool, Error> {
+    match self.first {
+      FirstEntry::Pending | FirstEntry::TimeRead => {
+        self.first = FirstEntry::Read;
+        Ok(true)
+      }
+      FirstEntry::Read => Ok(false),
+      FirstEntry::Failed => Err(Error::BitReaderError("Unreadable first entry")),
+      FirstEntry::WrongDim => Err(Error::BadDimensionError),
+      FirstEntry::BadChecksum => Err(Error::BlockChecksumMismatch),
+      FirstEntry::Millis => Err(Error::BadPrecisionError),
+    }
+  }
+
+  pub fn get_next_time(&mut self) -> GorillaDateTime {
+    self.try_get_next_time().unwrap()
+  }
+
+  // same as 'get_next_time', but a truncated or corrupt block is reported
+  // rather than panicking
+  pub fn try_get_next_time(&mut self) -> Result<GorillaDateTime, Error> {
+    match self.first {
+      FirstEntry::Pending => {
+        self.first = FirstEntry::TimeRead;
+        return Ok(self.prev_entry.time);
+      }
+      FirstEntry::TimeRead => return Err(Error::BitReaderError("Values of the first entry not read")),
+      FirstEntry::Read => {}
+      FirstEntry::Failed => return Err(Error::BitReaderError("Unreadable first entry")),
+      FirstEntry::WrongDim => return Err(Error::BadDimensionError),
+      FirstEntry::BadChecksum => return Err(Error::BlockChecksumMismatch),
+      FirstEntry::Millis => return Err(Error::BadPrecisionError),
+    }
+
+    let bits = {
+      if !self.reader.read_bit()? {
+        self.prev_entry.time += self.prev_diff;
+        return Ok(self.prev_entry.time);
+      } else if !self.reader.read_bit()? {
+        7
+      } else if !self.reader.read_bit()? {
+        9
+      } else if !self.reader.read_bit()? {
+        12
       } else {
-        (32, std::i32::MAX as u64)
+        32
       }
     };
 
-    let x = self.reader.read(bits).unwrap();
-    let dod = to_dod(x, bits as u32, max);
+    let dod = Duration::seconds(read_delta_of_delta(&mut self.reader, bits)?);
     let diff = dod + self.prev_diff;
     let time = self.prev_entry.time + diff;
     self.prev_entry.time = time;
     self.prev_diff = diff;
-    time
+    Ok(time)
   }
 
   pub fn get_next_entry(&mut self) -> MVEntry {
@@ -196,6 +384,164 @@ impl GorillaReaderMV {
       values: values.clone(),
     }
   }
+
+  // the next entry, or None once every written bit has been read (the zero
+  // padding of the last byte is not part of the stream) or the rest of the
+  // stream doesn't decode
+  pub fn try_next_entry(&mut self) -> Option<MVEntry> {
+    if !self.has_next() {
+      return None;
+    }
+    self.decode_nth(0).ok()
+  }
+
+  // move past the next n entries, values are XORed against the previous ones
+  // so they are decoded in place (without allocating) rather than skipped to
+  // keep the entries after them readable
+  pub fn skip_entries(&mut self, n: usize) -> Result<(), Error> {
+    for _ in 0..n {
+      self.try_get_next_time()?;
+      self.read_next_values()?;
+    }
+    Ok(())
+  }
+
+  // advance the reader n + 1 entries from its current position and return the
+  // last one, the first n entries only update the decoder state in place
+  pub fn decode_nth(&mut self, n: usize) -> Result<MVEntry, Error> {
+    self.skip_entries(n)?;
+    let time = self.try_get_next_time()?;
+    self.read_next_values()?;
+    self.entry = MVEntry {
+      time,
+      values: self.prev_entry.values.clone(),
+    };
+    Ok(self.entry.clone())
+  }
+
+  // advance to the first entry with a time at or after 't' and return it,
+  // None if the block ends (or is malformed) before one, the entries passed
+  // only update the decoder state in place, so seeking only goes forward
+  pub fn seek_to_time(&mut self, t: GorillaDateTime) -> Option<MVEntry> {
+    while self.has_next() {
+      let time = self.try_get_next_time().ok()?;
+      self.read_next_values().ok()?;
+      if time >= t {
+        self.entry = MVEntry {
+          time,
+          values: self.prev_entry.values.clone(),
+        };
+        return Some(self.entry.clone());
+      }
+    }
+    None
+  }
+
+  // decode the next 'num_entries' entries column-wise: their times and, for
+  // each dimension, its values across them, panics like 'get_next_entry' if
+  // the block holds fewer entries
+  pub fn decode_columns(mut self, num_entries: usize) -> (Vec<GorillaDateTime>, Vec<Vec<f64>>) {
+    let mut times = Vec::with_capacity(num_entries);
+    let mut columns = vec![Vec::with_capacity(num_entries); self.dim];
+    for _ in 0..num_entries {
+      times.push(self.get_next_time());
+      self.read_next_values().unwrap();
+      for (column, value) in columns.iter_mut().zip(&self.prev_entry.values) {
+        column.push(*value);
+      }
+    }
+    (times, columns)
+  }
+
+  // decode the remaining entries and combine them per window, windows are
+  // aligned to multiples of 'window' since the epoch and each output entry is
+  // stamped with its window start, windows without entries are skipped
+  pub fn window_resample(&mut self, window: Duration, dim: usize, method: DownsampleMethod)
+    -> Result<Vec<MVEntry>, Error>
+  {
+    if dim != self.dim {
+      return Err(Error::BadDimensionError);
+    }
+    let entries = std::iter::from_fn(|| if self.has_next() { Some(self.decode_nth(0)) } else { None });
+    resample_entries(entries, window, method)
+  }
+}
+
+// combine entries in time order per window like 'window_resample' does with
+// the entries of a block
+pub(crate) fn resample_entries(entries: impl Iterator<Item = Result<MVEntry, Error>>, window: Duration,
+                               method: DownsampleMethod) -> Result<Vec<MVEntry>, Error>
+{
+  let width = window.num_seconds();
+  if width <= 0 {
+    return Err(Error::BadWindowError);
+  }
+
+  let mut result = Vec::new();
+  // (window start, accumulated values, number of entries)
+  let mut current: Option<(GorillaDateTime, Vec<f64>, usize)> = None;
+
+  for entry in entries {
+    let entry = entry?;
+    let secs = entry.time.timestamp();
+    let start = *EPOCH + Duration::seconds(secs - secs.rem_euclid(width));
+
+    match current {
+      Some((curr_start, ref mut acc, ref mut count)) if curr_start == start => {
+        method.fold(acc, &entry.values);
+        *count += 1;
+      }
+      _ => {
+        if let Some((curr_start, acc, count)) = current.take() {
+          result.push(MVEntry::new(curr_start, method.finish(acc, count)));
+        }
+        current = Some((start, entry.values, 1));
+      }
+    }
+  }
+
+  if let Some((curr_start, acc, count)) = current {
+    result.push(MVEntry::new(curr_start, method.finish(acc, count)));
+  }
+  Ok(result)
+}
+
+// the entries of the block in order, the same as calling 'try_next_entry'
+// until it returns None
+impl Iterator for GorillaReaderMV {
+  type Item = MVEntry;
+
+  fn next(&mut self) -> Option<Self::Item> {
+    self.try_next_entry()
+  }
+}
+
+// iterates over the timestamps of a block, value bits are skipped rather
+// than decoded
+pub struct GorillaTimestampIter {
+  reader: GorillaReaderMV,
+}
+
+impl GorillaTimestampIter {
+  pub fn new(block: GorillaBlock, dim: usize) -> Self {
+    GorillaTimestampIter {
+      reader: GorillaReaderMV::from_block(block, dim),
+    }
+  }
+}
+
+impl Iterator for GorillaTimestampIter {
+  type Item = GorillaDateTime;
+
+  fn next(&mut self) -> Option<Self::Item> {
+    if !self.reader.has_next() {
+      return None;
+    }
+
+    let time = self.reader.try_get_next_time().ok()?;
+    self.reader.skip_values().ok()?;
+    Some(time)
+  }
 }
 
 #[cfg(test)]
@@ -215,7 +561,7 @@ mod test {
   }
 
   fn setup_writer() -> GorillaWriterMV {
-    let mut block = GorillaWriterMV::with_vec(*EPOCH, 10);
+    let mut block = GorillaWriterMV::with_vec(*EPOCH, 10).unwrap();
 
     // make first delta 50 minutes (delta of 3000 seconds)
     let ts = *EPOCH + Duration::minutes(50);
@@ -401,4 +747,431 @@ mod test {
     assert!(is_all_same(&entry2.values, &values2));
 
   }
+
+  #[test]
+  pub fn decode_nth() {
+    let dim = 4;
+    let mut entries = Vec::new();
+    for i in 0..1000 {
+      let ts = *EPOCH + Duration::seconds(10 * (i + 1));
+      let x = i as f64;
+      entries.push(MVEntry::new(ts, vec![x, x * 2.5, (x / 7.0).sin(), 42.0]));
+    }
+    let block = crate::gorilla::api::compress_values(entries.clone(), *EPOCH, dim);
+
+    for n in [0, 500, 999] {
+      let mut reader = GorillaReaderMV::from_block(block.clone(), dim);
+      let entry = reader.decode_nth(n).unwrap();
+      assert!(entry.time == entries[n].time);
+      assert!(is_all_same(&entry.values, &entries[n].values));
+    }
+
+    // decode_nth advances from the current position
+    let mut reader = GorillaReaderMV::from_block(block.clone(), dim);
+    reader.decode_nth(9).unwrap();
+    let entry = reader.decode_nth(0).unwrap();
+    assert!(entry.time == entries[10].time);
+    assert!(is_all_same(&entry.values, &entries[10].values));
+
+    // past the end of the block
+    let mut reader = GorillaReaderMV::from_block(block, dim);
+    assert!(reader.decode_nth(1000).is_err());
+  }
+
+  #[test]
+  pub fn seek_to_time() {
+    let dim = 2;
+    let entries: Vec<MVEntry> = (0..100)
+      .map(|i| MVEntry::new(*EPOCH + Duration::seconds(10 * (i + 1)), vec![i as f64, (i as f64).sqrt()]))
+      .collect();
+    let block = crate::gorilla::api::compress_values(entries.clone(), *EPOCH, dim);
+
+    // an exact timestamp
+    let mut reader = GorillaReaderMV::from_block(block.clone(), dim);
+    let entry = reader.seek_to_time(*EPOCH + Duration::seconds(500)).unwrap();
+    assert!(entry.time == entries[49].time);
+    assert!(is_all_same(&entry.values, &entries[49].values));
+
+    // between two samples, the reader goes on from the entry found
+    let entry = reader.seek_to_time(*EPOCH + Duration::seconds(625)).unwrap();
+    assert!(entry.time == entries[62].time);
+    assert!(is_all_same(&entry.values, &entries[62].values));
+    let entry = reader.try_next_entry().unwrap();
+    assert!(is_all_same(&entry.values, &entries[63].values));
+
+    // a time already passed returns the next entry
+    let entry = reader.seek_to_time(*EPOCH).unwrap();
+    assert!(entry.time == entries[64].time);
+
+    // past the last entry
+    assert!(reader.seek_to_time(*EPOCH + Duration::seconds(1001)).is_none());
+    assert!(!reader.has_next());
+    let mut reader = GorillaReaderMV::from_block(block, dim);
+    assert!(reader.seek_to_time(*EPOCH + Duration::seconds(1000)).unwrap().time == entries[99].time);
+  }
+
+  #[test]
+  pub fn decode_columns() {
+    let dim = 4;
+    let entries: Vec<MVEntry> = (0..500).map(|i| {
+      let x = i as f64;
+      MVEntry::new(*EPOCH + Duration::seconds(10 * (i + 1)), vec![x, x * 2.5, (x / 7.0).sin(), 42.0])
+    }).collect();
+    let block = crate::gorilla::api::compress_values(entries.clone(), *EPOCH, dim);
+
+    let rows = crate::gorilla::api::retrieve_values(block.clone(), dim, entries.len());
+    let (times, columns) = GorillaReaderMV::from_block(block.clone(), dim).decode_columns(entries.len());
+    assert_eq!(times, rows.iter().map(|row| row.time).collect::<Vec<_>>());
+    assert_eq!(columns.len(), dim);
+    for (j, column) in columns.iter().enumerate() {
+      assert_eq!(*column, rows.iter().map(|row| row.values[j]).collect::<Vec<_>>());
+    }
+
+    // a prefix of the block
+    let (times, columns) = GorillaReaderMV::from_block(block, dim).decode_columns(10);
+    assert_eq!(times.len(), 10);
+    assert!(columns.iter().all(|column| column.len() == 10));
+    assert_eq!(columns[1][9], entries[9].values[1]);
+  }
+
+  #[test]
+  pub fn from_block_first_entry() {
+    let entries = vec![
+      MVEntry::new(*EPOCH + Duration::minutes(24), vec![1.0, 2.0, 3.0]),
+      MVEntry::new(*EPOCH + Duration::minutes(52), vec![13.0, 12.0, 35.5]),
+    ];
+    let block = crate::gorilla::api::compress_values(entries.clone(), *EPOCH, 3);
+
+    let mut reader = GorillaReaderMV::from_block(block, 3);
+    let first = reader.next();
+    assert!(first.time == entries[0].time);
+    assert!(is_all_same(&first.values, &entries[0].values));
+    let second = reader.get_next_entry();
+    assert!(second.time == entries[1].time);
+    assert!(is_all_same(&second.values, &entries[1].values));
+    assert!(!reader.has_next());
+  }
+
+  #[test]
+  pub fn try_next_entry() {
+    let entries = vec![
+      MVEntry::new(*EPOCH + Duration::seconds(10), vec![1.0, 2.0]),
+      MVEntry::new(*EPOCH + Duration::seconds(20), vec![1.5, 2.0]),
+      MVEntry::new(*EPOCH + Duration::seconds(35), vec![-7.25, 1e10]),
+    ];
+    let block = crate::gorilla::api::compress_values(entries.clone(), *EPOCH, 2);
+
+    let mut reader = GorillaReaderMV::from_block(block, 2);
+    let mut decoded = Vec::new();
+    while let Some(entry) = reader.try_next_entry() {
+      decoded.push(entry);
+    }
+    assert_eq!(decoded.len(), 3);
+    for (entry, expected) in decoded.iter().zip(entries.iter()) {
+      assert!(entry.time == expected.time);
+      assert!(is_all_same(&entry.values, &expected.values));
+    }
+    assert!(reader.try_next_entry().is_none());
+  }
+
+  #[test]
+  pub fn iterate_entries() {
+    let entries: Vec<MVEntry> = (0..50)
+      .map(|i| MVEntry::new(*EPOCH + Duration::seconds(10 * (i + 1)), vec![i as f64 * 0.25, 3.0]))
+      .collect();
+    let block = crate::gorilla::api::compress_values(entries.clone(), *EPOCH, 2);
+
+    let decoded: Vec<MVEntry> = GorillaReaderMV::from_block(block.clone(), 2).collect();
+    assert_eq!(decoded.len(), entries.len());
+    for (entry, expected) in decoded.iter().zip(entries.iter()) {
+      assert!(entry.time == expected.time);
+      assert!(is_all_same(&entry.values, &expected.values));
+    }
+
+    // filtering on time stops at the first entry past the cutoff
+    let cutoff = *EPOCH + Duration::seconds(100);
+    let reader = GorillaReaderMV::from_block(block.clone(), 2);
+    let early: Vec<MVEntry> = reader.take_while(|entry| entry.time <= cutoff).collect();
+    assert_eq!(early.len(), 10);
+
+    // the first entry handed out by the inherent 'next' isn't repeated
+    let mut reader = GorillaReaderMV::from_block(block, 2);
+    assert!(reader.next().time == entries[0].time);
+    let rest: Vec<MVEntry> = reader.collect();
+    assert_eq!(rest.len(), entries.len() - 1);
+    assert!(rest[0].time == entries[1].time);
+  }
+
+  #[test]
+  pub fn truncated_block() {
+    let entries: Vec<MVEntry> = (0..10)
+      .map(|i| MVEntry::new(*EPOCH + Duration::seconds(10 * (i + 1)), vec![i as f64 * 1.7, -(i as f64)]))
+      .collect();
+    let block = crate::gorilla::api::compress_values(entries.clone(), *EPOCH, 2);
+
+    // the string form is a 16 byte length, count, dim and crc, then the stream
+    let blockstr = block.to_string();
+    let truncate = |len: usize| {
+      GorillaBlock::new(unsafe { std::str::from_utf8_unchecked(&blockstr.as_bytes()[..len]) })
+    };
+
+    // the last two bytes are cut off: the leading entries still decode
+    // and the reader errors rather than panicking once it runs out
+    let mut reader = GorillaReaderMV::from_block(truncate(16 + block.compressed_bytes() - 2), 2);
+    let mut decoded = 0;
+    let err = loop {
+      let time = match reader.try_get_next_time() {
+        Ok(time) => time,
+        Err(e) => break e,
+      };
+      match reader.try_get_next_values() {
+        Ok(values) => {
+          assert!(time == entries[decoded].time);
+          assert!(is_all_same(&values, &entries[decoded].values));
+          decoded += 1;
+        }
+        Err(e) => break e,
+      }
+    };
+    assert!(decoded > 0 && decoded < entries.len());
+    assert!(matches!(err, Error::BitReaderError(_)));
+
+    // cut off inside the header
+    let mut reader = GorillaReaderMV::from_block(truncate(16 + 4), 2);
+    assert!(reader.has_next());
+    assert!(matches!(reader.try_get_next_time(), Err(Error::BitReaderError(_))));
+    assert!(matches!(reader.try_get_next_values(), Err(Error::BitReaderError(_))));
+  }
+
+  #[test]
+  pub fn skip_entries() {
+    let dim = 3;
+    let mut entries = Vec::new();
+    for i in 0..20 {
+      let ts = *EPOCH + Duration::seconds(10 * (i + 1) + i % 3);
+      let x = i as f64;
+      entries.push(MVEntry::new(ts, vec![x, x * 1.25, 7.0]));
+    }
+    let block = crate::gorilla::api::compress_values(entries.clone(), *EPOCH, dim);
+
+    let mut reader = GorillaReaderMV::from_block(block.clone(), dim);
+    assert!(reader.skip_entries(5).is_ok());
+    for expected in &entries[5..8] {
+      let entry = reader.get_next_entry();
+      assert!(entry.time == expected.time);
+      assert!(is_all_same(&entry.values, &expected.values));
+    }
+
+    // skipping only the values still leaves the timestamps readable
+    let mut reader = GorillaReaderMV::from_block(block.clone(), dim);
+    for expected in &entries {
+      assert!(reader.get_next_time() == expected.time);
+      assert!(reader.skip_values().is_ok());
+    }
+    assert!(reader.skip_values().is_err());
+
+    let mut reader = GorillaReaderMV::from_block(block, dim);
+    assert!(reader.skip_entries(21).is_err());
+  }
+
+  #[test]
+  pub fn window_resample() {
+    // 600 entries 10 seconds apart, starting on a minute boundary
+    let mut entries = Vec::new();
+    for i in 0..600 {
+      let ts = *EPOCH + Duration::seconds(10 * i);
+      entries.push(MVEntry::new(ts, vec![i as f64, -(i as f64)]));
+    }
+    let block = crate::gorilla::api::compress_values(entries, *EPOCH, 2);
+    let resample = |window: Duration, method: DownsampleMethod| -> Vec<MVEntry> {
+      GorillaReaderMV::from_block(block.clone(), 2).window_resample(window, 2, method).unwrap()
+    };
+
+    // minute windows hold 6 entries each
+    let mean = resample(Duration::minutes(1), DownsampleMethod::Mean);
+    assert_eq!(mean.len(), 100);
+    assert!(mean[0].time == *EPOCH);
+    assert!(mean[1].time == *EPOCH + Duration::minutes(1));
+    assert!(is_all_same(&mean[1].values, &vec![8.5, -8.5]));
+
+    let min = resample(Duration::minutes(1), DownsampleMethod::Min);
+    assert!(is_all_same(&min[1].values, &vec![6.0, -11.0]));
+    let max = resample(Duration::minutes(1), DownsampleMethod::Max);
+    assert!(is_all_same(&max[1].values, &vec![11.0, -6.0]));
+    let last = resample(Duration::minutes(1), DownsampleMethod::Last);
+    assert!(is_all_same(&last[99].values, &vec![599.0, -599.0]));
+
+    // 10 minute windows
+    let coarse = resample(Duration::minutes(10), DownsampleMethod::Mean);
+    assert_eq!(coarse.len(), 10);
+    assert!(coarse[9].time == *EPOCH + Duration::minutes(90));
+
+    let mut reader = GorillaReaderMV::from_block(block.clone(), 2);
+    assert!(reader.window_resample(Duration::seconds(0), 2, DownsampleMethod::Mean).is_err());
+    assert!(reader.window_resample(Duration::minutes(1), 3, DownsampleMethod::Mean).is_err());
+  }
+
+  #[test]
+  pub fn window_resample_skips_empty_windows() {
+    let times = [0, 10, 200, 210, 215];
+    let entries = times.iter()
+      .map(|s| MVEntry::new(*EPOCH + Duration::seconds(*s), vec![*s as f64]))
+      .collect();
+    let block = crate::gorilla::api::compress_values(entries, *EPOCH, 1);
+    let mut reader = GorillaReaderMV::from_block(block, 1);
+    let resampled = reader.window_resample(Duration::minutes(1), 1, DownsampleMethod::Mean).unwrap();
+    assert_eq!(resampled.len(), 2);
+    assert!(resampled[0].time == *EPOCH);
+    assert!(resampled[1].time == *EPOCH + Duration::minutes(3));
+    assert!(is_all_same(&resampled[1].values, &vec![(200.0 + 210.0 + 215.0) / 3.0]));
+  }
+
+  #[test]
+  pub fn f32_round_trip() {
+    let values = |i: i64| -> Vec<f64> {
+      vec![1.5f32 as f64, 0.1 * i as f64, -1e-3 * (i % 3) as f64, 12345.678]
+    };
+    let mut writer = GorillaWriterMV::with_vec_f32(*EPOCH, 4).unwrap();
+    let mut writer_f64 = GorillaWriterMV::with_vec(*EPOCH, 4).unwrap();
+    for i in 0..50 {
+      let entry = MVEntry::new(*EPOCH + Duration::seconds(10 * (i + 1)), values(i));
+      assert!(writer.append_entry(entry.clone()).is_ok());
+      assert!(writer_f64.append_entry(entry).is_ok());
+    }
+    let block = writer.close_with_checksum();
+    assert!(block.compressed_bytes() < writer_f64.close().compressed_bytes());
+
+    // the flag survives the string round trip
+    let block = GorillaBlock::new(&block.to_string());
+    assert!(block.validate_checksum(block.checksum().unwrap(), 4, 50).unwrap());
+    let mut reader = GorillaReaderMV::from_block(block.clone(), 4);
+    for i in 0..50 {
+      let entry = reader.try_next_entry().unwrap();
+      assert!(entry.time == *EPOCH + Duration::seconds(10 * (i + 1)));
+      let expected: Vec<f64> = values(i).iter().map(|v| *v as f32 as f64).collect();
+      assert_eq!(entry.values, expected);
+    }
+    assert!(reader.try_next_entry().is_none());
+
+    // appending to an existing block keeps the f32 mode
+    let mut writer = GorillaWriterMV::from_existing(block, 4).unwrap();
+    assert!(writer.append_entry(MVEntry::new(*EPOCH + Duration::seconds(1000), values(7))).is_ok());
+    let mut reader = GorillaReaderMV::from_block(writer.close(), 4);
+    let last = reader.decode_nth(50).unwrap();
+    assert_eq!(last.values[1], (0.1 * 7.0) as f32 as f64);
+  }
+
+  #[test]
+  pub fn from_block_auto() {
+    for dim in [1, 10] {
+      let entries: Vec<MVEntry> = (0..20)
+        .map(|i| MVEntry::new(*EPOCH + Duration::seconds(10 * (i + 1)),
+                              (0..dim).map(|d| (i * dim + d) as f64).collect()))
+        .collect();
+      let block = crate::gorilla::api::compress_values(entries.clone(), *EPOCH, dim as usize);
+      assert_eq!(block.dim(), Some(dim as usize));
+
+      // the dim survives both stored forms
+      let stored = [GorillaBlock::new(&block.to_string()), GorillaBlock::from_bytes(&block.to_bytes()).unwrap()];
+      for block in stored.iter() {
+        let mut reader = GorillaReaderMV::from_block_auto(block.clone());
+        for entry in entries.iter() {
+          let read = reader.try_next_entry().unwrap();
+          assert!(read.time == entry.time);
+          assert!(is_all_same(&read.values, &entry.values));
+        }
+        assert!(reader.try_next_entry().is_none());
+      }
+
+      // a conflicting dim is an error rather than garbage
+      let wrong = dim as usize + 1;
+      assert!(matches!(GorillaReaderMV::try_from_block(block.clone(), wrong), Err(Error::BadDimensionError)));
+      assert!(GorillaReaderMV::try_from_block(block.clone(), dim as usize).is_ok());
+      let mut reader = GorillaReaderMV::from_block(block.clone(), wrong);
+      assert!(reader.has_next());
+      assert!(matches!(reader.decode_nth(0), Err(Error::BadDimensionError)));
+      assert!(reader.try_next_entry().is_none());
+      assert!(matches!(GorillaWriterMV::from_existing(block, wrong), Err(Error::BadDimensionError)));
+    }
+  }
+
+  #[test]
+  pub fn block_crc() {
+    let entries: Vec<MVEntry> = (0..30)
+      .map(|i| MVEntry::new(*EPOCH + Duration::seconds(10 * (i + 1)), vec![i as f64, 0.5 * i as f64]))
+      .collect();
+    let block = crate::gorilla::api::compress_values(entries.clone(), *EPOCH, 2);
+    assert!(block.data.crc().is_some());
+    assert!(block.data.verify_crc().is_ok());
+
+    // the crc survives both stored forms
+    let parsed = GorillaBlock::from_bytes(&block.to_bytes()).unwrap();
+    assert_eq!(parsed.data.crc(), block.data.crc());
+    assert_eq!(GorillaBlock::new(&block.to_string()).data.crc(), block.data.crc());
+    let mut reader = GorillaReaderMV::try_from_block(parsed, 2).unwrap();
+    for entry in entries.iter() {
+      let read = reader.try_next_entry().unwrap();
+      assert!(read.time == entry.time);
+      assert!(is_all_same(&read.values, &entry.values));
+    }
+
+    // flipping a byte of the bits is caught before decoding
+    let mut bytes = block.to_bytes();
+    let last = bytes.len() - 2;
+    bytes[last] ^= 0x10;
+    let corrupted = GorillaBlock::from_bytes(&bytes).unwrap();
+    assert!(matches!(corrupted.data.verify_crc(), Err(Error::BlockChecksumMismatch)));
+    assert!(matches!(GorillaReaderMV::try_from_block(corrupted.clone(), 2), Err(Error::BlockChecksumMismatch)));
+    let mut reader = GorillaReaderMV::from_block(corrupted, 2);
+    assert!(reader.has_next());
+    assert!(matches!(reader.decode_nth(0), Err(Error::BlockChecksumMismatch)));
+    assert!(matches!(reader.try_get_next_time(), Err(Error::BlockChecksumMismatch)));
+
+    // blocks written before the crc still read: clear its flag on the bit
+    // count and drop it from after the entry count and dim
+    let mut bytes = block.to_string().into_bytes();
+    bytes[3] &= !(1 << 2);
+    bytes.drain(12..16);
+    let legacy = GorillaBlock::new(unsafe { std::str::from_utf8_unchecked(&bytes) });
+    assert_eq!(legacy.data.crc(), None);
+    let mut reader = GorillaReaderMV::try_from_block(legacy, 2).unwrap();
+    assert_eq!(reader.decode_nth(29).unwrap().values, entries[29].values);
+  }
+
+  #[test]
+  pub fn corrupt_value_window() {
+    let entries = [
+      MVEntry::new(*EPOCH + Duration::seconds(10), vec![1.0]),
+      MVEntry::new(*EPOCH + Duration::seconds(20), vec![2.0]),
+    ];
+    let mut writer = GorillaWriterMV::with_vec(*EPOCH, 1).unwrap();
+    writer.append_entry(entries[0].clone()).unwrap();
+    let first_bits = writer.body.length();
+    writer.append_entry(entries[1].clone()).unwrap();
+    let block = writer.close();
+
+    // without a crc the corruption reaches the decoder. The second entry is
+    // a 0b0 delta of delta and the 0b11 control word of its value, the
+    // leading zeros and meaningful bits after it are set to 31 and 63
+    let mut bytes = block.to_string().into_bytes();
+    bytes[3] &= !(1 << 2);
+    bytes.drain(12..16);
+    for bit in first_bits + 3..first_bits + 3 + 11 {
+      bytes[12 + bit / 8] |= 1 << (bit % 8);
+    }
+    let corrupted = GorillaBlock::new(unsafe { std::str::from_utf8_unchecked(&bytes) });
+
+    let mut reader = GorillaReaderMV::try_from_block(corrupted.clone(), 1).unwrap();
+    let first = reader.try_next_entry().unwrap();
+    assert!(first.time == entries[0].time);
+    assert!(is_all_same(&first.values, &entries[0].values));
+    assert!(reader.try_get_next_time().unwrap() == entries[1].time);
+    assert!(matches!(reader.try_get_next_values(), Err(Error::BitReaderError(_))));
+
+    let mut reader = GorillaReaderMV::try_from_block(corrupted, 1).unwrap();
+    reader.try_get_next_time().unwrap();
+    reader.skip_values().unwrap();
+    reader.try_get_next_time().unwrap();
+    assert!(matches!(reader.skip_values(), Err(Error::BitReaderError(_))));
+  }
 }
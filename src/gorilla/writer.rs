@@ -1,51 +1,75 @@
 use crate::gorilla::*;
 
 pub struct GorillaWriter {
+    precision: TimePrecision,
     header: GorillaDateTime,
     prev_ts: GorillaDateTime,
     prev_delta: u32,
     prev_value: f64,
     prev_zeros: Zeros,
+    num_entries: usize,
     pub body: BitWriter,
 }
 
 impl GorillaWriter {
 
-    pub fn with_vec(header: GorillaDateTime)-> Self {
+    pub fn with_vec(header: GorillaDateTime) -> Result<Self, Error> {
+        Self::with_precision(header, TimePrecision::Seconds)
+    }
+
+    // same as 'with_vec', but timestamps keep millisecond precision
+    pub fn with_vec_millis(header: GorillaDateTime) -> Result<Self, Error> {
+        Self::with_precision(header, TimePrecision::Millis)
+    }
+
+    fn with_precision(header: GorillaDateTime, precision: TimePrecision) -> Result<Self, Error> {
+        validate_header(header)?;
 
         // initialize to have no leading or trailing zeros
         let prev_zeros = Zeros{ leading: 32u8, trailing: 32u8 };
 
         let mut block = GorillaWriter {
+            precision,
             header,
             prev_ts: header,
             prev_delta: 0,
             prev_value: 0.0,
             prev_zeros,
+            num_entries: 0,
             body: BitWriter::new(),
         };
 
-        let timestamp = header.timestamp();
+        let timestamp = precision.units(header - *EPOCH);
         block.body.write(64, timestamp as u64).unwrap();
-        block
+        Ok(block)
+    }
+
+    pub fn precision(&self) -> TimePrecision {
+        self.precision
     }
 
     pub fn close(self) -> GorillaBlock {
+        let data = self.body.close().with_entries(self.num_entries);
+        let data = match self.precision {
+            TimePrecision::Seconds => data,
+            TimePrecision::Millis => data.with_millis(),
+        };
         GorillaBlock {
-            data: self.body.close()
+            data,
+            checksum: None,
         }
     }
 
     fn validate_timestamp(&self, time: GorillaDateTime) -> Result<u32, Error> {
 
-        let delta = (time - self.prev_ts).num_seconds();
+        let delta = self.precision.units(time - self.prev_ts);
 
         if delta < 0 {
             Err(Error::AppendOrderError)
         }
 
-        // Can't append more than 14 bits
-        else if delta > 16384 {
+        // Can't append more than the first delta field holds
+        else if delta > self.precision.max_delta() {
             Err(Error::AppendDurationError)
         }
 
@@ -58,11 +82,12 @@ impl GorillaWriter {
     pub fn append_first(&mut self, entry: Entry) -> Result<(), Error> {
         let delta = self.validate_timestamp(entry.time)?;
         let val = u64::from_le_bytes(entry.value.to_le_bytes());
-        self.body.write(14, delta as u64)?;
+        self.body.write(self.precision.first_delta_bits(), delta as u64)?;
         self.body.write(64, val)?;
         self.prev_value = entry.value;
         self.prev_ts = entry.time;
         self.prev_delta = delta;
+        self.num_entries += 1;
         Ok(())
     }
 
@@ -96,6 +121,9 @@ impl GorillaWriter {
             if inside {
                 leading = self.prev_zeros.leading;
                 trailing = self.prev_zeros.trailing;
+            } else if leading > 31 {
+                // the leading zero count is stored in 5 bits
+                leading = 31;
             }
 
             (inside, leading, trailing)
@@ -134,30 +162,37 @@ impl GorillaWriter {
         let delta_of_delta = delta as i32 - self.prev_delta as i32;
         self.prev_delta = delta;
         self.prev_ts = time;
+        self.num_entries += 1;
+
+        let [bits1, bits2, bits3] = self.precision.dod_bucket_bits();
+        let fits = |bits: u32| -> bool {
+            let max = 1 << (bits - 1);
+            delta_of_delta > -max && delta_of_delta <= max
+        };
 
         if delta_of_delta == 0 {
             self.body.write_bit(false)?;
         }
 
-        else if delta_of_delta >= -63 && delta_of_delta <= 64 {
+        else if fits(bits1) {
             self.body.write_bit(true)?;
             self.body.write_bit(false)?;
-            self.body.write(7, delta_of_delta as u64)?;
+            self.body.write_signed(bits1, delta_of_delta as i64)?;
         }
 
-        else if delta_of_delta >= -255 && delta_of_delta <= 256 {
+        else if fits(bits2) {
             self.body.write_bit(true)?;
             self.body.write_bit(true)?;
             self.body.write_bit(false)?;
-            self.body.write(9, delta_of_delta as u64)?;
+            self.body.write_signed(bits2, delta_of_delta as i64)?;
         }
 
-        else if delta_of_delta >= -2047 && delta_of_delta <= 2048 {
+        else if fits(bits3) {
             self.body.write_bit(true)?;
             self.body.write_bit(true)?;
             self.body.write_bit(true)?;
             self.body.write_bit(false)?;
-            self.body.write(12, delta_of_delta as u64)?;
+            self.body.write_signed(bits3, delta_of_delta as i64)?;
         }
 
         else {
@@ -165,7 +200,7 @@ impl GorillaWriter {
             self.body.write_bit(true)?;
             self.body.write_bit(true)?;
             self.body.write_bit(true)?;
-            self.body.write(32, delta_of_delta as u64)?;
+            self.body.write_signed(32, delta_of_delta as i64)?;
         }
 
         Ok(())
@@ -195,7 +230,7 @@ mod test {
     #[test]
     fn initialize() {
         let x = epoch() + Duration::days(1);
-        let block = GorillaWriter::with_vec(x).close().data;
+        let block = GorillaWriter::with_vec(x).unwrap().close().data;
         let mut reader = BitReader::new(block);
         assert!(x.timestamp() == reader.read(64).unwrap() as i64);
     }
@@ -203,7 +238,7 @@ mod test {
     #[test]
     fn append_first() {
         let x = epoch();
-        let mut block = GorillaWriter::with_vec(x);
+        let mut block = GorillaWriter::with_vec(x).unwrap();
         let entry = {
             let ts = x - Duration::days(-1);
             let value = 1.01;
@@ -233,7 +268,7 @@ mod test {
 
         let setup = |dur: Duration| -> BitReader {
             let x = epoch();
-            let mut block = GorillaWriter::with_vec(x);
+            let mut block = GorillaWriter::with_vec(x).unwrap();
 
             // make first delta 50 minutes (delta of 3000 seconds)
             let ts = x + Duration::minutes(50);
@@ -372,7 +407,7 @@ mod test {
 
         let setup_writer = || -> GorillaWriter {
             let x = epoch();
-            let mut block = GorillaWriter::with_vec(x);
+            let mut block = GorillaWriter::with_vec(x).unwrap();
 
             // make first delta 50 minutes (delta of 3000 seconds)
             let ts = x + Duration::minutes(50);
@@ -1,5 +1,8 @@
 use crate::gorilla::*;
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
 
+#[derive(Clone)]
 pub struct GorillaWriterMV {
   dim: usize,
   header: GorillaDateTime,
@@ -7,19 +10,78 @@ pub struct GorillaWriterMV {
   prev_delta: u32,
   prev_value: Vec<f64>,
   prev_zeros: Vec<Zeros>,
+  checksum: u64,
+  num_entries: usize,
+  // entries appended out of order, written in time order once more than
+  // 'max_unordered' are held or the block is closed
+  unordered: BTreeMap<GorillaDateTime, Vec<f64>>,
+  max_unordered: usize,
+  width: ValueWidth,
+  delta_width: DeltaWidth,
+  encoding: ValueEncoding,
+  // the last delta of every value, only used by DeltaOfDelta
+  prev_value_deltas: Vec<i64>,
+  // whether NaN and infinite values may be appended
+  allow_non_finite: bool,
+  // whether a value that fits the previous window much more tightly writes
+  // a fresh window rather than reusing the previous one
+  reset_windows: bool,
   pub body: BitWriter,
 }
 
+// the closed body along with what a reader needs to know about it
+fn close_stream(body: BitWriter, num_entries: usize, dim: usize, width: ValueWidth,
+                delta_width: DeltaWidth, encoding: ValueEncoding) -> BitStream {
+  let stream = body.close().with_entries(num_entries).with_dim(dim).with_crc();
+  let stream = match width {
+    ValueWidth::F64 => stream,
+    ValueWidth::F32 => stream.with_f32_values(),
+  };
+  let stream = match delta_width {
+    DeltaWidth::Narrow => stream,
+    DeltaWidth::Wide => stream.with_wide_first_delta(),
+  };
+  match encoding {
+    ValueEncoding::Xor => stream,
+    ValueEncoding::DeltaOfDelta => stream.with_int_values(),
+  }
+}
+
 impl GorillaWriterMV {
-  pub fn with_vec(header: GorillaDateTime, dim: usize) -> Self {
+  pub fn with_vec(header: GorillaDateTime, dim: usize) -> Result<Self, Error> {
+    Self::with_vec_unordered(header, dim, 0)
+  }
+
+  // same as 'with_vec', but up to 'max_unordered' entries appended with
+  // 'append_entry_unordered' are held back so they may arrive in any order
+  pub fn with_vec_unordered(header: GorillaDateTime, dim: usize, max_unordered: usize) -> Result<Self, Error> {
+    Self::with_width(header, dim, max_unordered, ValueWidth::F64, DeltaWidth::Narrow, ValueEncoding::Xor)
+  }
+
+  // same as 'with_vec', but values are stored as f32, anything beyond f32
+  // precision is lost
+  pub fn with_vec_f32(header: GorillaDateTime, dim: usize) -> Result<Self, Error> {
+    Self::with_width(header, dim, 0, ValueWidth::F32, DeltaWidth::Narrow, ValueEncoding::Xor)
+  }
+
+  // same as 'with_vec', but entries may be up to ~4 years apart rather than
+  // 16384 seconds, so a block can cover a day or more of sparse entries
+  pub fn with_vec_wide(header: GorillaDateTime, dim: usize) -> Result<Self, Error> {
+    Self::with_width(header, dim, 0, ValueWidth::F64, DeltaWidth::Wide, ValueEncoding::Xor)
+  }
+
+  // same as 'with_vec', but for integer values such as counters: each value
+  // is stored as the change of its delta, which takes a single bit while a
+  // series grows at a steady rate. Values must be integers up to 2^53
+  pub fn with_vec_int(header: GorillaDateTime, dim: usize) -> Result<Self, Error> {
+    Self::with_width(header, dim, 0, ValueWidth::F64, DeltaWidth::Narrow, ValueEncoding::DeltaOfDelta)
+  }
+
+  fn with_width(header: GorillaDateTime, dim: usize, max_unordered: usize, width: ValueWidth,
+                delta_width: DeltaWidth, encoding: ValueEncoding) -> Result<Self, Error> {
+    validate_header(header)?;
     // initialize to have no leading or trailing zeros
-    let prev_zeros = vec![
-      Zeros {
-        leading: 32u8,
-        trailing: 32u8,
-      };
-      dim
-    ];
+    let prev_zeros = vec![width.initial_zeros(); dim];
 
     let mut block = GorillaWriterMV {
       dim,
@@ -28,41 +90,194 @@ impl GorillaWriterMV {
       prev_delta: 0,
       prev_value: vec![0.0; dim],
       prev_zeros,
+      checksum: FNV_OFFSET_BASIS,
+      num_entries: 0,
+      unordered: BTreeMap::new(),
+      max_unordered,
+      width,
+      delta_width,
+      encoding,
+      prev_value_deltas: vec![0; dim],
+      allow_non_finite: false,
+      reset_windows: false,
       body: BitWriter::new(),
     };
 
     let timestamp = header.timestamp();
     block.body.write(64, timestamp as u64).unwrap();
-    block
+    Ok(block)
+  }
+
+  // start a new block with 'new_header' in place, the body keeps its buffer
+  // so no allocation is needed once it has grown to a full block
+  pub fn reset(&mut self, new_header: GorillaDateTime) -> Result<(), Error> {
+    validate_header(new_header)?;
+    self.header = new_header;
+    self.prev_ts = new_header;
+    self.prev_delta = 0;
+    for value in self.prev_value.iter_mut() {
+      *value = 0.0;
+    }
+    for zeros in self.prev_zeros.iter_mut() {
+      *zeros = self.width.initial_zeros();
+    }
+    for delta in self.prev_value_deltas.iter_mut() {
+      *delta = 0;
+    }
+    self.checksum = FNV_OFFSET_BASIS;
+    self.num_entries = 0;
+    self.unordered.clear();
+
+    self.body.reset();
+    self.body.write(64, new_header.timestamp() as u64).unwrap();
+    Ok(())
+  }
+
+  // continue appending to a closed block, the block is decoded once to
+  // recover the last timestamp, delta, values and zero windows, and writing
+  // resumes right after its last bit
+  pub fn from_existing(block: GorillaBlock, dim: usize) -> Result<Self, Error> {
+    let header = block.header()?;
+    validate_header(header)?;
+    let mut reader = GorillaReaderMV::from_block(block.clone(), dim);
+
+    let mut prev_ts = header;
+    let mut prev_delta = 0;
+    let mut prev_value = vec![0.0; dim];
+    let mut checksum = FNV_OFFSET_BASIS;
+    let mut num_entries = 0;
+    while reader.has_next() {
+      let entry = reader.decode_nth(0)?;
+      prev_delta = (entry.time - prev_ts).num_seconds() as u32;
+      prev_ts = entry.time;
+      checksum = fnv1a_time(checksum, entry.time);
+      checksum = fnv1a_values(checksum, &entry.values);
+      prev_value = entry.values;
+      num_entries += 1;
+    }
+
+    Ok(GorillaWriterMV {
+      dim,
+      header,
+      prev_ts,
+      prev_delta,
+      prev_value,
+      prev_zeros: reader.prev_zeros().to_vec(),
+      prev_value_deltas: reader.prev_value_deltas().to_vec(),
+      checksum,
+      num_entries,
+      unordered: BTreeMap::new(),
+      max_unordered: 0,
+      width: ValueWidth::of_stream(&block.data),
+      delta_width: DeltaWidth::of_stream(&block.data),
+      encoding: ValueEncoding::of_stream(&block.data),
+      allow_non_finite: false,
+      reset_windows: false,
+      body: BitWriter::from_stream(block.data),
+    })
+  }
+
+  // same as 'from_existing', to keep appending to a block a writer closed
+  pub fn reopen(block: GorillaBlock, dim: usize) -> Result<Self, Error> {
+    Self::from_existing(block, dim)
+  }
+
+  // accept NaN and infinite values rather than failing with NonFiniteValue,
+  // f64 values keep their exact bits (NaN payloads included)
+  pub fn allow_non_finite(mut self) -> Self {
+    self.allow_non_finite = true;
+    self
+  }
+
+  // write a fresh leading and trailing zero window whenever a value's own
+  // window saves more payload bits than the window costs to write, rather
+  // than always reusing the previous window it fits in. Blocks read the same
+  // either way, this pays off once a wide change is followed by small ones
+  pub fn reset_windows(mut self) -> Self {
+    self.reset_windows = true;
+    self
   }
 
   pub fn dim(&self) -> usize {
     self.dim
   }
 
-  pub fn close(self) -> GorillaBlock {
+  // the most bits the next entry appended in order can take, so a caller can
+  // tell it fits without encoding it
+  pub fn max_entry_bits(&self) -> usize {
+    if self.num_entries == 0 {
+      return self.delta_width.first_delta_bits() as usize + self.dim * self.width.bits() as usize;
+    }
+    let value_bits = match self.encoding {
+      ValueEncoding::Xor => 2 + self.width.leading_bits() + self.width.nbits_bits() + self.width.bits() as u32,
+      // a flag and a varint of up to 10 groups of 7 bits
+      ValueEncoding::DeltaOfDelta => 1 + 10 * 8,
+    };
+    // the widest delta of delta bucket
+    4 + 32 + self.dim * value_bits as usize
+  }
+
+  // a copy of this writer that shares everything written so far, appending to
+  // the fork does not affect this writer and vice versa
+  pub fn fork(&self) -> GorillaWriterMV {
+    self.clone()
+  }
+
+  pub fn close(mut self) -> GorillaBlock {
+    self.flush_unordered();
+    GorillaBlock {
+      data: close_stream(self.body, self.num_entries, self.dim, self.width, self.delta_width,
+                         self.encoding),
+      checksum: None,
+    }
+  }
+
+  // close the current block and 'reset' the writer for the next one, a bad
+  // 'new_header' leaves the writer as it was
+  pub fn close_and_reset(&mut self, new_header: GorillaDateTime) -> Result<GorillaBlock, Error> {
+    validate_header(new_header)?;
+    self.flush_unordered();
+    let block = GorillaBlock {
+      data: close_stream(std::mem::replace(&mut self.body, BitWriter::new()), self.num_entries, self.dim,
+                         self.width, self.delta_width, self.encoding),
+      checksum: None,
+    };
+    self.reset(new_header)?;
+    Ok(block)
+  }
+
+  // same as 'close', but the block carries a checksum of everything appended
+  pub fn close_with_checksum(mut self) -> GorillaBlock {
+    self.flush_unordered();
     GorillaBlock {
-      data: self.body.close(),
+      data: close_stream(self.body, self.num_entries, self.dim, self.width, self.delta_width,
+                         self.encoding),
+      checksum: Some(self.checksum),
     }
   }
 
   fn validate_values(&self, values: &Vec<f64>) -> Result<(), Error> {
     if values.len() != self.dim {
       Err(Error::BadDimensionError)
+    } else if !self.allow_non_finite && !values.iter().all(|v| v.is_finite()) {
+      Err(Error::NonFiniteValue)
+    } else if !values.iter().all(|v| self.encoding.accepts(*v)) {
+      Err(Error::NonIntegerValueError)
     } else {
       Ok(())
     }
   }
 
-  fn validate_timestamp(&self, time: GorillaDateTime) -> Result<u32, Error> {
-    let delta = (time - self.prev_ts).num_seconds();
+  // the delta of an entry at 'time' written right after one at 'prev'
+  fn validate_timestamp(&self, prev: GorillaDateTime, time: GorillaDateTime) -> Result<u32, Error> {
+    let delta = (time - prev).num_seconds();
 
     if delta < 0 {
       //Err(Error::AppendOrderError)
       Err(Error::AppendOrderError)
     }
-    // Can't append more than 14 bits
-    else if delta > 16384 {
+    // Can't append more than the first delta field holds
+    else if delta > self.delta_width.max_delta() {
       Err(Error::AppendDurationError)
     } else {
       Ok(delta as u32)
@@ -70,52 +285,152 @@ impl GorillaWriterMV {
   }
 
   pub fn append_entry(&mut self, entry: MVEntry) -> Result<(), Error> {
-    // Arguably, this should be an atomic operation
-    self.validate_values(&(entry.values))?;
-    self.append_time(entry.time)?;
-    self.append_values(entry.values)?;
+    // held back entries go first, they are at least as old as the last one
+    // written
+    self.flush_unordered();
+    self.write_entry(entry)
+  }
+
+  // append 'entries' in order like 'append_entry', every one of them is
+  // validated before any is written, so on error nothing is appended and
+  // the error is a 'BatchEntryError' with the index of the first bad entry
+  pub fn append_entries(&mut self, entries: &[MVEntry]) -> Result<(), Error> {
+    self.flush_unordered();
+    let mut deltas = Vec::with_capacity(entries.len());
+    let mut prev_ts = self.prev_ts;
+    for (i, entry) in entries.iter().enumerate() {
+      let delta = self.validate_values(&entry.values)
+        .and_then(|_| self.validate_timestamp(prev_ts, entry.time))
+        .map_err(|e| Error::BatchEntryError(i, Box::new(e)))?;
+      deltas.push(delta);
+      prev_ts = entry.time;
+    }
+
+    for (i, (entry, delta)) in entries.iter().zip(deltas).enumerate() {
+      self.write_valid_entry(entry.clone(), delta)
+        .map_err(|e| Error::BatchEntryError(i, Box::new(e)))?;
+    }
     Ok(())
   }
 
+  fn write_entry(&mut self, entry: MVEntry) -> Result<(), Error> {
+    self.validate_values(&entry.values)?;
+    let delta = self.validate_timestamp(self.prev_ts, entry.time)?;
+    self.write_valid_entry(entry, delta)
+  }
+
+  // write an entry already validated, 'delta' seconds after the last one
+  fn write_valid_entry(&mut self, entry: MVEntry, delta: u32) -> Result<(), Error> {
+    // readers expect the first entry of a block in the raw layout
+    if self.body.length() == 64 {
+      return self.write_first(entry, delta);
+    }
+    self.write_time(entry.time, delta)?;
+    self.write_values(entry.values)
+  }
+
+  // append an entry that may be older than entries appended before it, as
+  // long as it is not older than the header or the entries already written
+  // nor at the time of an entry still held back
+  pub fn append_entry_unordered(&mut self, entry: MVEntry) -> Result<(), Error> {
+    self.validate_values(&entry.values)?;
+    if entry.time < self.prev_ts || self.unordered.contains_key(&entry.time) {
+      return Err(Error::AppendOrderError);
+    }
+
+    // an entry in between others only shortens the gaps around it, the newest
+    // one has to be within reach of the one before it
+    if self.unordered.range(entry.time..).next().is_none() {
+      let prev = match self.unordered.keys().next_back() {
+        Some(time) => *time,
+        None => self.prev_ts,
+      };
+      if (entry.time - prev).num_seconds() > self.delta_width.max_delta() {
+        return Err(Error::AppendDurationError);
+      }
+    }
+
+    self.unordered.insert(entry.time, entry.values);
+    while self.unordered.len() > self.max_unordered {
+      self.write_oldest_unordered();
+    }
+    Ok(())
+  }
+
+  fn write_oldest_unordered(&mut self) {
+    let time = *self.unordered.keys().next().unwrap();
+    let values = self.unordered.remove(&time).unwrap();
+    self.write_entry(MVEntry::new(time, values))
+      .expect("held back entries are validated when appended");
+  }
+
+  fn flush_unordered(&mut self) {
+    while !self.unordered.is_empty() {
+      self.write_oldest_unordered();
+    }
+  }
+
   pub fn append_first(&mut self, entry: MVEntry) -> Result<(), Error> {
-    let delta = self.validate_timestamp(entry.time)?;
-    self.body.write(14, delta as u64)?;
-    let mut val;
-    for i in 0..self.dim {
-      val = u64::from_le_bytes(entry.values[i].to_le_bytes());
-      self.body.write(64, val)?;
+    self.validate_values(&entry.values)?;
+    let delta = self.validate_timestamp(self.prev_ts, entry.time)?;
+    self.write_first(entry, delta)
+  }
+
+  fn write_first(&mut self, entry: MVEntry, delta: u32) -> Result<(), Error> {
+    let values = self.encoding.round(self.width.round(entry.values));
+    self.body.write(self.delta_width.first_delta_bits(), delta as u64)?;
+    for value in values.iter() {
+      self.body.write(self.width.bits() as u32, self.width.to_bits(*value))?;
     }
-    self.prev_value = entry.values;
+    self.checksum = fnv1a_time(self.checksum, entry.time);
+    self.checksum = fnv1a_values(self.checksum, &values);
+    self.prev_value = values;
     self.prev_ts = entry.time;
     self.prev_delta = delta;
+    self.num_entries += 1;
     Ok(())
   }
 
   pub fn append_values(&mut self, values: Vec<f64>) -> Result<(), Error> {
     self.validate_values(&values)?;
-    let u64bytes = |v: f64| -> u64 { u64::from_le_bytes(v.to_le_bytes()) };
+    self.write_values(values)
+  }
 
-    let xor_f64 = |l: f64, r: f64| -> u64 { u64bytes(l) ^ u64bytes(r) };
+  fn write_values(&mut self, values: Vec<f64>) -> Result<(), Error> {
+    if self.encoding == ValueEncoding::DeltaOfDelta {
+      return self.append_int_values(self.encoding.round(values));
+    }
+    let values = self.width.round(values);
+    let width = self.width;
+    let max_leading = (1u8 << width.leading_bits()) - 1;
 
-    //let l = u64bytes(value);
-    //let r = u64bytes(self.prev_value);
+    let xor_f64 = |l: f64, r: f64| -> u64 { width.to_bits(l) ^ width.to_bits(r) };
 
     for i in 0..self.dim {
       let xored = xor_f64(values[i], self.prev_value[i]);
       let (inside_block, leading, trailing) = {
-        let mut leading = xored.leading_zeros() as u8;
+        // zeros are counted within the value width
+        let mut leading = xored.leading_zeros() as u8 - (64 - width.bits());
         let mut trailing = xored.trailing_zeros() as u8;
-        let inside =
+        let mut inside =
           leading >= self.prev_zeros[i].leading && trailing >= self.prev_zeros[i].trailing;
+        if inside && self.reset_windows && xored != 0 {
+          let prev = &self.prev_zeros[i];
+          let saved = (leading.min(max_leading) - prev.leading) + (trailing - prev.trailing);
+          inside = saved as u32 <= width.leading_bits() + width.nbits_bits();
+        }
         if inside {
           leading = self.prev_zeros[i].leading;
           trailing = self.prev_zeros[i].trailing;
+        } else if leading > max_leading {
+          // the leading zero count is stored in 'leading_bits' bits
+          leading = max_leading;
         }
 
         (inside, leading, trailing)
       };
 
-      let nbits = 64 - leading - trailing;
+      let nbits = width.bits() - leading - trailing;
       let to_write = xored >> trailing;
 
       if xored == 0 {
@@ -127,8 +442,8 @@ impl GorillaWriterMV {
       } else {
         self.body.write_bit(true)?;
         self.body.write_bit(true)?;
-        self.body.write(5, leading as u64)?;
-        self.body.write(6, nbits as u64)?;
+        self.body.write(width.leading_bits(), leading as u64)?;
+        self.body.write(width.nbits_bits(), nbits as u64)?;
         self.body.write(nbits as u32, to_write)?;
         self.prev_zeros[i] = Zeros { leading, trailing };
       }
@@ -136,40 +451,512 @@ impl GorillaWriterMV {
       self.prev_value[i] = values[i];
     }
 
+    self.checksum = fnv1a_values(self.checksum, &values);
+    Ok(())
+  }
+
+  // '0' when a value changed by the same amount as the last time, otherwise
+  // '1' followed by the zigzagged delta of deltas as a varint
+  fn append_int_values(&mut self, values: Vec<f64>) -> Result<(), Error> {
+    let prev = self.prev_value.iter().zip(self.prev_value_deltas.iter_mut());
+    for (value, (prev_value, prev_delta)) in values.iter().zip(prev) {
+      let delta = *value as i64 - *prev_value as i64;
+      let delta_of_delta = delta - *prev_delta;
+      if delta_of_delta == 0 {
+        self.body.write_bit(false)?;
+      } else {
+        self.body.write_bit(true)?;
+        self.body.write_varint(zigzag(delta_of_delta))?;
+      }
+      *prev_delta = delta;
+    }
+
+    self.checksum = fnv1a_values(self.checksum, &values);
+    self.prev_value = values;
     Ok(())
   }
 
   pub fn append_time(&mut self, time: GorillaDateTime) -> Result<(), Error> {
-    let delta = self.validate_timestamp(time)?;
+    let delta = self.validate_timestamp(self.prev_ts, time)?;
+    self.write_time(time, delta)
+  }
+
+  fn write_time(&mut self, time: GorillaDateTime, delta: u32) -> Result<(), Error> {
     let delta_of_delta = delta as i32 - self.prev_delta as i32;
     self.prev_delta = delta;
     self.prev_ts = time;
+    self.checksum = fnv1a_time(self.checksum, time);
+    self.num_entries += 1;
 
     if delta_of_delta == 0 {
       self.body.write_bit(false)?;
     } else if delta_of_delta >= -63 && delta_of_delta <= 64 {
       self.body.write_bit(true)?;
       self.body.write_bit(false)?;
-      self.body.write(7, delta_of_delta as u64)?;
+      self.body.write_signed(7, delta_of_delta as i64)?;
     } else if delta_of_delta >= -255 && delta_of_delta <= 256 {
       self.body.write_bit(true)?;
       self.body.write_bit(true)?;
       self.body.write_bit(false)?;
-      self.body.write(9, delta_of_delta as u64)?;
+      self.body.write_signed(9, delta_of_delta as i64)?;
     } else if delta_of_delta >= -2047 && delta_of_delta <= 2048 {
       self.body.write_bit(true)?;
       self.body.write_bit(true)?;
       self.body.write_bit(true)?;
       self.body.write_bit(false)?;
-      self.body.write(12, delta_of_delta as u64)?;
+      self.body.write_signed(12, delta_of_delta as i64)?;
     } else {
       self.body.write_bit(true)?;
       self.body.write_bit(true)?;
       self.body.write_bit(true)?;
       self.body.write_bit(true)?;
-      self.body.write(32, delta_of_delta as u64)?;
+      self.body.write_signed(32, delta_of_delta as i64)?;
     }
 
     Ok(())
   }
 }
+
+// a GorillaWriterMV shared between threads, every call holds the lock for
+// its whole duration, clones refer to the same writer
+#[derive(Clone)]
+pub struct GorillaWriterMVSafe {
+  inner: Arc<Mutex<GorillaWriterMV>>,
+}
+
+impl GorillaWriterMVSafe {
+  pub fn new(writer: GorillaWriterMV) -> Self {
+    GorillaWriterMVSafe {
+      inner: Arc::new(Mutex::new(writer)),
+    }
+  }
+
+  pub fn append_entry(&self, entry: MVEntry) -> Result<(), Error> {
+    self.inner.lock().unwrap().append_entry(entry)
+  }
+
+  pub fn append_first(&self, entry: MVEntry) -> Result<(), Error> {
+    self.inner.lock().unwrap().append_first(entry)
+  }
+
+  pub fn append_entries(&self, entries: &[MVEntry]) -> Result<(), Error> {
+    self.inner.lock().unwrap().append_entries(entries)
+  }
+
+  // run 'f' with the lock held, e.g. to pick a timestamp and append it
+  // without another thread appending in between
+  pub fn with_writer<F, R>(&self, f: F) -> R
+  where
+    F: FnOnce(&mut GorillaWriterMV) -> R,
+  {
+    f(&mut self.inner.lock().unwrap())
+  }
+
+  // the last handle takes the writer out of the mutex, if other handles are
+  // still alive they keep appending to the writer and this returns what has
+  // been appended so far
+  pub fn close(self) -> GorillaBlock {
+    match Arc::try_unwrap(self.inner) {
+      Ok(inner) => inner.into_inner().unwrap().close(),
+      Err(inner) => inner.lock().unwrap().fork().close(),
+    }
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+  use chrono::Duration;
+
+  fn entry(i: i64) -> MVEntry {
+    let x = i as f64;
+    MVEntry::new(*EPOCH + Duration::seconds(10 * (i + 1)), vec![x, x * 0.5, 100.0 - x])
+  }
+
+  #[test]
+  fn max_entry_bits() {
+    // entries far apart in time and value take the widest encodings
+    let writers = vec![
+      GorillaWriterMV::with_vec(*EPOCH, 2).unwrap(),
+      GorillaWriterMV::with_vec_f32(*EPOCH, 2).unwrap(),
+      GorillaWriterMV::with_vec_int(*EPOCH, 2).unwrap(),
+    ];
+    for mut writer in writers {
+      let mut time = *EPOCH;
+      for i in 0..200i64 {
+        time += Duration::seconds([16384, 0, 1, 9000][i as usize % 4]);
+        let big = if i % 2 == 0 { 9e15 } else { -9e15 };
+        let entry = MVEntry::new(time, vec![big, (i * i) as f64]);
+        let before = writer.body.length();
+        let max = writer.max_entry_bits();
+        assert!(writer.append_entry(entry).is_ok());
+        assert!(writer.body.length() - before <= max);
+      }
+    }
+  }
+
+  #[test]
+  fn fork() {
+    let mut writer = GorillaWriterMV::with_vec(*EPOCH, 3).unwrap();
+    for i in 0..100 {
+      assert!(writer.append_entry(entry(i)).is_ok());
+    }
+
+    let mut fork = writer.fork();
+    for i in 100..110 {
+      assert!(fork.append_entry(entry(i)).is_ok());
+    }
+    let fork_block = fork.close();
+    let block = writer.close();
+
+    let fork_index = fork_block.build_timestamp_index(3).unwrap();
+    let index = block.build_timestamp_index(3).unwrap();
+    assert_eq!(fork_index.len(), 110);
+    assert_eq!(index.len(), 100);
+
+    let entries = crate::gorilla::api::retrieve_values(block, 3, 100);
+    for (i, decoded) in entries.iter().enumerate() {
+      assert!(decoded.time() == entry(i as i64).time());
+      assert!(decoded.values() == entry(i as i64).values());
+    }
+  }
+
+  #[test]
+  fn append_entries() {
+    let entries: Vec<MVEntry> = (0..100).map(entry).collect();
+
+    // the same block as appending one entry at a time
+    let mut writer = GorillaWriterMV::with_vec(*EPOCH, 3).unwrap();
+    let mut single = GorillaWriterMV::with_vec(*EPOCH, 3).unwrap();
+    assert!(writer.append_entries(&entries[..50]).is_ok());
+    assert!(writer.append_entries(&entries[50..]).is_ok());
+    for e in &entries {
+      assert!(single.append_entry(e.clone()).is_ok());
+    }
+    assert_eq!(writer.close_with_checksum().to_string(), single.close_with_checksum().to_string());
+
+    // a bad entry in the middle is reported by its index and nothing is written
+    let mut bad = entries.clone();
+    bad[37].values.push(1.0);
+    let mut writer = GorillaWriterMV::with_vec(*EPOCH, 3).unwrap();
+    match writer.append_entries(&bad) {
+      Err(Error::BatchEntryError(37, e)) => assert!(matches!(*e, Error::BadDimensionError)),
+      other => panic!("unexpected result {:?}", other),
+    }
+    let mut late = entries.clone();
+    late.swap(60, 61);
+    match writer.append_entries(&late) {
+      Err(Error::BatchEntryError(61, e)) => assert!(matches!(*e, Error::AppendOrderError)),
+      other => panic!("unexpected result {:?}", other),
+    }
+    assert_eq!(writer.close().num_entries(3).unwrap(), 0);
+  }
+
+  #[test]
+  fn reset() {
+    let mut writer = GorillaWriterMV::with_vec(*EPOCH, 3).unwrap();
+    for i in 0..100 {
+      assert!(writer.append_entry(entry(i)).is_ok());
+    }
+
+    // a reset writer produces the same block as a new one
+    let header = *EPOCH + Duration::hours(2);
+    let later = |i: i64| -> MVEntry {
+      MVEntry::new(header + Duration::seconds(10 * (i + 1)), entry(i).values)
+    };
+    let first = writer.close_and_reset(header).unwrap();
+    let mut fresh = GorillaWriterMV::with_vec(header, 3).unwrap();
+    for i in 0..50 {
+      assert!(writer.append_entry(later(i)).is_ok());
+      assert!(fresh.append_entry(later(i)).is_ok());
+    }
+    assert_eq!(writer.close_with_checksum().to_string(), fresh.close_with_checksum().to_string());
+
+    assert_eq!(first.build_timestamp_index(3).unwrap().len(), 100);
+  }
+
+  #[test]
+  fn safe_writer() {
+    use std::sync::atomic::{AtomicU64, Ordering};
+    use std::thread;
+
+    let writer = GorillaWriterMVSafe::new(GorillaWriterMV::with_vec(*EPOCH, 1).unwrap());
+    let counter = Arc::new(AtomicU64::new(0));
+
+    let mut handles = Vec::new();
+    for _ in 0..10 {
+      let writer = writer.clone();
+      let counter = counter.clone();
+      handles.push(thread::spawn(move || {
+        for _ in 0..100 {
+          // take the next timestamp while holding the lock so appends stay in order
+          writer.with_writer(|w| {
+            let i = counter.fetch_add(1, Ordering::SeqCst) as i64;
+            let entry = MVEntry::new(*EPOCH + Duration::seconds(10 * (i + 1)), vec![i as f64]);
+            w.append_entry(entry)
+          }).unwrap();
+        }
+      }));
+    }
+    for handle in handles {
+      handle.join().unwrap();
+    }
+
+    let block = writer.close();
+    assert_eq!(block.iter_timestamps(1).count(), 1000);
+    let entries = crate::gorilla::api::retrieve_values(block, 1, 1000);
+    for (i, decoded) in entries.iter().enumerate() {
+      assert!(decoded.time() == *EPOCH + Duration::seconds(10 * (i as i64 + 1)));
+      assert!(decoded.values() == vec![i as f64]);
+    }
+  }
+
+  #[test]
+  fn from_existing() {
+    let mut writer = GorillaWriterMV::with_vec(*EPOCH, 3).unwrap();
+    for i in 0..50 {
+      assert!(writer.append_entry(entry(i)).is_ok());
+    }
+    let block = writer.close();
+
+    let mut writer = GorillaWriterMV::from_existing(block, 3).unwrap();
+    for i in 50..100 {
+      assert!(writer.append_entry(entry(i)).is_ok());
+    }
+    let block = writer.close_with_checksum();
+
+    // identical to writing all 100 entries in one go
+    let mut whole = GorillaWriterMV::with_vec(*EPOCH, 3).unwrap();
+    for i in 0..100 {
+      assert!(whole.append_entry(entry(i)).is_ok());
+    }
+    assert_eq!(block.to_string(), whole.fork().close_with_checksum().to_string());
+
+    assert_eq!(block.num_entries(3).unwrap(), 100);
+    let entries = crate::gorilla::api::retrieve_values(block, 3, 100);
+    for (i, decoded) in entries.iter().enumerate() {
+      assert!(decoded.time() == entry(i as i64).time());
+      assert!(decoded.values() == entry(i as i64).values());
+    }
+
+    // an empty block
+    let empty = GorillaWriterMV::with_vec(*EPOCH, 3).unwrap().close();
+    let mut writer = GorillaWriterMV::from_existing(empty, 3).unwrap();
+    assert!(writer.append_entry(entry(0)).is_ok());
+    assert_eq!(writer.close().num_entries(3).unwrap(), 1);
+
+    // a block growing by a few entries at a time, reopened after each, some
+    // closed with a checksum
+    let mut block = GorillaWriterMV::with_vec(*EPOCH, 3).unwrap().close();
+    for chunk in 0..10 {
+      let mut writer = GorillaWriterMV::reopen(block, 3).unwrap();
+      for i in 10 * chunk..10 * (chunk + 1) {
+        assert!(writer.append_entry(entry(i)).is_ok());
+      }
+      // entries can't go back before the ones already in the block
+      assert!(matches!(writer.append_entry(entry(10 * chunk)), Err(Error::AppendOrderError)));
+      block = if chunk % 2 == 0 { writer.close_with_checksum() } else { writer.close() };
+    }
+    assert_eq!(block.to_string(), whole.close().to_string());
+    let entries = crate::gorilla::api::retrieve_values(block, 3, 100);
+    for (i, decoded) in entries.iter().enumerate() {
+      assert!(decoded.time() == entry(i as i64).time());
+      assert!(decoded.values() == entry(i as i64).values());
+    }
+  }
+
+  #[test]
+  fn append_entry_unordered() {
+    let mut writer = GorillaWriterMV::with_vec_unordered(*EPOCH, 2, 16).unwrap();
+    for i in (1..=10).rev() {
+      let entry = MVEntry::new(*EPOCH + Duration::seconds(10 * i), vec![i as f64, -(i as f64)]);
+      assert!(writer.append_entry_unordered(entry).is_ok());
+    }
+    // older than the header
+    let early = MVEntry::new(*EPOCH - Duration::seconds(1), vec![0.0, 0.0]);
+    assert!(writer.append_entry_unordered(early).is_err());
+    // too far past the newest entry
+    let late = MVEntry::new(*EPOCH + Duration::hours(6), vec![0.0, 0.0]);
+    assert!(writer.append_entry_unordered(late).is_err());
+    // at the time of an entry held back, which is kept
+    let duplicate = MVEntry::new(*EPOCH + Duration::seconds(50), vec![0.0, 0.0]);
+    assert!(matches!(writer.append_entry_unordered(duplicate), Err(Error::AppendOrderError)));
+
+    let block = writer.close();
+    assert_eq!(block.entry_count(), 10);
+    let mut reader = GorillaReaderMV::from_block(block, 2);
+    for i in 1..=10 {
+      let entry = reader.try_next_entry().unwrap();
+      assert!(entry.time == *EPOCH + Duration::seconds(10 * i));
+      assert_eq!(entry.values, vec![i as f64, -(i as f64)]);
+    }
+    assert!(reader.try_next_entry().is_none());
+
+    // once the buffer is full the oldest entry is written, nothing older
+    // than it is accepted afterwards
+    let mut writer = GorillaWriterMV::with_vec_unordered(*EPOCH, 1, 2).unwrap();
+    for i in &[30, 20, 40] {
+      let entry = MVEntry::new(*EPOCH + Duration::seconds(*i), vec![1.0]);
+      assert!(writer.append_entry_unordered(entry).is_ok());
+    }
+    let entry = MVEntry::new(*EPOCH + Duration::seconds(10), vec![1.0]);
+    assert!(writer.append_entry_unordered(entry).is_err());
+    let entry = MVEntry::new(*EPOCH + Duration::seconds(25), vec![1.0]);
+    assert!(writer.append_entry_unordered(entry).is_ok());
+    let times: Vec<_> = writer.close().iter_timestamps(1).collect();
+    let expected: Vec<_> = [20, 25, 30, 40].iter().map(|s| *EPOCH + Duration::seconds(*s)).collect();
+    assert_eq!(times, expected);
+  }
+
+  #[test]
+  fn wide_first_delta() {
+    // 6 hours from the header to the first entry doesn't fit in 14 bits
+    let first = *EPOCH + Duration::hours(6);
+    let mut narrow = GorillaWriterMV::with_vec(*EPOCH, 2).unwrap();
+    let entry = MVEntry::new(first, vec![1.0, 2.0]);
+    assert!(matches!(narrow.append_entry(entry), Err(Error::AppendDurationError)));
+
+    // a day of entries every 5 minutes, with a 6 hour gap in the middle
+    let mut entries = Vec::new();
+    for i in 0..288 {
+      let gap = if i >= 144 { Duration::hours(6) } else { Duration::seconds(0) };
+      let time = first + Duration::minutes(5 * i) + gap;
+      entries.push(MVEntry::new(time, vec![i as f64 * 0.5, 100.0 - i as f64]));
+    }
+    let mut writer = GorillaWriterMV::with_vec_wide(*EPOCH, 2).unwrap();
+    for entry in &entries {
+      assert!(writer.append_entry(entry.clone()).is_ok());
+    }
+
+    // the width survives the string form
+    let block = GorillaBlock::new(&writer.close().to_string());
+    assert_eq!(block.entry_count(), entries.len());
+    let mut reader = GorillaReaderMV::from_block(block.clone(), 2);
+    for expected in &entries {
+      let entry = reader.try_next_entry().unwrap();
+      assert!(entry.time == expected.time);
+      assert_eq!(entry.values, expected.values);
+    }
+    assert!(reader.try_next_entry().is_none());
+
+    // and appending to the block keeps using it
+    let mut writer = GorillaWriterMV::from_existing(block, 2).unwrap();
+    let last = entries[entries.len() - 1].time + Duration::hours(12);
+    assert!(writer.append_entry(MVEntry::new(last, vec![0.0, 0.0])).is_ok());
+    let times: Vec<_> = writer.close().iter_timestamps(2).collect();
+    assert_eq!(times.len(), entries.len() + 1);
+    assert!(times[entries.len()] == last);
+  }
+
+  #[test]
+  fn int_values() {
+    // a counter going up by 100 every 10 seconds, and a noisier integer series
+    let entries: Vec<_> = (0..500i64).map(|i| {
+      let noisy = (i * i * 7919) % 1000 - 500;
+      MVEntry::new(*EPOCH + Duration::seconds(10 * (i + 1)), vec![100.0 * (i + 1) as f64, noisy as f64])
+    }).collect();
+
+    let mut writer = GorillaWriterMV::with_vec_int(*EPOCH, 2).unwrap();
+    let mut xor_writer = GorillaWriterMV::with_vec(*EPOCH, 2).unwrap();
+    for entry in &entries {
+      assert!(writer.append_entry(entry.clone()).is_ok());
+      assert!(xor_writer.append_entry(entry.clone()).is_ok());
+    }
+    let values = writer.close_with_checksum();
+    let xor_values = xor_writer.close();
+    assert!(values.data.num_bytes() < xor_values.data.num_bytes());
+
+    // the encoding survives the string form
+    let block = GorillaBlock::new(&values.to_string());
+    let mut reader = GorillaReaderMV::from_block(block.clone(), 2);
+    for expected in &entries {
+      let entry = reader.try_next_entry().unwrap();
+      assert!(entry.time == expected.time);
+      assert_eq!(entry.values, expected.values);
+    }
+    assert!(reader.try_next_entry().is_none());
+
+    // the counter alone takes a bit per value once its delta is known
+    let mut counter = GorillaWriterMV::with_vec_int(*EPOCH, 1).unwrap();
+    let mut xor_counter = GorillaWriterMV::with_vec(*EPOCH, 1).unwrap();
+    for i in 1..=3 {
+      let entry = MVEntry::new(*EPOCH + Duration::seconds(10 * i), vec![100.0 * i as f64]);
+      counter.append_entry(entry.clone()).unwrap();
+      xor_counter.append_entry(entry).unwrap();
+    }
+    assert!(counter.body.length() < xor_counter.body.length());
+
+    // only integers are accepted
+    let time = entries[entries.len() - 1].time + Duration::seconds(10);
+    let mut writer = GorillaWriterMV::from_existing(block, 2).unwrap();
+    let fraction = MVEntry::new(time, vec![1.5, 0.0]);
+    assert!(matches!(writer.append_entry(fraction), Err(Error::NonIntegerValueError)));
+
+    // and appending to the block keeps the deltas going
+    assert!(writer.append_entry(MVEntry::new(time, vec![50100.0, -(1i64 << 53) as f64])).is_ok());
+    let mut reader = GorillaReaderMV::from_block(writer.close(), 2);
+    let last = reader.skip_entries(entries.len()).and_then(|_| reader.decode_nth(0)).unwrap();
+    assert_eq!(last.values, vec![50100.0, -(1i64 << 53) as f64]);
+  }
+
+  #[test]
+  fn non_finite_values() {
+    let time = |i: i64| *EPOCH + Duration::seconds(10 * i);
+    let mut writer = GorillaWriterMV::with_vec(*EPOCH, 2).unwrap();
+    let nan = MVEntry::new(time(1), vec![f64::NAN, 1.0]);
+    assert!(matches!(writer.append_first(nan.clone()), Err(Error::NonFiniteValue)));
+    assert!(matches!(writer.append_entry(nan), Err(Error::NonFiniteValue)));
+    assert!(writer.append_entry(MVEntry::new(time(1), vec![0.0, 1.0])).is_ok());
+    let inf = MVEntry::new(time(2), vec![0.0, f64::INFINITY]);
+    assert!(matches!(writer.append_entry(inf), Err(Error::NonFiniteValue)));
+    assert!(matches!(writer.append_values(vec![f64::NEG_INFINITY, 1.0]), Err(Error::NonFiniteValue)));
+    assert_eq!(writer.close().entry_count(), 1);
+
+    // a NaN with a payload keeps its bits
+    let payload_nan = f64::from_bits(0x7ff8_0000_dead_beef);
+    let entries = vec![
+      MVEntry::new(time(1), vec![f64::NAN, 1.0]),
+      MVEntry::new(time(2), vec![payload_nan, f64::INFINITY]),
+      MVEntry::new(time(3), vec![2.5, f64::NEG_INFINITY]),
+      MVEntry::new(time(4), vec![-f64::NAN, 1.0]),
+    ];
+    let mut writer = GorillaWriterMV::with_vec(*EPOCH, 2).unwrap().allow_non_finite();
+    for entry in &entries {
+      assert!(writer.append_entry(entry.clone()).is_ok());
+    }
+    let reader = GorillaReaderMV::from_block(writer.close(), 2);
+    let decoded: Vec<MVEntry> = reader.collect();
+    assert_eq!(decoded.len(), entries.len());
+    for (entry, expected) in decoded.iter().zip(entries.iter()) {
+      let bits: Vec<u64> = entry.values.iter().map(|v| v.to_bits()).collect();
+      let expected_bits: Vec<u64> = expected.values.iter().map(|v| v.to_bits()).collect();
+      assert_eq!(bits, expected_bits);
+    }
+  }
+
+  #[test]
+  fn reset_windows() {
+    // a value oscillating by a small step with a spike every 20 entries,
+    // after a spike the window is too wide for the small steps
+    let entries: Vec<MVEntry> = (0..500).map(|i| {
+      let value = if i % 20 == 0 { 1e6 + i as f64 } else { 20.0 + (i % 2) as f64 * 0.5 };
+      MVEntry::new(*EPOCH + Duration::seconds(10 * (i + 1)), vec![value])
+    }).collect();
+
+    let mut plain = GorillaWriterMV::with_vec(*EPOCH, 1).unwrap();
+    let mut reset = GorillaWriterMV::with_vec(*EPOCH, 1).unwrap().reset_windows();
+    for entry in &entries {
+      assert!(plain.append_entry(entry.clone()).is_ok());
+      assert!(reset.append_entry(entry.clone()).is_ok());
+    }
+    let plain = plain.close();
+    let reset = reset.close();
+    assert!(reset.compressed_bytes() < plain.compressed_bytes());
+
+    // both read back the same
+    let decoded = crate::gorilla::api::retrieve_values(reset, 1, entries.len());
+    for (entry, expected) in decoded.iter().zip(entries.iter()) {
+      assert_eq!(entry.values, expected.values);
+    }
+  }
+}
@@ -19,7 +19,6 @@ use std::collections::hash_map::DefaultHasher;
 use std::hash::{Hash, Hasher};
 
 use chrono::{Utc, TimeZone};
-use byteorder::*;
 
 use storage::lsmtree::*;
 use gorilla::*;
@@ -106,7 +105,6 @@ fn main() {
                                 // 2. Use MVEntry vector to construct a GorillaBlock
                                 // 3. insert {importkey, GorillaBlock} to LSMTree
                                 // 4. reset the MVEntry array
-                                let tagstr = prev_tag.clone();
                                 let start_dt = curr_mventries[0].time();
                                 let start_dt_nanots = start_dt.timestamp_nanos();
 
@@ -116,30 +114,14 @@ fn main() {
                                     firstkey = false;
                                 }
 
-                                // convert ckeyhash to string format
-                                let mut ckeybuf = Vec::new();
-                                ckeybuf.write_u64::<LittleEndian>(ckeyhash);
-                                let ckeybytes = unsafe {
-                                    str::from_utf8_unchecked(&ckeybuf)
-                                };
-                                let mut ckeystr = String::from(ckeybytes);
-
-                                // convert start timestamp to string format
-                                let mut start_tsbuf = Vec::new();
-                                start_tsbuf.write_u64::<LittleEndian>(start_dt_nanots as u64);
-                                let start_tsbytes = unsafe {
-                                    str::from_utf8_unchecked(&start_tsbuf)
-                                };
-                                let start_tsstr = String::from(start_tsbytes);
-
-                                // combine construct key {tag & metric} with init timestamp
-                                ckeystr.push_str(&start_tsstr);
+                                // the import key {metric, tags, start_dt}, keys of the
+                                // same series sort by time
+                                let ckeystr = SeriesKey::new(tokens[0], &prev_tag, start_dt).to_key();
 
                                 let entryblk = compress_values(curr_mventries.to_vec(),
                                                                start_dt,
-                                                               curr_mventries[0].values().len());
-                                let entryblkstr = entryblk.to_string();
-                                tree.set(&ckeystr, &entryblkstr);
+                                                               curr_mventries[0].values_ref().len());
+                                tree.set_bytes(&ckeystr, &entryblk.to_bytes()).unwrap();
 
                                 // reset MVEntry vector for current {tags, metric}
                                 key_entry_table.remove(&ckeyhash);
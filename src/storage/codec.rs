@@ -0,0 +1,37 @@
+// Codecs of the series blocks stored in a RustyStore, so series stored in
+// different formats share a store
+use crate::storage::error::Error;
+use crate::gorilla::{GorillaBlock, GorillaDateTime, GorillaWriterMV, MVEntry};
+use crate::gorilla::api::retrieve_values;
+
+// A block written through a codec is stored as the id (u8) the codec is
+// registered under on the store followed by the bytes of 'Codec::encode'.
+// The tree doesn't look into values, 'RustyStore::query' picks the codec of a
+// block by its first byte. Gorilla blocks stored without an id still read.
+
+pub trait Codec: Send {
+    // the bytes of a block of 'dim' dimensional entries starting at 'start'
+    fn encode(&self, start: GorillaDateTime, entries: &[MVEntry], dim: usize) -> Result<Vec<u8>, Error>;
+
+    // the entries of a block written by 'encode'
+    fn decode(&self, bytes: &[u8], dim: usize) -> Result<Vec<MVEntry>, Error>;
+}
+
+// blocks serialized by 'GorillaBlock::to_bytes'
+pub struct GorillaCodec;
+
+impl Codec for GorillaCodec {
+    fn encode(&self, start: GorillaDateTime, entries: &[MVEntry], dim: usize) -> Result<Vec<u8>, Error> {
+        let invalid = |e| Error::InvalidArgument(format!("can't encode GorillaBlock: {}", e));
+        let mut writer = GorillaWriterMV::with_vec(start, dim).map_err(invalid)?;
+        writer.append_entries(entries).map_err(invalid)?;
+        Ok(writer.close().to_bytes())
+    }
+
+    fn decode(&self, bytes: &[u8], dim: usize) -> Result<Vec<MVEntry>, Error> {
+        let corrupt = |e| Error::Corrupt(format!("bad GorillaBlock value: {}", e));
+        let block = GorillaBlock::from_bytes(bytes).map_err(corrupt)?;
+        let num_entries = block.num_entries(dim).map_err(corrupt)?;
+        Ok(retrieve_values(block, dim, num_entries))
+    }
+}
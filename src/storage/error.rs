@@ -0,0 +1,60 @@
+use std::error::Error as StdError;
+use std::fmt;
+use std::io;
+
+#[derive(Debug)]
+pub enum Error {
+    Io(io::Error),
+    // data read back from disk that doesn't decode, with what is wrong
+    Corrupt(String),
+    BadUtf8,
+    // an SSTable footer pointing outside the file
+    InvalidFooter,
+    // a setting or argument the storage can't work with
+    InvalidArgument(String),
+    // a file written in a format version this build can't read
+    UnsupportedVersion { format: &'static str, version: u32 },
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Io(e) => write!(f, "IO error: {}", e),
+            Error::Corrupt(reason) => write!(f, "Corrupt data: {}", reason),
+            Error::BadUtf8 => f.write_str("Key or value is not valid UTF-8"),
+            Error::InvalidFooter => f.write_str("SSTable footer is out of bounds"),
+            Error::InvalidArgument(reason) => write!(f, "Invalid argument: {}", reason),
+            Error::UnsupportedVersion { format, version } => write!(f, "Unsupported {} format version {}", format, version),
+        }
+    }
+}
+
+impl StdError for Error {
+    fn source(&self) -> Option<&(dyn StdError + 'static)> {
+        match self {
+            Error::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    // a storage error passed through an io::Error, e.g. by a Read impl, is
+    // unwrapped again
+    fn from(error: io::Error) -> Self {
+        if error.get_ref().is_some_and(|inner| inner.is::<Error>()) {
+            return *error.into_inner().unwrap().downcast::<Error>().unwrap();
+        }
+        Error::Io(error)
+    }
+}
+
+impl From<Error> for io::Error {
+    fn from(error: Error) -> Self {
+        match error {
+            Error::Io(e) => e,
+            Error::InvalidArgument(_) => io::Error::new(io::ErrorKind::InvalidInput, error),
+            _ => io::Error::new(io::ErrorKind::InvalidData, error),
+        }
+    }
+}
@@ -0,0 +1,85 @@
+// Format versions of the files read back across releases
+use std::io::{BufRead, Write};
+
+use byteorder::*;
+
+use crate::storage::error::Error;
+
+// The WAL segments and the metadata file start with a header: a magic naming
+// the kind of file (4 bytes) followed by the version of its format (u8).
+// Files written before the header was added start right away with their
+// contents and read as 'LEGACY_VERSION'. SSTables carry their own magic and
+// version, see sstable.rs. Every number on disk is little endian.
+
+pub const LEGACY_VERSION: u8 = 0;
+
+pub struct Format {
+    // what the file is called in errors
+    pub name: &'static str,
+    pub magic: &'static [u8; 4],
+    // the version written, the only one read besides 'LEGACY_VERSION'
+    pub version: u8,
+}
+
+// the magic doesn't start with a WAL record type, so a legacy segment never
+// reads as having a header
+pub const WAL_FORMAT: Format = Format { name: "WAL", magic: b"RWAL", version: 1 };
+
+pub const META_FORMAT: Format = Format { name: "metadata", magic: b"RMET", version: 1 };
+
+impl Format {
+    pub fn write_header<W: Write>(&self, writer: &mut W) -> Result<(), Error> {
+        writer.write_all(self.magic)?;
+        writer.write_u8(self.version)?;
+        Ok(())
+    }
+
+    // the format version of the file 'reader' is at the start of, its header
+    // is consumed, a legacy file is left as it is
+    // any version other than 'self.version' is an 'Error::UnsupportedVersion'
+    pub fn read_header<R: BufRead>(&self, reader: &mut R) -> Result<u8, Error> {
+        if !reader.fill_buf()?.starts_with(self.magic) {
+            return Ok(LEGACY_VERSION);
+        }
+        reader.consume(self.magic.len());
+
+        let version = reader.read_u8()?;
+        if version != self.version {
+            return Err(Error::UnsupportedVersion { format: self.name, version: version as u32 });
+        }
+        Ok(version)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::storage::format::*;
+    use std::io::{Cursor, Read};
+
+    #[test]
+    fn format_version() {
+        let mut buf = Vec::new();
+        WAL_FORMAT.write_header(&mut buf).unwrap();
+        buf.extend_from_slice(b"contents");
+
+        let mut reader = Cursor::new(buf.clone());
+        assert_eq!(WAL_FORMAT.read_header(&mut reader).unwrap(), WAL_FORMAT.version);
+        let mut contents = String::new();
+        reader.read_to_string(&mut contents).unwrap();
+        assert_eq!(contents, "contents");
+
+        // a reader expecting the next version refuses the file
+        let bumped = Format { version: WAL_FORMAT.version + 1, ..WAL_FORMAT };
+        match bumped.read_header(&mut Cursor::new(buf.clone())) {
+            Err(Error::UnsupportedVersion { format: "WAL", version }) => assert_eq!(version, WAL_FORMAT.version as u32),
+            other => panic!("unexpected result {:?}", other),
+        }
+
+        // another kind of file without a header reads from its start
+        let mut reader = Cursor::new(buf.clone());
+        assert_eq!(META_FORMAT.read_header(&mut reader).unwrap(), LEGACY_VERSION);
+        assert_eq!(reader.position(), 0);
+        let mut reader = Cursor::new(Vec::new());
+        assert_eq!(META_FORMAT.read_header(&mut reader).unwrap(), LEGACY_VERSION);
+    }
+}
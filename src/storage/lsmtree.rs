@@ -1,12 +1,23 @@
 use crate::storage::sstable::*;
+use crate::storage::wal::log_files;
+use crate::storage::format::META_FORMAT;
+use crate::storage::error::Error;
+use crate::gorilla::{GorillaBlock, GorillaDateTime, MVEntry};
+use crate::gorilla::api::retrieve_values;
 
 use std::io;
 use std::fs;
 use std::mem;
-use std::io::{Read, Write};
+use std::str;
+use std::io::{BufReader, Read, Write};
 use std::path::{Path, PathBuf};
-use std::sync::{Arc, Mutex};
-use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex, Weak};
+use std::thread;
+use std::cmp::Reverse;
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::collections::{BTreeMap, BinaryHeap};
+use std::ops::{Bound, RangeBounds, RangeFull};
 
 use uuid::Uuid;
 use byteorder::*;
@@ -42,32 +53,190 @@ use byteorder::*;
 // the metadata filename
 const META_FILENAME: &'static str = "rustydb.meta";
 
+// where the metadata is written before it replaces the metadata file
+const META_TMP_FILENAME: &str = "rustydb.meta.tmp";
+
 // memtable threshold in bytes (4MB)
 const MEMTABLE_THRESHOLD: usize = 4 * 1024 * 1024;
 
+// WAL threshold in bytes (64MB)
+const WAL_THRESHOLD: usize = 64 * 1024 * 1024;
+
 const SSTABLE_FANOUT: usize = 4;
 
+// the sstable readers kept open, each holds its index and a file handle
+const READER_CACHE_SIZE: usize = 64;
+
+// the tunables of an LSMTree, 'LSMTree::new' uses the defaults above
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct LSMConfig {
+    // memtable size in bytes that triggers a flush
+    pub memtable_threshold: usize,
+    // number of sstables in a level that get merged into the next level
+    pub fanout: usize,
+    // threads a 'get' searches the sstables holding its key with, 1 or less
+    // searches them one at a time
+    pub get_threads: usize,
+    // sstable readers kept open for 'get', 0 opens a reader every time
+    pub reader_cache_size: usize,
+    // bytes of WAL records that trigger a flush in a RustyStore, bounding
+    // what a recovery replays, 0 leaves flushes to the memtable threshold
+    pub wal_threshold: usize,
+}
+
+impl Default for LSMConfig {
+    fn default() -> Self {
+        LSMConfig {
+            memtable_threshold: MEMTABLE_THRESHOLD,
+            fanout: SSTABLE_FANOUT,
+            get_threads: 1,
+            reader_cache_size: READER_CACHE_SIZE,
+            wal_threshold: WAL_THRESHOLD,
+        }
+    }
+}
+
+// -------------------- Keys --------------------
+
+// encode a timestamp as a key suffix, fixed width hex of the nanoseconds
+// since epoch so lexicographic key order matches time order
+pub fn timestamp_key(ts: GorillaDateTime) -> String {
+    nanos_key(saturating_nanos(ts))
+}
+
+// nanoseconds since epoch, times outside of the i64 range (about 1677 to
+// 2262) saturate to its ends so they still sort before or after every other
+fn saturating_nanos(ts: GorillaDateTime) -> i64 {
+    ts.timestamp_nanos_opt().unwrap_or(if ts.timestamp() < 0 { i64::MIN } else { i64::MAX })
+}
+
+// an inclusive bound above every key 'prefix + timestamp_key(t)' with t not
+// after 'ts', whatever follows the timestamp
+fn timestamp_key_bound(prefix: &str, ts: GorillaDateTime) -> String {
+    format!("{}{}{}", prefix, timestamp_key(ts), char::MAX)
+}
+
+// the sign bit is flipped so times before the epoch sort first
+fn nanos_key(nanos: i64) -> String {
+    format!("{:016x}", (nanos as u64) ^ (1 << 63))
+}
+
+// the key prefix of every block of a series: fixed width hex of its construct
+// key hash
+pub fn series_key_prefix(construct_key_hash: u64) -> String {
+    format!("{:016x}", construct_key_hash)
+}
+
+// the key of the block of a series (a metric and its tags) starting at
+// 'start_ts' nanoseconds since epoch
+#[derive(Clone, Debug, PartialEq)]
+pub struct SeriesKey {
+    pub metric: String,
+    pub tags: String,
+    pub start_ts: i64,
+}
+
+impl SeriesKey {
+    pub fn new(metric: &str, tags: &str, start: GorillaDateTime) -> Self {
+        SeriesKey {
+            metric: metric.to_string(),
+            tags: tags.to_string(),
+            start_ts: saturating_nanos(start),
+        }
+    }
+
+    // the construct key hash of the series, the same for every block of it
+    pub fn series_hash(&self) -> u64 {
+        let mut hasher = DefaultHasher::new();
+        self.tags.hash(&mut hasher);
+        self.metric.hash(&mut hasher);
+        hasher.finish()
+    }
+
+    // 'series_key_prefix' and the timestamp key, so keys sort by series and
+    // then by time, followed by the metric length (8 hex digits), the metric
+    // and the tags
+    pub fn to_key(&self) -> String {
+        format!("{}{}{:08x}{}{}", series_key_prefix(self.series_hash()), nanos_key(self.start_ts),
+                self.metric.len(), self.metric, self.tags)
+    }
+
+    pub fn to_key_bytes(&self) -> Vec<u8> {
+        self.to_key().into_bytes()
+    }
+
+    // the key written by 'to_key_bytes', fails on any other bytes
+    pub fn from_key_bytes(bytes: &[u8]) -> Result<Self, Error> {
+        let malformed = || Error::Corrupt(String::from("malformed series key"));
+        let key = str::from_utf8(bytes).map_err(|_| malformed())?;
+        let hex = |range: std::ops::Range<usize>| {
+            key.get(range)
+                .filter(|digits| digits.bytes().all(|b| b.is_ascii_hexdigit()))
+                .and_then(|digits| u64::from_str_radix(digits, 16).ok())
+                .ok_or_else(malformed)
+        };
+        let hash = hex(0..16)?;
+        let start_ts = (hex(16..32)? ^ (1 << 63)) as i64;
+        let metric_end = 40usize.checked_add(hex(32..40)? as usize).ok_or_else(malformed)?;
+        let series = SeriesKey {
+            metric: key.get(40..metric_end).ok_or_else(malformed)?.to_string(),
+            tags: key.get(metric_end..).ok_or_else(malformed)?.to_string(),
+            start_ts,
+        };
+        if series.series_hash() != hash {
+            return Err(Error::Corrupt(String::from("series key hash mismatch")));
+        }
+        Ok(series)
+    }
+}
+
 // -------------------- SSTableMeta --------------------
 
 // contains the metainfo of a single SSTable file, the LSM Tree keeps track of
 // all SSTable files using a vector of these structs.
 // reconstructed on database initialization
 struct SSTableMeta {
-    filename: String,           // the filename of the SSTable file and index
+    filename: String,           // the filename of the SSTable file and index, an
+                                // absolute path once evicted to cold storage
     level: usize,               // the level of the SSTable
     min_key: String,            // the minimum key of the SSTable
     max_key: String,            // the maximum key of the SSTable
+    seqno: u64,                 // the generation of the SSTable, a key in
+                                // several SSTables resolves to the highest one
+    stats: Mutex<SSTableStats>, // accumulated over every reader opened on the file
+    obsolete: Mutex<Option<PathBuf>>,   // the path of a file no longer listed by
+                                        // the tree, deleted with the last reference
 }
 
 impl SSTableMeta {
-    fn new(minkey: &str, maxkey: &str) -> Self {
+    fn new(minkey: &str, maxkey: &str, seqno: u64) -> Self {
         let ufname = Uuid::new_v4().to_hyphenated().to_string();
         SSTableMeta {
             filename: format!("{}.sst", ufname),
             level: 0,
             min_key: String::from(minkey),
             max_key: String::from(maxkey),
+            seqno,
+            stats: Mutex::new(SSTableStats::default()),
+            obsolete: Mutex::new(None),
+        }
+    }
+
+    // add the access counters of a reader about to be dropped
+    fn record_stats(&self, reader_stats: &SSTableStats) {
+        self.stats.lock().unwrap().add(reader_stats);
+    }
+
+    // delete the file at 'path' once the tree no longer lists it, right away
+    // unless a snapshot still reads it, then when the last snapshot is dropped
+    fn unlink(self: Arc<Self>, path: PathBuf) -> Result<(), Error> {
+        *self.obsolete.lock().unwrap() = Some(path);
+        if let Ok(mut sstable) = Arc::try_unwrap(self) {
+            if let Some(path) = sstable.obsolete.get_mut().unwrap().take() {
+                fs::remove_file(path)?;
+            }
         }
+        Ok(())
     }
 
     fn in_range(&self, key: &str) -> bool {
@@ -76,34 +245,67 @@ impl SSTableMeta {
     }
 }
 
+impl Drop for SSTableMeta {
+    fn drop(&mut self) {
+        if let Some(path) = self.obsolete.get_mut().unwrap().take() {
+            let _ = fs::remove_file(path);
+        }
+    }
+}
+
 // -------------------- LSMTree --------------------
 
 // a memtable stores both (key, val) pairs as well as the anticipated
 // size if it get flushed to disk as sstable file
+#[derive(Clone)]
 struct MemTable {
-    map: BTreeMap<String, String>,
+    // a None value is a tombstone for a deleted key, values aren't
+    // necessarily valid utf8
+    map: BTreeMap<String, Option<Vec<u8>>>,
     flush_size: usize,
+    // the flush_size that triggers a flush
+    threshold: usize,
 }
 
 impl MemTable {
-    fn new() -> Self {
+    fn new(threshold: usize) -> Self {
         MemTable {
             map: BTreeMap::new(),
             flush_size: 0,
+            threshold,
         }
     }
 
-    fn insert(&mut self, key: &str, val: &str) {
-        self.map.insert(key.to_string(), val.to_string());
+    fn insert(&mut self, key: &str, val: &[u8]) {
+        self.map.insert(key.to_string(), Some(val.to_vec()));
 
         // if flushed to disk, we store the following format:
         // | keylen: u32 | key bytes | valuelen: u32 | value bytes |
         self.flush_size += 2 * mem::size_of::<u32>() + key.len() + val.len();
     }
 
-    fn need_flush(&self, key: &str, val: &str) -> bool {
+    fn insert_tombstone(&mut self, key: &str) {
+        self.map.insert(key.to_string(), None);
+
+        // | keylen: u32 | key bytes | TOMBSTONE_LEN: u32 |
+        self.flush_size += 2 * mem::size_of::<u32>() + key.len();
+    }
+
+    fn need_flush(&self, key: &str, val: &[u8]) -> bool {
         let pairsz = 2 * mem::size_of::<u32>() + key.len() + val.len();
-        self.flush_size + pairsz > MEMTABLE_THRESHOLD
+        self.flush_size + pairsz > self.threshold
+    }
+
+    // drop every key in [start, end)
+    fn remove_range(&mut self, start: &str, end: &str) {
+        let keys: Vec<String> = self.map.range::<str, _>((Bound::Included(start), Bound::Excluded(end)))
+            .map(|(k, _)| k.clone())
+            .collect();
+        for key in keys {
+            let val = self.map.remove(&key).unwrap();
+            let valsz = val.map_or(0, |val| val.len());
+            self.flush_size = self.flush_size.saturating_sub(2 * mem::size_of::<u32>() + key.len() + valsz);
+        }
     }
 
     fn get_minkey(&self) -> String {
@@ -114,46 +316,515 @@ impl MemTable {
         self.map.keys().next_back().unwrap().to_string()
     }
 
-    fn reset(&mut self) {
-        self.map.clear();
-        self.flush_size = 0;
-    }
-
-    fn write_entries_to_sstable(&self, sst: &mut SSTableFileBuilder) -> Result<(), io::Error> {
+    fn write_entries_to_sstable(&self, sst: &mut SSTableFileBuilder<BytesCodec>) -> Result<(), Error> {
         for entry in &self.map {
-            sst.add(&entry.0, &entry.1)?;
+            match entry.1 {
+                Some(val) => sst.add(entry.0, val)?,
+                None => sst.add_tombstone(entry.0)?,
+            }
         }
         Ok(())
     }
 }
 
+// a memtable on its way to an sstable file, returned by 'LSMTree::begin_flush'
+// the file is written without access to the tree, so a writer thread can keep
+// using the tree meanwhile
+pub struct PendingFlush {
+    path: PathBuf,
+    memtable: Arc<MemTable>,
+    meta: SSTableMeta,
+}
+
+impl PendingFlush {
+    pub fn write(&self) -> Result<(), Error> {
+        let mut sst_builder = SSTableFileBuilder::<BytesCodec>::with_codec(&self.path)?;
+        sst_builder.set_tree_position(self.meta.seqno, self.meta.level);
+        self.memtable.write_entries_to_sstable(&mut sst_builder)?;
+        sst_builder.commit()
+    }
+}
+
+// how far 'LSMTree::compact_with_progress' has got, reported once per sstable
+// it merges
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct CompactionProgress {
+    // the level whose sstables were merged into the next one
+    pub level: usize,
+    // source sstables merged so far
+    pub files_merged: usize,
+    // the size of the merged sstables written so far
+    pub bytes_written: u64,
+}
+
+// the sstables of a level, see 'LSMTree::level_stats'
+#[derive(Clone, Debug, PartialEq)]
+pub struct LevelInfo {
+    pub level: usize,
+    pub num_files: usize,
+    // the size of the files on disk
+    pub total_bytes: u64,
+    // the key range covered by the files of the level
+    pub min_key: String,
+    pub max_key: String,
+}
+
+// -------------------- Watches --------------------
+
+// called with (key, val) after a matching key is written
+pub type WatchCallback = Arc<dyn Fn(&str, &str) + Send + Sync>;
+
+struct Watch {
+    id: u64,
+    prefix: String,
+    callback: WatchCallback,
+}
+
+// returned by 'LSMTree::watch', cancel it to stop receiving callbacks
+pub struct WatchHandle {
+    id: u64,
+    watches: Weak<Mutex<Vec<Watch>>>,
+}
+
+impl WatchHandle {
+    pub fn cancel(self) {
+        if let Some(watches) = self.watches.upgrade() {
+            watches.lock().unwrap().retain(|w| w.id != self.id);
+        }
+    }
+}
+
+// -------------------- LSMScan --------------------
+
+// a source of (key, val) pairs in key order, a None value is a tombstone
+type ScanSource<'a> = Box<dyn Iterator<Item = (String, Option<Vec<u8>>)> + 'a>;
+
+// every (key, val) pair of an LSMTree in key order, returned by 'LSMTree::scan'
+// a k-way merge of the memtables and sstables listed newest first, a key in
+// several of them is taken from the newest and tombstones are left out
+pub struct LSMScan<'a> {
+    sources: Vec<ScanSource<'a>>,
+    // the value of the next key of every source, the heap pops the smallest
+    // key and for equal keys the newest source first
+    heads: Vec<Option<Option<Vec<u8>>>>,
+    heap: BinaryHeap<Reverse<(String, usize)>>,
+    last_key: Option<String>,
+}
+
+impl<'a> LSMScan<'a> {
+    fn new(sources: Vec<ScanSource<'a>>) -> Self {
+        let mut scan = LSMScan {
+            heads: vec![None; sources.len()],
+            sources,
+            heap: BinaryHeap::new(),
+            last_key: None,
+        };
+        for source in 0..scan.sources.len() {
+            scan.advance(source);
+        }
+        scan
+    }
+
+    // read the next entry of 'source'
+    fn advance(&mut self, source: usize) {
+        if let Some((key, val)) = self.sources[source].next() {
+            self.heap.push(Reverse((key, source)));
+            self.heads[source] = Some(val);
+        }
+    }
+}
+
+impl<'a> Iterator for LSMScan<'a> {
+    type Item = (String, Vec<u8>);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while let Some(Reverse((key, source))) = self.heap.pop() {
+            let val = self.heads[source].take().unwrap();
+            self.advance(source);
+
+            // an older source's entry for a key already seen
+            if self.last_key.as_ref() == Some(&key) {
+                continue;
+            }
+            self.last_key = Some(key.clone());
+            if let Some(val) = val {
+                return Some((key, val));
+            }
+        }
+        None
+    }
+}
+
+// -------------------- Range tombstones --------------------
+
+// every key in [start, end) deleted by 'LSMTree::delete_range', it hides the
+// values read from memtables and sstables with a seqno below its own, and
+// compaction leaves them out of the sstables it merges
+#[derive(Clone, Debug, PartialEq)]
+struct RangeTombstone {
+    start: String,
+    end: String,
+    seqno: u64,
+}
+
+impl RangeTombstone {
+    fn hides(&self, key: &str, seqno: u64) -> bool {
+        seqno < self.seqno && self.start.as_str() <= key && key < self.end.as_str()
+    }
+
+    // whether a key in [min_key, max_key] may be in the range
+    fn overlaps(&self, min_key: &str, max_key: &str) -> bool {
+        self.start.as_str() <= max_key && min_key < self.end.as_str()
+    }
+}
+
+// whether the value of 'key' read from the memtable or sstable with 'seqno'
+// was deleted by a range
+fn is_hidden(tombstones: &[RangeTombstone], key: &str, seqno: u64) -> bool {
+    tombstones.iter().any(|t| t.hides(key, seqno))
+}
+
+// a (len: u32, bytes) string of the metadata file
+fn read_meta_string<R: Read>(metafile: &mut R) -> Result<String, Error> {
+    let len = metafile.read_u32::<LittleEndian>()? as usize;
+    let mut buf = vec![0u8; len];
+    metafile.read_exact(&mut buf)?;
+    String::from_utf8(buf).map_err(|_| Error::BadUtf8)
+}
+
+// -------------------- Snapshot --------------------
+
+// the sstables from the highest seqno down, the order reads resolve keys
+// present in several of them
+fn newest_first(sstables: &[Arc<SSTableMeta>]) -> Vec<&SSTableMeta> {
+    let mut sstables: Vec<&SSTableMeta> = sstables.iter().map(|s| &**s).collect();
+    sstables.sort_by_key(|s| Reverse(s.seqno));
+    sstables
+}
+
+// -------------------- ReaderCache --------------------
+
+// an sstable reader shared by the gets of a tree and its snapshots
+type CachedReader = Arc<Mutex<SSTableFileReader<BytesCodec>>>;
+
+// the sstable readers used last by 'get', so their indexes are only loaded
+// once, keyed by the sstable filename, the least recently used one is closed
+// when there are more than 'capacity'
+struct ReaderCache {
+    capacity: usize,
+    // least recently used first
+    readers: Mutex<Vec<(String, CachedReader)>>,
+}
+
+impl ReaderCache {
+    fn new(capacity: usize) -> Self {
+        ReaderCache {
+            capacity,
+            readers: Mutex::new(Vec::new()),
+        }
+    }
+
+    // the reader of the sstable 'filename' under 'path', opened unless cached
+    fn get(&self, path: &Path, filename: &str) -> Result<CachedReader, Error> {
+        {
+            let mut readers = self.readers.lock().unwrap();
+            if let Some(i) = readers.iter().position(|(f, _)| f == filename) {
+                let entry = readers.remove(i);
+                let reader = entry.1.clone();
+                readers.push(entry);
+                return Ok(reader);
+            }
+        }
+
+        // the file is opened without holding the lock, so a get racing this
+        // one may open it too, only one of the readers is kept
+        let reader = SSTableFileReader::<BytesCodec>::open_with_codec(&path.join(filename))?;
+        let reader = Arc::new(Mutex::new(reader));
+        if self.capacity > 0 {
+            let mut readers = self.readers.lock().unwrap();
+            if !readers.iter().any(|(f, _)| f == filename) {
+                readers.push((filename.to_string(), reader.clone()));
+                if readers.len() > self.capacity {
+                    readers.remove(0);
+                }
+            }
+        }
+        Ok(reader)
+    }
+
+    // close the reader of an sstable the tree no longer lists
+    fn invalidate(&self, filename: &str) {
+        self.readers.lock().unwrap().retain(|(f, _)| f != filename);
+    }
+}
+
+// the entry of 'key' in a single sstable
+fn search_sstable(readers: &ReaderCache, path: &Path, sstable: &SSTableMeta, key: &str)
+    -> Result<Option<SSTableValue<Vec<u8>>>, Error> {
+    let currsst = readers.get(path, &sstable.filename)?;
+    let currsst = currsst.lock().unwrap();
+    let val = currsst.get_entry(key);
+    sstable.record_stats(&currsst.take_stats());
+    val
+}
+
+// an entry found by a 'get' and the sstable it was found in
+type SSTableHit<'a> = (SSTableValue<Vec<u8>>, &'a SSTableMeta);
+
+// the entry of 'key' in the first of 'candidates', newest first, that has one
+fn search_sequential<'a>(readers: &ReaderCache, path: &Path, candidates: &[&'a SSTableMeta], key: &str)
+    -> Result<Option<SSTableHit<'a>>, Error> {
+    for sstable in candidates {
+        if let Some(val) = search_sstable(readers, path, sstable, key)? {
+            return Ok(Some((val, *sstable)));
+        }
+    }
+    Ok(None)
+}
+
+// same as 'search_sequential' with the candidates spread over 'threads'
+// threads, each one searches every threads-th candidate in order and stops at
+// its first entry or error, the earliest of those is what a sequential search
+// would have stopped at
+fn search_parallel<'a>(readers: &ReaderCache, path: &Path, candidates: &[&'a SSTableMeta], key: &str,
+                       threads: usize)
+    -> Result<Option<SSTableHit<'a>>, Error> {
+    let threads = threads.min(candidates.len());
+    let hits = thread::scope(|scope| {
+        let workers: Vec<_> = (0..threads).map(|worker| scope.spawn(move || {
+            for (i, sstable) in candidates.iter().enumerate().skip(worker).step_by(threads) {
+                match search_sstable(readers, path, sstable, key) {
+                    Ok(None) => continue,
+                    found => return Some((i, found)),
+                }
+            }
+            None
+        })).collect();
+        workers.into_iter().filter_map(|worker| worker.join().unwrap()).collect::<Vec<_>>()
+    });
+
+    match hits.into_iter().min_by_key(|(i, _)| *i) {
+        Some((i, found)) => Ok(found?.map(|val| (val, candidates[i]))),
+        None => Ok(None),
+    }
+}
+
+// a value read back as a string
+fn utf8_value(val: Vec<u8>) -> Result<String, Error> {
+    String::from_utf8(val).map_err(|_| Error::BadUtf8)
+}
+
+// the entries of the blocks of a series with a time in [start, end] in time
+// order, an entry at the seam of two blocks is only returned once, from the
+// first block
+pub fn series_window(blocks: impl Iterator<Item = Vec<MVEntry>>, start: GorillaDateTime, end: GorillaDateTime)
+    -> Vec<MVEntry>
+{
+    let mut entries: Vec<MVEntry> = blocks.flatten()
+        .filter(|entry| start <= entry.time() && entry.time() <= end)
+        .collect();
+
+    // the sort is stable, so of equal times the first block's entry is kept
+    entries.sort_by_key(|entry| entry.time());
+    entries.dedup_by_key(|entry| entry.time());
+    entries
+}
+
+// what reads resolve keys against, the current state of an LSMTree or the
+// state a snapshot was taken in
+struct View<'a> {
+    path: &'a Path,
+    // newest first, the first one is flushed with 'next_seqno' and the one
+    // after it, when a flush is in progress, with the seqno before
+    memtables: Vec<&'a MemTable>,
+    next_seqno: u64,
+    sstables: &'a [Arc<SSTableMeta>],
+    tombstones: &'a [RangeTombstone],
+    get_threads: usize,
+    readers: &'a ReaderCache,
+}
+
+impl<'a> View<'a> {
+    // the memtables newest first, with the seqno of their flush
+    fn memtables(&self) -> impl Iterator<Item = (&'a MemTable, u64)> + '_ {
+        self.memtables.iter().enumerate().map(move |(i, memtable)| (*memtable, self.next_seqno - i as u64))
+    }
+
+    // same as 'get' for a key still in memory, None if it is only on disk
+    fn get_memtable(&self, key: &str) -> Option<Option<(Vec<u8>, u64)>> {
+        for (memtable, seqno) in self.memtables() {
+            if let Some(s) = memtable.map.get(key) {
+                if is_hidden(self.tombstones, key, seqno) {
+                    return Some(None);
+                }
+                return Some(s.clone().map(|s| (s, seqno)));
+            }
+        }
+        None
+    }
+
+    // the value of 'key' with the seqno of the memtable or sstable it was
+    // read from, None if it was deleted
+    fn get(&self, key: &str) -> Result<Option<(Vec<u8>, u64)>, Error> {
+        if let Some(found) = self.get_memtable(key) {
+            return Ok(found);
+        }
+
+        let candidates: Vec<&SSTableMeta> = newest_first(self.sstables).into_iter()
+            .filter(|sstable| sstable.in_range(key))
+            .collect();
+        let found = if self.get_threads > 1 && candidates.len() > 1 {
+            search_parallel(self.readers, self.path, &candidates, key, self.get_threads)?
+        } else {
+            search_sequential(self.readers, self.path, &candidates, key)?
+        };
+
+        if let Some((val, sstable)) = found {
+            if is_hidden(self.tombstones, key, sstable.seqno) {
+                return Ok(None);
+            }
+            return Ok(val.value().map(|val| (val, sstable.seqno)));
+        }
+        Ok(None)
+    }
+
+    // every (key, val) pair with a key in [start, end] in key order, the
+    // memtables shadow the sstables and higher seqnos shadow lower ones
+    fn range(&self, start: &str, end: &str) -> Result<Vec<(String, Vec<u8>)>, Error> {
+        if end < start {
+            return Ok(Vec::new());
+        }
+
+        let mut merged: BTreeMap<String, Option<Vec<u8>>> = BTreeMap::new();
+        for (memtable, seqno) in self.memtables() {
+            for (k, v) in memtable.map.range(start.to_string()..=end.to_string()) {
+                let hidden = is_hidden(self.tombstones, k, seqno);
+                merged.entry(k.clone()).or_insert_with(|| if hidden { None } else { v.clone() });
+            }
+        }
+
+        for sstable in newest_first(self.sstables) {
+            if sstable.max_key.as_str() < start || end < sstable.min_key.as_str() {
+                continue;
+            }
+            let path = self.path.join(&sstable.filename);
+            let currsst = SSTableFileReader::<BytesCodec>::open_with_codec(&path)?;
+            for (key, val) in currsst.iter_range(start, end) {
+                let hidden = is_hidden(self.tombstones, &key, sstable.seqno);
+                merged.entry(key).or_insert_with(|| if hidden { None } else { val.value() });
+            }
+            sstable.record_stats(&currsst.stats());
+        }
+
+        // drop deleted keys
+        Ok(merged.into_iter().filter_map(|(k, v)| v.map(|v| (k, v))).collect())
+    }
+}
+
+// a read-only view of an LSMTree at the time 'LSMTree::snapshot' was called
+// it shares the memtables, written to copy-on-write, and the sstable list of
+// the tree, the sstable files it reads aren't deleted until it is dropped
+pub struct Snapshot {
+    path: PathBuf,
+    // newest first
+    memtables: Vec<Arc<MemTable>>,
+    next_seqno: u64,
+    sstables: Arc<Vec<Arc<SSTableMeta>>>,
+    range_tombstones: Arc<Vec<RangeTombstone>>,
+    get_threads: usize,
+    readers: Arc<ReaderCache>,
+}
+
+impl Snapshot {
+    fn view(&self) -> View<'_> {
+        View {
+            path: &self.path,
+            memtables: self.memtables.iter().map(|m| &**m).collect(),
+            next_seqno: self.next_seqno,
+            sstables: &self.sstables,
+            tombstones: &self.range_tombstones,
+            get_threads: self.get_threads,
+            readers: &self.readers,
+        }
+    }
+
+    // same as 'LSMTree::get' at the time of the snapshot
+    pub fn get(&self, key: &str) -> Result<Option<String>, Error> {
+        self.view().get(key)?.map(|(val, _)| utf8_value(val)).transpose()
+    }
+
+    // same as 'LSMTree::range' at the time of the snapshot
+    pub fn range(&self, start: &str, end: &str) -> Result<Vec<(String, String)>, Error> {
+        self.view().range(start, end)?.into_iter()
+            .map(|(key, val)| Ok((key, utf8_value(val)?)))
+            .collect()
+    }
+}
+
 pub struct LSMTree {
     // the base path of the lsmtree
     path: PathBuf,
 
     // read/write access this first, then periodically flushed
-    // these can be accessed by both writer thread and compaction thread
-    memtable: MemTable,
+    // while a flush is in progress it is shared with the PendingFlush and
+    // doesn't change
+    memtable: Arc<MemTable>,
 
-    // buffered memtable sections, use these when compaction is running
+    // buffered memtable sections, use these when a flush is in progress
     // these will only be accessed by writer thread
-    buffered_memtable: MemTable,
+    buffered_memtable: Arc<MemTable>,
+    flushing: bool,
+
+    // metainfo about all sstables this lsmtree is holding, the list and every
+    // entry are shared with the snapshots taken, changes copy them first
+    sstables: Arc<Vec<Arc<SSTableMeta>>>,
+
+    // the ranges deleted by 'delete_range' that still hide values of a
+    // memtable or sstable, shared with the snapshots taken
+    range_tombstones: Arc<Vec<RangeTombstone>>,
+
+    config: LSMConfig,
 
-    // metainfo about all sstables this lsmtree is holding
-    sstables: Vec<SSTableMeta>,
+    // the sstable readers used by gets, shared with the snapshots taken
+    readers: Arc<ReaderCache>,
+
+    // the seqno of the next sstable flushed, above that of every sstable
+    next_seqno: u64,
 
     total_flushed_size: usize,
+
+    // callbacks registered on key prefixes, run synchronously by 'set'
+    watches: Arc<Mutex<Vec<Watch>>>,
+    next_watch_id: u64,
 }
 
 impl LSMTree {
     // initialize a new LSMTree
-    pub fn new(rootpath: &Path) -> Result<Self, io::Error> {
+    pub fn new(rootpath: &Path) -> Result<Self, Error> {
+        Self::with_config(rootpath, LSMConfig::default())
+    }
+
+    // same as 'new' with the memtable threshold and fanout of 'config'
+    pub fn with_config(rootpath: &Path, config: LSMConfig) -> Result<Self, Error> {
+        // a level can't be merged into the next one any smaller than 2
+        if config.memtable_threshold == 0 || config.fanout < 2 {
+            return Err(Error::InvalidArgument(
+                String::from("memtable threshold must be positive and fanout at least 2")));
+        }
+
         let mut newtree = Self {
             path: rootpath.to_path_buf(),
-            memtable: MemTable::new(),
-            buffered_memtable: MemTable::new(),
-            sstables: Vec::new(),
+            memtable: Arc::new(MemTable::new(config.memtable_threshold)),
+            buffered_memtable: Arc::new(MemTable::new(config.memtable_threshold)),
+            flushing: false,
+            sstables: Arc::new(Vec::new()),
+            range_tombstones: Arc::new(Vec::new()),
+            readers: Arc::new(ReaderCache::new(config.reader_cache_size)),
+            config,
+            next_seqno: 0,
             total_flushed_size: 0,
+            watches: Arc::new(Mutex::new(Vec::new())),
+            next_watch_id: 0,
         };
 
         newtree.tryload_meta()?;
@@ -161,25 +832,27 @@ impl LSMTree {
     }
 
     // try to load the metadata file if exists
-    fn tryload_meta(&mut self) -> Result<(), io::Error> {
+    fn tryload_meta(&mut self) -> Result<(), Error> {
         // try to reload the sstable metainfo from existing root path if any
         let metafpath = self.path.join(META_FILENAME);
         if !metafpath.exists() {
             return Ok(())
         }
 
-        let mut metafile = fs::File::open(metafpath)?;
+        let mut metafile = BufReader::new(fs::File::open(metafpath)?);
+        META_FORMAT.read_header(&mut metafile)?;
 
         // number of entries in the metadata file
         let num_sstables = metafile.read_u32::<LittleEndian>()?;
 
         // for each entry, allocate a new SSTableMeta struct and push to the tree
+        let mut sstables = Vec::with_capacity(num_sstables as usize);
         for _ in 0..num_sstables {
             // read filename
-            let sst_fname_len = metafile.read_u8()? as usize;
+            let sst_fname_len = metafile.read_u32::<LittleEndian>()? as usize;
             let mut sst_fname_buf = vec![0 as u8; sst_fname_len];
             metafile.read_exact(&mut sst_fname_buf)?;
-            let sst_fname = String::from_utf8(sst_fname_buf).unwrap();
+            let sst_fname = String::from_utf8(sst_fname_buf).map_err(|_| Error::BadUtf8)?;
 
             // read level
             let sst_level = metafile.read_u8()? as usize;
@@ -188,42 +861,175 @@ impl LSMTree {
             let minkey_len = metafile.read_u32::<LittleEndian>()? as usize;
             let mut minkey_buf = vec![0 as u8; minkey_len];
             metafile.read_exact(&mut minkey_buf)?;
-            let minkey = String::from_utf8(minkey_buf).unwrap();
+            let minkey = String::from_utf8(minkey_buf).map_err(|_| Error::BadUtf8)?;
 
             // read max key
             let maxkey_len = metafile.read_u32::<LittleEndian>()? as usize;
             let mut maxkey_buf = vec![0 as u8; maxkey_len];
             metafile.read_exact(&mut maxkey_buf)?;
-            let maxkey = String::from_utf8(maxkey_buf).unwrap();
+            let maxkey = String::from_utf8(maxkey_buf).map_err(|_| Error::BadUtf8)?;
 
             // add to the newtree's sstable info list
-            self.sstables.push(SSTableMeta {
+            sstables.push(SSTableMeta {
                 filename: sst_fname,
                 level: sst_level,
                 min_key: minkey,
                 max_key: maxkey,
+                seqno: 0,
+                stats: Mutex::new(SSTableStats::default()),
+                obsolete: Mutex::new(None),
             });
         }
+
+        // the seqnos follow the entries, older metadata files have none and
+        // list the sstables from oldest to newest
+        let mut seqnos = Vec::with_capacity(sstables.len());
+        for _ in 0..num_sstables {
+            match metafile.read_u64::<LittleEndian>() {
+                Ok(seqno) => seqnos.push(seqno),
+                Err(e) if e.kind() == io::ErrorKind::UnexpectedEof && seqnos.is_empty() => break,
+                Err(e) => return Err(e.into()),
+            }
+        }
+        for (i, sstable) in sstables.iter_mut().enumerate() {
+            sstable.seqno = seqnos.get(i).copied().unwrap_or(i as u64);
+        }
+        self.sstables = Arc::new(sstables.into_iter().map(Arc::new).collect());
+
+        // the range tombstones follow, older metadata files have none
+        let num_tombstones = match metafile.read_u32::<LittleEndian>() {
+            Ok(num_tombstones) => num_tombstones,
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => 0,
+            Err(e) => return Err(e.into()),
+        };
+        let mut tombstones = Vec::with_capacity(num_tombstones as usize);
+        for _ in 0..num_tombstones {
+            let start = read_meta_string(&mut metafile)?;
+            let end = read_meta_string(&mut metafile)?;
+            let seqno = metafile.read_u64::<LittleEndian>()?;
+            tombstones.push(RangeTombstone { start, end, seqno });
+        }
+        self.range_tombstones = Arc::new(tombstones);
+
+        // the memtable is flushed after every range deleted so far
+        let sstables_next = self.sstables.iter().map(|s| s.seqno + 1).max().unwrap_or(0);
+        let tombstones_next = self.range_tombstones.iter().map(|t| t.seqno).max().unwrap_or(0);
+        self.next_seqno = sstables_next.max(tombstones_next);
         Ok(())
     }
 
+    // recover a tree whose metadata file is lost or corrupt by listing every
+    // sstable file under 'rootpath' again, each keeps the seqno and level
+    // recorded in its stats
+    // files written before those were recorded get level 0 and, in the order
+    // they were written, seqnos below every recorded one
+    // sstables evicted to cold storage and range tombstones can't be
+    // recovered
+    // files that don't open as sstables, or whose keys can't be read, are
+    // left out and returned along with the error, the rebuild itself fails with Error::Io if the
+    // directory can't be listed or the new metadata file can't be written
+    pub fn rebuild_metadata(rootpath: &Path) -> Result<Vec<(PathBuf, Error)>, Error> {
+        let mut files = Vec::new();
+        for entry in fs::read_dir(rootpath)? {
+            let path = entry?.path();
+            if path.extension().is_some_and(|ext| ext == "sst") {
+                files.push((fs::metadata(&path)?.modified()?, path));
+            }
+        }
+        files.sort();
+
+        let mut recorded = Vec::new();
+        let mut unrecorded = Vec::new();
+        let mut skipped = Vec::new();
+        for (_, path) in files {
+            let opened = SSTableFileReader::<BytesCodec>::open_with_codec(&path)
+                .and_then(|reader| reader.verify().map(|_| reader));
+            let reader = match opened {
+                Ok(reader) => reader,
+                Err(e) => {
+                    skipped.push((path, e));
+                    continue;
+                },
+            };
+            let first = reader.try_iter_entries().and_then(|mut entries| entries.next().transpose());
+            let (minkey, maxkey) = match (first, reader.last_key_in_range::<RangeFull>(..)) {
+                (Ok(Some((minkey, _))), Some(maxkey)) => (minkey, maxkey),
+                // an empty sstable holds nothing to recover
+                (Ok(None), None) => continue,
+                (Err(e), _) => {
+                    skipped.push((path, e));
+                    continue;
+                },
+                _ => {
+                    skipped.push((path, Error::Corrupt(String::from("SSTable index and data disagree on its keys"))));
+                    continue;
+                },
+            };
+            let mut meta = SSTableMeta::new(&minkey, &maxkey, 0);
+            meta.filename = path.file_name().unwrap().to_string_lossy().into_owned();
+            match reader.stats_footer().and_then(|stats| stats.tree_position) {
+                Some((seqno, level)) => {
+                    meta.seqno = seqno;
+                    meta.level = level;
+                    recorded.push(meta);
+                },
+                None => unrecorded.push(meta),
+            }
+        }
+
+        // the sstables are listed from oldest to newest
+        let num_unrecorded = unrecorded.len() as u64;
+        for (i, meta) in unrecorded.iter_mut().enumerate() {
+            meta.seqno = i as u64;
+        }
+        for meta in recorded.iter_mut() {
+            meta.seqno += num_unrecorded;
+        }
+        recorded.sort_by_key(|meta| meta.seqno);
+        let sstables: Vec<_> = unrecorded.into_iter().chain(recorded).map(Arc::new).collect();
+
+        // a tree loading the metadata would fail on a corrupt one
+        let metafpath = rootpath.join(META_FILENAME);
+        if metafpath.exists() {
+            fs::remove_file(&metafpath)?;
+        }
+        let mut tree = Self::new(rootpath)?;
+        tree.next_seqno = sstables.iter().map(|s| s.seqno + 1).max().unwrap_or(0);
+        tree.sstables = Arc::new(sstables);
+        tree.flush_metadata()?;
+        Ok(skipped)
+    }
+
     // insert a (key, value) pair into the LSMTree
     // 
     // If the compaction thread is flushing memtable to sstable:
-    // 1. 'memtable' is locked by compaction thread
+    // 1. 'memtable' is shared with the compaction thread's PendingFlush
     // 2. 'set' write the (key, val) to 'buffered_memtable' and return
     // 3. After compaction thread finish flushing, it replace the 'memtable'
     //    with 'buffered_memtable', then allocate a new 'buffered_memtable'
-    // 4. If 'buffered_memtable' also reaches the threshold, then block
-    pub fn set(&mut self, key: &str, val: &str) -> Result<(), io::Error> {
+    // 'buffered_memtable' isn't bounded here, a Store waits in
+    // 'lock_with_room' until it has room before calling 'set'
+    // Otherwise a full memtable is flushed right away
+    pub fn set(&mut self, key: &str, val: &str) -> Result<(), Error> {
+        self.set_bytes(key, val.as_bytes())?;
+        self.notify_watches(key, val);
+        Ok(())
+    }
+
+    // same as 'set' for a value that isn't necessarily valid utf8 such as
+    // 'GorillaBlock::to_bytes', watches aren't notified
+    pub fn set_bytes(&mut self, key: &str, val: &[u8]) -> Result<(), Error> {
+        // a pair the memtable couldn't be flushed with is refused right away
+        check_entry_len(key, val.len())?;
+
         // compact if this insertion causes an overflow
-        if self.memtable.need_flush(key, val) {
+        if self.need_flush(key, val) {
             println!("Flushing Memtable to disk: {} bytes", self.memtable.flush_size);
             self.flush_memtable()?;
         }
         
         // all insertions go to the memtable first
-        self.memtable.insert(key, val);
+        self.active_memtable().insert(key, val);
 
         // when memtable is flushed to disk as sstables, we will store:
         // 1. (key, val) pair --> len(key) + len(val)
@@ -235,68 +1041,609 @@ impl LSMTree {
         Ok(())
     }
 
-    // retrieve a value by a specific key
-    // try lock 'memtable' if it's locked then check 
-    // 1. check the memtable first, retrieve it if present
-    // 2. open each SSTable and check the min, max key range
-    pub fn get(&self, key: &str) -> Result<Option<String>, io::Error> {
-        // if the (k, v) is still in memory
-        if let Some(s) = self.memtable.map.get(key) {
-            return Ok(Some(s.to_string()));
+    // delete a key, a tombstone is inserted that hides the key from reads
+    // until it is written again, including values already flushed to disk
+    pub fn delete(&mut self, key: &str) -> Result<(), Error> {
+        check_entry_len(key, 0)?;
+        if self.need_flush(key, b"") {
+            println!("Flushing Memtable to disk: {} bytes", self.memtable.flush_size);
+            self.flush_memtable()?;
         }
 
-        // search SSTable files for value
-        for sstable in &self.sstables {
-            if sstable.in_range(key) {
-                let path = self.path.join(&sstable.filename);
-                let mut currsst = SSTableFileReader::open(&path)?;
-                if let Some(val) = currsst.get(key)? {
-                    return Ok(Some(val));
-                }
-            }
+        self.active_memtable().insert_tombstone(key);
+        Ok(())
+    }
+
+    // delete every key in [start, end), including values already flushed to
+    // disk, a key written again afterwards reads as usual
+    // the range is recorded in the metadata right away and hides the older
+    // values until compaction has merged away every sstable that holds them
+    pub fn delete_range(&mut self, start: &str, end: &str) -> Result<(), Error> {
+        if end <= start {
+            return Ok(());
         }
 
-        Ok(None)
+        // the values of the memtable taking writes are older than the range
+        // but share its seqno, so they are dropped from it instead
+        self.active_memtable().remove_range(start, end);
+        Arc::make_mut(&mut self.range_tombstones).push(RangeTombstone {
+            start: start.to_string(),
+            end: end.to_string(),
+            seqno: self.next_seqno,
+        });
+        self.flush_metadata()
     }
 
-    // flush the current memtable to disk and store it as sstable files
-    pub fn flush_memtable(&mut self) -> Result<(), io::Error> {
-        let minkey = self.memtable.get_minkey();
-        let maxkey = self.memtable.get_maxkey();
-        let new_sstable = SSTableMeta::new(&minkey, &maxkey);
+    // whether inserting the pair overflows the memtable, never while a flush
+    // is in progress since writes go to 'buffered_memtable' then
+    pub fn need_flush(&self, key: &str, val: &[u8]) -> bool {
+        !self.flushing && self.memtable.need_flush(key, val)
+    }
 
-        let mut sst_builder = SSTableFileBuilder::new(&self.path.join(&new_sstable.filename))?;
-        self.memtable.write_entries_to_sstable(&mut sst_builder)?;
-        sst_builder.commit()?;
+    // whether a flush can be started, none is in progress and the memtable
+    // holds writes
+    pub fn can_flush(&self) -> bool {
+        !self.flushing && !self.memtable.map.is_empty()
+    }
 
-        self.sstables.push(new_sstable);
-        self.flush_metadata()?;
+    // whether the pair overflows 'buffered_memtable' while a flush is in
+    // progress, writers then wait for the flush rather than buffering without
+    // bound
+    pub fn buffer_full(&self, key: &str, val: &[u8]) -> bool {
+        self.flushing && self.buffered_memtable.need_flush(key, val)
+    }
 
-        self.total_flushed_size += self.memtable.flush_size;
-        
-        // reset the current memtable
-        self.memtable.reset();
-        Ok(())
+    // the memtable taking writes
+    fn active_memtable(&mut self) -> &mut MemTable {
+        // only cloned while shared with a flush or a snapshot
+        if self.flushing {
+            Arc::make_mut(&mut self.buffered_memtable)
+        } else {
+            Arc::make_mut(&mut self.memtable)
+        }
     }
 
-    // write out the current LSMTree metadata to a metadata file
-    pub fn flush_metadata(&mut self) -> Result<(), io::Error> {
-        let mut metafile = fs::File::create(self.path.join(META_FILENAME))?;
+    // the memtables in memory, newest first
+    fn memtables(&self) -> impl Iterator<Item = &Arc<MemTable>> {
+        let buffered = if self.flushing { Some(&self.buffered_memtable) } else { None };
+        buffered.into_iter().chain(std::iter::once(&self.memtable))
+    }
 
-        // record number of sstables
-        metafile.write_u8(self.sstables.len() as u8)?;
+    // a consistent view of the tree as it is now, unaffected by later writes,
+    // flushes and compactions
+    pub fn snapshot(&self) -> Snapshot {
+        Snapshot {
+            path: self.path.clone(),
+            memtables: self.memtables().cloned().collect(),
+            next_seqno: self.next_seqno,
+            sstables: self.sstables.clone(),
+            range_tombstones: self.range_tombstones.clone(),
+            get_threads: self.config.get_threads,
+            readers: self.readers.clone(),
+        }
+    }
 
-        // record each SSTableMeta info
-        for sstable in &self.sstables {
-            // write filename
-            metafile.write_u32::<LittleEndian>(sstable.filename.len() as u32)?;
-            metafile.write_all(sstable.filename.as_bytes())?;
+    fn view(&self) -> View<'_> {
+        View {
+            path: &self.path,
+            memtables: self.memtables().map(|m| &**m).collect(),
+            next_seqno: self.next_seqno,
+            sstables: &self.sstables,
+            tombstones: &self.range_tombstones,
+            get_threads: self.config.get_threads,
+            readers: &self.readers,
+        }
+    }
 
-            // write level
-            metafile.write_u8(sstable.level as u8)?;
+    // register a callback that runs on the writer thread after every 'set'
+    // of a key starting with 'prefix'
+    pub fn watch(&mut self, prefix: &str, callback: WatchCallback) -> WatchHandle {
+        let id = self.next_watch_id;
+        self.next_watch_id += 1;
+        self.watches.lock().unwrap().push(Watch {
+            id,
+            prefix: prefix.to_string(),
+            callback,
+        });
+        WatchHandle {
+            id,
+            watches: Arc::downgrade(&self.watches),
+        }
+    }
 
-            // write min key
-            metafile.write_u32::<LittleEndian>(sstable.min_key.len() as u32)?;
+    fn notify_watches(&self, key: &str, val: &str) {
+        // collect first so callbacks may cancel watches without deadlocking
+        let callbacks: Vec<WatchCallback> = self.watches.lock().unwrap().iter()
+            .filter(|w| key.starts_with(&w.prefix))
+            .map(|w| w.callback.clone())
+            .collect();
+        for callback in callbacks {
+            callback(key, val);
+        }
+    }
+
+    // the sstables from the highest seqno down, the order reads resolve
+    // keys present in several of them
+    fn sstables_newest_first(&self) -> Vec<&SSTableMeta> {
+        newest_first(&self.sstables)
+    }
+
+    // retrieve a value by a specific key
+    pub fn get(&self, key: &str) -> Result<Option<String>, Error> {
+        Ok(self.get_with_generation(key)?.map(|(val, _)| val))
+    }
+
+    // same as 'get', along with the seqno of the sstable the value was read
+    // from, values still in memory report the seqno their flush will get
+    // try lock 'memtable' if it's locked then check 
+    // 1. check the memtable first, retrieve it if present
+    // 2. open each SSTable, highest seqno first, and check the min, max key range
+    // a tombstone found on the way means the key was deleted
+    // a value that isn't valid utf8 is an 'Error::BadUtf8', see 'get_bytes'
+    // a key in a deleted range reads as deleted unless written again since
+    pub fn get_with_generation(&self, key: &str) -> Result<Option<(String, u64)>, Error> {
+        // if the (k, v) is still in memory, the frozen memtable of a flush in
+        // progress already has its seqno
+        self.view().get(key)?
+            .map(|(val, seqno)| Ok((utf8_value(val)?, seqno)))
+            .transpose()
+    }
+
+    // the value of 'key' if it is still in the memtables, without looking at
+    // any sstable, so a key that was already flushed reads as None even
+    // though 'get' finds it, as do deleted keys and values that aren't valid
+    // utf8
+    pub fn get_memtable(&self, key: &str) -> Option<String> {
+        let (val, _) = self.view().get_memtable(key)??;
+        String::from_utf8(val).ok()
+    }
+
+    // same as 'get' for values that aren't necessarily valid utf8
+    pub fn get_bytes(&self, key: &str) -> Result<Option<Vec<u8>>, Error> {
+        Ok(self.view().get(key)?.map(|(val, _)| val))
+    }
+
+    // every (key, val) pair with a key in [start, end] in key order, the
+    // memtable shadows the sstables and higher seqnos shadow lower ones
+    // a value that isn't valid utf8 is an 'Error::BadUtf8', see 'range_bytes'
+    pub fn range(&self, start: &str, end: &str) -> Result<Vec<(String, String)>, Error> {
+        self.range_bytes(start, end)?.into_iter()
+            .map(|(key, val)| Ok((key, utf8_value(val)?)))
+            .collect()
+    }
+
+    // same as 'range' for values that aren't necessarily valid utf8
+    pub fn range_bytes(&self, start: &str, end: &str) -> Result<Vec<(String, Vec<u8>)>, Error> {
+        self.view().range(start, end)
+    }
+
+    // every (key, val) pair in key order, read lazily from the memtables and
+    // every sstable at once, resolving keys like 'get'
+    pub fn scan(&self) -> Result<LSMScan<'_>, Error> {
+        // a value in a deleted range reads as a tombstone
+        let tombstones = &self.range_tombstones[..];
+        let mut sources: Vec<ScanSource> = Vec::new();
+        for (i, memtable) in self.memtables().enumerate() {
+            let seqno = self.next_seqno - i as u64;
+            sources.push(Box::new(memtable.map.iter().map(move |(k, v)| {
+                (k.clone(), if is_hidden(tombstones, k, seqno) { None } else { v.clone() })
+            })));
+        }
+
+        for sstable in self.sstables_newest_first() {
+            let path = self.path.join(&sstable.filename);
+            let mut entries = SSTableFileReader::<BytesCodec>::open_with_codec(&path)?.into_entries()?;
+            sources.push(Box::new(std::iter::from_fn(move || {
+                match entries.next() {
+                    Some((key, _)) if is_hidden(tombstones, &key, sstable.seqno) => Some((key, None)),
+                    Some((key, val)) => Some((key, val.value())),
+                    None => {
+                        sstable.record_stats(&entries.stats());
+                        None
+                    },
+                }
+            })));
+        }
+        Ok(LSMScan::new(sources))
+    }
+
+    // the entries of a series with a time in [start, end] in time order, decoded
+    // from the blocks stored under 'series_key_prefix(construct_key_hash) +
+    // timestamp_key(block start)', possibly followed by more such as in a
+    // 'SeriesKey', so a window may span several blocks
+    // an entry at the seam of two blocks is only returned once, from the block
+    // with the lower key, values that aren't blocks of 'dim' dimensional
+    // entries are skipped
+    pub fn get_series(&self, construct_key_hash: u64, start: GorillaDateTime, end: GorillaDateTime, dim: usize)
+        -> Result<Vec<MVEntry>, Error>
+    {
+        let blocks = self.get_series_values(construct_key_hash, start, end)?.into_iter()
+            .filter_map(|val| GorillaBlock::from_stored_bytes(&val).ok())
+            .filter_map(|block| {
+                let num_entries = block.num_entries(dim).ok()?;
+                Some(retrieve_values(block, dim, num_entries))
+            });
+        Ok(series_window(blocks, start, end))
+    }
+
+    // the values of the blocks of a series that may hold entries with a time
+    // in [start, end], in key order, whatever their format
+    pub fn get_series_values(&self, construct_key_hash: u64, start: GorillaDateTime, end: GorillaDateTime)
+        -> Result<Vec<Vec<u8>>, Error>
+    {
+        if end < start {
+            return Ok(Vec::new());
+        }
+
+        let prefix = series_key_prefix(construct_key_hash);
+        let keystart = format!("{}{}", prefix, timestamp_key(start));
+        // a key of a block starting at 'end' may go on after the timestamp
+        let keyend = timestamp_key_bound(&prefix, end);
+
+        // the block started before 'start' may still hold entries in the window
+        let mut values = Vec::new();
+        if let Some(before) = start.checked_sub_signed(chrono::Duration::nanoseconds(1)) {
+            if let Some(val) = self.get_latest_bytes_before(&prefix, before)? {
+                values.push(val);
+            }
+        }
+        values.extend(self.range_bytes(&keystart, &keyend)?.into_iter().map(|(_, val)| val));
+        Ok(values)
+    }
+
+    // the GorillaBlock stored under the largest key 'key_prefix + timestamp_key(ts)',
+    // possibly followed by more (as in a 'SeriesKey'), with a timestamp not
+    // after 'ts_cutoff'
+    pub fn get_latest_before(&self, key_prefix: &str, ts_cutoff: GorillaDateTime)
+        -> Result<Option<GorillaBlock>, Error>
+    {
+        match self.get_latest_bytes_before(key_prefix, ts_cutoff)? {
+            Some(val) => {
+                let block = GorillaBlock::from_stored_bytes(&val)
+                    .map_err(|e| Error::Corrupt(format!("bad GorillaBlock value: {}", e)))?;
+                Ok(Some(block))
+            },
+            None => Ok(None),
+        }
+    }
+
+    // same as 'get_latest_before' for the value stored, whatever its format
+    pub fn get_latest_bytes_before(&self, key_prefix: &str, ts_cutoff: GorillaDateTime)
+        -> Result<Option<Vec<u8>>, Error>
+    {
+        let start = key_prefix.to_string();
+        let mut end = Bound::Included(timestamp_key_bound(key_prefix, ts_cutoff));
+
+        // the latest key from the memtable and every sstable, if it was
+        // deleted look for the latest key before it
+        loop {
+            let range = (Bound::Included(start.as_str()), end.as_ref().map(String::as_str));
+            let mut latest: Option<String> = self.memtables()
+                .filter_map(|memtable| memtable.map.range::<str, _>(range).next_back())
+                .map(|(k, _)| k.clone())
+                .max();
+
+            for sstable in self.sstables.iter() {
+                if !range.contains(sstable.min_key.as_str()) && !sstable.in_range(&start) {
+                    continue;
+                }
+                let path = self.path.join(&sstable.filename);
+                let currsst = SSTableFileReader::<BytesCodec>::open_with_codec(&path)?;
+                if let Some(key) = currsst.last_key_in_range(range) {
+                    if latest.as_ref().is_none_or(|l| *l < key) {
+                        latest = Some(key);
+                    }
+                }
+            }
+
+            let key = match latest {
+                Some(key) => key,
+                None => return Ok(None),
+            };
+            match self.get_bytes(&key)? {
+                Some(val) => return Ok(Some(val)),
+                None => end = Bound::Excluded(key),
+            }
+        }
+    }
+
+    // every (key, block) pair in the store in key order, values that are not
+    // blocks of 'dim' dimensional entries are skipped, a key present in several
+    // places resolves like in get
+    // note that the whole store is read into memory first
+    pub fn scan_all_gorilla_blocks(&self, dim: usize)
+        -> impl Iterator<Item = Result<(String, GorillaBlock), Error>>
+    {
+        let mut merged: BTreeMap<String, Option<Vec<u8>>> = BTreeMap::new();
+        for (memtable, seqno) in self.view().memtables() {
+            for (k, v) in &memtable.map {
+                let hidden = is_hidden(&self.range_tombstones, k, seqno);
+                merged.entry(k.clone()).or_insert_with(|| if hidden { None } else { v.clone() });
+            }
+        }
+        let mut errors = Vec::new();
+
+        for sstable in self.sstables_newest_first() {
+            let path = self.path.join(&sstable.filename);
+            match SSTableFileReader::<BytesCodec>::open_with_codec(&path) {
+                Ok(currsst) => {
+                    for (key, val) in currsst.iter_entries() {
+                        let hidden = is_hidden(&self.range_tombstones, &key, sstable.seqno);
+                        merged.entry(key).or_insert_with(|| if hidden { None } else { val.value() });
+                    }
+                    sstable.record_stats(&currsst.stats());
+                },
+                Err(e) => errors.push(Err(e)),
+            }
+        }
+
+        // deleted keys are left out along with the values that aren't blocks
+        let blocks = merged.into_iter().filter_map(move |(key, val)| {
+            match GorillaBlock::from_stored_bytes(&val?) {
+                Ok(block) if block.num_entries(dim).is_ok() => Some(Ok((key, block))),
+                _ => None,
+            }
+        });
+        errors.into_iter().chain(blocks)
+    }
+
+    // flush the current memtable to disk and store it as sstable files,
+    // an empty memtable writes nothing
+    pub fn flush_memtable(&mut self) -> Result<(), Error> {
+        if self.memtable.map.is_empty() {
+            return Ok(());
+        }
+        let pending = self.begin_flush()?;
+        match pending.write() {
+            Ok(()) => self.finish_flush(pending),
+            Err(e) => {
+                self.abort_flush(pending);
+                Err(e)
+            },
+        }
+    }
+
+    // start flushing the memtable in the background: it stops taking writes,
+    // which go to 'buffered_memtable' until 'finish_flush' or 'abort_flush'
+    // is called with the PendingFlush once its file is written
+    pub fn begin_flush(&mut self) -> Result<PendingFlush, Error> {
+        if self.flushing {
+            return Err(io::Error::other("a memtable flush is already in progress").into());
+        }
+
+        let minkey = self.memtable.get_minkey();
+        let maxkey = self.memtable.get_maxkey();
+        let meta = SSTableMeta::new(&minkey, &maxkey, self.next_seqno);
+        self.next_seqno += 1;
+
+        self.flushing = true;
+        Ok(PendingFlush {
+            path: self.path.join(&meta.filename),
+            memtable: self.memtable.clone(),
+            meta,
+        })
+    }
+
+    // add the written sstable and make the writes buffered meanwhile the
+    // memtable
+    pub fn finish_flush(&mut self, pending: PendingFlush) -> Result<(), Error> {
+        self.total_flushed_size += pending.memtable.flush_size;
+        Arc::make_mut(&mut self.sstables).push(Arc::new(pending.meta));
+        let memtable = Arc::new(MemTable::new(self.config.memtable_threshold));
+        self.memtable = mem::replace(&mut self.buffered_memtable, memtable);
+        self.flushing = false;
+        self.flush_metadata()
+    }
+
+    // give up on a flush whose file couldn't be written, the writes buffered
+    // meanwhile go back on top of the memtable
+    // the memtable takes the seqno of the buffered writes, so the values of
+    // the ranges deleted meanwhile are dropped from it first
+    pub fn abort_flush(&mut self, pending: PendingFlush) {
+        let _ = fs::remove_file(&pending.path);
+        let seqno = pending.meta.seqno;
+        drop(pending);
+
+        self.flushing = false;
+        let memtable = Arc::new(MemTable::new(self.config.memtable_threshold));
+        let buffered = mem::replace(&mut self.buffered_memtable, memtable);
+        let memtable = Arc::make_mut(&mut self.memtable);
+        for tombstone in self.range_tombstones.iter().filter(|t| t.seqno > seqno) {
+            memtable.remove_range(&tombstone.start, &tombstone.end);
+        }
+        for (key, val) in &buffered.map {
+            match val {
+                Some(val) => memtable.insert(key, val),
+                None => memtable.insert_tombstone(key),
+            }
+        }
+    }
+
+    // write pairs sorted by key straight to new level 0 sstables, bypassing
+    // the memtable, each sstable holds about as much as a memtable flush
+    // the memtable is flushed first so the loaded values are the newest,
+    // watches aren't notified
+    // keys that aren't in increasing order are an 'Error::InvalidArgument'
+    // and nothing is loaded
+    pub fn bulk_load(&mut self, sorted_pairs: impl Iterator<Item = (String, String)>) -> Result<(), Error> {
+        self.flush_memtable()?;
+
+        let mut loaded = Vec::new();
+        if let Err(e) = self.write_bulk(sorted_pairs, &mut loaded) {
+            for (meta, _) in loaded {
+                let _ = fs::remove_file(self.path.join(&meta.filename));
+            }
+            return Err(e);
+        }
+
+        self.next_seqno += loaded.len() as u64;
+        let sstables = Arc::make_mut(&mut self.sstables);
+        for (meta, size) in loaded {
+            self.total_flushed_size += size;
+            sstables.push(Arc::new(meta));
+        }
+        self.flush_metadata()
+    }
+
+    // write the sstables of 'bulk_load', 'loaded' collects each one as it is
+    // started along with its size counted like 'MemTable::flush_size'
+    fn write_bulk(&self, pairs: impl Iterator<Item = (String, String)>, loaded: &mut Vec<(SSTableMeta, usize)>)
+        -> Result<(), Error>
+    {
+        let mut builder: Option<SSTableFileBuilder<BytesCodec>> = None;
+        for (key, val) in pairs {
+            if matches!(loaded.last(), Some((meta, _)) if meta.max_key >= key) {
+                if let Some(sst) = builder.take() {
+                    sst.discard()?;
+                }
+                return Err(Error::InvalidArgument(format!("bulk loaded key {} is out of order", key)));
+            }
+
+            let pairsz = 2 * mem::size_of::<u32>() + key.len() + val.len();
+            if matches!(loaded.last(), Some((_, size)) if size + pairsz > self.config.memtable_threshold) {
+                if let Some(mut full) = builder.take() {
+                    full.commit()?;
+                }
+            }
+            let sst = match &mut builder {
+                Some(sst) => sst,
+                None => {
+                    let meta = SSTableMeta::new(&key, &key, self.next_seqno + loaded.len() as u64);
+                    let mut sst = SSTableFileBuilder::<BytesCodec>::with_codec(&self.path.join(&meta.filename))?;
+                    sst.set_tree_position(meta.seqno, meta.level);
+                    loaded.push((meta, 0));
+                    builder.insert(sst)
+                },
+            };
+
+            sst.add(&key, &val.into_bytes())?;
+            let (meta, size) = loaded.last_mut().unwrap();
+            meta.max_key = key;
+            *size += pairsz;
+        }
+
+        if let Some(mut sst) = builder {
+            sst.commit()?;
+        }
+        Ok(())
+    }
+
+    // merge the sstables of a level into one sstable of the next level once
+    // the level holds 'fanout' of them, repeated up the levels
+    // a key in several sources keeps the entry with the highest seqno
+    pub fn compact(&mut self) -> Result<(), Error> {
+        self.compact_with_progress(|_| ())
+    }
+
+    // same as 'compact', 'progress' is called after each merged sstable is
+    // in place with the totals so far
+    pub fn compact_with_progress(&mut self, mut progress: impl FnMut(CompactionProgress)) -> Result<(), Error> {
+        let mut done = CompactionProgress::default();
+        let mut level = 0;
+        loop {
+            let mut sources: Vec<usize> = (0..self.sstables.len())
+                .filter(|i| self.sstables[*i].level == level)
+                .collect();
+            if sources.len() < self.config.fanout {
+                return Ok(());
+            }
+
+            let minkey = sources.iter().map(|i| &self.sstables[*i].min_key).min().unwrap().clone();
+            let maxkey = sources.iter().map(|i| &self.sstables[*i].max_key).max().unwrap().clone();
+            // every sstable of a lower level was flushed after the sources,
+            // so the merged sstable can take the newest generation among them
+            sources.sort_by_key(|i| self.sstables[*i].seqno);
+            let seqno = self.sstables[*sources.last().unwrap()].seqno;
+            let mut merged = SSTableMeta::new(&minkey, &maxkey, seqno);
+            merged.level = level + 1;
+
+            // block strings aren't necessarily valid utf8
+            let mut sst_builder = SSTableFileBuilder::<BytesCodec>::with_codec(&self.path.join(&merged.filename))?;
+            sst_builder.set_tree_position(merged.seqno, merged.level);
+            let paths: Vec<PathBuf> = sources.iter()
+                .map(|i| self.path.join(&self.sstables[*i].filename))
+                .collect();
+            // the values of deleted ranges are left out
+            let seqnos: Vec<u64> = sources.iter().map(|i| self.sstables[*i].seqno).collect();
+            let tombstones = &self.range_tombstones;
+            sst_builder.merge_files_filtered(&paths.iter().map(PathBuf::as_path).collect::<Vec<_>>(),
+                                             |source, key| !is_hidden(tombstones, key, seqnos[source]))?;
+            sst_builder.commit()?;
+            let merged_size = fs::metadata(self.path.join(&merged.filename))?.len();
+
+            // the merged sstable takes the place of the oldest source, so
+            // the sstables stay ordered from oldest to newest
+            sources.sort_unstable();
+            let sstables = Arc::make_mut(&mut self.sstables);
+            let mut removed = Vec::with_capacity(sources.len());
+            for i in sources.iter().rev() {
+                removed.push(sstables.remove(*i));
+            }
+            sstables.insert(sources[0], Arc::new(merged));
+            self.drop_range_tombstones();
+            self.flush_metadata()?;
+
+            // the sources are only deleted once the metadata no longer refers
+            // to them, and no snapshot reads them
+            for sstable in removed {
+                self.readers.invalidate(&sstable.filename);
+                let path = self.path.join(&sstable.filename);
+                sstable.unlink(path)?;
+            }
+
+            done.level = level;
+            done.files_merged += sources.len();
+            done.bytes_written += merged_size;
+            progress(done);
+            level += 1;
+        }
+    }
+
+    // forget the deleted ranges that no longer hide any value, i.e. no
+    // older sstable may hold a key in them
+    // the memtable of a flush in progress may be older than any of them
+    fn drop_range_tombstones(&mut self) {
+        if self.flushing {
+            return;
+        }
+        let sstables = &self.sstables;
+        let needed = |t: &RangeTombstone| {
+            sstables.iter().any(|s| s.seqno < t.seqno && t.overlaps(&s.min_key, &s.max_key))
+        };
+        if !self.range_tombstones.iter().all(needed) {
+            Arc::make_mut(&mut self.range_tombstones).retain(needed);
+        }
+    }
+
+    // write out the current LSMTree metadata to a metadata file, it is
+    // written aside first and renamed over the old one, so a crash leaves
+    // either of them in place
+    pub fn flush_metadata(&mut self) -> Result<(), Error> {
+        let tmppath = self.path.join(META_TMP_FILENAME);
+        self.write_metadata(&tmppath, &self.sstables)?;
+        fs::rename(&tmppath, self.path.join(META_FILENAME))?;
+        Ok(())
+    }
+
+    // the metadata of the tree with 'sstables' in place of its own
+    fn write_metadata(&self, path: &Path, sstables: &[Arc<SSTableMeta>]) -> Result<(), Error> {
+        let mut metafile = fs::File::create(path)?;
+        META_FORMAT.write_header(&mut metafile)?;
+
+        // record number of sstables
+        metafile.write_u32::<LittleEndian>(sstables.len() as u32)?;
+
+        // record each SSTableMeta info
+        for sstable in sstables.iter() {
+            // write filename
+            metafile.write_u32::<LittleEndian>(sstable.filename.len() as u32)?;
+            metafile.write_all(sstable.filename.as_bytes())?;
+
+            // write level
+            metafile.write_u8(sstable.level as u8)?;
+
+            // write min key
+            metafile.write_u32::<LittleEndian>(sstable.min_key.len() as u32)?;
             metafile.write_all(sstable.min_key.as_bytes())?;
 
             // write max key
@@ -304,6 +1651,22 @@ impl LSMTree {
             metafile.write_all(sstable.max_key.as_bytes())?;
         }
 
+        // record the seqnos after every entry, so older versions still read
+        // the entries
+        for sstable in sstables.iter() {
+            metafile.write_u64::<LittleEndian>(sstable.seqno)?;
+        }
+
+        // record the range tombstones after the seqnos
+        metafile.write_u32::<LittleEndian>(self.range_tombstones.len() as u32)?;
+        for tombstone in self.range_tombstones.iter() {
+            metafile.write_u32::<LittleEndian>(tombstone.start.len() as u32)?;
+            metafile.write_all(tombstone.start.as_bytes())?;
+            metafile.write_u32::<LittleEndian>(tombstone.end.len() as u32)?;
+            metafile.write_all(tombstone.end.as_bytes())?;
+            metafile.write_u64::<LittleEndian>(tombstone.seqno)?;
+        }
+
         // make sure all in-memory data reaches disk
         metafile.sync_all()?;
         Ok(())
@@ -312,6 +1675,141 @@ impl LSMTree {
     pub fn total_bytes_flushed(&self) -> usize {
         self.total_flushed_size
     }
+
+    // the flush size of the entries held in memory, the memtable being
+    // flushed included
+    pub fn memtable_bytes(&self) -> usize {
+        self.memtables().map(|memtable| memtable.flush_size).sum()
+    }
+
+    // the sstables of every level holding any, from level 0 up
+    // a file that can't be found counts no bytes
+    pub fn level_stats(&self) -> Vec<LevelInfo> {
+        let mut levels: BTreeMap<usize, LevelInfo> = BTreeMap::new();
+        for sstable in self.sstables.iter() {
+            let size = fs::metadata(self.path.join(&sstable.filename)).map_or(0, |m| m.len());
+            let info = levels.entry(sstable.level).or_insert_with(|| LevelInfo {
+                level: sstable.level,
+                num_files: 0,
+                total_bytes: 0,
+                min_key: sstable.min_key.clone(),
+                max_key: sstable.max_key.clone(),
+            });
+            info.num_files += 1;
+            info.total_bytes += size;
+            if sstable.min_key < info.min_key {
+                info.min_key = sstable.min_key.clone();
+            }
+            if sstable.max_key > info.max_key {
+                info.max_key = sstable.max_key.clone();
+            }
+        }
+        levels.into_values().collect()
+    }
+
+    // the access counters of every sstable file since the tree was opened
+    pub fn per_file_stats(&self) -> Vec<(String, SSTableStats)> {
+        self.sstables.iter()
+            .map(|sstable| (sstable.filename.clone(), *sstable.stats.lock().unwrap()))
+            .collect()
+    }
+
+    // move every sstable whose keys all sort before 'cutoff_key' to 'cold_path',
+    // reads keep working through the absolute path recorded in its metainfo
+    // returns the number of sstables moved
+    pub fn evict_to_cold(&mut self, cutoff_key: &str, cold_path: &Path) -> Result<usize, Error> {
+        fs::create_dir_all(cold_path)?;
+        let cold_path = cold_path.canonicalize()?;
+
+        let mut num_evicted = 0;
+        for sstable in Arc::make_mut(&mut self.sstables) {
+            // a file a snapshot still reads stays where it is
+            let sstable = match Arc::get_mut(sstable) {
+                Some(sstable) => sstable,
+                None => continue,
+            };
+            if sstable.max_key.as_str() >= cutoff_key || Path::new(&sstable.filename).is_absolute() {
+                continue;
+            }
+
+            let cold_file = cold_path.join(&sstable.filename);
+            let cold_filename = match cold_file.to_str() {
+                Some(name) => name.to_string(),
+                None => return Err(Error::InvalidArgument(
+                    String::from("cold storage path is not valid UTF-8"))),
+            };
+            fs::rename(self.path.join(&sstable.filename), &cold_file)?;
+            self.readers.invalidate(&sstable.filename);
+            sstable.filename = cold_filename;
+            num_evicted += 1;
+        }
+
+        // persist the new locations
+        self.flush_metadata()?;
+        Ok(num_evicted)
+    }
+
+    // make an offline copy of the storage directory at 'dest_path', the tree
+    // itself is left as it is
+    // 1. write the memtables to sstable files of the copy only, with the
+    //    seqnos they would be flushed with
+    // 2. copy every sstable file and the WAL (if any)
+    // 3. write the metadata of the copy
+    // the destination must not already hold a store, so we never overwrite a live one
+    // sstables evicted to cold storage are not copied, the copy refers to the same files
+    pub fn copy_to(&self, dest_path: &Path) -> Result<(), Error> {
+        if dest_path.join(META_FILENAME).exists() {
+            return Err(io::Error::new(io::ErrorKind::AlreadyExists,
+                                      "destination already contains a rustydb store").into());
+        }
+        fs::create_dir_all(dest_path)?;
+
+        // the memtables newest first, as in 'Snapshot::memtables'
+        let memtables: Vec<_> = self.memtables().enumerate()
+            .map(|(i, memtable)| (memtable, self.next_seqno - i as u64))
+            .collect();
+        let mut sstables = self.sstables.to_vec();
+        for (memtable, seqno) in memtables.into_iter().rev() {
+            if memtable.map.is_empty() {
+                continue;
+            }
+            let meta = SSTableMeta::new(&memtable.get_minkey(), &memtable.get_maxkey(), seqno);
+            let pending = PendingFlush { path: dest_path.join(&meta.filename), memtable: memtable.clone(), meta };
+            pending.write()?;
+            sstables.push(Arc::new(pending.meta));
+        }
+
+        for sstable in self.sstables.iter() {
+            if !Path::new(&sstable.filename).is_absolute() {
+                fs::copy(self.path.join(&sstable.filename), dest_path.join(&sstable.filename))?;
+            }
+        }
+
+        for walpath in log_files(&self.path)? {
+            if let Some(filename) = walpath.file_name() {
+                fs::copy(&walpath, dest_path.join(filename))?;
+            }
+        }
+
+        // write the metadata last, a partial copy will not look like a valid store
+        let tmppath = dest_path.join(META_TMP_FILENAME);
+        self.write_metadata(&tmppath, &sstables)?;
+        fs::rename(&tmppath, dest_path.join(META_FILENAME))?;
+        Ok(())
+    }
+}
+
+// flush the writes left in the memtable so a tree going out of scope doesn't
+// lose them, a memtable already being flushed is left to its PendingFlush
+impl Drop for LSMTree {
+    fn drop(&mut self) {
+        if self.flushing {
+            return;
+        }
+        if let Err(e) = self.flush_memtable() {
+            println!("Flush of the memtable of {} on drop failed: {}", self.path.display(), e);
+        }
+    }
 }
 
 #[cfg(test)]
@@ -320,6 +1818,190 @@ mod tests {
     use tempfile::Builder;
     use rand::prelude::*;
 
+    #[test]
+    fn lsmtree_level_stats() {
+        let tmpdir = Builder::new().prefix("rustydb_lsmtree_test").tempdir().unwrap();
+        let config = LSMConfig { fanout: 2, ..LSMConfig::default() };
+        let mut tree = LSMTree::with_config(tmpdir.path(), config).unwrap();
+        assert!(tree.level_stats().is_empty());
+
+        let mut counts = Vec::new();
+        for round in 0..5 {
+            for i in 0..10 {
+                tree.set(&format!("key{}-{:02}", round, i), "val").unwrap();
+            }
+            tree.flush_memtable().unwrap();
+            tree.compact().unwrap();
+            counts.push(tree.level_stats().iter().map(|l| (l.level, l.num_files)).collect::<Vec<_>>());
+        }
+        assert_eq!(counts, vec![
+            vec![(0, 1)],
+            vec![(1, 1)],
+            vec![(0, 1), (1, 1)],
+            vec![(2, 1)],
+            vec![(0, 1), (2, 1)],
+        ]);
+
+        let levels = tree.level_stats();
+        assert_eq!((levels[0].min_key.as_str(), levels[0].max_key.as_str()), ("key4-00", "key4-09"));
+        assert_eq!((levels[1].min_key.as_str(), levels[1].max_key.as_str()), ("key0-00", "key3-09"));
+        let on_disk: u64 = fs::read_dir(tmpdir.path()).unwrap()
+            .map(|e| e.unwrap().path())
+            .filter(|p| p.extension().is_some_and(|x| x == "sst"))
+            .map(|p| fs::metadata(p).unwrap().len())
+            .sum();
+        assert_eq!(levels.iter().map(|l| l.total_bytes).sum::<u64>(), on_disk);
+        assert!(levels[1].total_bytes > levels[0].total_bytes);
+    }
+
+    #[test]
+    fn lsmtree_interrupted_metadata() {
+        let tmpdir = Builder::new().prefix("rustydb_lsmtree_test").tempdir().unwrap();
+        let mut tree = LSMTree::new(tmpdir.path()).unwrap();
+        tree.set("foo", "bar").unwrap();
+        tree.flush_memtable().unwrap();
+        assert!(!tmpdir.path().join(META_TMP_FILENAME).exists());
+
+        // a crash while the metadata of the next flush is written, before it
+        // replaces the metadata file
+        tree.set("zoo", "kee").unwrap();
+        let pending = tree.begin_flush().unwrap();
+        pending.write().unwrap();
+        Arc::make_mut(&mut tree.sstables).push(Arc::new(pending.meta));
+        let tmppath = tmpdir.path().join(META_TMP_FILENAME);
+        tree.write_metadata(&tmppath, &tree.sstables).unwrap();
+        let metadata = fs::read(&tmppath).unwrap();
+        fs::write(&tmppath, &metadata[..metadata.len() / 2]).unwrap();
+        mem::forget(tree);
+
+        // the metadata before the flush still loads
+        let mut tree = LSMTree::new(tmpdir.path()).unwrap();
+        assert_eq!(tree.sstables.len(), 1);
+        assert_eq!(tree.get("foo").unwrap(), Some(String::from("bar")));
+        assert_eq!(tree.get("zoo").unwrap(), None);
+
+        // and the leftover is replaced by the next flush
+        tree.set("zoo", "kee").unwrap();
+        tree.flush_memtable().unwrap();
+        assert!(!tmppath.exists());
+        let tree = LSMTree::new(tmpdir.path()).unwrap();
+        assert_eq!(tree.get("zoo").unwrap(), Some(String::from("kee")));
+    }
+
+    #[test]
+    fn lsmtree_rebuild_metadata() {
+        let tmpdir = Builder::new().prefix("rustydb_lsmtree_test").tempdir().unwrap();
+        let config = LSMConfig { memtable_threshold: 256, fanout: 2, ..LSMConfig::default() };
+
+        // overwritten and deleted keys over several levels
+        let mut tree = LSMTree::with_config(tmpdir.path(), config).unwrap();
+        for round in 0..4 {
+            for i in 0..20 {
+                tree.set(&format!("key{:02}", i), &format!("val{}-{}", i, round)).unwrap();
+            }
+            tree.delete(&format!("key{:02}", round)).unwrap();
+            tree.flush_memtable().unwrap();
+            tree.compact().unwrap();
+        }
+        let expected = tree.range("key", "kez").unwrap();
+        assert!(tree.sstables.iter().any(|s| s.level > 0));
+        let positions = |tree: &LSMTree| {
+            let mut positions: Vec<_> = tree.sstables.iter()
+                .map(|s| (s.filename.clone(), s.seqno, s.level))
+                .collect();
+            positions.sort();
+            positions
+        };
+        let expected_positions = positions(&tree);
+        drop(tree);
+
+        // a lost metadata file leaves the tree empty
+        fs::remove_file(tmpdir.path().join(META_FILENAME)).unwrap();
+        assert!(LSMTree::new(tmpdir.path()).unwrap().range("key", "kez").unwrap().is_empty());
+        assert!(LSMTree::rebuild_metadata(tmpdir.path()).unwrap().is_empty());
+        let tree = LSMTree::with_config(tmpdir.path(), config).unwrap();
+        assert_eq!(tree.range("key", "kez").unwrap(), expected);
+        assert_eq!(tree.get("key03").unwrap(), None);
+        assert_eq!(positions(&tree), expected_positions);
+        drop(tree);
+
+        // a file that isn't an sstable is left out and reported
+        let bogus = tmpdir.path().join("bogus.sst");
+        fs::write(&bogus, b"not an sstable").unwrap();
+        let skipped = LSMTree::rebuild_metadata(tmpdir.path()).unwrap();
+        assert_eq!(skipped.len(), 1);
+        assert_eq!(skipped[0].0, bogus);
+        fs::remove_file(&bogus).unwrap();
+        let tree = LSMTree::with_config(tmpdir.path(), config).unwrap();
+        assert_eq!(positions(&tree), expected_positions);
+        drop(tree);
+
+        // as is a truncated one, the others are still recovered
+        let truncated = tmpdir.path().join(&expected_positions[0].0);
+        let contents = fs::read(&truncated).unwrap();
+        fs::write(&truncated, &contents[..contents.len() / 2]).unwrap();
+        let skipped = LSMTree::rebuild_metadata(tmpdir.path()).unwrap();
+        assert_eq!(skipped.len(), 1);
+        assert_eq!(skipped[0].0, truncated);
+        let tree = LSMTree::with_config(tmpdir.path(), config).unwrap();
+        assert_eq!(positions(&tree), expected_positions[1..].to_vec());
+        drop(tree);
+        fs::write(&truncated, &contents).unwrap();
+        LSMTree::rebuild_metadata(tmpdir.path()).unwrap();
+
+        // and so does one cut short by a crash while it was written
+        let metafpath = tmpdir.path().join(META_FILENAME);
+        let metadata = fs::read(&metafpath).unwrap();
+        fs::write(&metafpath, &metadata[..metadata.len() / 2]).unwrap();
+        assert!(LSMTree::new(tmpdir.path()).is_err());
+        LSMTree::rebuild_metadata(tmpdir.path()).unwrap();
+        let mut tree = LSMTree::with_config(tmpdir.path(), config).unwrap();
+        assert_eq!(tree.range("key", "kez").unwrap(), expected);
+
+        // writes after the rebuild are the newest
+        tree.set("key05", "rebuilt").unwrap();
+        tree.flush_memtable().unwrap();
+        assert_eq!(tree.get("key05").unwrap(), Some(String::from("rebuilt")));
+    }
+
+    #[test]
+    fn lsmtree_flush_empty_memtable() {
+        let tmpdir = Builder::new().prefix("rustydb_lsmtree_test").tempdir().unwrap();
+        let mut tree = LSMTree::new(tmpdir.path()).unwrap();
+        tree.flush_memtable().unwrap();
+        assert!(tree.sstables.is_empty());
+        assert_eq!(tree.total_bytes_flushed(), 0);
+
+        tree.set("key", "val").unwrap();
+        tree.flush_memtable().unwrap();
+        tree.flush_memtable().unwrap();
+        assert_eq!(tree.sstables.len(), 1);
+        assert_eq!(tree.get("key").unwrap(), Some(String::from("val")));
+    }
+
+    #[test]
+    fn lsmtree_flush_on_drop() {
+        let tmpdir = Builder::new().prefix("rustydb_lsmtree_test").tempdir().unwrap();
+
+        let mut tree = LSMTree::new(tmpdir.path()).unwrap();
+        tree.set("flushed", "1").unwrap();
+        tree.flush_memtable().unwrap();
+        tree.set("buffered", "2").unwrap();
+        tree.delete("flushed").unwrap();
+        drop(tree);
+
+        let tree = LSMTree::new(tmpdir.path()).unwrap();
+        assert_eq!(tree.memtable_bytes(), 0);
+        assert_eq!(tree.get("buffered").unwrap(), Some(String::from("2")));
+        assert_eq!(tree.get("flushed").unwrap(), None);
+        let num_sstables = tree.sstables.len();
+        drop(tree);
+
+        // an empty memtable writes nothing
+        let tree = LSMTree::new(tmpdir.path()).unwrap();
+        assert_eq!(tree.sstables.len(), num_sstables);
+    }
+
     #[test]
     fn lsmtree_single_entry() {
         let lsmpath = Builder::new().prefix("rustydb_lsmtree_test").tempdir().unwrap();
@@ -380,4 +2062,988 @@ mod tests {
             assert_eq!(newtree.get(key.as_str()).unwrap(), Some(val));
         }
     }  
+
+    #[test]
+    fn lsmtree_copy_to() {
+        let num = 500;
+        let lsmpath = Builder::new().prefix("rustydb_lsmtree_test").tempdir().unwrap();
+        let mut newtree = LSMTree::new(lsmpath.path()).unwrap();
+
+        let mut pairs: Vec<(String, String)> = Vec::new();
+        for i in 0..num {
+            let key = format!("key_{:05}", i);
+            let val = format!("val_{}", i);
+            newtree.set(&key, &val).unwrap();
+            pairs.push((key, val));
+        }
+
+        // half of them on disk, the rest in the memtable
+        newtree.flush_memtable().unwrap();
+        for (key, val) in pairs.iter_mut().step_by(2) {
+            *val = format!("{}-newer", val);
+            newtree.set(key, val).unwrap();
+        }
+
+        let backup = Builder::new().prefix("rustydb_lsmtree_backup").tempdir().unwrap();
+        newtree.copy_to(backup.path()).unwrap();
+
+        // the tree is untouched, its memtable isn't flushed
+        assert_eq!(newtree.sstables.len(), 1);
+        assert_eq!(newtree.memtable.map.len(), num / 2);
+
+        // the copy is a complete store on its own
+        let copied = LSMTree::new(backup.path()).unwrap();
+        for (key, val) in pairs {
+            assert_eq!(copied.get(&key).unwrap(), Some(val));
+        }
+
+        // refuse to overwrite an existing store
+        assert!(newtree.copy_to(backup.path()).is_err());
+
+        // while a flush is in progress the buffered writes are the newest
+        let pending = newtree.begin_flush().unwrap();
+        newtree.set("key_00000", "buffered").unwrap();
+        let backup = Builder::new().prefix("rustydb_lsmtree_backup").tempdir().unwrap();
+        newtree.copy_to(backup.path()).unwrap();
+        let copied = LSMTree::new(backup.path()).unwrap();
+        assert_eq!(copied.get("key_00000").unwrap(), Some(String::from("buffered")));
+        assert_eq!(copied.get("key_00002").unwrap(), Some(String::from("val_2-newer")));
+        assert_eq!(copied.get("key_00001").unwrap(), Some(String::from("val_1")));
+        newtree.abort_flush(pending);
+    }
+
+    #[test]
+    fn lsmtree_watch() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        let lsmpath = Builder::new().prefix("rustydb_lsmtree_test").tempdir().unwrap();
+        let mut newtree = LSMTree::new(lsmpath.path()).unwrap();
+
+        let counter = Arc::new(AtomicUsize::new(0));
+        let cb_counter = counter.clone();
+        let handle = newtree.watch("cpu", Arc::new(move |key: &str, _val: &str| {
+            assert!(key.starts_with("cpu"));
+            cb_counter.fetch_add(1, Ordering::SeqCst);
+        }));
+
+        newtree.set("cpu,host=a", "1").unwrap();
+        assert_eq!(counter.load(Ordering::SeqCst), 1);
+        newtree.set("mem,host=a", "2").unwrap();
+        assert_eq!(counter.load(Ordering::SeqCst), 1);
+        newtree.set("cpu,host=b", "3").unwrap();
+        assert_eq!(counter.load(Ordering::SeqCst), 2);
+
+        // no more callbacks after cancelling
+        handle.cancel();
+        newtree.set("cpu,host=c", "4").unwrap();
+        assert_eq!(counter.load(Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    fn lsmtree_get_latest_before() {
+        use crate::gorilla::MVEntry;
+        use crate::gorilla::api::compress_values;
+        use chrono::{Duration, TimeZone, Utc};
+
+        let lsmpath = Builder::new().prefix("rustydb_lsmtree_test").tempdir().unwrap();
+        let mut newtree = LSMTree::new(lsmpath.path()).unwrap();
+
+        // 5 blocks of the same series an hour apart, plus a neighbouring series
+        let base = Utc.timestamp_opt(1_500_000_000, 0).unwrap();
+        let starts: Vec<GorillaDateTime> = (0..5).map(|i| base + Duration::hours(i)).collect();
+        let mut blockstrs = Vec::new();
+        for (i, start) in starts.iter().enumerate() {
+            let entry = MVEntry::new(*start + Duration::seconds(10), vec![i as f64]);
+            let blockstr = compress_values(vec![entry], *start, 1).to_string();
+            newtree.set(&format!("cpu|{}", timestamp_key(*start)), &blockstr).unwrap();
+            newtree.set(&format!("cpv|{}", timestamp_key(*start)), "other series").unwrap();
+            blockstrs.push(blockstr);
+
+            // keep the first blocks on disk, the rest in the memtable
+            if i == 2 {
+                newtree.flush_memtable().unwrap();
+            }
+        }
+
+        let latest = |cutoff: GorillaDateTime| -> Option<String> {
+            newtree.get_latest_before("cpu|", cutoff).unwrap().map(|b| b.to_string())
+        };
+
+        assert_eq!(latest(starts[1] + Duration::minutes(30)), Some(blockstrs[1].clone()));
+        assert_eq!(latest(starts[3]), Some(blockstrs[3].clone()));
+        assert_eq!(latest(starts[4] + Duration::days(1)), Some(blockstrs[4].clone()));
+        assert_eq!(latest(starts[0] - Duration::seconds(1)), None);
+    }
+
+    #[test]
+    fn lsmtree_get_series() {
+        use crate::gorilla::api::compress_values;
+        use chrono::{Duration, TimeZone, Utc};
+
+        let lsmpath = Builder::new().prefix("rustydb_lsmtree_test").tempdir().unwrap();
+        let mut newtree = LSMTree::new(lsmpath.path()).unwrap();
+
+        // two adjacent blocks of a series with an entry every 10 minutes, the
+        // second one starts with the last entry of the first and runs past
+        // the start of a third
+        let base = Utc.timestamp_opt(1_500_000_000, 0).unwrap();
+        let block_entries = |start: i64, minutes: &[i64], value: f64| -> Vec<MVEntry> {
+            minutes.iter().map(|m| MVEntry::new(base + Duration::minutes(start + m), vec![value, *m as f64])).collect()
+        };
+        let blocks = [
+            (0, block_entries(0, &[0, 10, 20, 30, 40, 50, 60], 1.0)),
+            (60, block_entries(60, &[0, 10, 20, 30, 40, 50, 60, 70], 2.0)),
+            (125, block_entries(125, &[0, 10], 3.0)),
+        ];
+        for (start, entries) in blocks.iter() {
+            let start = base + Duration::minutes(*start);
+            let key = SeriesKey::new("cpu", "hostname=host_0", start);
+            newtree.set_bytes(&key.to_key(), &compress_values(entries.clone(), start, 2).to_bytes()).unwrap();
+
+            // another series in between
+            let other = SeriesKey::new("cpu", "hostname=host_1", start);
+            newtree.set_bytes(&other.to_key(), &compress_values(entries.clone(), start, 2).to_bytes()).unwrap();
+        }
+        newtree.flush_memtable().unwrap();
+        let hash = SeriesKey::new("cpu", "hostname=host_0", base).series_hash();
+
+        // a window across the seam, starting inside the first block
+        let entries = newtree.get_series(hash, base + Duration::minutes(35), base + Duration::minutes(95), 2).unwrap();
+        let minutes: Vec<i64> = entries.iter().map(|e| (e.time() - base).num_minutes()).collect();
+        assert_eq!(minutes, vec![40, 50, 60, 70, 80, 90]);
+        // the seam entry comes from the first block
+        let values: Vec<f64> = entries.iter().map(|e| e.values_ref()[0]).collect();
+        assert_eq!(values, vec![1.0, 1.0, 1.0, 2.0, 2.0, 2.0]);
+
+        // blocks that overlap in time come back merged in time order
+        let entries = newtree.get_series(hash, base + Duration::minutes(110), base + Duration::minutes(140), 2).unwrap();
+        let minutes: Vec<i64> = entries.iter().map(|e| (e.time() - base).num_minutes()).collect();
+        assert_eq!(minutes, vec![110, 120, 125, 130, 135]);
+
+        assert!(newtree.get_series(hash, base + Duration::minutes(10), base, 2).unwrap().is_empty());
+        assert!(newtree.get_series(hash, base - Duration::hours(2), base - Duration::hours(1), 2).unwrap().is_empty());
+
+        // an open ended window holds every entry
+        let entries = newtree.get_series(hash, chrono::DateTime::<Utc>::MIN_UTC, chrono::DateTime::<Utc>::MAX_UTC, 2).unwrap();
+        assert_eq!(entries.len(), 16);
+        let latest = newtree.get_latest_before(&series_key_prefix(hash), chrono::DateTime::<Utc>::MAX_UTC).unwrap();
+        assert_eq!(latest.unwrap().entry_count(), 2);
+    }
+
+    #[test]
+    fn lsmtree_series_key() {
+        use chrono::{TimeZone, Utc};
+
+        let series = [("cpu", "hostname=host_0"), ("cpu", "hostname=host_1"), ("mem", "hostname=host_0"), ("", "")];
+        let times = [i64::MIN, -1_000_000_000, -1, 0, 1, 1_500_000_000_000_000_000, i64::MAX];
+        let mut keys = Vec::new();
+        for (metric, tags) in series.iter() {
+            for start_ts in times.iter().rev() {
+                let key = SeriesKey { metric: metric.to_string(), tags: tags.to_string(), start_ts: *start_ts };
+                assert_eq!(SeriesKey::from_key_bytes(&key.to_key_bytes()).unwrap(), key);
+                keys.push(key);
+            }
+        }
+
+        // the byte keys sort by series hash and then by time
+        let mut by_bytes = keys.clone();
+        by_bytes.sort_by_key(|key| key.to_key_bytes());
+        let mut by_hash_time = keys.clone();
+        by_hash_time.sort_by_key(|key| (key.series_hash(), key.start_ts));
+        assert_eq!(by_bytes, by_hash_time);
+
+        // every block of a series shares 'series_key_prefix'
+        let start = Utc.timestamp_opt(1_500_000_000, 0).unwrap();
+        let key = SeriesKey::new("cpu", "hostname=host_0", start);
+        assert_eq!(key.start_ts, 1_500_000_000_000_000_000);
+        assert!(key.to_key().starts_with(&format!("{}{}", series_key_prefix(key.series_hash()), timestamp_key(start))));
+
+        // times past the nanosecond range sort at its ends
+        let far_future = Utc.with_ymd_and_hms(9999, 12, 31, 0, 0, 0).unwrap();
+        let far_past = Utc.with_ymd_and_hms(1000, 1, 1, 0, 0, 0).unwrap();
+        assert_eq!(timestamp_key(far_future), "ffffffffffffffff");
+        assert_eq!(timestamp_key(far_past), "0000000000000000");
+        assert!(timestamp_key(far_past) < timestamp_key(start) && timestamp_key(start) < timestamp_key(far_future));
+        let far_key = SeriesKey::new("cpu", "hostname=host_0", far_future);
+        assert_eq!(far_key.start_ts, i64::MAX);
+        assert_eq!(SeriesKey::from_key_bytes(&far_key.to_key_bytes()).unwrap(), far_key);
+        assert_eq!(SeriesKey::new("cpu", "hostname=host_0", far_past).start_ts, i64::MIN);
+
+        // anything else doesn't decode
+        let bytes = key.to_key_bytes();
+        assert!(SeriesKey::from_key_bytes(&bytes[..39]).is_err());
+        assert!(SeriesKey::from_key_bytes(&bytes[..bytes.len() - 1]).is_err());
+        assert!(SeriesKey::from_key_bytes(&[0xff; 48]).is_err());
+        let mut tampered = bytes.clone();
+        tampered[0] = if tampered[0] == b'0' { b'1' } else { b'0' };
+        assert!(SeriesKey::from_key_bytes(&tampered).is_err());
+        let mut signed = bytes.clone();
+        signed[32] = b'+';
+        assert!(SeriesKey::from_key_bytes(&signed).is_err());
+    }
+
+    #[test]
+    fn lsmtree_range() {
+        let lsmpath = Builder::new().prefix("rustydb_lsmtree_test").tempdir().unwrap();
+        let mut newtree = LSMTree::new(lsmpath.path()).unwrap();
+
+        // two overlapping sstables and the memtable, each overwriting some keys
+        for i in 0..10 {
+            newtree.set(&format!("k{}", i), "old").unwrap();
+        }
+        newtree.flush_memtable().unwrap();
+        for i in (4..8).step_by(2) {
+            newtree.set(&format!("k{}", i), "newer").unwrap();
+        }
+        newtree.flush_memtable().unwrap();
+        newtree.set("k6", "newest").unwrap();
+        newtree.set("k35", "newest").unwrap();
+
+        let range = newtree.range("k3", "k7").unwrap();
+        let expected: Vec<(String, String)> = vec![
+            ("k3", "old"), ("k35", "newest"), ("k4", "newer"), ("k5", "old"),
+            ("k6", "newest"), ("k7", "old"),
+        ].into_iter().map(|(k, v)| (k.to_string(), v.to_string())).collect();
+        assert_eq!(range, expected);
+
+        assert!(newtree.range("l", "m").unwrap().is_empty());
+        assert!(newtree.range("k7", "k3").unwrap().is_empty());
+    }
+
+    #[test]
+    fn lsmtree_delete() {
+        let lsmpath = Builder::new().prefix("rustydb_lsmtree_test").tempdir().unwrap();
+        let mut newtree = LSMTree::new(lsmpath.path()).unwrap();
+
+        // deleted while still in the memtable
+        newtree.set("foo", "bar").unwrap();
+        newtree.delete("foo").unwrap();
+        assert_eq!(newtree.get("foo").unwrap(), None);
+
+        // the tombstone shadows a value already on disk, before and after
+        // it is flushed itself
+        newtree.set("zoohoo", "keefuu").unwrap();
+        newtree.set("meemu", "mauha").unwrap();
+        newtree.flush_memtable().unwrap();
+        newtree.delete("zoohoo").unwrap();
+        assert_eq!(newtree.get("zoohoo").unwrap(), None);
+        newtree.flush_memtable().unwrap();
+        assert_eq!(newtree.get("zoohoo").unwrap(), None);
+        assert_eq!(newtree.get("meemu").unwrap(), Some(String::from("mauha")));
+        assert_eq!(newtree.range("a", "z").unwrap(), vec![(String::from("meemu"), String::from("mauha"))]);
+
+        // writing the key again brings it back
+        newtree.set("zoohoo", "again").unwrap();
+        assert_eq!(newtree.get("zoohoo").unwrap(), Some(String::from("again")));
+    }
+
+    #[test]
+    fn lsmtree_compact() {
+        let lsmpath = Builder::new().prefix("rustydb_lsmtree_test").tempdir().unwrap();
+        let mut newtree = LSMTree::new(lsmpath.path()).unwrap();
+        let count_files = || fs::read_dir(lsmpath.path()).unwrap()
+            .filter(|e| e.as_ref().unwrap().path().extension().is_some_and(|x| x == "sst"))
+            .count();
+
+        // one flush per time range, with the last one deleting an earlier key
+        for i in 0..SSTABLE_FANOUT {
+            for j in 0..10 {
+                newtree.set(&format!("key{}{:02}", i, j), &format!("val{}{}", i, j)).unwrap();
+            }
+            if i == SSTABLE_FANOUT - 1 {
+                newtree.delete("key309").unwrap();
+            }
+            newtree.flush_memtable().unwrap();
+        }
+        newtree.delete("key000").unwrap();
+        assert_eq!(count_files(), SSTABLE_FANOUT);
+
+        newtree.compact().unwrap();
+        assert_eq!(newtree.sstables.len(), 1);
+        assert_eq!(newtree.sstables[0].level, 1);
+        assert_eq!(count_files(), 1);
+
+        let check = |tree: &LSMTree| {
+            assert_eq!(tree.get("key000").unwrap(), None);
+            assert_eq!(tree.get("key309").unwrap(), None);
+            assert_eq!(tree.get("key205").unwrap(), Some(String::from("val25")));
+            let entries = tree.range("key", "key999").unwrap();
+            assert_eq!(entries.len(), 10 * SSTABLE_FANOUT - 2);
+            assert!(entries.windows(2).all(|w| w[0].0 < w[1].0));
+        };
+        check(&newtree);
+
+        // the level survives a reopen
+        newtree.flush_memtable().unwrap();
+        let reopened = LSMTree::new(lsmpath.path()).unwrap();
+        assert_eq!(reopened.sstables.len(), 2);
+        assert_eq!(reopened.sstables[0].level, 1);
+        assert_eq!(reopened.sstables[1].level, 0);
+        check(&reopened);
+
+        // overlapping sstables keep the newest value of every key
+        let otherpath = Builder::new().prefix("rustydb_lsmtree_test").tempdir().unwrap();
+        let mut othertree = LSMTree::new(otherpath.path()).unwrap();
+        for i in 0..SSTABLE_FANOUT {
+            othertree.set("a", &format!("{}", i)).unwrap();
+            othertree.set(&format!("b{}", i), &format!("{}", i)).unwrap();
+            if i == 1 {
+                othertree.delete("a").unwrap();
+            }
+            othertree.flush_memtable().unwrap();
+        }
+        othertree.compact().unwrap();
+        assert_eq!(othertree.sstables.len(), 1);
+        assert_eq!(othertree.get("a").unwrap(), Some(format!("{}", SSTABLE_FANOUT - 1)));
+        let entries = othertree.range("a", "z").unwrap();
+        assert_eq!(entries.len(), 1 + SSTABLE_FANOUT);
+        let path = othertree.path.join(&othertree.sstables[0].filename);
+        let reader = SSTableFileReader::<BytesCodec>::open_with_codec(&path).unwrap();
+        assert_eq!(reader.iter_entries().count(), 1 + SSTABLE_FANOUT);
+    }
+
+    #[test]
+    fn lsmtree_compact_with_progress() {
+        let lsmpath = Builder::new().prefix("rustydb_lsmtree_test").tempdir().unwrap();
+        let config = LSMConfig { memtable_threshold: 1000, fanout: 2, ..LSMConfig::default() };
+        let mut newtree = LSMTree::with_config(lsmpath.path(), config).unwrap();
+
+        // 2 level 0 sstables merge into a level 1 one, which makes 2 there
+        for i in 0..4 {
+            newtree.set(&format!("key{}", i), &format!("val{}", i)).unwrap();
+            newtree.flush_memtable().unwrap();
+            if i == 1 {
+                newtree.compact().unwrap();
+            }
+        }
+
+        let mut reports = Vec::new();
+        newtree.compact_with_progress(|p| reports.push(p)).unwrap();
+        assert_eq!(newtree.sstables.len(), 1);
+        assert_eq!(newtree.sstables[0].level, 2);
+        let levels: Vec<usize> = reports.iter().map(|p| p.level).collect();
+        assert_eq!(levels, vec![0, 1]);
+        assert_eq!(reports.last().unwrap().files_merged, 4);
+
+        // the bytes written add up to the merged files, the last one of
+        // which is all that is left
+        assert!(reports[0].bytes_written > 0);
+        let final_size = fs::metadata(newtree.path.join(&newtree.sstables[0].filename)).unwrap().len();
+        assert!(reports[1].bytes_written > final_size);
+        assert_eq!(reports[1].bytes_written - reports[0].bytes_written, final_size);
+
+        // nothing to merge, nothing reported
+        let mut calls = 0;
+        newtree.compact_with_progress(|_| calls += 1).unwrap();
+        assert_eq!(calls, 0);
+    }
+
+    #[test]
+    fn lsmtree_set_while_flushing() {
+        let lsmpath = Builder::new().prefix("rustydb_lsmtree_test").tempdir().unwrap();
+        let mut newtree = LSMTree::new(lsmpath.path()).unwrap();
+        for i in 0..1000 {
+            newtree.set(&format!("key{:04}", i), &format!("val{}", i)).unwrap();
+        }
+
+        // the file is written on another thread while this one keeps using
+        // the tree
+        let pending = newtree.begin_flush().unwrap();
+        assert!(newtree.begin_flush().is_err());
+        let flusher = std::thread::spawn(move || {
+            pending.write().unwrap();
+            pending
+        });
+        for i in 0..1000 {
+            if i % 2 == 0 {
+                newtree.set(&format!("key{:04}", i), &format!("new{}", i)).unwrap();
+            } else if i % 3 == 0 {
+                newtree.delete(&format!("key{:04}", i)).unwrap();
+            }
+            newtree.set(&format!("more{:04}", i), &format!("val{}", i)).unwrap();
+        }
+        assert_eq!(newtree.get("key0001").unwrap(), Some(String::from("val1")));
+        assert_eq!(newtree.get("key0002").unwrap(), Some(String::from("new2")));
+        assert_eq!(newtree.get("key0003").unwrap(), None);
+        assert_eq!(newtree.get("more0003").unwrap(), Some(String::from("val3")));
+
+        let pending = flusher.join().unwrap();
+        newtree.finish_flush(pending).unwrap();
+        assert_eq!(newtree.sstables.len(), 1);
+
+        let check = |tree: &LSMTree| {
+            for i in 0..1000 {
+                let expected = match i {
+                    i if i % 2 == 0 => Some(format!("new{}", i)),
+                    i if i % 3 == 0 => None,
+                    i => Some(format!("val{}", i)),
+                };
+                assert_eq!(tree.get(&format!("key{:04}", i)).unwrap(), expected);
+                assert_eq!(tree.get(&format!("more{:04}", i)).unwrap(), Some(format!("val{}", i)));
+            }
+        };
+        check(&newtree);
+        let num_deleted = (0..1000).filter(|i| i % 2 == 1 && i % 3 == 0).count();
+        assert_eq!(newtree.range("key", "key9999").unwrap().len(), 1000 - num_deleted);
+
+        // the buffered writes are the memtable now and flush like usual
+        newtree.flush_memtable().unwrap();
+        assert_eq!(newtree.sstables.len(), 2);
+        check(&LSMTree::new(lsmpath.path()).unwrap());
+
+        // an aborted flush keeps every write in memory
+        newtree.set("foo", "bar").unwrap();
+        let pending = newtree.begin_flush().unwrap();
+        newtree.set("foo", "baz").unwrap();
+        newtree.set("zoohoo", "keefuu").unwrap();
+        newtree.abort_flush(pending);
+        assert_eq!(newtree.sstables.len(), 2);
+        assert_eq!(newtree.get("foo").unwrap(), Some(String::from("baz")));
+        assert_eq!(newtree.get("zoohoo").unwrap(), Some(String::from("keefuu")));
+        newtree.flush_memtable().unwrap();
+        assert_eq!(newtree.sstables.len(), 3);
+    }
+
+    #[test]
+    fn lsmtree_many_sstables() {
+        let lsmpath = Builder::new().prefix("rustydb_lsmtree_test").tempdir().unwrap();
+        let mut newtree = LSMTree::new(lsmpath.path()).unwrap();
+
+        // more sstables than a u8 count could hold
+        let num = 300;
+        for i in 0..num {
+            newtree.set(&format!("key{:04}", i), &format!("val{}", i)).unwrap();
+            newtree.flush_memtable().unwrap();
+        }
+
+        let reopened = LSMTree::new(lsmpath.path()).unwrap();
+        assert_eq!(reopened.sstables.len(), num);
+        for i in 0..num {
+            assert_eq!(reopened.get(&format!("key{:04}", i)).unwrap(), Some(format!("val{}", i)));
+        }
+    }
+
+    #[test]
+    fn lsmtree_parallel_get() {
+        let lsmpath = Builder::new().prefix("rustydb_lsmtree_test").tempdir().unwrap();
+        let mut newtree = LSMTree::new(lsmpath.path()).unwrap();
+
+        // overlapping sstables with overwrites, deletes and a deleted range
+        let mut rng = rand::thread_rng();
+        for i in 0..20 {
+            for _ in 0..30 {
+                let key = format!("key{:03}", rng.gen_range(0, 200));
+                if rng.gen_range(0, 5) == 0 {
+                    newtree.delete(&key).unwrap();
+                } else {
+                    newtree.set(&key, &format!("val{}", i)).unwrap();
+                }
+            }
+            if i == 10 {
+                newtree.delete_range("key050", "key080").unwrap();
+            }
+            newtree.flush_memtable().unwrap();
+        }
+        drop(newtree);
+
+        let sequential = LSMTree::new(lsmpath.path()).unwrap();
+        let config = LSMConfig { get_threads: 4, ..LSMConfig::default() };
+        let parallel = LSMTree::with_config(lsmpath.path(), config).unwrap();
+        for i in 0..210 {
+            let key = format!("key{:03}", i);
+            assert_eq!(parallel.get_with_generation(&key).unwrap(), sequential.get_with_generation(&key).unwrap());
+            assert_eq!(parallel.snapshot().get(&key).unwrap(), sequential.get(&key).unwrap());
+        }
+    }
+
+    #[test]
+    fn lsmtree_bulk_load() {
+        let config = LSMConfig { memtable_threshold: 4096, fanout: 2, ..LSMConfig::default() };
+        let loadpath = Builder::new().prefix("rustydb_lsmtree_test").tempdir().unwrap();
+        let mut loaded = LSMTree::with_config(loadpath.path(), config).unwrap();
+        let setpath = Builder::new().prefix("rustydb_lsmtree_test").tempdir().unwrap();
+        let mut inserted = LSMTree::with_config(setpath.path(), config).unwrap();
+
+        // older values on disk and in the memtable are overwritten
+        for tree in [&mut loaded, &mut inserted] {
+            tree.set("key0000", "old").unwrap();
+            tree.flush_memtable().unwrap();
+            tree.set("key0001", "old").unwrap();
+            tree.set("other", "old").unwrap();
+        }
+
+        let mut rng = rand::thread_rng();
+        let pairs: BTreeMap<String, String> = (0..2000)
+            .map(|_| {
+                let i = rng.gen_range(0, 5000);
+                (format!("key{:04}", i), format!("val{}", rng.gen::<u32>()))
+            })
+            .chain([(String::from("key0000"), String::from("new")), (String::from("key0001"), String::from("new"))])
+            .collect();
+        loaded.bulk_load(pairs.clone().into_iter()).unwrap();
+        for (key, val) in &pairs {
+            inserted.set(key, val).unwrap();
+        }
+
+        // split into sstables the size of a memtable flush
+        assert!(loaded.sstables.len() > 3);
+        assert!(loaded.memtable.map.is_empty());
+        let loaded_all: Vec<(String, String)> = loaded.range("", "~").unwrap();
+        assert_eq!(loaded_all, inserted.range("", "~").unwrap());
+        for i in 0..5000 {
+            let key = format!("key{:04}", i);
+            assert_eq!(loaded.get(&key).unwrap(), inserted.get(&key).unwrap());
+        }
+        assert_eq!(loaded.get("key0000").unwrap(), Some(String::from("new")));
+        assert_eq!(loaded.get("other").unwrap(), Some(String::from("old")));
+
+        // the loaded sstables are listed in the metadata
+        drop(loaded);
+        let mut loaded = LSMTree::with_config(loadpath.path(), config).unwrap();
+        assert_eq!(loaded.range("", "~").unwrap(), loaded_all);
+
+        // keys out of order load nothing
+        let num_sstables = loaded.sstables.len();
+        let num_files = fs::read_dir(loadpath.path()).unwrap().count();
+        let unsorted = (0..1000).map(|i| (format!("key{:04}", 999 - i), String::from("val")));
+        match loaded.bulk_load(unsorted) {
+            Err(Error::InvalidArgument(_)) => (),
+            other => panic!("unexpected result {:?}", other),
+        }
+        assert_eq!(loaded.sstables.len(), num_sstables);
+        assert_eq!(fs::read_dir(loadpath.path()).unwrap().count(), num_files);
+        assert_eq!(loaded.range("", "~").unwrap(), loaded_all);
+    }
+
+    #[test]
+    fn lsmtree_get_memtable() {
+        let lsmpath = Builder::new().prefix("rustydb_lsmtree_test").tempdir().unwrap();
+        let mut newtree = LSMTree::new(lsmpath.path()).unwrap();
+
+        newtree.set("foo", "bar").unwrap();
+        newtree.set("zoo", "kee").unwrap();
+        newtree.delete("zoo").unwrap();
+        assert_eq!(newtree.get_memtable("foo"), Some(String::from("bar")));
+        assert_eq!(newtree.get_memtable("zoo"), None);
+        assert_eq!(newtree.get_memtable("missing"), None);
+
+        // flushed keys are only found by 'get'
+        newtree.flush_memtable().unwrap();
+        assert_eq!(newtree.get_memtable("foo"), None);
+        assert_eq!(newtree.get("foo").unwrap(), Some(String::from("bar")));
+
+        // and the buffered memtable is consulted during a flush
+        newtree.set("foo", "baz").unwrap();
+        let pending = newtree.begin_flush().unwrap();
+        newtree.set("moo", "mee").unwrap();
+        assert_eq!(newtree.get_memtable("foo"), Some(String::from("baz")));
+        assert_eq!(newtree.get_memtable("moo"), Some(String::from("mee")));
+        pending.write().unwrap();
+        newtree.finish_flush(pending).unwrap();
+        assert_eq!(newtree.get_memtable("foo"), None);
+        assert_eq!(newtree.get_memtable("moo"), Some(String::from("mee")));
+    }
+
+    #[test]
+    fn lsmtree_reader_cache() {
+        let lsmpath = Builder::new().prefix("rustydb_lsmtree_test").tempdir().unwrap();
+        let config = LSMConfig { reader_cache_size: 2, fanout: 3, ..LSMConfig::default() };
+        let mut newtree = LSMTree::with_config(lsmpath.path(), config).unwrap();
+
+        // three sstables with disjoint key ranges
+        for prefix in ["a", "b", "c"] {
+            newtree.set(&format!("{}1", prefix), "x").unwrap();
+            newtree.flush_memtable().unwrap();
+        }
+        let opens = |tree: &LSMTree| -> Vec<u64> {
+            tree.per_file_stats().iter().map(|(_, stats)| stats.num_opens).collect()
+        };
+
+        // a second query to the same sstable reuses its reader
+        assert_eq!(newtree.get("a1").unwrap(), Some(String::from("x")));
+        assert_eq!(newtree.get("a1").unwrap(), Some(String::from("x")));
+        assert_eq!(newtree.snapshot().get("a1").unwrap(), Some(String::from("x")));
+        assert_eq!(opens(&newtree), vec![1, 0, 0]);
+        assert_eq!(newtree.per_file_stats()[0].1.num_get_calls, 3);
+
+        // the least recently used reader is closed past the capacity
+        newtree.get("b1").unwrap();
+        newtree.get("a1").unwrap();
+        newtree.get("c1").unwrap();
+        newtree.get("a1").unwrap();
+        assert_eq!(opens(&newtree), vec![1, 1, 1]);
+        newtree.get("b1").unwrap();
+        assert_eq!(opens(&newtree), vec![1, 2, 1]);
+
+        // the readers of merged sstables are dropped
+        newtree.compact().unwrap();
+        assert!(newtree.readers.readers.lock().unwrap().is_empty());
+        assert_eq!(newtree.get("c1").unwrap(), Some(String::from("x")));
+        assert_eq!(newtree.get("b1").unwrap(), Some(String::from("x")));
+        assert_eq!(opens(&newtree), vec![1]);
+
+        // without a cache every query opens the sstable
+        drop(newtree);
+        let config = LSMConfig { reader_cache_size: 0, ..config };
+        let newtree = LSMTree::with_config(lsmpath.path(), config).unwrap();
+        newtree.get("a1").unwrap();
+        newtree.get("a1").unwrap();
+        assert_eq!(opens(&newtree), vec![2]);
+    }
+
+    #[test]
+    fn lsmtree_per_file_stats() {
+        let lsmpath = Builder::new().prefix("rustydb_lsmtree_test").tempdir().unwrap();
+        let mut newtree = LSMTree::new(lsmpath.path()).unwrap();
+
+        // two sstables with disjoint key ranges
+        newtree.set("a1", "x").unwrap();
+        newtree.set("a2", "x").unwrap();
+        newtree.flush_memtable().unwrap();
+        newtree.set("b1", "x").unwrap();
+        newtree.flush_memtable().unwrap();
+
+        assert_eq!(newtree.get("a1").unwrap(), Some(String::from("x")));
+        assert_eq!(newtree.get("a2").unwrap(), Some(String::from("x")));
+        assert_eq!(newtree.get("a15").unwrap(), None);
+        assert_eq!(newtree.get("b1").unwrap(), Some(String::from("x")));
+
+        let stats = newtree.per_file_stats();
+        assert_eq!(stats.len(), 2);
+        assert_eq!((stats[0].1.num_get_calls, stats[0].1.num_get_hits), (3, 2));
+        assert_eq!((stats[1].1.num_get_calls, stats[1].1.num_get_hits), (1, 1));
+    }
+
+    #[test]
+    fn lsmtree_evict_to_cold() {
+        let lsmpath = Builder::new().prefix("rustydb_lsmtree_test").tempdir().unwrap();
+        let coldpath = Builder::new().prefix("rustydb_lsmtree_cold").tempdir().unwrap();
+        let mut newtree = LSMTree::new(lsmpath.path()).unwrap();
+
+        // an old and a recent sstable
+        newtree.set("2019-01-01", "old").unwrap();
+        newtree.set("2019-06-01", "old").unwrap();
+        newtree.flush_memtable().unwrap();
+        newtree.set("2020-01-01", "new").unwrap();
+        newtree.flush_memtable().unwrap();
+
+        assert_eq!(newtree.evict_to_cold("2020", coldpath.path()).unwrap(), 1);
+        assert_eq!(fs::read_dir(coldpath.path()).unwrap().count(), 1);
+        // already evicted sstables stay where they are
+        assert_eq!(newtree.evict_to_cold("2020", coldpath.path()).unwrap(), 0);
+
+        assert_eq!(newtree.get("2019-06-01").unwrap(), Some(String::from("old")));
+        assert_eq!(newtree.get("2020-01-01").unwrap(), Some(String::from("new")));
+
+        // the cold location survives a restart
+        let reopened = LSMTree::new(lsmpath.path()).unwrap();
+        assert_eq!(reopened.get("2019-01-01").unwrap(), Some(String::from("old")));
+        assert_eq!(reopened.get("2020-01-01").unwrap(), Some(String::from("new")));
+    }
+
+    #[test]
+    fn lsmtree_scan_all_gorilla_blocks() {
+        use crate::gorilla::MVEntry;
+        use crate::gorilla::api::{compress_values, retrieve_values};
+        use chrono::{Duration, TimeZone, Utc};
+
+        let lsmpath = Builder::new().prefix("rustydb_lsmtree_test").tempdir().unwrap();
+        let mut newtree = LSMTree::new(lsmpath.path()).unwrap();
+
+        let base = Utc.timestamp_opt(1_500_000_000, 0).unwrap();
+        for i in 0..10 {
+            let start = base + Duration::hours(i);
+            let entries = vec![
+                MVEntry::new(start + Duration::seconds(10), vec![i as f64, 1.0]),
+                MVEntry::new(start + Duration::seconds(20), vec![i as f64 * 2.0, 2.0]),
+            ];
+            let blockstr = compress_values(entries, start, 2).to_string();
+            newtree.set(&format!("cpu|{}", timestamp_key(start)), &blockstr).unwrap();
+
+            // half of the blocks on disk
+            if i == 4 {
+                newtree.flush_memtable().unwrap();
+            }
+        }
+        // not a block
+        newtree.set("aaa", "x").unwrap();
+
+        let scanned: Vec<(String, GorillaBlock)> = newtree.scan_all_gorilla_blocks(2)
+            .map(|r| r.unwrap())
+            .collect();
+        assert_eq!(scanned.len(), 10);
+        for (i, (key, block)) in scanned.into_iter().enumerate() {
+            let start = base + Duration::hours(i as i64);
+            assert_eq!(key, format!("cpu|{}", timestamp_key(start)));
+            let entries = retrieve_values(block, 2, 2);
+            assert!(entries[0].time() == start + Duration::seconds(10));
+            assert_eq!(entries[1].values(), vec![i as f64 * 2.0, 2.0]);
+        }
+    }
+
+    #[test]
+    fn lsmtree_get_with_generation() {
+        let lsmpath = Builder::new().prefix("rustydb_lsmtree_test").tempdir().unwrap();
+        let mut newtree = LSMTree::new(lsmpath.path()).unwrap();
+
+        // the same key across two overlapping sstables
+        newtree.set("bar", "old").unwrap();
+        newtree.set("foo", "old").unwrap();
+        newtree.flush_memtable().unwrap();
+        newtree.set("foo", "new").unwrap();
+        newtree.set("zoo", "new").unwrap();
+        assert_eq!(newtree.get_with_generation("foo").unwrap(), Some((String::from("new"), 1)));
+        newtree.flush_memtable().unwrap();
+
+        assert_eq!(newtree.get("foo").unwrap(), Some(String::from("new")));
+        assert_eq!(newtree.get_with_generation("foo").unwrap(), Some((String::from("new"), 1)));
+        assert_eq!(newtree.get_with_generation("bar").unwrap(), Some((String::from("old"), 0)));
+        assert_eq!(newtree.range("a", "z").unwrap()[1], (String::from("foo"), String::from("new")));
+
+        // the seqno decides, not where the sstable is listed
+        Arc::make_mut(&mut newtree.sstables).reverse();
+        assert_eq!(newtree.get("foo").unwrap(), Some(String::from("new")));
+        newtree.flush_metadata().unwrap();
+
+        // seqnos are kept in the metadata, new flushes go above them
+        let mut reopened = LSMTree::new(lsmpath.path()).unwrap();
+        assert_eq!(reopened.get_with_generation("foo").unwrap(), Some((String::from("new"), 1)));
+        reopened.set("foo", "newest").unwrap();
+        reopened.flush_memtable().unwrap();
+        assert_eq!(reopened.get_with_generation("foo").unwrap(), Some((String::from("newest"), 2)));
+        reopened.delete("foo").unwrap();
+        assert_eq!(reopened.get_with_generation("foo").unwrap(), None);
+    }
+
+    #[test]
+    fn lsmtree_with_config() {
+        let lsmpath = Builder::new().prefix("rustydb_lsmtree_test").tempdir().unwrap();
+        let config = LSMConfig { memtable_threshold: 100, fanout: 2, ..LSMConfig::default() };
+        let mut newtree = LSMTree::with_config(lsmpath.path(), config).unwrap();
+
+        // each pair takes 8 + 5 + 5 bytes, so 5 of them fit in 100
+        for i in 0..5 {
+            newtree.set(&format!("key{:02}", i), &format!("val{:02}", i)).unwrap();
+        }
+        assert_eq!(newtree.sstables.len(), 0);
+        newtree.set("key05", "val05").unwrap();
+        assert_eq!(newtree.sstables.len(), 1);
+        for i in 6..11 {
+            newtree.set(&format!("key{:02}", i), &format!("val{:02}", i)).unwrap();
+        }
+        assert_eq!(newtree.sstables.len(), 2);
+
+        // two sstables are enough for a merge
+        newtree.compact().unwrap();
+        assert_eq!(newtree.sstables.len(), 1);
+        assert_eq!(newtree.sstables[0].level, 1);
+        for i in 0..11 {
+            assert_eq!(newtree.get(&format!("key{:02}", i)).unwrap(), Some(format!("val{:02}", i)));
+        }
+
+        let bad = LSMConfig { memtable_threshold: 100, fanout: 1, ..LSMConfig::default() };
+        assert!(LSMTree::with_config(lsmpath.path(), bad).is_err());
+        assert_eq!(LSMConfig::default(), LSMConfig {
+            memtable_threshold: MEMTABLE_THRESHOLD,
+            fanout: SSTABLE_FANOUT,
+            get_threads: 1,
+            reader_cache_size: READER_CACHE_SIZE,
+            wal_threshold: WAL_THRESHOLD,
+        });
+    }
+
+    #[test]
+    fn lsmtree_scan() {
+        let lsmpath = Builder::new().prefix("rustydb_lsmtree_test").tempdir().unwrap();
+        let mut newtree = LSMTree::new(lsmpath.path()).unwrap();
+        assert_eq!(newtree.scan().unwrap().count(), 0);
+
+        newtree.set("b", "old").unwrap();
+        newtree.set("d", "old").unwrap();
+        newtree.set("f", "old").unwrap();
+        newtree.flush_memtable().unwrap();
+        newtree.set("a", "new").unwrap();
+        newtree.set("d", "new").unwrap();
+        newtree.delete("f").unwrap();
+        newtree.flush_memtable().unwrap();
+
+        // in the memtable and in both sstables
+        newtree.set("d", "newest").unwrap();
+        newtree.set("c", "newest").unwrap();
+        newtree.delete("b").unwrap();
+
+        let pairs: Vec<(String, Vec<u8>)> = newtree.scan().unwrap().collect();
+        let expected: Vec<(String, Vec<u8>)> = [("a", "new"), ("c", "newest"), ("d", "newest")].iter()
+            .map(|(k, v)| (k.to_string(), v.as_bytes().to_vec()))
+            .collect();
+        assert_eq!(pairs, expected);
+        assert_eq!(pairs, newtree.range_bytes("", "z").unwrap());
+
+        // a deleted key written again
+        newtree.set("f", "again").unwrap();
+        let keys: Vec<String> = newtree.scan().unwrap().map(|(k, _)| k).collect();
+        assert_eq!(keys, vec!["a", "c", "d", "f"]);
+    }
+
+    #[test]
+    fn lsmtree_snapshot() {
+        let lsmpath = Builder::new().prefix("rustydb_lsmtree_test").tempdir().unwrap();
+        let config = LSMConfig { memtable_threshold: 1000, fanout: 2, ..LSMConfig::default() };
+        let mut newtree = LSMTree::with_config(lsmpath.path(), config).unwrap();
+
+        newtree.set("a", "old").unwrap();
+        newtree.set("b", "old").unwrap();
+        newtree.flush_memtable().unwrap();
+        newtree.set("c", "old").unwrap();
+        let snapshot = newtree.snapshot();
+
+        // a flush doesn't show through the snapshot
+        newtree.set("a", "new").unwrap();
+        newtree.delete("c").unwrap();
+        newtree.flush_memtable().unwrap();
+        newtree.set("d", "new").unwrap();
+        assert_eq!(snapshot.get("a").unwrap(), Some(String::from("old")));
+        assert_eq!(snapshot.get("c").unwrap(), Some(String::from("old")));
+        assert_eq!(snapshot.get("d").unwrap(), None);
+        let expected: Vec<(String, String)> = [("a", "old"), ("b", "old"), ("c", "old")].iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect();
+        assert_eq!(snapshot.range("a", "z").unwrap(), expected);
+        assert_eq!(newtree.get("a").unwrap(), Some(String::from("new")));
+        assert_eq!(newtree.get("c").unwrap(), None);
+
+        // a merged source stays on disk while the snapshot reads it, the one
+        // flushed after the snapshot is deleted right away
+        let sources: Vec<PathBuf> = newtree.sstables.iter()
+            .map(|s| newtree.path.join(&s.filename))
+            .collect();
+        newtree.compact().unwrap();
+        assert_eq!(newtree.sstables.len(), 1);
+        assert!(sources[0].exists());
+        assert!(!sources[1].exists());
+        assert_eq!(snapshot.range("a", "z").unwrap(), expected);
+
+        // nor are they moved to cold storage
+        let coldpath = Builder::new().prefix("rustydb_lsmtree_cold").tempdir().unwrap();
+        let later = newtree.snapshot();
+        assert_eq!(newtree.evict_to_cold("z", coldpath.path()).unwrap(), 0);
+        drop(later);
+
+        drop(snapshot);
+        assert!(!sources[0].exists());
+        assert_eq!(newtree.get("a").unwrap(), Some(String::from("new")));
+    }
+
+    #[test]
+    fn lsmtree_block_bytes() {
+        use crate::gorilla::MVEntry;
+        use crate::gorilla::api::{compress_values, retrieve_values};
+        use chrono::{Duration, TimeZone, Utc};
+
+        let lsmpath = Builder::new().prefix("rustydb_lsmtree_test").tempdir().unwrap();
+        let mut newtree = LSMTree::new(lsmpath.path()).unwrap();
+
+        // values that need the high bit of their bytes
+        let base = Utc.timestamp_opt(1_500_000_000, 0).unwrap();
+        let entries: Vec<MVEntry> = (0..20)
+            .map(|i| MVEntry::new(base + Duration::seconds(10 * i), vec![-1e300 * i as f64, 0.1 * i as f64]))
+            .collect();
+        let block = compress_values(entries.clone(), base, 2);
+        let bytes = block.to_bytes();
+        assert!(String::from_utf8(bytes.clone()).is_err());
+
+        let key = format!("cpu|{}", timestamp_key(base));
+        newtree.set_bytes(&key, &bytes).unwrap();
+        assert_eq!(newtree.get_bytes(&key).unwrap(), Some(bytes.clone()));
+        newtree.flush_memtable().unwrap();
+        assert_eq!(newtree.get_bytes(&key).unwrap(), Some(bytes.clone()));
+        assert_eq!(newtree.range_bytes("cpu|", "cpu|~").unwrap(), vec![(key.clone(), bytes.clone())]);
+
+        // the string reads refuse the value rather than handing out bad utf8
+        assert!(matches!(newtree.get(&key), Err(Error::BadUtf8)));
+        assert!(matches!(newtree.range("cpu|", "cpu|~"), Err(Error::BadUtf8)));
+
+        let stored = newtree.get_latest_before("cpu|", base + Duration::hours(1)).unwrap().unwrap();
+        assert_eq!(stored.to_bytes(), bytes);
+        let decoded = retrieve_values(stored, 2, 20);
+        for (entry, expected) in decoded.iter().zip(entries.iter()) {
+            assert_eq!(entry.time(), expected.time());
+            assert_eq!(entry.values_ref(), expected.values_ref());
+        }
+    }
+
+    #[test]
+    fn lsmtree_delete_range() {
+        let lsmpath = Builder::new().prefix("rustydb_lsmtree_test").tempdir().unwrap();
+        let config = LSMConfig { memtable_threshold: 4096, fanout: 2, ..LSMConfig::default() };
+        let mut newtree = LSMTree::with_config(lsmpath.path(), config).unwrap();
+
+        // half of the keys on disk, the other half in the memtable
+        for i in 0..10 {
+            newtree.set(&format!("key{:02}", i), "old").unwrap();
+        }
+        newtree.flush_memtable().unwrap();
+        for i in 10..20 {
+            newtree.set(&format!("key{:02}", i), "new").unwrap();
+        }
+        newtree.set("key05", "new").unwrap();
+
+        newtree.delete_range("key03", "key15").unwrap();
+        let kept = |tree: &LSMTree| -> Vec<String> {
+            tree.range("key00", "key99").unwrap().into_iter().map(|(k, _)| k).collect()
+        };
+        let expected: Vec<String> = [0, 1, 2, 15, 16, 17, 18, 19].iter().map(|i| format!("key{:02}", i)).collect();
+        assert_eq!(kept(&newtree), expected);
+        assert_eq!(newtree.get("key02").unwrap(), Some(String::from("old")));
+        assert_eq!(newtree.get("key05").unwrap(), None);
+        assert_eq!(newtree.get("key14").unwrap(), None);
+        assert_eq!(newtree.get("key15").unwrap(), Some(String::from("new")));
+        let scanned: Vec<String> = newtree.scan().unwrap().map(|(k, _)| k).collect();
+        assert_eq!(scanned, expected);
+
+        // a key written again after the deletion
+        newtree.set("key07", "again").unwrap();
+        assert_eq!(newtree.get("key07").unwrap(), Some(String::from("again")));
+
+        // the range survives a flush and reopening the tree
+        newtree.flush_memtable().unwrap();
+        drop(newtree);
+        let mut newtree = LSMTree::with_config(lsmpath.path(), config).unwrap();
+        assert_eq!(newtree.get("key05").unwrap(), None);
+        assert_eq!(newtree.get("key07").unwrap(), Some(String::from("again")));
+        assert_eq!(newtree.range_tombstones.len(), 1);
+
+        // compaction drops the deleted values and then the range itself
+        newtree.compact().unwrap();
+        assert_eq!(newtree.sstables.len(), 1);
+        assert!(newtree.range_tombstones.is_empty());
+        let path = newtree.path.join(&newtree.sstables[0].filename);
+        let keys: Vec<String> = SSTableFileReader::open(&path).unwrap().iter().map(|(k, _)| k).collect();
+        let mut expected = expected.clone();
+        expected.insert(3, String::from("key07"));
+        assert_eq!(keys, expected);
+        assert_eq!(kept(&newtree), expected);
+
+        // an empty range deletes nothing
+        newtree.delete_range("key10", "key00").unwrap();
+        assert!(newtree.range_tombstones.is_empty());
+
+        // a range deleted while a flush is in progress stays deleted when the
+        // flush is given up on
+        newtree.set("key20", "frozen").unwrap();
+        newtree.set("key30", "frozen").unwrap();
+        let pending = newtree.begin_flush().unwrap();
+        newtree.set("key21", "buffered").unwrap();
+        newtree.delete_range("key20", "key25").unwrap();
+        newtree.set("key22", "again").unwrap();
+        newtree.abort_flush(pending);
+        assert_eq!(newtree.get("key20").unwrap(), None);
+        assert_eq!(newtree.get("key21").unwrap(), None);
+        assert_eq!(newtree.get("key22").unwrap(), Some(String::from("again")));
+        assert_eq!(newtree.get("key30").unwrap(), Some(String::from("frozen")));
+        newtree.flush_memtable().unwrap();
+        assert_eq!(newtree.get("key20").unwrap(), None);
+        assert_eq!(newtree.get("key22").unwrap(), Some(String::from("again")));
+        assert_eq!(newtree.get("key30").unwrap(), Some(String::from("frozen")));
+    }
 }
@@ -1,4 +1,9 @@
 pub mod store;
 pub mod lsmtree;
+pub mod error;
+pub mod codec;
 mod wal;
+mod format;
 mod sstable;
+
+pub use error::Error;
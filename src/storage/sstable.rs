@@ -3,135 +3,1181 @@
 use std::io;
 use std::fs;
 use std::mem;
+use std::thread;
 use std::str;
+use std::cell::{Cell, RefCell};
+use std::sync::Arc;
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, BinaryHeap};
 use std::io::{Read, Write, BufReader, BufWriter, Seek, SeekFrom};
 use std::path::{Path, PathBuf};
-use std::collections::HashMap;
+use std::borrow::Borrow;
+use std::marker::PhantomData;
+use std::ops::{Bound, RangeBounds};
 
 use byteorder::*;
 
+use crate::gorilla::GorillaBlock;
+use crate::storage::error::Error;
+
 // There is a separate metadata file that keeps track of information of
 // all SSTable files including the key range and 
 // An SSTable file contains compressed data
 
 // An SSTable has the following sections:
-// 1) data: (key, val) pairs
-// 2) index: (key, location_to_data: u32) pairs
-// 3) footer: (num_entries: u32, location_to_index: u32)
+// 0) header: (magic: 8 bytes, version: u32)
+// 1) data: (keylen: u32, key, vallen: u32, val) entries, a deleted key is
+//    stored as a tombstone: vallen set to TOMBSTONE_LEN and no value bytes
+// 2) index: (shared_len: u8, suffixlen: u32, suffix, location_to_data: u32)
+//    per key, the key being the first shared_len bytes of the key before it
+//    followed by the suffix, older files without PREFIX_INDEX_FLAG in their
+//    version store (keylen: u32, key, location_to_data: u32) instead
+// 3) stats: (num_entries: u32, total_value_bytes: u64), absent in older files,
+//    followed by (seqno: u64, level: u32) in files with TREE_POSITION_FLAG in
+//    their version, where the LSMTree that wrote the file placed it
+// 4) bloom: (num_hashes: u32, num_bytes: u32, bits) over every key, absent
+//    in older files or when disabled on the builder
+// 5) checksums: (crc: u32 per chunk, chunk_size: u32, num_chunks: u32,
+//    tail_crc: u32) in files with CHUNK_CRC_FLAG in their version, the
+//    CRC32 of every chunk_size bytes of the data section as stored (the last
+//    chunk may be shorter), then the CRC32 of everything from the index up
+//    to tail_crc
+// 6) footer: (num_entries: u32, location_to_index: u32, crc: u32), the CRC32
+//    covers everything between the header and the footer
+//
+// Opening a file with the checksums only checks the sections after the data
+// section, each chunk is checked as it is read. Older files are checked in
+// full when opened.
+//
+// A block compressed SSTable (version 2) stores the data section as a run of
+// blocks, each the compressed bytes of whole entries, and the index holds
+// where entries start in the uncompressed data section as if it came right
+// after the header. The index is followed by the block index:
+// (compressor_id: u8, num_blocks: u32) and per block (keylen: u32,
+// first_key, location_to_block: u32, compressed_len: u32,
+// location_to_data: u32), where location_to_data is where the block's first
+// entry starts in the uncompressed data section
+//
+// While committing, a sidecar "<file>.prepare" is written first with the
+// expected file size and the CRC32 of the index and stats sections, and removed
+// once the commit is durable. An SSTable with a sidecar present is considered
+// incomplete.
+// The sidecar has the format: (file_size: u64, location_to_index: u32, index_crc: u32)
 // TODO: storing keys twice in both data and index seems redundant
 // it's currently implemented to speed up iteration, but maybe compressed
 // timeseries data can be optimized so we have both iteration speed and
 // no key duplication
 
+// -------------------- ValueCodec --------------------
+
+// converts the values stored in an SSTable to and from bytes
+// values are passed in borrowed form (e.g. str) and read back in owned form (e.g. String)
+pub trait ValueCodec {
+    type Value: ?Sized + ToOwned;
+
+    fn encode(value: &Self::Value) -> Vec<u8>;
+    fn decode(bytes: &[u8]) -> Result<Decoded<Self>, Error>;
+}
+
+// the owned type an SSTable hands back for a codec
+pub type Decoded<C> = <<C as ValueCodec>::Value as ToOwned>::Owned;
+
+// UTF-8 string values, the default
+pub struct StringCodec;
+
+impl ValueCodec for StringCodec {
+    type Value = str;
+
+    fn encode(value: &str) -> Vec<u8> {
+        value.as_bytes().to_vec()
+    }
+
+    fn decode(bytes: &[u8]) -> Result<String, Error> {
+        String::from_utf8(bytes.to_vec()).map_err(|_| Error::BadUtf8)
+    }
+}
+
+// raw byte values, such as blocks serialized by GorillaBlock::to_bytes or
+// written as strings by GorillaBlock::to_string
+pub struct BytesCodec;
+
+impl ValueCodec for BytesCodec {
+    type Value = [u8];
+
+    fn encode(value: &[u8]) -> Vec<u8> {
+        value.to_vec()
+    }
+
+    fn decode(bytes: &[u8]) -> Result<Vec<u8>, Error> {
+        Ok(bytes.to_vec())
+    }
+}
+
+// GorillaBlock values, serialized with bincode
+pub struct GorillaBlockCodec;
+
+impl ValueCodec for GorillaBlockCodec {
+    type Value = GorillaBlock;
+
+    fn encode(value: &GorillaBlock) -> Vec<u8> {
+        bincode::serialize(value).unwrap()
+    }
+
+    fn decode(bytes: &[u8]) -> Result<GorillaBlock, Error> {
+        bincode::deserialize(bytes)
+            .map_err(|e| Error::Corrupt(format!("bad GorillaBlock value: {}", e)))
+    }
+}
+
+// -------------------- Compressor --------------------
+
+// compresses the data blocks of a block compressed SSTable, the id is stored
+// in the file so it can be read back with the same compressor
+pub trait Compressor: Send + Sync {
+    fn id(&self) -> u8;
+    fn compress(&self, data: &[u8]) -> Result<Vec<u8>, Error>;
+    fn decompress(&self, data: &[u8]) -> Result<Vec<u8>, Error>;
+}
+
+// blocks stored as they are
+pub struct NoCompressor;
+
+impl Compressor for NoCompressor {
+    fn id(&self) -> u8 {
+        0
+    }
+
+    fn compress(&self, data: &[u8]) -> Result<Vec<u8>, Error> {
+        Ok(data.to_vec())
+    }
+
+    fn decompress(&self, data: &[u8]) -> Result<Vec<u8>, Error> {
+        Ok(data.to_vec())
+    }
+}
+
+// snappy, an LZ77 style compressor, the default
+pub struct SnappyCompressor;
+
+impl Compressor for SnappyCompressor {
+    fn id(&self) -> u8 {
+        1
+    }
+
+    fn compress(&self, data: &[u8]) -> Result<Vec<u8>, Error> {
+        snap::raw::Encoder::new().compress_vec(data)
+            .map_err(|e| Error::InvalidArgument(e.to_string()))
+    }
+
+    fn decompress(&self, data: &[u8]) -> Result<Vec<u8>, Error> {
+        snap::raw::Decoder::new().decompress_vec(data)
+            .map_err(|e| Error::Corrupt(format!("bad compressed block: {}", e)))
+    }
+}
+
+// the compressor a file was written with, a new compressor needs an entry
+// here to be readable
+fn compressor_for(id: u8) -> Option<Box<dyn Compressor>> {
+    match id {
+        0 => Some(Box::new(NoCompressor)),
+        1 => Some(Box::new(SnappyCompressor)),
+        _ => None,
+    }
+}
+
+// the uncompressed size a data block is filled up to before it is compressed
+pub const DEFAULT_BLOCK_SIZE: usize = 64 * 1024;
+
+// -------------------- SSTableValue --------------------
+
+// what an SSTable holds for a key, a tombstone records that the key was
+// deleted and shadows the values older SSTables hold for it
+#[derive(Clone, Debug, PartialEq)]
+pub enum SSTableValue<T> {
+    Value(T),
+    Tombstone,
+}
+
+impl<T> SSTableValue<T> {
+    // the value, None for a tombstone
+    pub fn value(self) -> Option<T> {
+        match self {
+            SSTableValue::Value(val) => Some(val),
+            SSTableValue::Tombstone => None,
+        }
+    }
+}
+
+// a key and what the SSTable holds for it
+pub type SSTableEntry<C> = (String, SSTableValue<Decoded<C>>);
+
+// the value length marking a tombstone, no value is ever this long
+const TOMBSTONE_LEN: u32 = u32::MAX;
+
+// the longest key an SSTable takes, a longer one is most likely a bug of the
+// caller and would bloat the index
+pub const MAX_KEY_LEN: usize = 64 * 1024;
+
+const SSTABLE_MAGIC: &[u8; 8] = b"RUSTYSST";
+const SSTABLE_VERSION: u32 = 1;
+// block compressed files
+const SSTABLE_VERSION_BLOCKS: u32 = 2;
+// set in the version of files whose index keys share prefixes
+const PREFIX_INDEX_FLAG: u32 = 1 << 8;
+// set in the version of files whose stats record their seqno and level
+const TREE_POSITION_FLAG: u32 = 1 << 9;
+// set in the version of files with the checksums section
+const CHUNK_CRC_FLAG: u32 = 1 << 10;
+// the data section is checksummed in chunks of this many bytes
+const CHUNK_SIZE: usize = 4 * 1024;
+// the (chunk_size: u32, num_chunks: u32, tail_crc: u32) ending the checksums
+const CHECKSUMS_TRAILER_SIZE: usize = 3 * mem::size_of::<u32>();
+// the longest prefix an index key shares with the key before it
+const MAX_SHARED_PREFIX: usize = u8::MAX as usize;
+const HEADER_SIZE: usize = SSTABLE_MAGIC.len() + mem::size_of::<u32>();
+const FOOTER_SIZE: usize = 3 * mem::size_of::<u32>();
+
+// -------------------- SSTableStats --------------------
+
+// access counters of a single SSTable file
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct SSTableStats {
+    // readers opened on the file, 1 for the counters of a single reader
+    pub num_opens: u64,
+    pub num_get_calls: u64,
+    pub num_get_hits: u64,
+    pub num_iter_calls: u64,
+    pub bytes_read: u64,
+}
+
+impl SSTableStats {
+    pub fn add(&mut self, other: &SSTableStats) {
+        self.num_opens += other.num_opens;
+        self.num_get_calls += other.num_get_calls;
+        self.num_get_hits += other.num_get_hits;
+        self.num_iter_calls += other.num_iter_calls;
+        self.bytes_read += other.bytes_read;
+    }
+}
+
+// -------------------- StatsFooter --------------------
+
+// the statistics section written after the index
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct StatsFooter {
+    pub num_entries: u32,
+    pub total_value_bytes: u64,
+    // the (seqno, level) the file was written with, None for files written
+    // before they were recorded
+    pub tree_position: Option<(u64, usize)>,
+}
+
+const STATS_FOOTER_SIZE: usize = mem::size_of::<u32>() + mem::size_of::<u64>();
+// the seqno and level after the stats of files with TREE_POSITION_FLAG
+const TREE_POSITION_SIZE: usize = mem::size_of::<u64>() + mem::size_of::<u32>();
+
+// -------------------- BloomFilter --------------------
+
+// the bloom filter section, a key it rules out is not in the SSTable
+#[derive(Clone, Debug, PartialEq)]
+pub struct BloomFilter {
+    num_hashes: u32,
+    bits: Vec<u8>,
+}
+
+// ~1% false positives
+pub const DEFAULT_BLOOM_BITS_PER_KEY: usize = 10;
+
+impl BloomFilter {
+    pub fn new(num_keys: usize, bits_per_key: usize) -> BloomFilter {
+        // ln(2) * bits_per_key hashes minimize the false positive rate
+        let num_hashes = (bits_per_key * 69 / 100).clamp(1, 30) as u32;
+        let num_bytes = (num_keys * bits_per_key).div_ceil(8).max(8);
+        BloomFilter { num_hashes, bits: vec![0; num_bytes] }
+    }
+
+    pub fn insert(&mut self, key: &str) {
+        for bit in self.bit_positions(key) {
+            self.bits[bit / 8] |= 1 << (bit % 8);
+        }
+    }
+
+    // false means the key was never inserted, true that it probably was
+    pub fn may_contain(&self, key: &str) -> bool {
+        self.bit_positions(key).all(|bit| self.bits[bit / 8] & (1 << (bit % 8)) != 0)
+    }
+
+    // double hashing on the two halves of a 64-bit FNV-1a hash
+    fn bit_positions(&self, key: &str) -> impl Iterator<Item = usize> {
+        let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+        for b in key.as_bytes() {
+            hash ^= *b as u64;
+            hash = hash.wrapping_mul(0x0000_0100_0000_01b3);
+        }
+        // spread the low bits of short keys
+        hash ^= hash >> 33;
+        hash = hash.wrapping_mul(0xff51_afd7_ed55_8ccd);
+        hash ^= hash >> 33;
+
+        let num_bits = self.bits.len() as u64 * 8;
+        let (h1, h2) = (hash & 0xffff_ffff, (hash >> 32) | 1);
+        (0..self.num_hashes as u64).map(move |i| (h1.wrapping_add(i * h2) % num_bits) as usize)
+    }
+
+    fn encoded_size(&self) -> usize {
+        2 * mem::size_of::<u32>() + self.bits.len()
+    }
+
+    fn encode(&self, buf: &mut Vec<u8>) -> Result<(), Error> {
+        buf.write_u32::<LittleEndian>(self.num_hashes)?;
+        buf.write_u32::<LittleEndian>(self.bits.len() as u32)?;
+        buf.write_all(&self.bits)?;
+        Ok(())
+    }
+
+    fn decode<R: Read>(reader: &mut R) -> Result<BloomFilter, Error> {
+        let num_hashes = reader.read_u32::<LittleEndian>()?;
+        let num_bytes = reader.read_u32::<LittleEndian>()? as usize;
+        let mut bits = vec![0; num_bytes];
+        reader.read_exact(&mut bits)?;
+        if num_bytes == 0 {
+            return Err(Error::Corrupt(String::from("empty SSTable bloom filter")));
+        }
+        Ok(BloomFilter { num_hashes, bits })
+    }
+}
+
 // -------------------- SSTableFileReader --------------------
 
-pub struct SSTableFileReader {
+pub struct SSTableFileReader<C = StringCodec> {
     // the path to the sstable file
     path: PathBuf,
     num_entries: u32,
-    index: HashMap<String, u32>,
+    // (key, location_to_data) sorted by key
+    index: Vec<(String, u32)>,
+    // where the index section (and the block index) ends and the stats
+    // section (if any) starts
+    index_end: u64,
+    // where the sections after the index end, at the checksums or the footer
+    sections_end: u64,
+    // the stats record the seqno and level
+    tree_position: bool,
+    bloom: Option<BloomFilter>,
+    // the checksums of the data section, None for files written without them
+    chunks: Option<Arc<DataChunks>>,
+    // the data blocks of a block compressed file, sorted by location
+    blocks: Arc<[DataBlock]>,
+    compressor: Option<Arc<dyn Compressor>>,
+    // updated through &self by get and iter
+    stats: Cell<SSTableStats>,
+    // the handle 'get' seeks in, kept open from 'open' on
+    lookup: RefCell<DataReader>,
+    codec: PhantomData<C>,
 }
 
-// iterating over an existing SSTable file
-pub struct SSTableFileIter<'a> {
-    reader: BufReader<fs::File>,
-    sstable: &'a SSTableFileReader,
-    curr_entry: u32
+// an entry of the block index
+#[derive(Clone, Debug, PartialEq)]
+struct DataBlock {
+    first_key: String,
+    // where the compressed block is in the file
+    offset: u32,
+    compressed_len: u32,
+    // where its first entry starts in the uncompressed data section
+    data_offset: u32,
 }
 
-impl<'a> SSTableFileIter<'a> {
-    fn read_entry(&mut self) -> Result<(String, String), io::Error> {
-        let keylen = self.reader.read_u32::<LittleEndian>()?;
-        let mut keybuf = vec![0 as u8; keylen as usize];
-        self.reader.read_exact(&mut keybuf)?;
-        let keystr = String::from_utf8(keybuf).unwrap();
+// the CRC32s of the chunks of the data section
+struct DataChunks {
+    size: usize,
+    // where the data section ends, the last chunk may end there short of
+    // 'size' bytes
+    end: u64,
+    crcs: Vec<u32>,
+}
 
-        // load the value from data section
-        let vallen = self.reader.read_u32::<LittleEndian>()?;
-        let mut valbuf = vec![0 as u8; vallen as usize];
-        self.reader.read_exact(&mut valbuf)?;
-        let valstr = String::from_utf8(valbuf).unwrap();
-        Ok((keystr, valstr))
+// reads a file with the checksums, its data section a chunk at a time, each
+// checked before any of it is handed out
+struct ChunkReader {
+    file: fs::File,
+    chunks: Arc<DataChunks>,
+    // the checked chunk last read
+    current: Option<usize>,
+    buf: Vec<u8>,
+    pos: u64,
+}
+
+impl ChunkReader {
+    fn load(&mut self, chunk: usize) -> Result<(), Error> {
+        let start = (HEADER_SIZE + chunk * self.chunks.size) as u64;
+        let len = (self.chunks.end - start).min(self.chunks.size as u64);
+        self.current = None;
+        self.buf.resize(len as usize, 0);
+        self.file.seek(SeekFrom::Start(start))?;
+        self.file.read_exact(&mut self.buf)?;
+        if crc32fast::hash(&self.buf) != self.chunks.crcs[chunk] {
+            return Err(Error::Corrupt(format!("SSTable data chunk {} checksum mismatch", chunk)));
+        }
+        self.current = Some(chunk);
+        Ok(())
     }
 }
 
-impl<'a> Iterator for SSTableFileIter<'a> {
-    type Item = (String, String);
-    
-    fn next(&mut self) -> Option<Self::Item> {
+impl Read for ChunkReader {
+    fn read(&mut self, out: &mut [u8]) -> Result<usize, io::Error> {
+        // only the data section is read through it
+        if self.pos < HEADER_SIZE as u64 || self.pos >= self.chunks.end {
+            return Ok(0);
+        }
+        let offset = (self.pos - HEADER_SIZE as u64) as usize;
+        let chunk = offset / self.chunks.size;
+        if self.current != Some(chunk) {
+            // kept as the storage error it is through the io::Error
+            self.load(chunk).map_err(io::Error::from)?;
+        }
+
+        let n = (&self.buf[offset - chunk * self.chunks.size..]).read(out)?;
+        self.pos += n as u64;
+        Ok(n)
+    }
+}
+
+impl Seek for ChunkReader {
+    fn seek(&mut self, pos: SeekFrom) -> Result<u64, io::Error> {
+        self.pos = match pos {
+            SeekFrom::Start(pos) => pos,
+            SeekFrom::Current(delta) => self.pos.checked_add_signed(delta)
+                .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "seek before the start of the file"))?,
+            SeekFrom::End(_) => {
+                return Err(io::Error::new(io::ErrorKind::Unsupported, "seek from the end of a data section"));
+            },
+        };
+        Ok(self.pos)
+    }
+}
+
+// the bytes of an SSTable file as stored, checked as they are read when the
+// file has the checksums
+enum FileReader {
+    Plain(BufReader<fs::File>),
+    Chunks(ChunkReader),
+}
+
+impl Read for FileReader {
+    fn read(&mut self, out: &mut [u8]) -> Result<usize, io::Error> {
+        match self {
+            FileReader::Plain(reader) => reader.read(out),
+            FileReader::Chunks(reader) => reader.read(out),
+        }
+    }
+}
+
+impl Seek for FileReader {
+    fn seek(&mut self, pos: SeekFrom) -> Result<u64, io::Error> {
+        match self {
+            FileReader::Plain(reader) => reader.seek(pos),
+            FileReader::Chunks(reader) => reader.seek(pos),
+        }
+    }
+}
+
+// reads the data section of a block compressed file as if it was stored
+// uncompressed, only the block being read is decompressed
+struct BlockReader {
+    file: FileReader,
+    blocks: Arc<[DataBlock]>,
+    compressor: Arc<dyn Compressor>,
+    // the decompressed block last read
+    current: Option<usize>,
+    buf: Vec<u8>,
+    // the location in the uncompressed data section
+    pos: u64,
+}
+
+impl BlockReader {
+    fn load(&mut self, block: usize) -> Result<(), Error> {
+        let mut compressed = vec![0u8; self.blocks[block].compressed_len as usize];
+        self.file.seek(SeekFrom::Start(self.blocks[block].offset as u64))?;
+        self.file.read_exact(&mut compressed)?;
+        // kept as the storage error it is through the io::Error
+        self.buf = self.compressor.decompress(&compressed).map_err(io::Error::from)?;
+        self.current = Some(block);
+        Ok(())
+    }
+}
+
+impl Read for BlockReader {
+    fn read(&mut self, out: &mut [u8]) -> Result<usize, io::Error> {
+        // the last block starting at or before the position
+        let block = match self.blocks.partition_point(|b| b.data_offset as u64 <= self.pos) {
+            0 => return Ok(0),
+            n => n - 1,
+        };
+        if self.current != Some(block) {
+            self.load(block)?;
+        }
+
+        // past the end of the last block
+        let start = (self.pos - self.blocks[block].data_offset as u64) as usize;
+        if start >= self.buf.len() {
+            return Ok(0);
+        }
+        let n = (&self.buf[start..]).read(out)?;
+        self.pos += n as u64;
+        Ok(n)
+    }
+}
+
+impl Seek for BlockReader {
+    fn seek(&mut self, pos: SeekFrom) -> Result<u64, io::Error> {
+        self.pos = match pos {
+            SeekFrom::Start(pos) => pos,
+            SeekFrom::Current(delta) => self.pos.checked_add_signed(delta)
+                .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "seek before the data section"))?,
+            SeekFrom::End(_) => {
+                return Err(io::Error::new(io::ErrorKind::Unsupported, "seek from the end of a data section"));
+            },
+        };
+        Ok(self.pos)
+    }
+}
+
+// the data section of an SSTable file, read at locations from the index
+enum DataReader {
+    Plain(FileReader),
+    Blocks(BlockReader),
+}
+
+impl Read for DataReader {
+    fn read(&mut self, out: &mut [u8]) -> Result<usize, io::Error> {
+        match self {
+            DataReader::Plain(reader) => reader.read(out),
+            DataReader::Blocks(reader) => reader.read(out),
+        }
+    }
+}
+
+impl Seek for DataReader {
+    fn seek(&mut self, pos: SeekFrom) -> Result<u64, io::Error> {
+        match self {
+            DataReader::Plain(reader) => reader.seek(pos),
+            DataReader::Blocks(reader) => reader.seek(pos),
+        }
+    }
+}
+
+// a reader over the data section of 'file', block compressed when the file
+// has a compressor, checked when it has the checksums
+fn data_reader(file: fs::File, blocks: &Arc<[DataBlock]>, compressor: &Option<Arc<dyn Compressor>>,
+               chunks: &Option<Arc<DataChunks>>)
+    -> DataReader
+{
+    let file = match chunks {
+        Some(chunks) => FileReader::Chunks(ChunkReader {
+            file,
+            chunks: chunks.clone(),
+            current: None,
+            buf: Vec::new(),
+            pos: 0,
+        }),
+        None => FileReader::Plain(BufReader::new(file)),
+    };
+    match compressor {
+        Some(compressor) => DataReader::Blocks(BlockReader {
+            file,
+            blocks: blocks.clone(),
+            compressor: compressor.clone(),
+            current: None,
+            buf: Vec::new(),
+            pos: 0,
+        }),
+        None => DataReader::Plain(file),
+    }
+}
+
+// iterating over an existing SSTable file, or the part of it up to 'end'
+pub struct SSTableFileIter<'a, C: ValueCodec = StringCodec> {
+    reader: DataReader,
+    sstable: &'a SSTableFileReader<C>,
+    remaining: u32,
+    end: Option<String>,
+}
+
+// read the (key, val) pair at the current position of the data section
+fn read_data_entry<C: ValueCodec, R: Read>(reader: &mut R, sstable: &SSTableFileReader<C>)
+    -> Result<SSTableEntry<C>, Error>
+{
+    let keylen = reader.read_u32::<LittleEndian>()?;
+    let mut keybuf = vec![0; keylen as usize];
+    reader.read_exact(&mut keybuf)?;
+    let keystr = String::from_utf8(keybuf).map_err(|_| Error::BadUtf8)?;
+
+    // load the value from data section
+    let vallen = reader.read_u32::<LittleEndian>()?;
+    if vallen == TOMBSTONE_LEN {
+        let entry_size = 2 * mem::size_of::<u32>() + keylen as usize;
+        sstable.update_stats(|stats| stats.bytes_read += entry_size as u64);
+        return Ok((keystr, SSTableValue::Tombstone));
+    }
+    let mut valbuf = vec![0; vallen as usize];
+    reader.read_exact(&mut valbuf)?;
+
+    let entry_size = 2 * mem::size_of::<u32>() + keylen as usize + vallen as usize;
+    sstable.update_stats(|stats| stats.bytes_read += entry_size as u64);
+    Ok((keystr, SSTableValue::Value(C::decode(&valbuf)?)))
+}
+
+impl<'a, C: ValueCodec> SSTableFileIter<'a, C> {
+    fn read_entry(&mut self) -> Result<SSTableEntry<C>, Error> {
+        read_data_entry(&mut self.reader, self.sstable)
+    }
+
+    // the next entry, None past the last one, an entry that can't be read
+    // ends the iteration after its error
+    fn try_next(&mut self) -> Result<Option<SSTableEntry<C>>, Error> {
         // no more items
-        if self.curr_entry >= self.sstable.num_entries {
-            return None;
+        if self.remaining == 0 {
+            return Ok(None);
         }
 
         match self.read_entry() {
+            Ok((key, _)) if self.end.as_ref().is_some_and(|end| key > *end) => {
+                self.remaining = 0;
+                Ok(None)
+            },
             Ok((key, val)) => {
-                self.curr_entry += 1;
-                Some((key, val))
+                self.remaining -= 1;
+                Ok(Some((key, val)))
+            },
+            Err(e) => {
+                self.remaining = 0;
+                Err(e)
+            },
+        }
+    }
+}
+
+impl<'a, C: ValueCodec> Iterator for SSTableFileIter<'a, C> {
+    type Item = SSTableEntry<C>;
+    
+    fn next(&mut self) -> Option<Self::Item> {
+        self.try_next().ok().flatten()
+    }
+}
+
+// iterating over every entry of an SSTable file along with its reader,
+// returned by 'SSTableFileReader::into_entries'
+pub struct SSTableFileIntoIter<C: ValueCodec = StringCodec> {
+    reader: DataReader,
+    sstable: SSTableFileReader<C>,
+    remaining: u32,
+}
+
+impl<C: ValueCodec> SSTableFileIntoIter<C> {
+    // the access counters of the reader
+    pub fn stats(&self) -> SSTableStats {
+        self.sstable.stats()
+    }
+}
+
+impl<C: ValueCodec> Iterator for SSTableFileIntoIter<C> {
+    type Item = SSTableEntry<C>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.remaining == 0 {
+            return None;
+        }
+
+        match read_data_entry(&mut self.reader, &self.sstable) {
+            Ok(entry) => {
+                self.remaining -= 1;
+                Some(entry)
             },
-            Err(e) => None,
+            Err(_) => {
+                self.remaining = 0;
+                None
+            },
+        }
+    }
+}
+
+// iterating over an existing SSTable file in descending key order, the data
+// section is written in ascending order so every entry needs a seek
+pub struct SSTableReverseIter<'a, C: ValueCodec = StringCodec> {
+    reader: DataReader,
+    sstable: &'a SSTableFileReader<C>,
+    // data locations sorted by ascending key, consumed from the back
+    locations: Vec<u32>,
+}
+
+impl<'a, C: ValueCodec> SSTableReverseIter<'a, C> {
+    fn read_entry(&mut self, loc: u32) -> Result<SSTableEntry<C>, Error> {
+        self.reader.seek(SeekFrom::Start(loc as u64))?;
+        read_data_entry(&mut self.reader, self.sstable)
+    }
+}
+
+impl<'a, C: ValueCodec> Iterator for SSTableReverseIter<'a, C> {
+    type Item = SSTableEntry<C>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let loc = self.locations.pop()?;
+        self.read_entry(loc).ok()
+    }
+}
+
+// an 'Error::InvalidArgument' unless an SSTable can hold an entry of 'key'
+// and a value of 'vallen' bytes: the key is at most 'MAX_KEY_LEN' bytes and
+// the length of the value fits its u32 length prefix
+pub fn check_entry_len(key: &str, vallen: usize) -> Result<(), Error> {
+    if key.len() > MAX_KEY_LEN {
+        // the whole characters within the first 32 bytes
+        let start = key.char_indices()
+            .map(|(i, c)| i + c.len_utf8())
+            .take_while(|end| *end <= 32)
+            .last()
+            .unwrap_or(0);
+        return Err(Error::InvalidArgument(format!("key of {} bytes starting with {:?} exceeds {} bytes",
+                                                  key.len(), &key[..start], MAX_KEY_LEN)));
+    }
+    if vallen >= TOMBSTONE_LEN as usize {
+        return Err(Error::InvalidArgument(format!("value of {} bytes for key {:?} doesn't fit in an SSTable",
+                                                  vallen, key)));
+    }
+    Ok(())
+}
+
+// the size of the stats section of a file with the stats
+fn stats_size(tree_position: bool) -> usize {
+    if tree_position {
+        STATS_FOOTER_SIZE + TREE_POSITION_SIZE
+    } else {
+        STATS_FOOTER_SIZE
+    }
+}
+
+// the sidecar file written while an SSTable is being committed
+fn prepare_path(path: &Path) -> PathBuf {
+    let mut fname = path.as_os_str().to_os_string();
+    fname.push(".prepare");
+    PathBuf::from(fname)
+}
+
+fn invalid_sstable(path: &Path, reason: &str) -> Error {
+    Error::Corrupt(format!("{}: {}", path.display(), reason))
+}
+
+// the number of leading bytes 'key' shares with 'prev', up to
+// MAX_SHARED_PREFIX
+fn shared_prefix_len(prev: &[u8], key: &[u8]) -> usize {
+    prev.iter().zip(key).take(MAX_SHARED_PREFIX).take_while(|(a, b)| a == b).count()
+}
+
+// the CRC32 of the next 'len' bytes of 'reader'
+fn checksum<R: Read>(reader: &mut R, len: u64) -> Result<u32, Error> {
+    let mut hasher = crc32fast::Hasher::new();
+    let mut buf = [0u8; 8192];
+    let mut reader = reader.take(len);
+    loop {
+        let n = reader.read(&mut buf)?;
+        if n == 0 {
+            break;
         }
+        hasher.update(&buf[..n]);
+    }
+    if reader.limit() > 0 {
+        return Err(Error::Corrupt(String::from("SSTable file is truncated")));
+    }
+    Ok(hasher.finalize())
+}
+
+// the checksums of a file whose sections after the data section are in
+// [index_loc, footer_loc), along with where they start, once the tail_crc
+// shows those sections are intact
+fn read_checksums<R: Read + Seek>(reader: &mut R, index_loc: u64, footer_loc: u64)
+    -> Result<(DataChunks, u64), &'static str>
+{
+    let mut tail = vec![0u8; (footer_loc - index_loc) as usize];
+    reader.seek(SeekFrom::Start(index_loc)).map_err(|_| "file is truncated")?;
+    reader.read_exact(&mut tail).map_err(|_| "file is truncated")?;
+    if tail.len() < CHECKSUMS_TRAILER_SIZE {
+        return Err("checksums are missing");
+    }
+    let (covered, mut tail_crc) = tail.split_at(tail.len() - mem::size_of::<u32>());
+    if crc32fast::hash(covered) != tail_crc.read_u32::<LittleEndian>().unwrap() {
+        return Err("checksum mismatch");
+    }
+
+    let mut trailer = &covered[covered.len() - 2 * mem::size_of::<u32>()..];
+    let size = trailer.read_u32::<LittleEndian>().unwrap() as usize;
+    let num_chunks = trailer.read_u32::<LittleEndian>().unwrap() as usize;
+    let data_len = index_loc as usize - HEADER_SIZE;
+    if size == 0 || num_chunks != data_len.div_ceil(size) {
+        return Err("checksums don't cover the data section");
+    }
+    let crcs_len = num_chunks * mem::size_of::<u32>();
+    if crcs_len + CHECKSUMS_TRAILER_SIZE > tail.len() {
+        return Err("checksums are missing");
     }
+    let mut crcbuf = &covered[covered.len() - 2 * mem::size_of::<u32>() - crcs_len..];
+    let crcs = (0..num_chunks).map(|_| crcbuf.read_u32::<LittleEndian>().unwrap()).collect();
+    let checksums_loc = footer_loc - (crcs_len + CHECKSUMS_TRAILER_SIZE) as u64;
+    Ok((DataChunks { size, end: index_loc, crcs }, checksums_loc))
 }
 
 impl SSTableFileReader {
-    pub fn open(path: &Path) -> Result<SSTableFileReader, io::Error> {
+    // open an SSTable holding string values
+    pub fn open(path: &Path) -> Result<SSTableFileReader, Error> {
+        Self::open_with_codec(path)
+    }
+
+    // check an SSTable file left behind by an interrupted commit
+    // if the sidecar shows the file reached its full size with an intact index,
+    // the commit only missed the sidecar removal: drop any trailing bytes and keep it
+    // otherwise the file is incomplete and both files are deleted
+    // returns whether the SSTable file is usable afterwards
+    pub fn repair(path: &Path) -> Result<bool, Error> {
+        let preparepath = prepare_path(path);
+        if !preparepath.exists() {
+            return Ok(path.exists());
+        }
+
+        let mut preparefile = fs::File::open(&preparepath)?;
+        let file_size = preparefile.read_u64::<LittleEndian>()?;
+        let index_loc = preparefile.read_u32::<LittleEndian>()? as u64;
+        let index_crc = preparefile.read_u32::<LittleEndian>()?;
+
+        let complete = match fs::metadata(path) {
+            Ok(meta) if meta.len() >= file_size => {
+                let mut indexbuf = vec![0u8; (file_size - FOOTER_SIZE as u64 - index_loc) as usize];
+                let mut sstfile = fs::File::open(path)?;
+                sstfile.seek(SeekFrom::Start(index_loc))?;
+                sstfile.read_exact(&mut indexbuf)?;
+                crc32fast::hash(&indexbuf) == index_crc
+            },
+            _ => false,
+        };
+
+        if complete {
+            fs::OpenOptions::new().write(true).open(path)?.set_len(file_size)?;
+        } else if path.exists() {
+            fs::remove_file(path)?;
+        }
+        fs::remove_file(&preparepath)?;
+        Ok(complete)
+    }
+}
+
+impl<C: ValueCodec> SSTableFileReader<C> {
+    pub fn open_with_codec(path: &Path) -> Result<SSTableFileReader<C>, Error> {
+        // refuse to read a file whose commit never finished
+        if prepare_path(path).exists() {
+            return Err(invalid_sstable(path, "file is incomplete, commit was interrupted"));
+        }
+
         // load the index
         let sstfile = fs::File::open(path)?;
+        let file_size = sstfile.metadata()?.len();
         let mut sst_reader = BufReader::new(sstfile);
+        if file_size < (HEADER_SIZE + FOOTER_SIZE) as u64 {
+            return Err(invalid_sstable(path, "file is truncated"));
+        }
+
+        let mut magic = [0u8; SSTABLE_MAGIC.len()];
+        sst_reader.read_exact(&mut magic)?;
+        if &magic != SSTABLE_MAGIC {
+            return Err(invalid_sstable(path, "not an SSTable file"));
+        }
+        let version = sst_reader.read_u32::<LittleEndian>()?;
+        let prefix_index = version & PREFIX_INDEX_FLAG != 0;
+        let tree_position = version & TREE_POSITION_FLAG != 0;
+        let chunked = version & CHUNK_CRC_FLAG != 0;
+        let version = version & !(PREFIX_INDEX_FLAG | TREE_POSITION_FLAG | CHUNK_CRC_FLAG);
+        if version != SSTABLE_VERSION && version != SSTABLE_VERSION_BLOCKS {
+            return Err(Error::UnsupportedVersion { format: "SSTable", version });
+        }
 
         // read the footer to locate the index section
-        let footer_offset = -2 * mem::size_of::<u32>() as i64;
-        sst_reader.seek(SeekFrom::End(footer_offset))?;
+        sst_reader.seek(SeekFrom::End(-(FOOTER_SIZE as i64)))?;
 
         let num_entries = sst_reader.read_u32::<LittleEndian>()?;
         let index_loc = sst_reader.read_u32::<LittleEndian>()?;
+        let crc = sst_reader.read_u32::<LittleEndian>()?;
+        let footer_loc = file_size - FOOTER_SIZE as u64;
+        if (index_loc as u64) < HEADER_SIZE as u64 || index_loc as u64 > footer_loc {
+            return Err(Error::InvalidFooter);
+        }
 
-        // load the index section
-        // note that we assume keys are distinct, but they don't necessary have to
-        // we might as well just read the index section sequentially and do a binary
-        // search when using "Get", then read the data section sequentially as well
-        let mut sst_index = HashMap::new();
+        // a file cut short by a crash fails here, only the sections after
+        // the data section are checked when the data has checksums of its own
+        let (chunks, checksums_loc) = if chunked {
+            let (chunks, checksums_loc) = read_checksums(&mut sst_reader, index_loc as u64, footer_loc)
+                .map_err(|reason| invalid_sstable(path, reason))?;
+            (Some(Arc::new(chunks)), checksums_loc)
+        } else {
+            sst_reader.seek(SeekFrom::Start(HEADER_SIZE as u64))?;
+            if checksum(&mut sst_reader, footer_loc - HEADER_SIZE as u64)? != crc {
+                return Err(invalid_sstable(path, "checksum mismatch"));
+            }
+            (None, footer_loc)
+        };
+
+        // load the index section, "get" does a binary search over it
+        let mut sst_index: Vec<(String, u32)> = Vec::with_capacity(num_entries as usize);
         sst_reader.seek(SeekFrom::Start(index_loc as u64))?;
         for _ in 0..num_entries {
+            let shared = if prefix_index { sst_reader.read_u8()? as usize } else { 0 };
+            let prev = match sst_index.last() {
+                Some((prev, _)) => prev.as_bytes(),
+                None => &[][..],
+            };
+            if shared > prev.len() {
+                return Err(invalid_sstable(path, "index key shares more than the key before it"));
+            }
             let keylen = sst_reader.read_u32::<LittleEndian>()? as usize;
-            let mut keybuf = vec![0 as u8; keylen];
-            sst_reader.read_exact(&mut keybuf)?;
-            let key = String::from_utf8(keybuf).unwrap();
+            let mut keybuf = Vec::with_capacity(shared + keylen);
+            keybuf.extend_from_slice(&prev[..shared]);
+            keybuf.resize(shared + keylen, 0);
+            sst_reader.read_exact(&mut keybuf[shared..])?;
+            let key = String::from_utf8(keybuf).map_err(|_| Error::BadUtf8)?;
 
             let offset = sst_reader.read_u32::<LittleEndian>()?;
-            sst_index.insert(key, offset);
+            sst_index.push((key, offset));
         }
 
+        let (blocks, compressor) = if version == SSTABLE_VERSION_BLOCKS {
+            let id = sst_reader.read_u8()?;
+            let compressor = compressor_for(id)
+                .ok_or_else(|| invalid_sstable(path, &format!("unknown compressor {}", id)))?;
+            let num_blocks = sst_reader.read_u32::<LittleEndian>()?;
+            let mut blocks = Vec::with_capacity(num_blocks as usize);
+            for _ in 0..num_blocks {
+                let keylen = sst_reader.read_u32::<LittleEndian>()? as usize;
+                let mut keybuf = vec![0u8; keylen];
+                sst_reader.read_exact(&mut keybuf)?;
+                blocks.push(DataBlock {
+                    first_key: String::from_utf8(keybuf).map_err(|_| Error::BadUtf8)?,
+                    offset: sst_reader.read_u32::<LittleEndian>()?,
+                    compressed_len: sst_reader.read_u32::<LittleEndian>()?,
+                    data_offset: sst_reader.read_u32::<LittleEndian>()?,
+                });
+            }
+            (blocks, Some(Arc::from(compressor)))
+        } else {
+            (Vec::new(), None)
+        };
+        let index_end = sst_reader.stream_position()?;
+
+        // keys are usually added in order already, the stable sort keeps a
+        // key added twice in insertion order so the later one wins
+        sst_index.sort_by(|a, b| a.0.cmp(&b.0));
+        sst_index.dedup_by(|later, earlier| {
+            if later.0 == earlier.0 {
+                mem::swap(later, earlier);
+                return true;
+            }
+            false
+        });
+
+        // the bloom filter follows the stats section
+        let stats_end = index_end + stats_size(tree_position) as u64;
+        let bloom = if checksums_loc > stats_end {
+            sst_reader.seek(SeekFrom::Start(stats_end))?;
+            Some(BloomFilter::decode(&mut sst_reader)?)
+        } else {
+            None
+        };
+
+        let blocks: Arc<[DataBlock]> = Arc::from(blocks);
+        let lookup = data_reader(sst_reader.into_inner(), &blocks, &compressor, &chunks);
         Ok(SSTableFileReader {
             path: path.to_path_buf(),
             num_entries: num_entries,
             index: sst_index,
+            index_end,
+            sections_end: checksums_loc,
+            tree_position,
+            bloom,
+            chunks,
+            blocks,
+            compressor,
+            stats: Cell::new(SSTableStats { num_opens: 1, ..SSTableStats::default() }),
+            lookup: RefCell::new(lookup),
+            codec: PhantomData,
         })
     }
     
-    pub fn iter<'a>(&'a self) -> SSTableFileIter {
-        let sstfile = fs::File::open(&self.path).unwrap();
-        
-        SSTableFileIter::<'a> {
-            reader: BufReader::new(sstfile),
-            sstable: self,
-            curr_entry: 0,
+    // a reader over the data section on a handle of its own
+    fn data_reader(&self) -> Result<DataReader, Error> {
+        let sstfile = fs::File::open(&self.path)?;
+        Ok(data_reader(sstfile, &self.blocks, &self.compressor, &self.chunks))
+    }
+
+    // check the CRC32 of everything between the header and the footer, which
+    // reads the whole file, opening a file only checks what it reads
+    pub fn verify(&self) -> Result<(), Error> {
+        let sstfile = fs::File::open(&self.path)?;
+        let file_size = sstfile.metadata()?.len();
+        let mut sst_reader = BufReader::new(sstfile);
+        sst_reader.seek(SeekFrom::End(-(mem::size_of::<u32>() as i64)))?;
+        let crc = sst_reader.read_u32::<LittleEndian>()?;
+        sst_reader.seek(SeekFrom::Start(HEADER_SIZE as u64))?;
+        if checksum(&mut sst_reader, file_size - (HEADER_SIZE + FOOTER_SIZE) as u64)? != crc {
+            return Err(invalid_sstable(&self.path, "checksum mismatch"));
         }
+        Ok(())
     }
 
-    // get an value based on a key string
-    // for current design we put index inside the latter half of the SSTable file
-    // consider change it to have a separate index load on LSMTree startup
-    pub fn get(&mut self, key: &str) -> Result<Option<String>, io::Error> {
-        // get the real offset from the index
-        let val_loc = match self.index.get(key) {
-            Some(loc) => *loc,
-            None => return Ok(None),
-        };
+    // the number of data blocks, 0 unless the file is block compressed
+    pub fn num_blocks(&self) -> usize {
+        self.blocks.len()
+    }
+
+    // every (key, val) pair in key order, tombstones are left out
+    pub fn iter<'a>(&'a self) -> impl Iterator<Item = (String, Decoded<C>)> + 'a {
+        self.iter_entries().filter_map(|(key, val)| val.value().map(|val| (key, val)))
+    }
+
+    // every entry in key order, tombstones included
+    pub fn iter_entries<'a>(&'a self) -> SSTableFileIter<'a, C> {
+        self.entries().unwrap()
+    }
+
+    // same as 'iter_entries', but an entry that can't be read comes back as
+    // its error rather than ending the iteration as if there were no more
+    pub fn try_iter_entries<'a>(&'a self)
+        -> Result<impl Iterator<Item = Result<SSTableEntry<C>, Error>> + 'a, Error>
+    {
+        let mut entries = self.entries()?;
+        Ok(std::iter::from_fn(move || entries.try_next().transpose()))
+    }
+
+    fn entries<'a>(&'a self) -> Result<SSTableFileIter<'a, C>, Error> {
+        let mut reader = self.data_reader()?;
+        self.update_stats(|stats| stats.num_iter_calls += 1);
+        reader.seek(SeekFrom::Start(HEADER_SIZE as u64))?;
+
+        Ok(SSTableFileIter::<'a, C> {
+            reader,
+            sstable: self,
+            remaining: self.num_entries,
+            end: None,
+        })
+    }
+
+    // same as 'iter_entries', but the iterator takes the reader along so it
+    // can be kept after the scope the file was opened in
+    pub fn into_entries(self) -> Result<SSTableFileIntoIter<C>, Error> {
+        let mut reader = self.data_reader()?;
+        self.update_stats(|stats| stats.num_iter_calls += 1);
+        reader.seek(SeekFrom::Start(HEADER_SIZE as u64))?;
+
+        Ok(SSTableFileIntoIter {
+            reader,
+            remaining: self.num_entries,
+            sstable: self,
+        })
+    }
+
+    // every entry with a key in [start, end] in key order, tombstones
+    // included, reading starts at the first key >= start and stops past 'end'
+    pub fn iter_range<'a>(&'a self, start: &str, end: &str) -> SSTableFileIter<'a, C> {
+        let mut reader = self.data_reader().unwrap();
+        self.update_stats(|stats| stats.num_iter_calls += 1);
+
+        let lo = self.index.partition_point(|(k, _)| k.as_str() < start);
+        let hi = self.index.partition_point(|(k, _)| k.as_str() <= end).max(lo);
+        if lo < hi {
+            reader.seek(SeekFrom::Start(self.index[lo].1 as u64)).unwrap();
+        }
+
+        SSTableFileIter::<'a, C> {
+            reader,
+            sstable: self,
+            remaining: (hi - lo) as u32,
+            end: Some(end.to_string()),
+        }
+    }
+
+    // every (key, val) pair in descending key order, tombstones are left out
+    pub fn iter_reverse<'a>(&'a self) -> impl Iterator<Item = (String, Decoded<C>)> + 'a {
+        self.iter_reverse_entries().filter_map(|(key, val)| val.value().map(|val| (key, val)))
+    }
+
+    fn iter_reverse_entries<'a>(&'a self) -> SSTableReverseIter<'a, C> {
+        let reader = self.data_reader().unwrap();
+        self.update_stats(|stats| stats.num_iter_calls += 1);
+
+        SSTableReverseIter::<'a, C> {
+            reader,
+            sstable: self,
+            locations: self.index.iter().map(|(_, loc)| *loc).collect(),
+        }
+    }
+
+    // the largest key in 'range', tombstones included, if any
+    pub fn last_key_in_range<R: RangeBounds<str>>(&self, range: R) -> Option<String> {
+        let end = match range.end_bound() {
+            Bound::Included(end) => self.index.partition_point(|(k, _)| k.as_str() <= end),
+            Bound::Excluded(end) => self.index.partition_point(|(k, _)| k.as_str() < end),
+            Bound::Unbounded => self.index.len(),
+        };
+        self.index[..end].last()
+            .map(|(k, _)| k)
+            .filter(|k| range.contains(k.as_str()))
+            .cloned()
+    }
+
+    // every entry with a key in [start, end] in key order, tombstones
+    // included, when the data section is sorted they are read in one pass
+    pub fn range(&self, start: &str, end: &str) -> Result<Vec<SSTableEntry<C>>, Error> {
+        self.update_stats(|stats| stats.num_iter_calls += 1);
+
+        let lo = self.index.partition_point(|(k, _)| k.as_str() < start);
+        let hi = self.index.partition_point(|(k, _)| k.as_str() <= end).max(lo);
+
+        let mut result = Vec::with_capacity(hi - lo);
+        if lo < hi {
+            let mut reader = self.data_reader()?;
+            let mut pos = None;
+            for (_, loc) in &self.index[lo..hi] {
+                // only seek when the next entry isn't the one after the last
+                if pos != Some(*loc as u64) {
+                    reader.seek(SeekFrom::Start(*loc as u64))?;
+                }
+                result.push(read_data_entry(&mut reader, self)?);
+                pos = Some(reader.stream_position()?);
+            }
+        }
+        Ok(result)
+    }
+
+    // get an value based on a key string
+    // for current design we put index inside the latter half of the SSTable file
+    // consider change it to have a separate index load on LSMTree startup
+    // a deleted key reads as None
+    pub fn get(&self, key: &str) -> Result<Option<Decoded<C>>, Error> {
+        Ok(self.get_entry(key)?.and_then(SSTableValue::value))
+    }
+
+    // same as 'get', but tells a deleted key from one this SSTable doesn't hold
+    pub fn get_entry(&self, key: &str) -> Result<Option<SSTableValue<Decoded<C>>>, Error> {
+        self.update_stats(|stats| stats.num_get_calls += 1);
+
+        if let Some(bloom) = &self.bloom {
+            if !bloom.may_contain(key) {
+                return Ok(None);
+            }
+        }
+
+        // get the real offset from the index
+        let val_loc = match self.index.binary_search_by(|(k, _)| k.as_str().cmp(key)) {
+            Ok(pos) => self.index[pos].1,
+            Err(_) => return Ok(None),
+        };
 
-        // open the file and seek to the value location
-        let mut sstfile = fs::File::open(&self.path)?;
+        // seek to the value location, for a block compressed file this only
+        // decompresses the block holding it
+        let mut sstfile = self.lookup.borrow_mut();
         sstfile.seek(SeekFrom::Start(val_loc as u64))?;
 
         // skip the key
@@ -140,14 +1186,63 @@ impl SSTableFileReader {
 
         // load the value from data section
         let vallen = sstfile.read_u32::<LittleEndian>()?;
+        if vallen == TOMBSTONE_LEN {
+            self.update_stats(|stats| {
+                stats.num_get_hits += 1;
+                stats.bytes_read += 2 * mem::size_of::<u32>() as u64;
+            });
+            return Ok(Some(SSTableValue::Tombstone));
+        }
         let mut valbuf = vec![0 as u8; vallen as usize];
         sstfile.read_exact(&mut valbuf)?;
 
-        let valstr = unsafe {
-            str::from_utf8_unchecked(&valbuf)
+        self.update_stats(|stats| {
+            stats.num_get_hits += 1;
+            stats.bytes_read += (2 * mem::size_of::<u32>() + vallen as usize) as u64;
+        });
+        Ok(Some(SSTableValue::Value(C::decode(&valbuf)?)))
+    }
+
+    // the bloom filter section, None for files written without one
+    pub fn bloom_filter(&self) -> Option<&BloomFilter> {
+        self.bloom.as_ref()
+    }
+
+    // the statistics section, None for files written before it existed
+    pub fn stats_footer(&self) -> Option<StatsFooter> {
+        if self.sections_end < self.index_end + stats_size(self.tree_position) as u64 {
+            return None;
+        }
+        let mut sstfile = fs::File::open(&self.path).ok()?;
+
+        sstfile.seek(SeekFrom::Start(self.index_end)).ok()?;
+        let num_entries = sstfile.read_u32::<LittleEndian>().ok()?;
+        let total_value_bytes = sstfile.read_u64::<LittleEndian>().ok()?;
+        let tree_position = if self.tree_position {
+            let seqno = sstfile.read_u64::<LittleEndian>().ok()?;
+            let level = sstfile.read_u32::<LittleEndian>().ok()?;
+            Some((seqno, level as usize))
+        } else {
+            None
         };
-        
-        Ok(Some(String::from(valstr)))
+        Some(StatsFooter { num_entries, total_value_bytes, tree_position })
+    }
+
+    // the access counters since this reader was opened
+    pub fn stats(&self) -> SSTableStats {
+        self.stats.get()
+    }
+
+    // the access counters since this reader was opened or they were last
+    // taken, which starts them over
+    pub fn take_stats(&self) -> SSTableStats {
+        self.stats.take()
+    }
+
+    fn update_stats<F: FnOnce(&mut SSTableStats)>(&self, f: F) {
+        let mut stats = self.stats.get();
+        f(&mut stats);
+        self.stats.set(stats);
     }
 }
 
@@ -161,297 +1256,1711 @@ pub struct SSTableIndexBuilder {
 
 // -------------------- SSTableFileBuilder --------------------
 
-pub struct SSTableFileBuilder {
+// the data block being filled by a block compressing builder
+struct BlockWriter {
+    compressor: Box<dyn Compressor>,
+    block_size: usize,
+    // uncompressed entries of the block being filled
+    buf: Vec<u8>,
+    first_key: Option<String>,
+    // the size of the uncompressed data section so far, header included
+    data_len: usize,
+    blocks: Vec<DataBlock>,
+}
+
+pub struct SSTableFileBuilder<C: ValueCodec = StringCodec> {
+    path: PathBuf,
     writer: BufWriter<fs::File>,
     index: Vec<(String, u32)>,
     bytes_written: usize,
+    // None when the data section is stored uncompressed
+    block: Option<BlockWriter>,
+    // over everything written after the header
+    crc: crc32fast::Hasher,
+    // over the chunk of the data section being written, and the CRC32s of
+    // the ones written before it
+    chunk_crc: crc32fast::Hasher,
+    chunk_len: usize,
+    chunk_crcs: Vec<u32>,
+    total_value_bytes: u64,
+    bloom_bits_per_key: usize,
+    // the seqno and level recorded in the stats
+    tree_position: (u64, usize),
+    // the entries of a sorting builder by key, written on commit, a None
+    // value is a tombstone
+    buffered: Option<BTreeMap<String, Option<Vec<u8>>>>,
+    // set once 'commit' is called, a builder dropped before then commits
+    // itself
+    committed: bool,
+    // set once adding or merging entries failed, the file may be missing
+    // some of them so a drop removes it rather than committing it
+    failed: bool,
+    codec: PhantomData<C>,
+}
+
+impl SSTableFileBuilder {
+    // build an SSTable holding string values
+    pub fn new(path: &Path) -> Result<SSTableFileBuilder, Error> {
+        Self::with_codec(path)
+    }
+
+    // same as 'new', for entries added in any order
+    pub fn new_sorting(path: &Path) -> Result<SSTableFileBuilder, Error> {
+        Self::with_codec_sorting(path)
+    }
+}
+
+impl<C: ValueCodec> SSTableFileBuilder<C> {
+    pub fn with_codec(path: &Path) -> Result<SSTableFileBuilder<C>, Error> {
+        Self::create(path, None)
+    }
+
+    // same as 'with_codec', but entries may be added in any order: they are
+    // kept in memory and written sorted by key on commit, a later entry for
+    // a key replaces an earlier one
+    pub fn with_codec_sorting(path: &Path) -> Result<SSTableFileBuilder<C>, Error> {
+        let mut builder = Self::create(path, None)?;
+        builder.buffered = Some(BTreeMap::new());
+        Ok(builder)
+    }
+
+    // same as 'with_codec', but entries are grouped into blocks of about
+    // 'block_size' bytes (DEFAULT_BLOCK_SIZE) which are each compressed
+    // with 'compressor' (SnappyCompressor)
+    pub fn with_block_compression(path: &Path, compressor: Box<dyn Compressor>, block_size: usize)
+        -> Result<SSTableFileBuilder<C>, Error>
+    {
+        Self::create(path, Some(BlockWriter {
+            compressor,
+            block_size,
+            buf: Vec::new(),
+            first_key: None,
+            data_len: HEADER_SIZE,
+            blocks: Vec::new(),
+        }))
+    }
+
+    fn create(path: &Path, block: Option<BlockWriter>) -> Result<SSTableFileBuilder<C>, Error> {
+        let sstfile = fs::File::create(path)?;
+        let mut writer = BufWriter::new(sstfile);
+        writer.write_all(SSTABLE_MAGIC)?;
+        let version = if block.is_some() { SSTABLE_VERSION_BLOCKS } else { SSTABLE_VERSION };
+        writer.write_u32::<LittleEndian>(version | PREFIX_INDEX_FLAG | TREE_POSITION_FLAG | CHUNK_CRC_FLAG)?;
+
+        Ok(SSTableFileBuilder {
+            path: path.to_path_buf(),
+            writer,
+            index: Vec::new(),
+            bytes_written: HEADER_SIZE,
+            block,
+            crc: crc32fast::Hasher::new(),
+            chunk_crc: crc32fast::Hasher::new(),
+            chunk_len: 0,
+            chunk_crcs: Vec::new(),
+            total_value_bytes: 0,
+            bloom_bits_per_key: DEFAULT_BLOOM_BITS_PER_KEY,
+            tree_position: (0, 0),
+            buffered: None,
+            committed: false,
+            failed: false,
+            codec: PhantomData,
+        }) 
+    }
+
+    // the size of the bloom filter written on commit, 0 leaves it out
+    pub fn set_bloom_bits_per_key(&mut self, bits_per_key: usize) {
+        self.bloom_bits_per_key = bits_per_key;
+    }
+
+    // the seqno and level of the SSTable in its LSMTree, recorded in the
+    // stats so the tree can be rebuilt from its files, 0 for both unless set
+    pub fn set_tree_position(&mut self, seqno: u64, level: usize) {
+        self.tree_position = (seqno, level);
+    }
+
+    // call this function to write an entry to a SSTable file, keys are
+    // added in non-decreasing order unless the builder sorts them. A refused
+    // entry leaves the file as it was, but it is then missing that entry:
+    // the builder can still be committed explicitly, a drop removes it
+    pub fn add(&mut self, key: &str, val: &C::Value) -> Result<(), Error> {
+        let encoded = C::encode(val);
+        if let Err(e) = check_entry_len(key, encoded.len()) {
+            self.failed = true;
+            return Err(e);
+        }
+        if let Some(buffered) = &mut self.buffered {
+            buffered.insert(key.to_string(), Some(encoded));
+            return Ok(());
+        }
+        let res = self.write_entry(key, &encoded);
+        self.failed |= res.is_err();
+        res
+    }
+
+    // record that 'key' was deleted
+    pub fn add_tombstone(&mut self, key: &str) -> Result<(), Error> {
+        if let Err(e) = check_entry_len(key, 0) {
+            self.failed = true;
+            return Err(e);
+        }
+        if let Some(buffered) = &mut self.buffered {
+            buffered.insert(key.to_string(), None);
+            return Ok(());
+        }
+        let res = self.write_tombstone(key);
+        self.failed |= res.is_err();
+        res
+    }
+
+    // write an entry to the data section and index it
+    fn write_entry(&mut self, key: &str, valbytes: &[u8]) -> Result<(), Error> {
+        let keybytes = key.as_bytes();
+        let keylen = keybytes.len();
+        let vallen = valbytes.len();
+
+        // record the tuple location (key locations)
+        self.start_entry(key);
+
+        // write keylen and key
+        self.write_data(&(keylen as u32).to_le_bytes())?;
+        self.write_data(keybytes)?;
+
+        // write vallen and val
+        self.write_data(&(vallen as u32).to_le_bytes())?;
+        self.write_data(valbytes)?;
+        self.total_value_bytes += vallen as u64;
+        self.finish_entry()
+    }
+
+    // same as 'write_entry' for a deleted key
+    fn write_tombstone(&mut self, key: &str) -> Result<(), Error> {
+        let keybytes = key.as_bytes();
+        let keylen = keybytes.len();
+
+        self.start_entry(key);
+
+        self.write_data(&(keylen as u32).to_le_bytes())?;
+        self.write_data(keybytes)?;
+        self.write_data(&TOMBSTONE_LEN.to_le_bytes())?;
+        self.finish_entry()
+    }
+
+    // write the entries buffered by a sorting builder in key order
+    fn write_buffered(&mut self) -> Result<(), Error> {
+        let buffered = match self.buffered.take() {
+            Some(buffered) => buffered,
+            None => return Ok(()),
+        };
+        for (key, val) in &buffered {
+            match val {
+                Some(val) => self.write_entry(key, val)?,
+                None => self.write_tombstone(key)?,
+            }
+        }
+        Ok(())
+    }
+
+    // index an entry for 'key' starting at the end of the data section, keys
+    // come in non-decreasing order
+    fn start_entry(&mut self, key: &str) {
+        debug_assert!(!matches!(self.index.last(), Some((last, _)) if last.as_str() > key),
+                      "key {} added after {}", key, self.index.last().unwrap().0);
+        let loc = match &mut self.block {
+            Some(block) => {
+                if block.first_key.is_none() {
+                    block.first_key = Some(key.to_string());
+                }
+                block.data_len + block.buf.len()
+            },
+            None => self.bytes_written,
+        };
+        self.index.push((key.to_string(), loc as u32));
+    }
+
+    // an entry was written in full, write out the block holding it once
+    // the block is full, so entries never span blocks
+    fn finish_entry(&mut self) -> Result<(), Error> {
+        match &self.block {
+            Some(block) if block.buf.len() >= block.block_size => self.write_block(),
+            _ => Ok(()),
+        }
+    }
+
+    // compress the block being filled, if any, into the data section
+    fn write_block(&mut self) -> Result<(), Error> {
+        let mut block = match self.block.take() {
+            Some(block) => block,
+            None => return Ok(()),
+        };
+        if let Some(first_key) = block.first_key.take() {
+            let compressed = block.compressor.compress(&block.buf)?;
+            block.blocks.push(DataBlock {
+                first_key,
+                offset: self.bytes_written as u32,
+                compressed_len: compressed.len() as u32,
+                data_offset: block.data_len as u32,
+            });
+            block.data_len += block.buf.len();
+            block.buf.clear();
+            self.write_file(&compressed)?;
+        }
+        self.block = Some(block);
+        Ok(())
+    }
+
+    // write to the data section, keeping the checksum up to date, entries
+    // of a block compressed file are gathered into the current block
+    fn write_data(&mut self, buf: &[u8]) -> Result<(), Error> {
+        match &mut self.block {
+            Some(block) => {
+                block.buf.extend_from_slice(buf);
+                Ok(())
+            },
+            None => self.write_file(buf),
+        }
+    }
+
+    fn write_file(&mut self, buf: &[u8]) -> Result<(), Error> {
+        self.writer.write_all(buf)?;
+        self.crc.update(buf);
+        self.update_chunks(buf);
+        self.bytes_written += buf.len();
+        Ok(())
+    }
+
+    // checksum the data section in chunks of CHUNK_SIZE bytes
+    fn update_chunks(&mut self, mut buf: &[u8]) {
+        while !buf.is_empty() {
+            let n = buf.len().min(CHUNK_SIZE - self.chunk_len);
+            self.chunk_crc.update(&buf[..n]);
+            self.chunk_len += n;
+            buf = &buf[n..];
+            if self.chunk_len == CHUNK_SIZE {
+                self.finish_chunk();
+            }
+        }
+    }
+
+    fn finish_chunk(&mut self) {
+        let chunk_crc = mem::replace(&mut self.chunk_crc, crc32fast::Hasher::new());
+        self.chunk_crcs.push(chunk_crc.finalize());
+        self.chunk_len = 0;
+    }
+
+    // this function merges another SSTable to the current file, tombstones
+    // included
+    pub fn merge_file(&mut self, path: &Path) -> Result<(), Error> {
+        let res = self.merge_file_entries(path);
+        self.failed |= res.is_err();
+        res
+    }
+
+    fn merge_file_entries(&mut self, path: &Path) -> Result<(), Error> {
+        let reader = SSTableFileReader::<C>::open_with_codec(path)?;
+
+        // insert all pairs into the current file
+        for entry in reader.try_iter_entries()? {
+            let (key, val) = entry?;
+            match val {
+                SSTableValue::Value(val) => self.add(key.as_str(), val.borrow())?,
+                SSTableValue::Tombstone => self.add_tombstone(key.as_str())?,
+            }
+        }
+        Ok(())
+    }
+
+    // k-way merge of SSTables whose data sections are in key order (as every
+    // SSTable built from a memtable or a merge is), tombstones included
+    // a key in several of them is only taken from the newest, the one listed
+    // last in 'paths'
+    pub fn merge_files_dedup(&mut self, paths: &[&Path]) -> Result<(), Error> {
+        self.merge_files_filtered(paths, |_, _| true)
+    }
+
+    // same as 'merge_files_dedup', leaving out every entry for which
+    // 'keep(source, key)' is false, 'source' being its index in 'paths'
+    pub fn merge_files_filtered<F>(&mut self, paths: &[&Path], keep: F) -> Result<(), Error>
+        where F: FnMut(usize, &str) -> bool
+    {
+        let res = self.merge_sources(paths, keep);
+        self.failed |= res.is_err();
+        res
+    }
+
+    fn merge_sources<F>(&mut self, paths: &[&Path], mut keep: F) -> Result<(), Error>
+        where F: FnMut(usize, &str) -> bool
+    {
+        let readers = paths.iter()
+            .map(|path| SSTableFileReader::<C>::open_with_codec(path))
+            .collect::<Result<Vec<_>, _>>()?;
+        // an entry that can't be read fails the merge, rather than leaving
+        // out the rest of its source
+        let mut iters = readers.iter()
+            .map(|reader| reader.try_iter_entries())
+            .collect::<Result<Vec<_>, _>>()?;
+
+        // the next entry of every source, the heap pops the smallest key and
+        // for equal keys the newest source first
+        let mut heads = Vec::with_capacity(iters.len());
+        let mut heap = BinaryHeap::with_capacity(iters.len());
+        for (source, iter) in iters.iter_mut().enumerate() {
+            let head = iter.next().transpose()?;
+            if let Some((key, _)) = &head {
+                heap.push((Reverse(key.clone()), source));
+            }
+            heads.push(head.map(|(_, val)| val));
+        }
+
+        let mut last_key: Option<String> = None;
+        while let Some((Reverse(key), source)) = heap.pop() {
+            let val = heads[source].take().unwrap();
+            if let Some((next_key, next_val)) = iters[source].next().transpose()? {
+                heap.push((Reverse(next_key), source));
+                heads[source] = Some(next_val);
+            }
+
+            // an older source's entry for a key already written
+            if last_key.as_ref() == Some(&key) || !keep(source, &key) {
+                continue;
+            }
+            match val {
+                SSTableValue::Value(val) => self.add(key.as_str(), val.borrow())?,
+                SSTableValue::Tombstone => self.add_tombstone(key.as_str())?,
+            }
+            last_key = Some(key);
+        }
+        Ok(())
+    }
+
+    // the size of the file once committed with the entries added so far:
+    // the data section, then (keylen: u32, key, location_to_data: u32) per
+    // index entry, then the stats, the bloom filter and the footer
+    // the block being filled by a block compressing builder is counted
+    // uncompressed, the entries a sorting builder holds without their index
+    // keys sharing a prefix
+    pub fn estimate_file_size(&self) -> usize {
+        let mut prev: &[u8] = &[];
+        let index_size: usize = self.index.iter()
+            .map(|(k, _)| {
+                let shared = shared_prefix_len(prev, k.as_bytes());
+                prev = k.as_bytes();
+                1 + k.len() - shared + 2 * mem::size_of::<u32>()
+            })
+            .sum();
+        let num_buffered = self.buffered.as_ref().map_or(0, BTreeMap::len);
+        let buffered_size: usize = self.buffered.iter().flatten()
+            .map(|(k, v)| {
+                let vallen = v.as_ref().map_or(0, Vec::len);
+                2 * mem::size_of::<u32>() + k.len() + vallen + 1 + k.len() + 2 * mem::size_of::<u32>()
+            })
+            .sum();
+        let bloom_size = match self.bloom_bits_per_key {
+            0 => 0,
+            bits_per_key => BloomFilter::new(self.index.len() + num_buffered, bits_per_key).encoded_size(),
+        };
+        let block_size = match &self.block {
+            Some(block) => {
+                let pending = block.first_key.iter().chain(block.blocks.iter().map(|b| &b.first_key));
+                let block_index_size: usize = pending.map(|k| k.len() + 4 * mem::size_of::<u32>()).sum();
+                block.buf.len() + 1 + mem::size_of::<u32>() + block_index_size
+            },
+            None => 0,
+        };
+        let data_size = self.bytes_written - HEADER_SIZE + buffered_size + block_size;
+        let checksums_size = data_size.div_ceil(CHUNK_SIZE) * mem::size_of::<u32>() + CHECKSUMS_TRAILER_SIZE;
+        self.bytes_written + block_size + index_size + buffered_size + stats_size(true) + bloom_size
+            + checksums_size + FOOTER_SIZE
+    }
+
+    // we finish building the SSTable file, close and commit it
+    // after this, the SSTable becomes immutable
+    pub fn commit(&mut self) -> Result<(), Error> {
+        self.committed = true;
+        self.write_buffered()?;
+        self.write_block()?;
+        let mut indexbuf = self.encode_index()?;
+        self.encode_block_index(&mut indexbuf)?;
+        self.write_stats_footer(&mut indexbuf)?;
+        if let Some(bloom) = self.build_bloom() {
+            bloom.encode(&mut indexbuf)?;
+        }
+        self.encode_checksums(&mut indexbuf)?;
+        self.write_prepare(&indexbuf)?;
+        self.write_tail(&indexbuf)?;
+        self.writer.get_ref().sync_all()?;
+
+        // the commit is durable, the file is complete
+        fs::remove_file(prepare_path(&self.path))?;
+        Ok(())
+    }
+
+    // write 'indexbuf' after the data section, then the footer
+    fn write_tail(&mut self, indexbuf: &[u8]) -> Result<(), Error> {
+        let index_loc = self.bytes_written as u32;
+        self.writer.write_all(indexbuf)?;
+        self.crc.update(indexbuf);
+
+        self.writer.write_u32::<LittleEndian>(self.index.len() as u32)?;
+        self.writer.write_u32::<LittleEndian>(index_loc)?;
+        self.writer.write_u32::<LittleEndian>(self.crc.clone().finalize())?;
+        self.writer.flush()?;
+        Ok(())
+    }
+
+    // serialize the index section: (shared_len: u8, suffixlen: u32, suffix,
+    // location_to_data: u32)
+    fn encode_index(&self) -> Result<Vec<u8>, Error> {
+        let mut indexbuf = Vec::new();
+        let mut prev: &[u8] = &[];
+        for (k, v) in &self.index {
+            let keybytes = k.as_bytes();
+            let shared = shared_prefix_len(prev, keybytes);
+            indexbuf.write_u8(shared as u8)?;
+            indexbuf.write_u32::<LittleEndian>((keybytes.len() - shared) as u32)?;
+            indexbuf.write_all(&keybytes[shared..])?;
+            indexbuf.write_u32::<LittleEndian>(*v)?;
+            prev = keybytes;
+        }
+        Ok(indexbuf)
+    }
+
+    // append the block index of a block compressed file: (compressor_id: u8,
+    // num_blocks: u32) and per block (keylen: u32, first_key,
+    // location_to_block: u32, compressed_len: u32, location_to_data: u32)
+    fn encode_block_index(&self, buf: &mut Vec<u8>) -> Result<(), Error> {
+        let block = match &self.block {
+            Some(block) => block,
+            None => return Ok(()),
+        };
+        buf.write_u8(block.compressor.id())?;
+        buf.write_u32::<LittleEndian>(block.blocks.len() as u32)?;
+        for b in &block.blocks {
+            buf.write_u32::<LittleEndian>(b.first_key.len() as u32)?;
+            buf.write_all(b.first_key.as_bytes())?;
+            buf.write_u32::<LittleEndian>(b.offset)?;
+            buf.write_u32::<LittleEndian>(b.compressed_len)?;
+            buf.write_u32::<LittleEndian>(b.data_offset)?;
+        }
+        Ok(())
+    }
+
+    // append the stats section: (num_entries: u32, total_value_bytes: u64,
+    // seqno: u64, level: u32)
+    fn write_stats_footer(&self, buf: &mut Vec<u8>) -> Result<(), Error> {
+        let (seqno, level) = self.tree_position;
+        buf.write_u32::<LittleEndian>(self.index.len() as u32)?;
+        buf.write_u64::<LittleEndian>(self.total_value_bytes)?;
+        buf.write_u64::<LittleEndian>(seqno)?;
+        buf.write_u32::<LittleEndian>(level as u32)?;
+        Ok(())
+    }
+
+    // append the checksums section: (crc: u32 per chunk, chunk_size: u32,
+    // num_chunks: u32, tail_crc: u32), 'buf' holding everything after the
+    // data section so far
+    fn encode_checksums(&mut self, buf: &mut Vec<u8>) -> Result<(), Error> {
+        if self.chunk_len > 0 {
+            self.finish_chunk();
+        }
+        for crc in &self.chunk_crcs {
+            buf.write_u32::<LittleEndian>(*crc)?;
+        }
+        buf.write_u32::<LittleEndian>(CHUNK_SIZE as u32)?;
+        buf.write_u32::<LittleEndian>(self.chunk_crcs.len() as u32)?;
+        let tail_crc = crc32fast::hash(buf);
+        buf.write_u32::<LittleEndian>(tail_crc)?;
+        Ok(())
+    }
+
+    // the bloom filter over every key added so far, None if disabled
+    fn build_bloom(&self) -> Option<BloomFilter> {
+        if self.bloom_bits_per_key == 0 {
+            return None;
+        }
+        let mut bloom = BloomFilter::new(self.index.len(), self.bloom_bits_per_key);
+        for (k, _) in &self.index {
+            bloom.insert(k);
+        }
+        Some(bloom)
+    }
+
+    // give up on the SSTable, removing its file instead of committing it
+    pub fn discard(mut self) -> Result<(), Error> {
+        self.committed = true;
+        self.remove_files()
+    }
+
+    fn remove_files(&self) -> Result<(), Error> {
+        fs::remove_file(&self.path)?;
+        let prepare = prepare_path(&self.path);
+        if prepare.exists() {
+            fs::remove_file(prepare)?;
+        }
+        Ok(())
+    }
+
+    // write the sidecar describing what a complete file looks like
+    // 'indexbuf' holds everything between the data section and the footer
+    fn write_prepare(&self, indexbuf: &[u8]) -> Result<(), Error> {
+        let file_size = self.bytes_written + indexbuf.len() + FOOTER_SIZE;
+
+        let mut preparefile = fs::File::create(prepare_path(&self.path))?;
+        preparefile.write_u64::<LittleEndian>(file_size as u64)?;
+        preparefile.write_u32::<LittleEndian>(self.bytes_written as u32)?;
+        preparefile.write_u32::<LittleEndian>(crc32fast::hash(indexbuf))?;
+        preparefile.sync_all()?;
+        Ok(())
+    }
 }
 
-impl SSTableFileBuilder {
-    pub fn new(path: &Path) -> Result<SSTableFileBuilder, io::Error> {
-        let sstfile = fs::File::create(path)?;
+// a builder dropped without a call to 'commit' would leave a file without its
+// index and footer behind, commit it instead
+// one dropped while unwinding or after a failed write holds an incomplete set
+// of entries, its file is removed
+impl<C: ValueCodec> Drop for SSTableFileBuilder<C> {
+    fn drop(&mut self) {
+        if self.committed {
+            return;
+        }
+        if self.failed || thread::panicking() {
+            eprintln!("SSTable {} dropped after a failure, removing it", self.path.display());
+            self.committed = true;
+            if let Err(e) = self.remove_files() {
+                eprintln!("Removal of SSTable {} failed: {}", self.path.display(), e);
+            }
+            return;
+        }
+        eprintln!("SSTable {} dropped before commit, committing it", self.path.display());
+        if let Err(e) = self.commit() {
+            eprintln!("Commit of SSTable {} failed, removing it: {}", self.path.display(), e);
+            if let Err(e) = self.remove_files() {
+                eprintln!("Removal of SSTable {} failed: {}", self.path.display(), e);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::storage::sstable::*;
+    use tempfile::Builder;
+    use rand::prelude::*;
+
+    #[test]
+    fn sstable_single_entry() {
+        let mut rng = rand::thread_rng();
+        let sstfpath = Builder::new().prefix("rustydb_sstable_test").tempdir().unwrap();
+        let sstfname = sstfpath.path().join(format!("test_{}.sst", rng.gen::<u32>()));
+        let mut writer = SSTableFileBuilder::new(&sstfname).unwrap();
+
+        writer.add("foo", "bar").unwrap();
+        writer.commit().unwrap();
+
+        let reader = SSTableFileReader::open(&sstfname).unwrap();
+        assert_eq!(reader.get("foo").unwrap(), Some("bar".to_string()));
+    }
+
+    #[test]
+    fn sstable_multiple_entries() {
+        let mut rng = rand::thread_rng();
+        let sstfpath = Builder::new().prefix("rustydb_sstable_test").tempdir().unwrap();
+        let sstfname = sstfpath.path().join(format!("test_{}.sst", rng.gen::<u32>()));
+        let mut writer = SSTableFileBuilder::new_sorting(&sstfname).unwrap();
+
+        writer.add("foo", "bar").unwrap();
+        writer.add("zoohoo", "keefuu").unwrap();
+        writer.add("meemu", "mauha").unwrap();
+        writer.add("be", "p").unwrap();
+        writer.commit().unwrap();
+
+        let reader = SSTableFileReader::open(&sstfname).unwrap();
+        assert_eq!(reader.get("foo").unwrap(), Some("bar".to_string()));
+        assert_eq!(reader.get("zoohoo").unwrap(), Some("keefuu".to_string()));
+        assert_eq!(reader.get("meemu").unwrap(), Some("mauha".to_string()));
+        assert_eq!(reader.get("be").unwrap(), Some("p".to_string()));
+    }
+
+    #[test]
+    fn sstable_random_entries() {
+        let num = 100;
+        let mut rng = rand::thread_rng();
+        let sstfpath = Builder::new().prefix("rustydb_sstable_test").tempdir().unwrap();
+        let sstfname = sstfpath.path().join(format!("test_{}.sst", rng.gen::<u32>()));
+        let mut writer = SSTableFileBuilder::new_sorting(&sstfname).unwrap();
+
+        // generate random keys and values
+        let mut keys: Vec<String> = Vec::new();
+        let mut vals: Vec<String> = Vec::new();
+
+        writer.add("foo", "bar").unwrap();
+        writer.add("zoohoo", "keefuu").unwrap();
+        writer.add("meemu", "mauha").unwrap();
+        writer.add("be", "p").unwrap();
+        for _ in 0..num {
+            let rkey: [char; 32] = rng.gen();
+            let key: String = rkey.into_iter().collect();
+            keys.push(key.clone());
+
+            let rval: [char; 32] = rng.gen();
+            let val: String = rval.into_iter().collect();
+            vals.push(val.clone());
+            writer.add(&key, &val).unwrap();
+        }
+        writer.commit().unwrap();
+
+        // verify
+        let reader = SSTableFileReader::open(&sstfname).unwrap();
+        for i in 0..num {
+            assert_eq!(reader.get(&keys[i]).unwrap(), Some(vals[i].clone()));
+        }
+    }
+
+    #[test]
+    fn sstable_simple_iter() {
+        let mut rng = rand::thread_rng();
+        let sstfpath = Builder::new().prefix("rustydb_sstable_test").tempdir().unwrap();
+        let sstfname = sstfpath.path().join(format!("test_{}.sst", rng.gen::<u32>()));
+        let mut writer = SSTableFileBuilder::new(&sstfname).unwrap();
+
+        // list of pairs for testing, in key order
+        let pairs = vec![("be", "p"), ("foo", "bar"), ("meemu", "mauha"), ("zoohoo", "keefuu")];
+        
+        for (key, val) in &pairs {
+            writer.add(key, val).unwrap();
+        }
+        writer.commit().unwrap();
+
+        // verify
+        let reader = SSTableFileReader::open(&sstfname).unwrap();
+        for (entry, record) in reader.iter().zip(pairs.iter()) {
+            let (key, val) = entry;
+            assert_eq!((key.as_str(), val.as_str()), *record);
+        }
+    }
+
+    #[test]
+    fn sstable_random_iter() {
+        let num = 100;
+        let mut rng = rand::thread_rng();
+        let sstfpath = Builder::new().prefix("rustydb_sstable_test").tempdir().unwrap();
+        let sstfname = sstfpath.path().join(format!("test_{}.sst", rng.gen::<u32>()));
+        let mut writer = SSTableFileBuilder::new(&sstfname).unwrap();
+
+        // generate random keys and values
+        let mut rand_pairs: Vec<(String, String)> = Vec::new();
+        for _ in 0..num {
+            let rkey: [char; 32] = rng.gen();
+            let key: String = rkey.into_iter().collect();
+            
+            let rval: [char; 32] = rng.gen();
+            let val: String = rval.into_iter().collect();
+            rand_pairs.push((key, val));
+        }
+        rand_pairs.sort();
+        for (key, val) in &rand_pairs {
+            writer.add(key, val).unwrap();
+        }
+        writer.commit().unwrap();
+
+        // verify
+        let reader = SSTableFileReader::open(&sstfname).unwrap();
+        for (entry, record) in reader.iter().zip(rand_pairs.iter()) {
+            assert_eq!(entry, *record);
+        }
+    }
+
+    #[test]
+    fn sstable_chain_two() {
+        let mut rng = rand::thread_rng();
+
+        // first SSTable file
+        let sstfpath = Builder::new().prefix("rustydb_sstable_test").tempdir().unwrap();
+        let sstfname1 = sstfpath.path().join(format!("test_{}.sst", rng.gen::<u32>()));
+        let mut sst1 = SSTableFileBuilder::new(&sstfname1).unwrap();
+
+        // second SSTable file
+        let sstfname2 = sstfpath.path().join(format!("test_{}.sst", rng.gen::<u32>()));
+        let mut sst2 = SSTableFileBuilder::new(&sstfname2).unwrap();
+        
+        // list of pairs for testing, in key order
+        let pairs = vec![("be", "p"), ("foo", "bar"), ("meemu", "mauha"), ("zoohoo", "keefuu")];
+
+        // sstable 1 takes the first 2
+        for entry in pairs.iter().take(2) {
+            let (key, val) = *entry;
+            sst1.add(key, val).unwrap();
+        }
+        sst1.commit().unwrap();
+
+        // sstable 2 takes the rest
+        for entry in pairs.iter().skip(2) {
+            let (key, val) = *entry;
+            sst2.add(key, val).unwrap();
+        }
+        sst2.commit().unwrap();
+
+        // merge sst1 and sst2 to a compacted new sst
+        let newsstfpath = sstfpath.path().join(format!("test_{}.sst", rng.gen::<u32>()));
+        let mut newsst = SSTableFileBuilder::new(&newsstfpath).unwrap();
+
+        newsst.merge_file(&sstfname1).unwrap();
+        newsst.merge_file(&sstfname2).unwrap();
+        newsst.commit().unwrap();
+
+        // verify the new sstable file is correct
+        let reader = SSTableFileReader::open(&newsstfpath).unwrap();
+        for (entry, record) in reader.iter().zip(pairs.iter()) {
+            let (key, val) = entry;
+            assert_eq!((key.as_str(), val.as_str()), *record);
+        }
+    }
+
+    #[test]
+    fn sstable_merge_dedup() {
+        let mut rng = rand::thread_rng();
+        let sstfpath = Builder::new().prefix("rustydb_sstable_test").tempdir().unwrap();
+        let mut build = |pairs: &[(&str, Option<&str>)]| -> PathBuf {
+            let sstfname = sstfpath.path().join(format!("test_{}.sst", rng.gen::<u32>()));
+            let mut writer = SSTableFileBuilder::new(&sstfname).unwrap();
+            for (key, val) in pairs {
+                match val {
+                    Some(val) => writer.add(key, val).unwrap(),
+                    None => writer.add_tombstone(key).unwrap(),
+                }
+            }
+            writer.commit().unwrap();
+            sstfname
+        };
+
+        let oldest = build(&[("a", Some("1")), ("c", Some("1")), ("e", Some("1")), ("g", Some("1"))]);
+        let older = build(&[("b", Some("2")), ("c", Some("2")), ("e", None), ("h", Some("2"))]);
+        let newest = build(&[("c", Some("3")), ("d", Some("3")), ("g", Some("3"))]);
+
+        let mergedfname = sstfpath.path().join(format!("test_{}.sst", rng.gen::<u32>()));
+        let mut merged = SSTableFileBuilder::new(&mergedfname).unwrap();
+        merged.merge_files_dedup(&[&oldest, &older, &newest]).unwrap();
+        merged.commit().unwrap();
+
+        let reader = SSTableFileReader::open(&mergedfname).unwrap();
+        let entries: Vec<(String, SSTableValue<String>)> = reader.iter_entries().collect();
+        let expected = vec![
+            ("a", Some("1")), ("b", Some("2")), ("c", Some("3")), ("d", Some("3")),
+            ("e", None), ("g", Some("3")), ("h", Some("2")),
+        ];
+        assert_eq!(entries.len(), expected.len());
+        for ((key, val), (expected_key, expected_val)) in entries.into_iter().zip(expected) {
+            assert_eq!(key, expected_key);
+            assert_eq!(val.value(), expected_val.map(String::from));
+        }
+        assert_eq!(reader.stats_footer().unwrap().num_entries, 7);
+
+        // a single source is copied as it is
+        let copyfname = sstfpath.path().join(format!("test_{}.sst", rng.gen::<u32>()));
+        let mut copy = SSTableFileBuilder::new(&copyfname).unwrap();
+        copy.merge_files_dedup(&[&older]).unwrap();
+        copy.commit().unwrap();
+        assert_eq!(fs::read(&copyfname).unwrap(), fs::read(&older).unwrap());
+    }
+
+    #[test]
+    fn sstable_chain_random() {
+        let num_pairs: i32 = 100;
+        let num_ssts: i32 = 10;
+
+        // how much pairs a single sstable should take
+        let chunk_size = num_pairs / num_ssts;
+        let mut rng = rand::thread_rng();
+
+        // generate random keys and values
+        let mut rand_pairs: Vec<(String, String)> = Vec::new();
+        for _ in 0..num_pairs {
+            let rkey: [char; 32] = rng.gen();
+            let key: String = rkey.iter().collect();
+            
+            let rval: [char; 32] = rng.gen();
+            let val: String = rval.iter().collect();
+            rand_pairs.push((key, val));
+        }
+        // the chunks are merged one after another, so they follow key order
+        rand_pairs.sort();
+
+        // the final sstable file
+        let sstfpath = Builder::new().prefix("rustydb_sstable_test").tempdir().unwrap();
+        let newsstfpath = sstfpath.path().join(format!("test_{}.sst", rng.gen::<u32>()));
+        let mut newsst = SSTableFileBuilder::new(&newsstfpath).unwrap();
+
+        // make "num_ssts" sstable files, filled with chunks of data, then
+        // merge into the final sstable file
+        for chunk in rand_pairs.chunks(chunk_size as usize) {
+            let sstfname = sstfpath.path().join(format!("test_{}.sst", rng.gen::<u32>()));
+            let mut sst = SSTableFileBuilder::new(&sstfname).unwrap();
+
+            // add these specific chunk of data to new sstable, then commit
+            for entry in chunk {
+                let (key, val) = entry;
+                sst.add(key, val).unwrap();
+            }
+            sst.commit().unwrap();
+
+            // merage the new sstable
+            newsst.merge_file(&sstfname).unwrap();
+        }
+        newsst.commit().unwrap();
+
+        // verify
+        let reader = SSTableFileReader::open(&newsstfpath).unwrap();
+        for (entry, record) in reader.iter().zip(rand_pairs.iter()) {
+            assert_eq!(entry, *record);
+        }
+    }
+
+    #[test]
+    fn sstable_interrupted_commit() {
+        let mut rng = rand::thread_rng();
+        let sstfpath = Builder::new().prefix("rustydb_sstable_test").tempdir().unwrap();
+        let pairs = vec![("be", "p"), ("foo", "bar"), ("meemu", "mauha"), ("zoohoo", "keefuu")];
+
+        // leave an SSTable behind as if the commit crashed after 'offset' bytes
+        let mut interrupted = |offset: Option<u64>| -> PathBuf {
+            let sstfname = sstfpath.path().join(format!("test_{}.sst", rng.gen::<u32>()));
+            let mut writer = SSTableFileBuilder::new(&sstfname).unwrap();
+            for (key, val) in &pairs {
+                writer.add(key, val).unwrap();
+            }
+            let mut indexbuf = writer.encode_index().unwrap();
+            writer.encode_checksums(&mut indexbuf).unwrap();
+            writer.write_prepare(&indexbuf).unwrap();
+            writer.write_tail(&indexbuf).unwrap();
+            // a crash doesn't commit the builder on drop
+            mem::forget(writer);
+            if let Some(offset) = offset {
+                fs::OpenOptions::new().write(true).open(&sstfname).unwrap().set_len(offset).unwrap();
+            }
+            sstfname
+        };
+
+        let full_size = fs::metadata(interrupted(None)).unwrap().len();
+        for offset in [0, 7, full_size / 2, full_size - 1] {
+            let sstfname = interrupted(Some(offset));
+            assert!(SSTableFileReader::open(&sstfname).is_err());
+
+            // the partial file is removed
+            assert!(!SSTableFileReader::repair(&sstfname).unwrap());
+            assert!(!sstfname.exists());
+            assert!(!prepare_path(&sstfname).exists());
+        }
+
+        // every byte reached disk but the sidecar was never removed
+        let sstfname = interrupted(None);
+        assert!(SSTableFileReader::open(&sstfname).is_err());
+        assert!(SSTableFileReader::repair(&sstfname).unwrap());
+        let reader = SSTableFileReader::open(&sstfname).unwrap();
+        assert_eq!(reader.get("meemu").unwrap(), Some("mauha".to_string()));
+
+        // a normal commit leaves no sidecar behind
+        let sstfname = sstfpath.path().join(format!("test_{}.sst", rng.gen::<u32>()));
+        let mut writer = SSTableFileBuilder::new(&sstfname).unwrap();
+        writer.add("foo", "bar").unwrap();
+        writer.commit().unwrap();
+        assert!(!prepare_path(&sstfname).exists());
+    }
+
+    #[test]
+    fn sstable_drop_uncommitted() {
+        let sstfpath = Builder::new().prefix("rustydb_sstable_test").tempdir().unwrap();
+
+        // a builder dropped without a commit is committed
+        let sstfname = sstfpath.path().join("uncommitted.sst");
+        let mut writer = SSTableFileBuilder::new(&sstfname).unwrap();
+        writer.add("foo", "bar").unwrap();
+        writer.add("meemu", "mauha").unwrap();
+        drop(writer);
+        assert!(!prepare_path(&sstfname).exists());
+        let reader = SSTableFileReader::open(&sstfname).unwrap();
+        assert_eq!(reader.get("foo").unwrap(), Some("bar".to_string()));
+        assert_eq!(reader.get("meemu").unwrap(), Some("mauha".to_string()));
+
+        // a committed one is left as it is
+        let committed = sstfpath.path().join("committed.sst");
+        let mut writer = SSTableFileBuilder::new(&committed).unwrap();
+        writer.add("foo", "bar").unwrap();
+        writer.commit().unwrap();
+        let size = fs::metadata(&committed).unwrap().len();
+        drop(writer);
+        assert_eq!(fs::metadata(&committed).unwrap().len(), size);
+        assert!(SSTableFileReader::open(&committed).is_ok());
+
+        // one whose merge failed part way is removed
+        let failed = sstfpath.path().join("failed.sst");
+        let mut writer = SSTableFileBuilder::new(&failed).unwrap();
+        writer.add("a", "b").unwrap();
+        let missing = sstfpath.path().join("missing.sst");
+        assert!(writer.merge_files_dedup(&[&committed, &missing]).is_err());
+        drop(writer);
+        assert!(!failed.exists());
+        assert!(!prepare_path(&failed).exists());
+
+        // as is one dropped while unwinding
+        let unwound = sstfpath.path().join("unwound.sst");
+        let res = std::panic::catch_unwind(|| {
+            let mut writer = SSTableFileBuilder::new(&unwound).unwrap();
+            writer.add("foo", "bar").unwrap();
+            panic!("interrupted");
+        });
+        assert!(res.is_err());
+        assert!(!unwound.exists());
+
+        // and one given up on
+        let discarded = sstfpath.path().join("discarded.sst");
+        let mut writer = SSTableFileBuilder::new(&discarded).unwrap();
+        writer.add("foo", "bar").unwrap();
+        writer.discard().unwrap();
+        assert!(!discarded.exists());
+    }
+
+    #[test]
+    fn sstable_truncated() {
+        let mut rng = rand::thread_rng();
+        let sstfpath = Builder::new().prefix("rustydb_sstable_test").tempdir().unwrap();
+        let sstfname = sstfpath.path().join(format!("test_{}.sst", rng.gen::<u32>()));
+        let mut writer = SSTableFileBuilder::new(&sstfname).unwrap();
+        for i in 0..100 {
+            writer.add(&format!("key{:03}", i), &format!("val{}", i)).unwrap();
+        }
+        writer.commit().unwrap();
+        let contents = fs::read(&sstfname).unwrap();
+
+        let copyfname = sstfpath.path().join(format!("test_{}.sst", rng.gen::<u32>()));
+        let open_with = |bytes: &[u8]| {
+            fs::write(&copyfname, bytes).unwrap();
+            SSTableFileReader::open(&copyfname)
+        };
+        assert_eq!(open_with(&contents).unwrap().get("key042").unwrap(), Some(String::from("val42")));
+
+        // cut within the header, the data, the index and the footer
+        for len in [0, 5, HEADER_SIZE + 20, contents.len() / 2, contents.len() - 200, contents.len() - 1] {
+            let err = open_with(&contents[..len]).err().unwrap();
+            assert!(matches!(err, Error::Corrupt(_) | Error::InvalidFooter));
+        }
+
+        // a flipped bit in the data section fails the reads of its chunk,
+        // opening the file doesn't read it
+        let mut corrupt = contents.clone();
+        corrupt[HEADER_SIZE + 10] ^= 1;
+        let reader = open_with(&corrupt).unwrap();
+        let err = reader.get("key000").err().unwrap();
+        assert!(matches!(err, Error::Corrupt(_)));
+        assert!(err.to_string().contains("checksum mismatch"));
+        assert!(matches!(reader.range("key000", "key099"), Err(Error::Corrupt(_))));
+        assert!(reader.try_iter_entries().unwrap().next().unwrap().is_err());
+        assert!(matches!(reader.verify(), Err(Error::Corrupt(_))));
+        assert!(open_with(&contents).unwrap().verify().is_ok());
+
+        // and one in the index fails the open
+        let index_loc = (&contents[contents.len() - 8..]).read_u32::<LittleEndian>().unwrap() as usize;
+        let mut corrupt = contents.clone();
+        corrupt[index_loc + 3] ^= 1;
+        let err = open_with(&corrupt).err().unwrap();
+        assert!(err.to_string().contains("checksum mismatch"));
+
+        // not an SSTable at all
+        let err = open_with(b"key,value\nfoo,bar\nmeemu,mauha\n").err().unwrap();
+        assert!(err.to_string().contains("not an SSTable file"));
+    }
+
+    #[test]
+    fn sstable_gorilla_codec() {
+        use crate::gorilla::*;
+        use crate::gorilla::api::*;
+        use chrono::{Duration, TimeZone, Utc};
+
+        let mut rng = rand::thread_rng();
+        let sstfpath = Builder::new().prefix("rustydb_sstable_test").tempdir().unwrap();
+        let sstfname = sstfpath.path().join(format!("test_{}.sst", rng.gen::<u32>()));
+        let mut writer = SSTableFileBuilder::<GorillaBlockCodec>::with_codec(&sstfname).unwrap();
+
+        // the encoded blocks are arbitrary bytes, not valid UTF-8
+        let header = Utc.timestamp_opt(0, 0).unwrap();
+        let mut blocks = Vec::new();
+        for b in 0..3 {
+            let entries: Vec<MVEntry> = (0..10)
+                .map(|i| MVEntry::new(header + Duration::seconds(10 * (i + 1)),
+                                      vec![(b * 10 + i) as f64 * 1.1, -1.0e300]))
+                .collect();
+            let block = compress_values(entries.clone(), header, 2);
+            writer.add(&format!("series_{}", b), &block).unwrap();
+            blocks.push(entries);
+        }
+        writer.commit().unwrap();
+
+        let reader = SSTableFileReader::<GorillaBlockCodec>::open_with_codec(&sstfname).unwrap();
+        let block = reader.get("series_1").unwrap().unwrap();
+        let decoded = retrieve_values(block, 2, 10);
+        for (entry, expected) in decoded.iter().zip(blocks[1].iter()) {
+            assert_eq!(entry.time(), expected.time());
+            assert_eq!(entry.values(), expected.values());
+        }
+        assert!(reader.get("series_9").unwrap().is_none());
+
+        // iterate, decoding every block
+        for ((key, block), (b, expected)) in reader.iter().zip(blocks.iter().enumerate()) {
+            assert_eq!(key, format!("series_{}", b));
+            assert_eq!(retrieve_values(block, 2, 10)[9].values(), expected[9].values());
+        }
+
+        // reading the same file as strings is refused rather than misread
+        let strreader = SSTableFileReader::open(&sstfname).unwrap();
+        assert!(matches!(strreader.get("series_1"), Err(crate::storage::Error::BadUtf8)));
+    }
+
+    #[test]
+    fn sstable_prefix_index() {
+        let sstfpath = Builder::new().prefix("rustydb_sstable_test").tempdir().unwrap();
+        let sstfname = sstfpath.path().join("prefix.sst");
+
+        // keys sharing a 12 byte series prefix, then a key sharing nothing
+        let mut keys: Vec<String> = (0..1000).map(|i| format!("{:012x}{:08x}", 0xabcdef_u64, i * 7919)).collect();
+        keys.push(String::from("zzz"));
+        let mut writer = SSTableFileBuilder::new(&sstfname).unwrap();
+        for key in &keys {
+            writer.add(key, &key[key.len() - 3..]).unwrap();
+        }
+
+        // the index takes less than half of the space of whole keys
+        let whole_keys: usize = keys.iter().map(|k| k.len() + 2 * mem::size_of::<u32>()).sum();
+        assert!(writer.encode_index().unwrap().len() * 2 < whole_keys);
+        writer.commit().unwrap();
+
+        let reader = SSTableFileReader::open(&sstfname).unwrap();
+        assert_eq!(reader.index.iter().map(|(k, _)| k.clone()).collect::<Vec<_>>(), keys);
+        for key in &keys {
+            assert_eq!(reader.get(key).unwrap(), Some(key[key.len() - 3..].to_string()));
+        }
+        assert_eq!(reader.get(&format!("{:012x}{:08x}", 0xabcdef_u64, 1)).unwrap(), None);
+
+        // a file written before index keys shared prefixes still reads
+        let oldfname = sstfpath.path().join("old.sst");
+        let mut contents = SSTABLE_MAGIC.to_vec();
+        contents.write_u32::<LittleEndian>(SSTABLE_VERSION).unwrap();
+        let mut index = Vec::new();
+        for key in &keys[..10] {
+            index.write_u32::<LittleEndian>(key.len() as u32).unwrap();
+            index.write_all(key.as_bytes()).unwrap();
+            index.write_u32::<LittleEndian>(contents.len() as u32).unwrap();
+            contents.write_u32::<LittleEndian>(key.len() as u32).unwrap();
+            contents.write_all(key.as_bytes()).unwrap();
+            contents.write_u32::<LittleEndian>(1).unwrap();
+            contents.write_all(b"v").unwrap();
+        }
+        let index_loc = contents.len() as u32;
+        contents.extend_from_slice(&index);
+        let crc = crc32fast::hash(&contents[HEADER_SIZE..]);
+        contents.write_u32::<LittleEndian>(10).unwrap();
+        contents.write_u32::<LittleEndian>(index_loc).unwrap();
+        contents.write_u32::<LittleEndian>(crc).unwrap();
+        fs::write(&oldfname, &contents).unwrap();
+
+        let reader = SSTableFileReader::open(&oldfname).unwrap();
+        let read: Vec<String> = reader.iter().map(|(k, _)| k).collect();
+        assert_eq!(read, keys[..10].to_vec());
+        assert_eq!(reader.get(&keys[5]).unwrap(), Some(String::from("v")));
+    }
+
+    #[test]
+    fn sstable_key_too_long() {
+        let sstfpath = Builder::new().prefix("rustydb_sstable_test").tempdir().unwrap();
+        let sstfname = sstfpath.path().join("long.sst");
+        let mut writer = SSTableFileBuilder::new(&sstfname).unwrap();
+
+        let longest = "k".repeat(MAX_KEY_LEN);
+        let too_long = "k".repeat(MAX_KEY_LEN + 1);
+        writer.add("a", "1").unwrap();
+        match writer.add(&too_long, "2") {
+            Err(Error::InvalidArgument(reason)) => assert!(reason.contains("exceeds 65536 bytes"), "{}", reason),
+            other => panic!("unexpected result {:?}", other),
+        }
+        assert!(matches!(writer.add_tombstone(&too_long), Err(Error::InvalidArgument(_))));
+        // the key is cut to whole characters in the error
+        let wide = format!("a{}", "é".repeat(MAX_KEY_LEN / 2));
+        match check_entry_len(&wide, 0) {
+            Err(Error::InvalidArgument(reason)) => assert!(reason.contains(&format!("{:?}", format!("a{}", "é".repeat(15))))),
+            other => panic!("unexpected result {:?}", other),
+        }
+        writer.add(&longest, "3").unwrap();
+        writer.commit().unwrap();
+
+        // the refused key left nothing behind
+        let reader = SSTableFileReader::open(&sstfname).unwrap();
+        let keys: Vec<String> = reader.iter().map(|(k, _)| k).collect();
+        assert_eq!(keys, vec![String::from("a"), longest.clone()]);
+        assert_eq!(reader.get(&longest).unwrap(), Some(String::from("3")));
+        assert_eq!(reader.get(&too_long).unwrap(), None);
+
+        // one dropped after a refused key is missing it and is not committed
+        let dropped = sstfpath.path().join("dropped.sst");
+        let mut writer = SSTableFileBuilder::new(&dropped).unwrap();
+        writer.add("a", "1").unwrap();
+        assert!(writer.add(&too_long, "2").is_err());
+        drop(writer);
+        assert!(!dropped.exists());
+        let mut writer = SSTableFileBuilder::<StringCodec>::new(&dropped).unwrap();
+        assert!(writer.add_tombstone(&too_long).is_err());
+        drop(writer);
+        assert!(!dropped.exists());
+    }
+
+    #[test]
+    fn sstable_bad_utf8_key() {
+        let sstfpath = Builder::new().prefix("rustydb_sstable_test").tempdir().unwrap();
+        let sstfname = sstfpath.path().join("bad.sst");
+
+        // a file with the given keys in its index and data section
+        let write = |index_keys: &[&[u8]], data_keys: &[&[u8]]| {
+            let mut contents = SSTABLE_MAGIC.to_vec();
+            contents.write_u32::<LittleEndian>(SSTABLE_VERSION).unwrap();
+            let mut index = Vec::new();
+            for (index_key, data_key) in index_keys.iter().zip(data_keys) {
+                index.write_u32::<LittleEndian>(index_key.len() as u32).unwrap();
+                index.write_all(index_key).unwrap();
+                index.write_u32::<LittleEndian>(contents.len() as u32).unwrap();
+                contents.write_u32::<LittleEndian>(data_key.len() as u32).unwrap();
+                contents.write_all(data_key).unwrap();
+                contents.write_u32::<LittleEndian>(1).unwrap();
+                contents.write_all(b"v").unwrap();
+            }
+            let index_loc = contents.len() as u32;
+            contents.extend_from_slice(&index);
+            let crc = crc32fast::hash(&contents[HEADER_SIZE..]);
+            contents.write_u32::<LittleEndian>(index_keys.len() as u32).unwrap();
+            contents.write_u32::<LittleEndian>(index_loc).unwrap();
+            contents.write_u32::<LittleEndian>(crc).unwrap();
+            fs::write(&sstfname, &contents).unwrap();
+        };
 
-        Ok(SSTableFileBuilder {
-            writer: BufWriter::new(sstfile),
-            index: Vec::new(),
-            bytes_written: 0,
-        }) 
+        // a bad key in the index refuses the whole file
+        write(&[b"a", b"b\xff"], &[b"a", b"b\xff"]);
+        assert!(matches!(SSTableFileReader::open(&sstfname), Err(Error::BadUtf8)));
+
+        // a bad key in the data section fails the range that reaches it
+        write(&[b"a", b"b"], &[b"a", b"b\xff"]);
+        let reader = SSTableFileReader::open(&sstfname).unwrap();
+        assert_eq!(reader.range("a", "a").unwrap().len(), 1);
+        assert!(matches!(reader.range("a", "b"), Err(Error::BadUtf8)));
+        let keys: Vec<String> = reader.iter().map(|(k, _)| k).collect();
+        assert_eq!(keys, vec![String::from("a")]);
+
+        // and fails a merge of the file, which leaves nothing behind
+        write(&[b"a", b"b", b"c"], &[b"a", b"b\xff", b"c"]);
+        let mergedfname = sstfpath.path().join("merged.sst");
+        let mut merged = SSTableFileBuilder::new(&mergedfname).unwrap();
+        assert!(matches!(merged.merge_files_dedup(&[&sstfname]), Err(Error::BadUtf8)));
+        assert!(matches!(merged.merge_file(&sstfname), Err(Error::BadUtf8)));
+        drop(merged);
+        assert!(!mergedfname.exists());
     }
 
-    // call this function to write an entry to a SSTable file
-    pub fn add(&mut self, key: &str, val: &str) -> Result<(), io::Error> {
-        let keybytes = key.as_bytes();
-        let valbytes = val.as_bytes();
-        let keylen = keybytes.len();
-        let vallen = valbytes.len();
+    #[test]
+    fn sstable_chunk_checksums() {
+        let sstfpath = Builder::new().prefix("rustydb_sstable_test").tempdir().unwrap();
+        let pairs: Vec<(String, String)> = (0..2000)
+            .map(|i| (format!("key{:04}", i), format!("val{}", i).repeat(i % 5 + 1)))
+            .collect();
+
+        for compress in [false, true] {
+            let sstfname = sstfpath.path().join(format!("chunks_{}.sst", compress));
+            let mut writer: SSTableFileBuilder = if compress {
+                SSTableFileBuilder::with_block_compression(&sstfname, Box::new(NoCompressor), 1024).unwrap()
+            } else {
+                SSTableFileBuilder::new(&sstfname).unwrap()
+            };
+            for (key, val) in &pairs {
+                writer.add(key, val).unwrap();
+            }
+            writer.commit().unwrap();
+
+            // every entry reads back, across the chunk boundaries
+            let contents = fs::read(&sstfname).unwrap();
+            let reader = SSTableFileReader::open(&sstfname).unwrap();
+            assert!(reader.chunks.as_ref().unwrap().crcs.len() > 5);
+            let read: Vec<(String, String)> = reader.iter().collect();
+            assert_eq!(read, pairs);
+            reader.verify().unwrap();
+
+            // a flipped bit fails only the reads of its chunk
+            let mut corrupt = contents.clone();
+            corrupt[HEADER_SIZE + 3 * CHUNK_SIZE + 100] ^= 1;
+            fs::write(&sstfname, &corrupt).unwrap();
+            let reader = SSTableFileReader::open(&sstfname).unwrap();
+            assert_eq!(reader.get("key0000").unwrap(), Some(String::from("val0")));
+            let last = &pairs[pairs.len() - 1];
+            assert_eq!(reader.get(&last.0).unwrap(), Some(last.1.clone()));
+            let results: Vec<_> = reader.try_iter_entries().unwrap().collect();
+            assert!(results.len() < pairs.len());
+            assert!(matches!(results.last(), Some(Err(Error::Corrupt(_)))));
+            assert!(reader.verify().is_err());
+        }
+    }
 
-        // record the tuple location (key locations)
-        self.index.push((key.to_string(), self.bytes_written as u32));
+    #[test]
+    fn sstable_sorting_builder() {
+        let mut rng = rand::thread_rng();
+        let sstfpath = Builder::new().prefix("rustydb_sstable_test").tempdir().unwrap();
+        let sstfname = sstfpath.path().join(format!("test_{}.sst", rng.gen::<u32>()));
+        let mut writer = SSTableFileBuilder::new_sorting(&sstfname).unwrap();
 
-        // write keylen and key
-        self.writer.write_u32::<LittleEndian>(keylen as u32)?;
-        self.writer.write_all(keybytes)?;
-        self.bytes_written += mem::size_of::<u32>() + keylen;
+        let mut keys: Vec<u32> = (0..200).collect();
+        keys.shuffle(&mut rng);
+        for i in &keys {
+            writer.add(&format!("key{:04}", i), &format!("val{}", i)).unwrap();
+        }
+        // a later entry for a key replaces an earlier one
+        writer.add("key0007", "again").unwrap();
+        writer.add_tombstone("key0100").unwrap();
+        writer.add_tombstone("a").unwrap();
+        assert!(matches!(writer.add(&"k".repeat(MAX_KEY_LEN + 1), "v"), Err(Error::InvalidArgument(_))));
+        let estimate = writer.estimate_file_size();
+        writer.commit().unwrap();
+        assert!(fs::metadata(&sstfname).unwrap().len() as usize <= estimate);
 
-        // write vallen and val
-        self.writer.write_u32::<LittleEndian>(vallen as u32)?;
-        self.writer.write_all(valbytes)?;
-        self.bytes_written += mem::size_of::<u32>() + vallen;
-        Ok(())
+        let reader = SSTableFileReader::open(&sstfname).unwrap();
+        let entries: Vec<(String, SSTableValue<String>)> = reader.iter_entries().collect();
+        assert_eq!(entries.len(), 201);
+        assert!(entries.windows(2).all(|pair| pair[0].0 < pair[1].0));
+        assert_eq!(entries[0], (String::from("a"), SSTableValue::Tombstone));
+        assert_eq!(reader.get("key0007").unwrap(), Some(String::from("again")));
+        assert_eq!(reader.get_entry("key0100").unwrap(), Some(SSTableValue::Tombstone));
+        assert_eq!(reader.get("key0199").unwrap(), Some(String::from("val199")));
+        let range: Vec<String> = reader.range("key0010", "key0013").unwrap().into_iter().map(|(k, _)| k).collect();
+        assert_eq!(range, vec!["key0010", "key0011", "key0012", "key0013"]);
+
+        // the sorted file can be merged like any other
+        let mergedfname = sstfpath.path().join(format!("test_{}.sst", rng.gen::<u32>()));
+        let mut merged = SSTableFileBuilder::new(&mergedfname).unwrap();
+        merged.merge_files_dedup(&[&sstfname]).unwrap();
+        merged.commit().unwrap();
+        assert_eq!(SSTableFileReader::open(&mergedfname).unwrap().iter_entries().count(), 201);
     }
 
-    // this function merges another SSTable to the current file
-    pub fn merge_file(&mut self, path: &Path) -> Result<(), io::Error> {
-        let reader = SSTableFileReader::open(path)?;
+    #[test]
+    fn sstable_estimate_file_size() {
+        let mut rng = rand::thread_rng();
+        let sstfpath = Builder::new().prefix("rustydb_sstable_test").tempdir().unwrap();
+        let sstfname = sstfpath.path().join(format!("test_{}.sst", rng.gen::<u32>()));
+        let mut writer = SSTableFileBuilder::new(&sstfname).unwrap();
 
-        // insert all pairs into the current file
-        for (key, val) in reader.iter() {
-            self.add(key.as_str(), val.as_str())?;
+        for i in 0..1000 {
+            let key = format!("cpu,host=host_{:04},region=us-west", i);
+            let val = format!("{}", rng.gen::<f64>()).repeat(rng.gen_range(1, 8));
+            writer.add(&key, &val).unwrap();
         }
-        Ok(())
+
+        let estimate = writer.estimate_file_size() as f64;
+        writer.commit().unwrap();
+        let actual = fs::metadata(&sstfname).unwrap().len() as f64;
+        assert!((actual - estimate).abs() <= actual * 0.05);
     }
 
-    // we finish building the SSTable file, close and commit it
-    // after this, the SSTable becomes immutable
-    pub fn commit(&mut self) -> Result<(), io::Error> {
-        let index_loc = self.bytes_written as u32;
-        for (k, v) in &self.index {
-            let keybytes = k.as_bytes();
-            self.writer.write_u32::<LittleEndian>(keybytes.len() as u32)?;
-            self.writer.write_all(keybytes)?;
-            self.writer.write_u32::<LittleEndian>(*v)?;
+    #[test]
+    fn sstable_stats() {
+        let mut rng = rand::thread_rng();
+        let sstfpath = Builder::new().prefix("rustydb_sstable_test").tempdir().unwrap();
+        let sstfname = sstfpath.path().join(format!("test_{}.sst", rng.gen::<u32>()));
+        let mut writer = SSTableFileBuilder::new(&sstfname).unwrap();
+        for i in 0..5 {
+            writer.add(&format!("key{}", i), &format!("val{}", i)).unwrap();
         }
+        writer.commit().unwrap();
 
-        // write footer
-        self.writer.write_u32::<LittleEndian>(self.index.len() as u32)?;
-        self.writer.write_u32::<LittleEndian>(index_loc as u32)?;
+        // 5 hits and 5 misses
+        let reader = SSTableFileReader::open(&sstfname).unwrap();
+        for i in 0..10 {
+            let val = reader.get(&format!("key{}", i)).unwrap();
+            assert_eq!(val.is_some(), i < 5);
+        }
 
-        self.writer.flush()?;
-        Ok(())
+        let stats = reader.stats();
+        assert_eq!(stats.num_get_calls, 10);
+        assert_eq!(stats.num_get_hits, 5);
+        assert_eq!(stats.num_iter_calls, 0);
+        // keylen, vallen and a 4 byte value per hit
+        assert_eq!(stats.bytes_read, 5 * 12);
+
+        assert_eq!(reader.iter().count(), 5);
+        let stats = reader.stats();
+        assert_eq!(stats.num_iter_calls, 1);
+        assert_eq!(stats.bytes_read, 5 * 12 + 5 * 16);
     }
-}
-
-#[cfg(test)]
-mod tests {
-    use crate::storage::sstable::*;
-    use tempfile::Builder;
-    use rand::prelude::*;
 
     #[test]
-    fn sstable_single_entry() {
+    fn sstable_stats_footer() {
         let mut rng = rand::thread_rng();
         let sstfpath = Builder::new().prefix("rustydb_sstable_test").tempdir().unwrap();
         let sstfname = sstfpath.path().join(format!("test_{}.sst", rng.gen::<u32>()));
         let mut writer = SSTableFileBuilder::new(&sstfname).unwrap();
+        let pairs = vec![("be", "p"), ("foo", "bar"), ("meemu", "mauha"), ("zoohoo", "keefuu")];
+        for (key, val) in &pairs {
+            writer.add(key, val).unwrap();
+        }
+        writer.commit().unwrap();
 
-        writer.add("foo", "bar").unwrap();
+        let reader = SSTableFileReader::open(&sstfname).unwrap();
+        let footer = reader.stats_footer().unwrap();
+        let expected = StatsFooter { num_entries: 4, total_value_bytes: 1 + 3 + 5 + 6, tree_position: Some((0, 0)) };
+        assert_eq!(footer, expected);
+        assert_eq!(reader.get("meemu").unwrap(), Some(String::from("mauha")));
+
+        // along with where the tree placed the file
+        let placed = sstfpath.path().join(format!("test_{}.sst", rng.gen::<u32>()));
+        let mut writer = SSTableFileBuilder::new(&placed).unwrap();
+        writer.set_tree_position(7, 2);
+        for (key, val) in &pairs {
+            writer.add(key, val).unwrap();
+        }
         writer.commit().unwrap();
+        let reader = SSTableFileReader::open(&placed).unwrap();
+        assert_eq!(reader.stats_footer().unwrap().tree_position, Some((7, 2)));
+        assert!(reader.bloom_filter().unwrap().may_contain("meemu"));
 
-        let mut reader = SSTableFileReader::open(&sstfname).unwrap();
-        assert_eq!(reader.get("foo").unwrap(), Some("bar".to_string()));
+        // files without a stats section are still readable
+        let oldfname = sstfpath.path().join(format!("test_{}.sst", rng.gen::<u32>()));
+        let mut writer = SSTableFileBuilder::new(&oldfname).unwrap();
+        for (key, val) in &pairs {
+            writer.add(key, val).unwrap();
+        }
+        let indexbuf = writer.encode_index().unwrap();
+        writer.write_tail(&indexbuf).unwrap();
+        // written by hand, not to be committed on drop
+        writer.committed = true;
+        drop(writer);
+        let mut contents = fs::read(&oldfname).unwrap();
+        contents[SSTABLE_MAGIC.len()..HEADER_SIZE].copy_from_slice(&(SSTABLE_VERSION | PREFIX_INDEX_FLAG).to_le_bytes());
+        fs::write(&oldfname, &contents).unwrap();
+
+        let reader = SSTableFileReader::open(&oldfname).unwrap();
+        assert_eq!(reader.stats_footer(), None);
+        assert_eq!(reader.get("meemu").unwrap(), Some(String::from("mauha")));
     }
 
     #[test]
-    fn sstable_multiple_entries() {
+    fn sstable_bloom_filter() {
+        let num = 3000;
         let mut rng = rand::thread_rng();
         let sstfpath = Builder::new().prefix("rustydb_sstable_test").tempdir().unwrap();
         let sstfname = sstfpath.path().join(format!("test_{}.sst", rng.gen::<u32>()));
         let mut writer = SSTableFileBuilder::new(&sstfname).unwrap();
 
+        let mut keys: Vec<String> = (0..num).map(|_| {
+            let rkey: [char; 16] = rng.gen();
+            rkey.iter().collect()
+        }).collect();
+        keys.sort();
+        keys.dedup();
+        for key in &keys {
+            writer.add(key, "v").unwrap();
+        }
+        writer.commit().unwrap();
+
+        // no false negatives
+        let reader = SSTableFileReader::open(&sstfname).unwrap();
+        let bloom = reader.bloom_filter().unwrap();
+        for key in &keys {
+            assert!(bloom.may_contain(key));
+            assert_eq!(reader.get(key).unwrap(), Some(String::from("v")));
+        }
+
+        // and few false positives
+        let false_positives = (0..num)
+            .filter(|i| bloom.may_contain(&format!("absent{}", i)))
+            .count();
+        assert!(false_positives < num / 20);
+        assert_eq!(reader.get("absent").unwrap(), None);
+
+        // without a bloom filter misses still read as None
+        let nobloomfname = sstfpath.path().join(format!("test_{}.sst", rng.gen::<u32>()));
+        let mut writer = SSTableFileBuilder::new(&nobloomfname).unwrap();
+        writer.set_bloom_bits_per_key(0);
         writer.add("foo", "bar").unwrap();
-        writer.add("zoohoo", "keefuu").unwrap();
-        writer.add("meemu", "mauha").unwrap();
-        writer.add("be", "p").unwrap();
         writer.commit().unwrap();
 
-        let mut reader = SSTableFileReader::open(&sstfname).unwrap();
-        assert_eq!(reader.get("foo").unwrap(), Some("bar".to_string()));
-        assert_eq!(reader.get("zoohoo").unwrap(), Some("keefuu".to_string()));
-        assert_eq!(reader.get("meemu").unwrap(), Some("mauha".to_string()));
-        assert_eq!(reader.get("be").unwrap(), Some("p".to_string()));
+        let reader = SSTableFileReader::open(&nobloomfname).unwrap();
+        assert_eq!(reader.bloom_filter(), None);
+        assert_eq!(reader.stats_footer().unwrap().num_entries, 1);
+        assert_eq!(reader.get("foo").unwrap(), Some(String::from("bar")));
+        assert_eq!(reader.get("absent").unwrap(), None);
     }
 
     #[test]
-    fn sstable_random_entries() {
-        let num = 100;
+    fn sstable_large_index() {
+        let num = 100_000;
         let mut rng = rand::thread_rng();
         let sstfpath = Builder::new().prefix("rustydb_sstable_test").tempdir().unwrap();
         let sstfname = sstfpath.path().join(format!("test_{}.sst", rng.gen::<u32>()));
         let mut writer = SSTableFileBuilder::new(&sstfname).unwrap();
+        for i in 0..num {
+            writer.add(&format!("key{:08}", i * 2), &format!("val{}", i)).unwrap();
+        }
+        writer.commit().unwrap();
 
-        // generate random keys and values
-        let mut keys: Vec<String> = Vec::new();
-        let mut vals: Vec<String> = Vec::new();
-
-        writer.add("foo", "bar").unwrap();
-        writer.add("zoohoo", "keefuu").unwrap();
-        writer.add("meemu", "mauha").unwrap();
-        writer.add("be", "p").unwrap();
-        for _ in 0..num {
-            let rkey: [char; 32] = rng.gen();
-            let key: String = rkey.into_iter().collect();
-            keys.push(key.clone());
+        // the index takes one slot per entry
+        let reader = SSTableFileReader::open(&sstfname).unwrap();
+        assert_eq!(reader.index.len(), num);
 
-            let rval: [char; 32] = rng.gen();
-            let val: String = rval.into_iter().collect();
-            vals.push(val.clone());
-            writer.add(&key, &val).unwrap();
+        for i in (0..num).step_by(10) {
+            assert_eq!(reader.get(&format!("key{:08}", i * 2)).unwrap(), Some(format!("val{}", i)));
+            assert_eq!(reader.get(&format!("key{:08}", i * 2 + 1)).unwrap(), None);
         }
+
+        assert_eq!(reader.last_key_in_range((Bound::Included("key00000101"), Bound::Excluded("key00000200"))), Some(String::from("key00000198")));
+        assert_eq!(reader.last_key_in_range((Bound::Unbounded, Bound::Included("key"))), None);
+        let entries = reader.range("key00001000", "key00001009").unwrap();
+        let keys: Vec<&str> = entries.iter().map(|(k, _)| k.as_str()).collect();
+        assert_eq!(keys, vec!["key00001000", "key00001002", "key00001004", "key00001006", "key00001008"]);
+
+        // a key added twice reads as the later value
+        let dupfname = sstfpath.path().join(format!("test_{}.sst", rng.gen::<u32>()));
+        let mut writer = SSTableFileBuilder::new(&dupfname).unwrap();
+        writer.add("be", "p").unwrap();
+        writer.add("foo", "bar").unwrap();
+        writer.add("foo", "baz").unwrap();
         writer.commit().unwrap();
 
-        // verify
-        let mut reader = SSTableFileReader::open(&sstfname).unwrap();
-        for i in 0..num {
-            assert_eq!(reader.get(&keys[i]).unwrap(), Some(vals[i].clone()));
-        }
+        let reader = SSTableFileReader::open(&dupfname).unwrap();
+        assert_eq!(reader.get("foo").unwrap(), Some(String::from("baz")));
+        assert_eq!(reader.get("be").unwrap(), Some(String::from("p")));
+        let entries: Vec<(String, String)> = reader.range("a", "z").unwrap().into_iter()
+            .map(|(k, v)| (k, v.value().unwrap()))
+            .collect();
+        assert_eq!(entries, vec![(String::from("be"), String::from("p")), (String::from("foo"), String::from("baz"))]);
     }
 
     #[test]
-    fn sstable_simple_iter() {
+    fn sstable_iter_reverse() {
+        let num = 100;
         let mut rng = rand::thread_rng();
         let sstfpath = Builder::new().prefix("rustydb_sstable_test").tempdir().unwrap();
         let sstfname = sstfpath.path().join(format!("test_{}.sst", rng.gen::<u32>()));
         let mut writer = SSTableFileBuilder::new(&sstfname).unwrap();
 
-        // list of pairs for testing
-        let pairs = vec![("foo", "bar"), ("zoohoo", "keefuu"), ("meemu", "mauha"), ("be", "p")];
-        
+        let mut pairs: Vec<(String, String)> = (0..num)
+            .map(|i| (format!("key{:03}", i), format!("val{}", rng.gen::<u32>())))
+            .collect();
         for (key, val) in &pairs {
             writer.add(key, val).unwrap();
         }
         writer.commit().unwrap();
 
-        // verify
         let reader = SSTableFileReader::open(&sstfname).unwrap();
-        for (entry, record) in reader.iter().zip(pairs.iter()) {
-            let (key, val) = entry;
-            assert_eq!((key.as_str(), val.as_str()), *record);
-        }
+        let reversed: Vec<(String, String)> = reader.iter_reverse().collect();
+        pairs.reverse();
+        assert_eq!(reversed, pairs);
     }
 
     #[test]
-    fn sstable_random_iter() {
-        let num = 100;
+    fn sstable_range() {
         let mut rng = rand::thread_rng();
         let sstfpath = Builder::new().prefix("rustydb_sstable_test").tempdir().unwrap();
         let sstfname = sstfpath.path().join(format!("test_{}.sst", rng.gen::<u32>()));
         let mut writer = SSTableFileBuilder::new(&sstfname).unwrap();
 
-        // generate random keys and values
-        let mut rand_pairs: Vec<(String, String)> = Vec::new();
-        for _ in 0..num {
-            let rkey: [char; 32] = rng.gen();
-            let key: String = rkey.into_iter().collect();
-            
-            let rval: [char; 32] = rng.gen();
-            let val: String = rval.into_iter().collect();
-            
-            writer.add(&key, &val).unwrap();
-            rand_pairs.push((key, val));
+        let pairs: Vec<(String, String)> = (0..100)
+            .map(|i| (format!("key{:03}", i), format!("val{}", i)))
+            .collect();
+        for (key, val) in &pairs {
+            writer.add(key, val).unwrap();
         }
         writer.commit().unwrap();
 
-        // verify
         let reader = SSTableFileReader::open(&sstfname).unwrap();
-        for (entry, record) in reader.iter().zip(rand_pairs.iter()) {
-            assert_eq!(entry, *record);
-        }
+        let range = |start: &str, end: &str| -> Vec<(String, String)> {
+            reader.range(start, end).unwrap().into_iter()
+                .map(|(key, val)| (key, val.value().unwrap()))
+                .collect()
+        };
+        assert_eq!(range("key010", "key019"), pairs[10..20].to_vec());
+        assert_eq!(range("key0955", "zzz"), pairs[96..].to_vec());
+        assert!(reader.range("a", "b").unwrap().is_empty());
     }
 
     #[test]
-    fn sstable_chain_two() {
+    fn sstable_iter_range() {
         let mut rng = rand::thread_rng();
-
-        // first SSTable file
         let sstfpath = Builder::new().prefix("rustydb_sstable_test").tempdir().unwrap();
-        let sstfname1 = sstfpath.path().join(format!("test_{}.sst", rng.gen::<u32>()));
-        let mut sst1 = SSTableFileBuilder::new(&sstfname1).unwrap();
-
-        // second SSTable file
-        let sstfname2 = sstfpath.path().join(format!("test_{}.sst", rng.gen::<u32>()));
-        let mut sst2 = SSTableFileBuilder::new(&sstfname2).unwrap();
-        
-        // list of pairs for testing
-        let pairs = vec![("foo", "bar"), ("zoohoo", "keefuu"), ("meemu", "mauha"), ("be", "p")];
-
-        // sstable 1 takes the first 2
-        for entry in pairs.iter().take(2) {
-            let (key, val) = *entry;
-            sst1.add(key, val).unwrap();
+        let sstfname = sstfpath.path().join(format!("test_{}.sst", rng.gen::<u32>()));
+        let mut writer = SSTableFileBuilder::new(&sstfname).unwrap();
+        for i in 10..20 {
+            writer.add(&format!("key{}", i), &format!("val{}", i)).unwrap();
         }
-        sst1.commit().unwrap();
+        writer.add_tombstone("key20").unwrap();
+        writer.commit().unwrap();
 
-        // sstable 2 takes the rest
-        for entry in pairs.iter().skip(2) {
-            let (key, val) = *entry;
-            sst2.add(key, val).unwrap();
-        }
-        sst2.commit().unwrap();
+        let reader = SSTableFileReader::open(&sstfname).unwrap();
+        let keys = |start: &str, end: &str| -> Vec<String> {
+            reader.iter_range(start, end).map(|(k, _)| k).collect()
+        };
 
-        // merge sst1 and sst2 to a compacted new sst
-        let newsstfpath = sstfpath.path().join(format!("test_{}.sst", rng.gen::<u32>()));
-        let mut newsst = SSTableFileBuilder::new(&newsstfpath).unwrap();
+        // starting before the first key
+        assert_eq!(keys("a", "key12"), vec!["key10", "key11", "key12"]);
+        // ending after the last, tombstones included
+        assert_eq!(keys("key185", "z"), vec!["key19", "key20"]);
+        // entirely within
+        assert_eq!(keys("key135", "key16"), vec!["key14", "key15", "key16"]);
+        assert_eq!(keys("a", "z").len(), 11);
+        // outside or empty
+        assert!(keys("a", "b").is_empty());
+        assert!(keys("z", "zz").is_empty());
+        assert!(keys("key15", "key14").is_empty());
+
+        let (key, val) = reader.iter_range("key13", "key13").next().unwrap();
+        assert_eq!((key.as_str(), val), ("key13", SSTableValue::Value(String::from("val13"))));
+
+        // only the entries in range are read
+        let before = reader.stats().bytes_read;
+        assert_eq!(reader.iter_range("key11", "key11").count(), 1);
+        let one_entry = reader.stats().bytes_read - before;
+        assert_eq!(reader.iter_range("key11", "key12").count(), 2);
+        assert_eq!(reader.stats().bytes_read - before, 3 * one_entry);
+    }
 
-        newsst.merge_file(&sstfname1).unwrap();
-        newsst.merge_file(&sstfname2).unwrap();
-        newsst.commit().unwrap();
+    #[test]
+    fn sstable_tombstone() {
+        let mut rng = rand::thread_rng();
+        let sstfpath = Builder::new().prefix("rustydb_sstable_test").tempdir().unwrap();
+        let sstfname = sstfpath.path().join(format!("test_{}.sst", rng.gen::<u32>()));
+        let mut writer = SSTableFileBuilder::new(&sstfname).unwrap();
 
-        // verify the new sstable file is correct
-        let reader = SSTableFileReader::open(&newsstfpath).unwrap();
-        for (entry, record) in reader.iter().zip(pairs.iter()) {
-            let (key, val) = entry;
-            assert_eq!((key.as_str(), val.as_str()), *record);
-        }
+        writer.add("be", "p").unwrap();
+        writer.add_tombstone("foo").unwrap();
+        writer.add("meemu", "mauha").unwrap();
+        writer.commit().unwrap();
+
+        let reader = SSTableFileReader::open(&sstfname).unwrap();
+        assert_eq!(reader.get("foo").unwrap(), None);
+        assert_eq!(reader.get_entry("foo").unwrap(), Some(SSTableValue::Tombstone));
+        assert_eq!(reader.get_entry("zoohoo").unwrap(), None);
+        assert_eq!(reader.get("meemu").unwrap(), Some(String::from("mauha")));
+
+        let keys: Vec<String> = reader.iter().map(|(k, _)| k).collect();
+        assert_eq!(keys, vec!["be", "meemu"]);
+        let entries: Vec<(String, SSTableValue<String>)> = reader.iter_entries().collect();
+        assert_eq!(entries[1], (String::from("foo"), SSTableValue::Tombstone));
+        assert_eq!(entries.len(), 3);
+        let owned: Vec<(String, SSTableValue<String>)> = SSTableFileReader::open(&sstfname).unwrap()
+            .into_entries().unwrap().collect();
+        assert_eq!(owned, entries);
+
+        // merging keeps the tombstone
+        let mergedfname = sstfpath.path().join(format!("test_{}.sst", rng.gen::<u32>()));
+        let mut writer = SSTableFileBuilder::new(&mergedfname).unwrap();
+        writer.merge_file(&sstfname).unwrap();
+        writer.commit().unwrap();
+        let reader = SSTableFileReader::open(&mergedfname).unwrap();
+        assert_eq!(reader.get_entry("foo").unwrap(), Some(SSTableValue::Tombstone));
     }
 
     #[test]
-    fn sstable_chain_random() {
-        let num_pairs: i32 = 100;
-        let num_ssts: i32 = 10;
-
-        // how much pairs a single sstable should take
-        let chunk_size = num_pairs / num_ssts;
+    fn sstable_block_compression() {
         let mut rng = rand::thread_rng();
-
-        // generate random keys and values
-        let mut rand_pairs: Vec<(String, String)> = Vec::new();
-        for _ in 0..num_pairs {
-            let rkey: [char; 32] = rng.gen();
-            let key: String = rkey.into_iter().collect();
-            
-            let rval: [char; 32] = rng.gen();
-            let val: String = rval.into_iter().collect();
-            rand_pairs.push((key, val));
+        let sstfpath = Builder::new().prefix("rustydb_sstable_test").tempdir().unwrap();
+        let plainfname = sstfpath.path().join(format!("test_{}.sst", rng.gen::<u32>()));
+        let sstfname = sstfpath.path().join(format!("test_{}.sst", rng.gen::<u32>()));
+        let mut plain = SSTableFileBuilder::new(&plainfname).unwrap();
+        let mut writer: SSTableFileBuilder = SSTableFileBuilder::with_block_compression(
+            &sstfname, Box::new(SnappyCompressor), 4096).unwrap();
+
+        // repetitive values, as string encoded series are
+        let pairs: Vec<(String, String)> = (0..1000)
+            .map(|i| (format!("cpu,host=host_{:04}", i), format!("usage_user={},", i % 7).repeat(16)))
+            .collect();
+        for (key, val) in &pairs {
+            plain.add(key, val).unwrap();
+            writer.add(key, val).unwrap();
         }
+        writer.add_tombstone("cpu,host=host_9999").unwrap();
+        let estimate = writer.estimate_file_size();
+        plain.commit().unwrap();
+        writer.commit().unwrap();
 
-        // the final sstable file
-        let sstfpath = Builder::new().prefix("rustydb_sstable_test").tempdir().unwrap();
-        let newsstfpath = sstfpath.path().join(format!("test_{}.sst", rng.gen::<u32>()));
-        let mut newsst = SSTableFileBuilder::new(&newsstfpath).unwrap();
+        let filesize = fs::metadata(&sstfname).unwrap().len() as usize;
+        assert!(filesize < fs::metadata(&plainfname).unwrap().len() as usize / 2);
+        assert!(estimate >= filesize);
 
-        // make "num_ssts" sstable files, filled with chunks of data, then
-        // merge into the final sstable file
-        for chunk in rand_pairs.chunks(chunk_size as usize) {
-            let sstfname = sstfpath.path().join(format!("test_{}.sst", rng.gen::<u32>()));
-            let mut sst = SSTableFileBuilder::new(&sstfname).unwrap();
+        let reader = SSTableFileReader::open(&sstfname).unwrap();
+        assert!(reader.num_blocks() > 1);
+        assert_eq!(SSTableFileReader::open(&plainfname).unwrap().num_blocks(), 0);
 
-            // add these specific chunk of data to new sstable, then commit
-            for entry in chunk {
-                let (key, val) = &*entry;
-                sst.add(&key, &val).unwrap();
-            }
-            sst.commit().unwrap();
+        // gets in the first, the last and the blocks in between
+        for i in [0, 1, 499, 500, 998, 999] {
+            assert_eq!(reader.get(&pairs[i].0).unwrap(), Some(pairs[i].1.clone()));
+        }
+        for _ in 0..100 {
+            let (key, val) = &pairs[rng.gen_range(0, pairs.len())];
+            assert_eq!(reader.get(key).unwrap().as_ref(), Some(val));
+        }
+        assert_eq!(reader.get("cpu,host=host_0000x").unwrap(), None);
+        assert_eq!(reader.get_entry("cpu,host=host_9999").unwrap(), Some(SSTableValue::Tombstone));
+
+        // iterating goes through every block in order
+        let entries: Vec<(String, String)> = reader.iter().collect();
+        assert_eq!(entries, pairs);
+        assert_eq!(reader.iter_entries().count(), 1001);
+        let keys: Vec<String> = reader.iter_reverse().map(|(k, _)| k).take(2).collect();
+        assert_eq!(keys, vec![pairs[999].0.clone(), pairs[998].0.clone()]);
+
+        // ranges across a block boundary
+        let range: Vec<String> = reader.iter_range(&pairs[100].0, &pairs[899].0).map(|(k, _)| k).collect();
+        assert_eq!(range.len(), 800);
+        assert_eq!(reader.range(&pairs[100].0, &pairs[899].0).unwrap().len(), 800);
+
+        // merging into an uncompressed file reads it back the same
+        let mergedfname = sstfpath.path().join(format!("test_{}.sst", rng.gen::<u32>()));
+        let mut merged = SSTableFileBuilder::new(&mergedfname).unwrap();
+        merged.merge_file(&sstfname).unwrap();
+        merged.commit().unwrap();
+        let merged: Vec<(String, String)> = SSTableFileReader::open(&mergedfname).unwrap().iter().collect();
+        assert_eq!(merged, pairs);
+
+        // blocks stored without compression
+        let rawfname = sstfpath.path().join(format!("test_{}.sst", rng.gen::<u32>()));
+        let mut writer: SSTableFileBuilder = SSTableFileBuilder::with_block_compression(
+            &rawfname, Box::new(NoCompressor), DEFAULT_BLOCK_SIZE).unwrap();
+        for (key, val) in &pairs {
+            writer.add(key, val).unwrap();
+        }
+        writer.commit().unwrap();
+        let reader = SSTableFileReader::open(&rawfname).unwrap();
+        assert!(reader.num_blocks() > 1);
+        assert_eq!(reader.get(&pairs[700].0).unwrap(), Some(pairs[700].1.clone()));
+        assert_eq!(reader.iter().count(), 1000);
+    }
 
-            // merage the new sstable
-            newsst.merge_file(&sstfname).unwrap();
+    // the file is deleted once the readers are open, gets that reopened it
+    // would fail
+    #[cfg(unix)]
+    #[test]
+    fn sstable_get_keeps_file_open() {
+        let mut rng = rand::thread_rng();
+        let sstfpath = Builder::new().prefix("rustydb_sstable_test").tempdir().unwrap();
+        let plainfname = sstfpath.path().join(format!("test_{}.sst", rng.gen::<u32>()));
+        let sstfname = sstfpath.path().join(format!("test_{}.sst", rng.gen::<u32>()));
+        let mut plain = SSTableFileBuilder::new(&plainfname).unwrap();
+        let mut writer: SSTableFileBuilder = SSTableFileBuilder::with_block_compression(
+            &sstfname, Box::new(SnappyCompressor), 4096).unwrap();
+        for i in 0..1000 {
+            plain.add(&format!("key{:04}", i), &format!("val{}", i)).unwrap();
+            writer.add(&format!("key{:04}", i), &format!("val{}", i)).unwrap();
         }
-        newsst.commit().unwrap();
+        plain.commit().unwrap();
+        writer.commit().unwrap();
 
-        // verify
-        let reader = SSTableFileReader::open(&newsstfpath).unwrap();
-        for (entry, record) in reader.iter().zip(rand_pairs.iter()) {
-            assert_eq!(entry, *record);
+        let readers = [SSTableFileReader::open(&plainfname).unwrap(), SSTableFileReader::open(&sstfname).unwrap()];
+        fs::remove_file(&plainfname).unwrap();
+        fs::remove_file(&sstfname).unwrap();
+        for reader in &readers {
+            for i in 0..1000 {
+                assert_eq!(reader.get(&format!("key{:04}", i)).unwrap(), Some(format!("val{}", i)));
+            }
+            assert_eq!(reader.get("key1000").unwrap(), None);
+            assert_eq!(reader.stats().num_get_hits, 1000);
         }
     }
 }
-
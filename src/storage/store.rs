@@ -10,12 +10,19 @@
 use crate::storage::lsmtree::*;
 use crate::storage::sstable::*;
 use crate::storage::wal::*;
+use crate::storage::codec::Codec;
+use crate::storage::error::Error;
+use crate::gorilla::{GorillaBlock, GorillaDateTime, MVEntry};
+use crate::gorilla::api::retrieve_values;
 
-use std::io;
+use chrono::{TimeZone, Utc};
+
+use std::mem;
 use std::thread;
-use std::time::SystemTime;
+use std::time::{Duration, SystemTime};
 use std::path::{Path, PathBuf};
-use std::sync::{Arc, Mutex, Condvar};
+use std::collections::HashMap;
+use std::sync::{mpsc, Arc, Mutex, MutexGuard, Condvar};
 
 // -------------------- RustyStore --------------------
 
@@ -23,119 +30,378 @@ use std::sync::{Arc, Mutex, Condvar};
 pub struct RustyStore {
     tree: Arc<Mutex<LSMTree>>,
 
-    // write ahead log
-    wal: WALWriter,
+    // write ahead log, shared with the compaction thread which truncates it
+    // once a memtable is flushed
+    wal: Arc<Mutex<WALWriter>>,
     num_wal_entries: usize,
+    // bytes of WAL records that trigger a flush, see 'LSMConfig'
+    wal_threshold: usize,
 
     // ---------- coordinate threads -----------
-    // The compaction thread will wait on this cond, and when an insertion causes
-    // an overflow, the main thread set the bool to True to wake up compaction thread
-    need_compact_cond: Arc<(Mutex<bool>, Condvar)>,
+    // when an insertion overflows the memtable, the main thread hands the
+    // memtable to the compaction thread through this channel, along with the
+    // sequence number of the last WAL record in it, and keeps writing to the
+    // buffered memtable
+    flush_sender: mpsc::Sender<(PendingFlush, u64)>,
+
+    // joined once the store is dropped, so the tree is done with its files
+    // before another store opens them
+    compaction_thread: Option<thread::JoinHandle<()>>,
 
-    // when the main thread wakes up compaction thread, we set this to False and wait
+    // when the main thread hands over a memtable, we set this to False
     // the compaction thread will set this to True once finished
-    // TODO: change this non-concurrent behavior
     compact_finish_cond: Arc<(Mutex<bool>, Condvar)>,
+
+    // the codecs of the series blocks, by the id their values start with
+    codecs: HashMap<u8, Box<dyn Codec>>,
 }
 
 impl RustyStore {
-    pub fn new(path: &Path) -> Result<Self, io::Error> {
-        let mut lsmtree = LSMTree::new(path)?;
+    pub fn new(path: &Path) -> Result<Self, Error> {
+        Self::with_config(path, LSMConfig::default())
+    }
+
+    // same as 'new', with the tunables of the tree
+    pub fn with_config(path: &Path, config: LSMConfig) -> Result<Self, Error> {
+        let mut lsmtree = LSMTree::with_config(path, config)?;
 
         // on start up, we search for WAL file under storage root
         // if a WAL file present, we do the following:
-        // 1. read each entry from WAL file, and re-insert them into memtable
+        // 1. read each entry after the last checkpoint from WAL file, up to
+        //    the first truncated record and skipping the ones that fail their
+        //    checksum, and re-insert them into memtable
         // 2. flush the memtable to disk as a new L0 SSTable file
-        // 3. reset the WAL file
-        for (_, key, val) in WALReader::new(&path)? {
-            lsmtree.set(&key, &val)?;
+        // 3. start a new WAL in place of the old segments
+        let mut walreader = WALReader::new(path)?;
+        let replayed = match walreader.replay() {
+            Ok(replayed) => replayed,
+            Err(WalError::Corrupt { offset, recovered }) => {
+                println!("WAL is unreadable from byte {}, dropping the rest of it", offset);
+                recovered
+            },
+            Err(WalError::Io(e)) => return Err(e.into()),
+        };
+        if !replayed.is_empty() || walreader.skipped() > 0 {
+            println!("Recovered {} WAL records, skipped {}", replayed.len(), walreader.skipped());
+        }
+        for (_, key, val) in &replayed {
+            match val {
+                Some(val) => lsmtree.set_bytes(key, val)?,
+                None => lsmtree.delete(key)?,
+            }
         }
 
         // flush the recovered WAL records to disk
-        lsmtree.flush_memtable()?;
+        if !replayed.is_empty() {
+            lsmtree.flush_memtable()?;
+        }
 
         // initially we don't start compact right away
         let newtree = Arc::new(Mutex::new(lsmtree));
-        let need_compact = Arc::new((Mutex::new(false), Condvar::new()));
+        let (flush_sender, flush_receiver) = mpsc::channel();
         let compact_finish = Arc::new((Mutex::new(true), Condvar::new()));
 
-        Self::start_compaction_thread(newtree.clone(), need_compact.clone(), compact_finish.clone());
+        // 'set' still flushes every record, 'set_batch' once per batch
+        let mut wal = WALWriter::new(path)?;
+        wal.set_sync_policy(SyncPolicy::SyncOnBatch);
+        let wal = Arc::new(Mutex::new(wal));
+
+        let compaction_thread = Self::start_compaction_thread(newtree.clone(), wal.clone(), flush_receiver,
+                                                              compact_finish.clone());
 
         Ok(Self {
             tree: newtree,
-            wal: WALWriter::new(path)?,
+            wal,
             num_wal_entries: 0,
-            need_compact_cond: need_compact,
+            wal_threshold: config.wal_threshold,
+            flush_sender,
+            compaction_thread: Some(compaction_thread),
             compact_finish_cond: compact_finish,
+            codecs: HashMap::new(),
         })
     }
 
+    // the thread ends once the store is dropped
     fn start_compaction_thread(tree: Arc<Mutex<LSMTree>>,
-                               need_compact_cond: Arc<(Mutex<bool>, Condvar)>,
+                               wal: Arc<Mutex<WALWriter>>,
+                               flush_receiver: mpsc::Receiver<(PendingFlush, u64)>,
                                compact_finish_cond: Arc<(Mutex<bool>, Condvar)>)
+        -> thread::JoinHandle<()>
     {
         thread::spawn(move || {
-            // wait condition
-            let (need_compact_bool, cvar) = &*need_compact_cond;
-            let mut need_compact = need_compact_bool.lock().unwrap();
-            // wait until notified by the main thread
-            while !*need_compact {
-                need_compact = cvar.wait(need_compact).unwrap();
+            // wait until the main thread hands over a memtable
+            for (pending, last_seq) in flush_receiver {
+                // write the memtable to disk as an SSTable file, the tree
+                // isn't locked meanwhile so writes and reads go on
+                println!("Compaction thread wakes up");
+                let written = pending.write();
+
+                // lock the tree to add the sstable
+                // TODO: may only need to lock certain components of the tree
+                let mut lsmtree = tree.lock().unwrap();
+                let flushed = match written {
+                    Ok(()) => lsmtree.finish_flush(pending),
+                    Err(e) => {
+                        lsmtree.abort_flush(pending);
+                        Err(e)
+                    },
+                };
+                let durable = flushed.is_ok();
+                if let Err(e) = flushed.and_then(|_| lsmtree.compact()) {
+                    println!("Compaction failed: {}", e);
+                }
+                drop(lsmtree);
+
+                // the records of the flushed memtable no longer need the WAL,
+                // the tree isn't locked here as writers lock it before the WAL
+                if durable {
+                    if let Err(e) = wal.lock().unwrap().truncate(last_seq) {
+                        println!("WAL truncation failed: {}", e);
+                    }
+                }
+
+                // compaction finished
+                println!("Compaction finished");
+                let (compact_finish_bool, cvar) = &*compact_finish_cond;
+                let mut compact_finished = compact_finish_bool.lock().unwrap();
+                *compact_finished = true;
+                // We notify the condvar that the value has changed.
+                cvar.notify_all();
             }
+        })
+    }
 
-            // flush current LSMTree's memtable to disk as SSTable files
-            // lock the tree to prevent modifications
-            // TODO: may only need to lock certain components of the tree
-            
-            println!("Compaction thread wakes up");
-            let mut lsmtree = tree.lock().unwrap();
-            
-            // compaction finished
-            println!("Compaction finished");
-            let (compact_finish_bool, cvar) = &*compact_finish_cond;
-            let mut compact_finished = compact_finish_bool.lock().unwrap();
-            *compact_finished = true;
-            // We notify the condvar that the value has changed.
-            cvar.notify_one();
-        });
+    // stop writing the memtable and start a WAL segment for the records after
+    // it, the returned sequence number is the last WAL record in the memtable
+    fn begin_flush(lsmtree: &mut LSMTree, wal: &mut WALWriter) -> Result<(PendingFlush, u64), Error> {
+        wal.rotate()?;
+        let last_seq = wal.last_seq();
+        Ok((lsmtree.begin_flush()?, last_seq))
     }
 
-    // get a value by key
-    pub fn get(&self, key: &str) -> Result<Option<String>, io::Error> {
-        // TODO: the idea is to not block even if compaction is going
-        self.tree.lock().unwrap().get(key)
+    // whether the WAL grew past its threshold, a flush of the memtable lets
+    // it drop the records written so far
+    fn wal_full(&self, lsmtree: &LSMTree, wal: &WALWriter) -> bool {
+        self.wal_threshold > 0 && wal.bytes_written() > self.wal_threshold && lsmtree.can_flush()
     }
 
-    // add a kv pair to the database
-    pub fn set(&mut self, key: &str, val: &str) -> Result<(), io::Error> {
-        // if inserting the pair will cause the current memtable size reaches its limit
-        // then we need to:
-        // 1. if the compaction thread is running, we block and wait on a condvar
-        //    when the condvar is notified, we wake up and insert the pair
-        //    TODO: if the compaction thread takes a long time to run, would this make
-        //    "set" very slow? A possible better approach may be temporaily store the pair
-        //    in a "buffered" area and return directly, then let the compaction thread
-        //    to look after this buffer area as well. Subject to further thoughts.
-        // 2. if the compaction thread is sleeping, we notify a condvar to wake it up
-        //    then hand over the pair and let it compact altogether, this does not block.
-        
-        // wait compaction to finish
+    // hand a memtable over to the compaction thread
+    fn start_flush(&self, pending: (PendingFlush, u64)) {
+        let (compact_finish_bool, _) = &*self.compact_finish_cond;
+        *compact_finish_bool.lock().unwrap() = false;
+        self.flush_sender.send(pending).expect("the compaction thread outlives the store");
+    }
+
+    // lock the tree once the buffered memtable has room for the pair, the
+    // tree is not locked while waiting so the compaction thread can finish
+    fn lock_with_room(&self, key: &str, val: &[u8]) -> MutexGuard<'_, LSMTree> {
+        loop {
+            let lsmtree = self.tree.lock().unwrap();
+            if !lsmtree.buffer_full(key, val) {
+                return lsmtree;
+            }
+            drop(lsmtree);
+            self.wait_for_flush();
+        }
+    }
+
+    // block until the compaction thread is done with the memtable handed
+    // over last, if any
+    pub fn wait_for_flush(&self) {
         let (compact_finish_bool, cvar) = &*self.compact_finish_cond;
         let mut compact_finish = compact_finish_bool.lock().unwrap();
-        // wait until notified by the main thread
         while !*compact_finish {
             compact_finish = cvar.wait(compact_finish).unwrap();
         }
+    }
 
-        // commit to to WAL
-        let timestamp = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap();
-        self.wal.add(&timestamp, key, val)?;
+    // get a value by key
+    pub fn get(&self, key: &str) -> Result<Option<String>, Error> {
+        // TODO: the idea is to not block even if compaction is going
+        self.tree.lock().unwrap().get(key)
+    }
 
-        // lock the tree and insert the pair
-        let mut lsmtree = self.tree.lock().unwrap();
-        (*lsmtree).set(key, val);
+    // the entries of a series with a time in [start, end] in time order, see
+    // 'LSMTree::get_series'
+    // blocks written through a registered codec are decoded by it
+    pub fn query(&self, construct_key_hash: u64, start: GorillaDateTime, end: GorillaDateTime, dim: usize)
+        -> Result<Vec<MVEntry>, Error>
+    {
+        let values = self.tree.lock().unwrap().get_series_values(construct_key_hash, start, end)?;
+        let blocks = values.iter().filter_map(|val| self.decode_block(val, dim));
+        Ok(series_window(blocks, start, end))
+    }
+
+    // the entries of a stored block, written through a registered codec or
+    // a GorillaBlock stored without a codec id, None for any other value
+    fn decode_block(&self, val: &[u8], dim: usize) -> Option<Vec<MVEntry>> {
+        if let Some((id, bytes)) = val.split_first() {
+            if let Some(Ok(entries)) = self.codecs.get(id).map(|codec| codec.decode(bytes, dim)) {
+                return Some(entries);
+            }
+        }
+        let block = GorillaBlock::from_stored_bytes(val).ok()?;
+        let num_entries = block.num_entries(dim).ok()?;
+        Some(retrieve_values(block, dim, num_entries))
+    }
+
+    // make 'codec' the codec of the blocks whose values start with 'id'
+    // an id can only be registered once
+    pub fn register_codec(&mut self, id: u8, codec: Box<dyn Codec>) -> Result<(), Error> {
+        if self.codecs.contains_key(&id) {
+            return Err(Error::InvalidArgument(format!("codec id {} is already registered", id)));
+        }
+        self.codecs.insert(id, codec);
+        Ok(())
+    }
+
+    // store the block of 'dim' dimensional entries of a series under 'key',
+    // encoded by the codec registered under 'codec'
+    pub fn set_series(&mut self, key: &SeriesKey, codec: u8, entries: &[MVEntry], dim: usize) -> Result<(), Error> {
+        let encoder = self.codecs.get(&codec)
+            .ok_or_else(|| Error::InvalidArgument(format!("no codec registered under id {}", codec)))?;
+        let mut val = vec![codec];
+        val.extend(encoder.encode(Utc.timestamp_nanos(key.start_ts), entries, dim)?);
+        self.set_bytes(&key.to_key(), &val)
+    }
+
+    // add a kv pair to the database
+    pub fn set(&mut self, key: &str, val: &str) -> Result<(), Error> {
+        self.put(key, val.as_bytes(), Some(val))
+    }
+
+    // same as 'set' for a value that isn't necessarily valid utf8, such as a
+    // block serialized by 'GorillaBlock::to_bytes'
+    pub fn set_bytes(&mut self, key: &str, val: &[u8]) -> Result<(), Error> {
+        self.put(key, val, None)
+    }
+
+    // 'text' is 'val' when it was set as a string, watches only see those
+    fn put(&mut self, key: &str, val: &[u8], text: Option<&str>) -> Result<(), Error> {
+        // the tree refuses a pair too long for an sstable, it can't be logged
+        check_entry_len(key, val.len())?;
+
+        // if inserting the pair will cause the current memtable size reaches its limit,
+        // or the WAL grew past its threshold, then we hand the memtable over to the compaction thread and insert the
+        // pair into the buffered memtable, this does not block
+        // the buffered memtable takes every write until the compaction thread
+        // finishes flushing, then it becomes the memtable, if it fills up
+        // before that the write blocks until the flush is done
+
+        // lock the tree, commit to WAL and insert the pair, the WAL record is
+        // written once the memtable it goes to is known
+        let timestamp = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap();
+        let pending = {
+            let mut lsmtree = self.lock_with_room(key, val);
+            let mut wal = self.wal.lock().unwrap();
+            let pending = if lsmtree.need_flush(key, val) || self.wal_full(&lsmtree, &wal) {
+                Some(Self::begin_flush(&mut lsmtree, &mut wal)?)
+            } else {
+                None
+            };
+            wal.add_bytes(&timestamp, key, val)?;
+            match text {
+                Some(text) => lsmtree.set(key, text)?,
+                None => lsmtree.set_bytes(key, val)?,
+            }
+            pending
+        };
+        if let Some(pending) = pending {
+            self.start_flush(pending);
+        }
         Ok(())
     }    
+
+    // delete a key, the deletion is logged to the WAL first so it survives a
+    // crash like any 'set'
+    pub fn delete(&mut self, key: &str) -> Result<(), Error> {
+        check_entry_len(key, 0)?;
+        let timestamp = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap();
+        let pending = {
+            let mut lsmtree = self.lock_with_room(key, b"");
+            let mut wal = self.wal.lock().unwrap();
+            let pending = if lsmtree.need_flush(key, b"") || self.wal_full(&lsmtree, &wal) {
+                Some(Self::begin_flush(&mut lsmtree, &mut wal)?)
+            } else {
+                None
+            };
+            wal.delete(&timestamp, key)?;
+            lsmtree.delete(key)?;
+            pending
+        };
+        if let Some(pending) = pending {
+            self.start_flush(pending);
+        }
+        Ok(())
+    }
+
+    // add several kv pairs with a single WAL flush, after a crash either all
+    // of them or the ones written before the crash point are recovered
+    pub fn set_batch(&mut self, pairs: &[(&str, &str)]) -> Result<(), Error> {
+        for (key, val) in pairs {
+            check_entry_len(key, val.len())?;
+        }
+        let timestamp = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap();
+        let records: Vec<(Duration, &str, &str)> = pairs.iter()
+            .map(|(key, val)| (timestamp, *key, *val))
+            .collect();
+
+        // at most one memtable is handed over, the rest of the batch goes
+        // to the buffered memtable, which is only waited on for room for the
+        // first pair so a batch overfills it by at most the batch
+        let mut pending = None;
+        {
+            let mut lsmtree = match pairs.first() {
+                Some((key, val)) => self.lock_with_room(key, val.as_bytes()),
+                None => self.tree.lock().unwrap(),
+            };
+            let mut wal = self.wal.lock().unwrap();
+
+            // commit to WAL
+            let first_seq = wal.last_seq() + 1;
+            wal.add_batch(&records)?;
+
+            for (i, (key, val)) in pairs.iter().enumerate() {
+                if lsmtree.need_flush(key, val.as_bytes()) || self.wal_full(&lsmtree, &wal) {
+                    // the whole batch stays in the closed segment, which is
+                    // removed once the memtable after this one is flushed
+                    wal.rotate()?;
+                    pending = Some((lsmtree.begin_flush()?, first_seq + i as u64 - 1));
+                }
+                lsmtree.set(key, val)?;
+            }
+        }
+        if let Some(pending) = pending {
+            self.start_flush(pending);
+        }
+        Ok(())
+    }
+}
+
+// close the channel so the compaction thread ends after the memtables handed
+// over, and wait for it
+// the writes left in the memtable are flushed here rather than by the tree's
+// drop, so the WAL records holding them are dropped as well
+impl Drop for RustyStore {
+    fn drop(&mut self) {
+        drop(mem::replace(&mut self.flush_sender, mpsc::channel().0));
+        if let Some(compaction_thread) = self.compaction_thread.take() {
+            let _ = compaction_thread.join();
+        }
+
+        let mut lsmtree = self.tree.lock().unwrap();
+        let mut wal = self.wal.lock().unwrap();
+        if !lsmtree.can_flush() {
+            return;
+        }
+        let last_seq = wal.last_seq();
+        let flushed = wal.rotate()
+            .and_then(|_| lsmtree.flush_memtable())
+            .and_then(|_| wal.truncate(last_seq));
+        if let Err(e) = flushed {
+            println!("Flush of the memtable on drop failed: {}", e);
+        }
+    }
 }
 
 // #[cfg(test)]
@@ -144,7 +410,7 @@ impl RustyStore {
 //     use tempfile::Builder;
 //     use rand::prelude::*;
 
-//     fn start_db() -> Result<RustyStore, io::Error> {
+//     fn start_db() -> Result<RustyStore, Error> {
 //         let test_root = Builder::new().prefix("rustydb_temp_test").tempdir()?;
 //         let store = RustyStore::new(test_root.path())?;
 //         return Ok(store);
@@ -202,3 +468,358 @@ impl RustyStore {
 //         }
 //     }
 // }
+
+#[cfg(test)]
+mod tests {
+    use crate::storage::store::*;
+    use crate::storage::codec::GorillaCodec;
+    use crate::gorilla::api::compress_values;
+    use std::fs;
+    use std::mem;
+    use chrono::{Duration, TimeZone, Utc};
+    use tempfile::Builder;
+
+    #[test]
+    fn store_query() {
+        let test_root = Builder::new().prefix("rustydb_temp_test").tempdir().unwrap();
+        let mut store = RustyStore::new(test_root.path()).unwrap();
+
+        // two hourly blocks of a series with an entry every 10 minutes, and
+        // one block of another series
+        let base = Utc.timestamp_opt(1_500_000_000, 0).unwrap();
+        let series = 0x3837_3635_3433_3231;
+        let other = 0x6867_6665_6463_6261;
+        for (hash, hours) in [(series, 0), (series, 1), (other, 0)] {
+            let start = base + Duration::hours(hours);
+            let entries: Vec<MVEntry> = (0..6)
+                .map(|i| MVEntry::new(start + Duration::minutes(10 * i), vec![(hours * 6 + i) as f64, 1.0]))
+                .collect();
+            let block = compress_values(entries, start, 2);
+            let key = format!("{}{}", series_key_prefix(hash), timestamp_key(start));
+            // blocks stored as strings still read
+            if hours == 0 {
+                store.set(&key, &block.to_string()).unwrap();
+            } else {
+                store.set_bytes(&key, &block.to_bytes()).unwrap();
+            }
+        }
+
+        // a window across both blocks, starting inside the first one
+        let entries = store.query(series, base + Duration::minutes(25), base + Duration::minutes(85), 2).unwrap();
+        let times: Vec<_> = entries.iter().map(|e| e.time()).collect();
+        let expected: Vec<_> = (3..9).map(|i| base + Duration::minutes(10 * i)).collect();
+        assert_eq!(times, expected);
+        let values: Vec<f64> = entries.iter().map(|e| e.values_ref()[0]).collect();
+        assert_eq!(values, vec![3.0, 4.0, 5.0, 6.0, 7.0, 8.0]);
+
+        // the bounds are inclusive
+        let entries = store.query(series, base + Duration::minutes(60), base + Duration::minutes(60), 2).unwrap();
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].values(), vec![6.0, 1.0]);
+
+        // outside the series' data
+        assert!(store.query(series, base - Duration::hours(2), base - Duration::hours(1), 2).unwrap().is_empty());
+        assert!(store.query(series, base + Duration::hours(3), base + Duration::hours(4), 2).unwrap().is_empty());
+
+        // blocks keyed by a SeriesKey, one starting right at the end of the window
+        for hours in 0..2 {
+            let start = base + Duration::hours(hours);
+            let entries = vec![MVEntry::new(start, vec![hours as f64, 2.0])];
+            let key = SeriesKey::new("cpu", "hostname=host_0", start);
+            store.set_bytes(&key.to_key(), &compress_values(entries, start, 2).to_bytes()).unwrap();
+        }
+        let hash = SeriesKey::new("cpu", "hostname=host_0", base).series_hash();
+        let entries = store.query(hash, base, base + Duration::hours(1), 2).unwrap();
+        assert_eq!(entries.iter().map(|e| e.values_ref()[0]).collect::<Vec<_>>(), vec![0.0, 1.0]);
+        let entries = store.query(hash, base + Duration::minutes(1), base + Duration::hours(2), 2).unwrap();
+        assert_eq!(entries.iter().map(|e| e.values_ref()[0]).collect::<Vec<_>>(), vec![1.0]);
+
+        // the blocks are recovered from the WAL
+        drop(store);
+        let store = RustyStore::new(test_root.path()).unwrap();
+        let entries = store.query(series, base + Duration::minutes(25), base + Duration::minutes(85), 2).unwrap();
+        assert_eq!(entries.iter().map(|e| e.time()).collect::<Vec<_>>(), expected);
+    }
+
+    // entries as text, 'time,value,value;...'
+    struct TextCodec;
+
+    impl Codec for TextCodec {
+        fn encode(&self, _: GorillaDateTime, entries: &[MVEntry], _: usize) -> Result<Vec<u8>, Error> {
+            let rows: Vec<String> = entries.iter()
+                .map(|e| format!("{},{}", e.time().timestamp(), e.values_ref().iter().map(f64::to_string).collect::<Vec<_>>().join(",")))
+                .collect();
+            Ok(rows.join(";").into_bytes())
+        }
+
+        fn decode(&self, bytes: &[u8], _: usize) -> Result<Vec<MVEntry>, Error> {
+            let bad = || Error::Corrupt(String::from("bad text block"));
+            let text = std::str::from_utf8(bytes).map_err(|_| bad())?;
+            text.split(';').map(|row| {
+                let mut fields = row.split(',');
+                let secs = fields.next().and_then(|f| f.parse().ok()).ok_or_else(bad)?;
+                let values = fields.map(|f| f.parse().map_err(|_| bad())).collect::<Result<_, _>>()?;
+                Ok(MVEntry::new(Utc.timestamp_opt(secs, 0).unwrap(), values))
+            }).collect()
+        }
+    }
+
+    #[test]
+    fn store_codecs() {
+        let test_root = Builder::new().prefix("rustydb_temp_test").tempdir().unwrap();
+        let mut store = RustyStore::new(test_root.path()).unwrap();
+        store.register_codec(1, Box::new(GorillaCodec)).unwrap();
+        store.register_codec(2, Box::new(TextCodec)).unwrap();
+        assert!(matches!(store.register_codec(2, Box::new(GorillaCodec)), Err(Error::InvalidArgument(_))));
+
+        // a series in each format, with a block each hour
+        let base = Utc.timestamp_opt(1_500_000_000, 0).unwrap();
+        let entries = |start: GorillaDateTime| -> Vec<MVEntry> {
+            (0..6).map(|i| MVEntry::new(start + Duration::minutes(10 * i), vec![i as f64 + 0.5, -1.0])).collect()
+        };
+        for hours in 0..2 {
+            let start = base + Duration::hours(hours);
+            store.set_series(&SeriesKey::new("cpu", "host=gorilla", start), 1, &entries(start), 2).unwrap();
+            store.set_series(&SeriesKey::new("cpu", "host=text", start), 2, &entries(start), 2).unwrap();
+        }
+        let key = SeriesKey::new("cpu", "host=text", base);
+        assert!(matches!(store.set_series(&key, 3, &entries(base), 2), Err(Error::InvalidArgument(_))));
+
+        // the values are tagged with the codec id
+        let val = store.tree.lock().unwrap().get_bytes(&key.to_key()).unwrap().unwrap();
+        assert_eq!(val[0], 2);
+        assert_eq!(TextCodec.decode(&val[1..], 2).unwrap().len(), 6);
+
+        let mut expected = entries(base);
+        expected.extend(entries(base + Duration::hours(1)));
+        let expected: Vec<_> = expected.iter().map(|e| (e.time(), e.values())).collect();
+        let check = |store: &RustyStore| {
+            for tags in &["host=gorilla", "host=text"] {
+                let hash = SeriesKey::new("cpu", tags, base).series_hash();
+                let read = store.query(hash, base, base + Duration::hours(2), 2).unwrap();
+                assert_eq!(read.iter().map(|e| (e.time(), e.values())).collect::<Vec<_>>(), expected);
+
+                // a window starting inside the first block
+                let read = store.query(hash, base + Duration::minutes(45), base + Duration::minutes(65), 2).unwrap();
+                assert_eq!(read.len(), 2);
+            }
+        };
+        check(&store);
+
+        // the blocks are recovered from the WAL, the codecs are registered again
+        drop(store);
+        let mut store = RustyStore::new(test_root.path()).unwrap();
+        store.register_codec(1, Box::new(GorillaCodec)).unwrap();
+        store.register_codec(2, Box::new(TextCodec)).unwrap();
+        check(&store);
+    }
+
+    #[test]
+    fn store_set_batch() {
+        let test_root = Builder::new().prefix("rustydb_temp_test").tempdir().unwrap();
+        let mut store = RustyStore::new(test_root.path()).unwrap();
+        store.set("foo", "bar").unwrap();
+        store.set_batch(&[("zoohoo", "keefuu"), ("meemu", "mauha"), ("foo", "baz")]).unwrap();
+        assert_eq!(store.get("foo").unwrap(), Some(String::from("baz")));
+        assert_eq!(store.get("meemu").unwrap(), Some(String::from("mauha")));
+
+        // the batch is recovered from the WAL
+        let recovered = RustyStore::new(test_root.path()).unwrap();
+        assert_eq!(recovered.get("foo").unwrap(), Some(String::from("baz")));
+        assert_eq!(recovered.get("zoohoo").unwrap(), Some(String::from("keefuu")));
+        assert_eq!(recovered.get("meemu").unwrap(), Some(String::from("mauha")));
+    }
+
+    #[test]
+    fn store_set_while_flushing() {
+        let test_root = Builder::new().prefix("rustydb_temp_test").tempdir().unwrap();
+        let mut store = RustyStore::new(test_root.path()).unwrap();
+
+        // ~6MB of values, more than a memtable holds
+        let val = "v".repeat(4096);
+        let num = 1500;
+        for i in 0..num {
+            store.set(&format!("key{:04}", i), &format!("{}{}", val, i)).unwrap();
+        }
+        for i in 0..num {
+            assert_eq!(store.get(&format!("key{:04}", i)).unwrap(), Some(format!("{}{}", val, i)));
+        }
+
+        store.wait_for_flush();
+        let num_sst = fs::read_dir(test_root.path()).unwrap()
+            .filter(|e| e.as_ref().unwrap().path().extension().is_some_and(|x| x == "sst"))
+            .count();
+        assert_eq!(num_sst, 1);
+        for i in 0..num {
+            assert_eq!(store.get(&format!("key{:04}", i)).unwrap(), Some(format!("{}{}", val, i)));
+        }
+    }
+
+    #[test]
+    fn store_delete() {
+        let test_root = Builder::new().prefix("rustydb_temp_test").tempdir().unwrap();
+        let mut store = RustyStore::new(test_root.path()).unwrap();
+        store.set("foo", "bar").unwrap();
+        store.set("zoo", "kee").unwrap();
+        store.delete("foo").unwrap();
+        assert_eq!(store.get("foo").unwrap(), None);
+        drop(store);
+
+        // the deletion is replayed from the WAL
+        let store = RustyStore::new(test_root.path()).unwrap();
+        assert_eq!(store.get("foo").unwrap(), None);
+        assert_eq!(store.get("zoo").unwrap(), Some(String::from("kee")));
+        drop(store);
+
+        // and stays once the replayed tombstone is on disk
+        let mut store = RustyStore::new(test_root.path()).unwrap();
+        assert_eq!(store.get("foo").unwrap(), None);
+        store.set("foo", "again").unwrap();
+        assert_eq!(store.get("foo").unwrap(), Some(String::from("again")));
+    }
+
+    #[test]
+    fn store_wal_truncate() {
+        let test_root = Builder::new().prefix("rustydb_temp_test").tempdir().unwrap();
+        let root = test_root.path();
+        let mut store = RustyStore::new(root).unwrap();
+
+        // write until a memtable is handed over
+        let val = "v".repeat(4096);
+        let mut num = 0;
+        while store.wal.lock().unwrap().segment() == 0 {
+            store.set(&format!("key{:04}", num), &val).unwrap();
+            num += 1;
+        }
+        store.wait_for_flush();
+
+        // the segment of the flushed memtable is gone, the record that
+        // overflowed it is all that is left to replay
+        assert_eq!(log_files(root).unwrap(), vec![segment_path(root, 1)]);
+        let last = format!("key{:04}", num - 1);
+        let keys = || -> Vec<String> {
+            WALReader::new(root).unwrap().replay().unwrap().into_iter().map(|(_, key, _)| key).collect()
+        };
+        assert_eq!(keys(), vec![last.clone()]);
+
+        // a crash after more writes replays only them
+        store.set("after", "flush").unwrap();
+        store.delete("key0000").unwrap();
+        mem::forget(store);
+        assert_eq!(keys(), vec![last.clone(), String::from("after"), String::from("key0000")]);
+
+        let store = RustyStore::new(root).unwrap();
+        assert_eq!(store.get("key0000").unwrap(), None);
+        assert_eq!(store.get("key0001").unwrap(), Some(val.clone()));
+        assert_eq!(store.get(&last).unwrap(), Some(val));
+        assert_eq!(store.get("after").unwrap(), Some(String::from("flush")));
+    }
+
+    #[test]
+    fn store_drop_flush() {
+        let test_root = Builder::new().prefix("rustydb_temp_test").tempdir().unwrap();
+        let root = test_root.path();
+        let mut store = RustyStore::new(root).unwrap();
+        store.set("foo", "bar").unwrap();
+        store.delete("gone").unwrap();
+        drop(store);
+
+        // the memtable flushed on drop leaves nothing for the WAL to replay
+        assert!(WALReader::new(root).unwrap().replay().unwrap().is_empty());
+        assert_eq!(log_files(root).unwrap(), vec![segment_path(root, 1)]);
+        let store = RustyStore::new(root).unwrap();
+        assert_eq!(store.get("foo").unwrap(), Some(String::from("bar")));
+        assert_eq!(store.get("gone").unwrap(), None);
+    }
+
+    #[test]
+    fn store_legacy_wal() {
+        let test_root = Builder::new().prefix("rustydb_temp_test").tempdir().unwrap();
+        let root = test_root.path();
+
+        // a WAL left behind by a release whose records were only the
+        // timestamp, key and value
+        let mut old = Vec::new();
+        for (key, val) in [("a", "1"), ("b", "2")] {
+            old.extend(7u64.to_le_bytes());
+            old.extend(0u32.to_le_bytes());
+            old.extend((key.len() as u32).to_le_bytes());
+            old.extend(key.as_bytes());
+            old.extend((val.len() as u32).to_le_bytes());
+            old.extend(val.as_bytes());
+        }
+        fs::write(root.join(WAL_FILENAME), &old).unwrap();
+
+        let store = RustyStore::new(root).unwrap();
+        assert_eq!(store.get("a").unwrap(), Some(String::from("1")));
+        assert_eq!(store.get("b").unwrap(), Some(String::from("2")));
+        assert_eq!(log_files(root).unwrap(), vec![segment_path(root, 0)]);
+    }
+
+    #[test]
+    fn store_wal_threshold() {
+        let test_root = Builder::new().prefix("rustydb_temp_test").tempdir().unwrap();
+        let root = test_root.path();
+        let config = LSMConfig { wal_threshold: 4096, ..LSMConfig::default() };
+        let mut store = RustyStore::with_config(root, config).unwrap();
+
+        // the memtable is far from full when the WAL crosses its threshold
+        let val = "v".repeat(100);
+        let mut num = 0;
+        while store.wal.lock().unwrap().segment() == 0 {
+            store.set(&format!("key{:04}", num), &val).unwrap();
+            num += 1;
+        }
+        store.wait_for_flush();
+        assert!(num * val.len() < config.memtable_threshold);
+        assert_eq!(store.tree.lock().unwrap().level_stats()[0].num_files, 1);
+
+        // the flushed records are dropped from the WAL, the one that crossed
+        // the threshold is all that is left
+        assert_eq!(log_files(root).unwrap(), vec![segment_path(root, 1)]);
+        assert!(store.wal.lock().unwrap().bytes_written() < config.wal_threshold);
+        let replayed = WALReader::new(root).unwrap().replay().unwrap();
+        assert_eq!(replayed.len(), 1);
+        for i in 0..num {
+            assert_eq!(store.get(&format!("key{:04}", i)).unwrap(), Some(val.clone()));
+        }
+
+        // a threshold of 0 never flushes
+        let other_root = Builder::new().prefix("rustydb_temp_test").tempdir().unwrap();
+        let config = LSMConfig { wal_threshold: 0, ..config };
+        let mut store = RustyStore::with_config(other_root.path(), config).unwrap();
+        for i in 0..num {
+            store.set(&format!("key{:04}", i), &val).unwrap();
+        }
+        assert_eq!(store.wal.lock().unwrap().segment(), 0);
+    }
+
+    #[test]
+    fn store_backpressure() {
+        let test_root = Builder::new().prefix("rustydb_temp_test").tempdir().unwrap();
+        let config = LSMConfig { memtable_threshold: 1024, ..LSMConfig::default() };
+        let mut store = RustyStore::with_config(test_root.path(), config).unwrap();
+
+        // continuous writes, a memtable holds about ten of them
+        let num = 2000;
+        let val = |i: usize| format!("{:0>80}", i);
+        for i in 0..num {
+            let key = format!("key{:05}", i);
+            match i % 50 {
+                0 => store.set_batch(&[(&key, &val(i))]).unwrap(),
+                49 => store.delete(&format!("key{:05}", i - 1)).unwrap(),
+                _ => store.set(&key, &val(i)).unwrap(),
+            }
+
+            // the memtable being flushed and the buffered one never hold
+            // more than a threshold each
+            assert!(store.tree.lock().unwrap().memtable_bytes() <= 2 * config.memtable_threshold);
+        }
+
+        store.wait_for_flush();
+        for i in 0..num {
+            let expected = if i % 50 >= 48 { None } else { Some(val(i)) };
+            assert_eq!(store.get(&format!("key{:05}", i)).unwrap(), expected);
+        }
+    }
+}
@@ -1,75 +1,390 @@
 // The Write Ahead Log
 use std::io;
 use std::fs;
+use std::fmt;
+use std::error::Error as StdError;
 use std::time::Duration;
-use std::io::{Read, BufReader, Write, BufWriter};
+use std::io::{Read, BufRead, BufReader, Write, BufWriter, Seek};
+use std::mem;
 use std::path::{Path, PathBuf};
 
 use byteorder::*;
 
-const WAL_FILENAME: &'static str = "rustydb.wal";
+use crate::storage::error::Error;
+use crate::storage::format::{LEGACY_VERSION, WAL_FORMAT};
 
-// Each WAL record has the following components:
-// 1. DURATION: sec(u64) & nanos(u32)
-// 2. KEY: keylen(u32) & key(bytes)
-// 3. VALUE: vallen & value(bytes)
+pub const WAL_FILENAME: &str = "rustydb.wal";
+
+// record types
+const RECORD_PUT: u8 = 0x01;
+const RECORD_CHECKPOINT: u8 = 0x02;
+const RECORD_DELETE: u8 = 0x03;
+
+// record flags
+const FLAG_PLAIN: u8 = 0;
+const FLAG_SNAPPY: u8 = 1;
+
+// Each WAL record starts with a TYPE (u8) and a SEQ (u64), sequence numbers
+// of PUT and DELETE records increase by one from 1
+//
+// A PUT record then has the following components:
+// 1. FLAG: whether key and value are snappy compressed (u8)
+// 2. DURATION: sec(u64) & nanos(u32)
+// 3. KEY: keylen(u32) & key(bytes)
+// 4. VALUE: vallen & value(bytes)
+// 5. CRC: crc32 of the DURATION, KEY and VALUE bytes (u32)
+// for compressed records the lengths are the compressed lengths
+//
+// A PUT record whose CRC doesn't match is reported as an InvalidData error,
+// replay skips it and goes on with the next record
+//
+// A DELETE record then has the DURATION, KEY (never compressed) and a CRC of
+// them, the same way as a PUT record
+//
+// A CHECKPOINT record has nothing else, its SEQ is the last PUT or DELETE
+// record that is durable elsewhere and doesn't need to be replayed
+//
+// Every segment starts with the WAL format header, see format.rs. A file
+// without it was written before records had a type, its records are only
+// the DURATION, KEY and VALUE of a PUT record with neither a SEQ nor a CRC,
+// they read as PUT records with a SEQ of 0
+//
+// The log is a sequence of segment files 'rustydb.wal.0', 'rustydb.wal.1', ...
+// that are read in order as one log, sequence numbers go on across them.
+// The writer starts a new segment when a memtable is handed over for
+// flushing, once the flush is durable the segments holding only flushed
+// records are removed. A 'rustydb.wal' file written before the log was
+// segmented is read before every segment
+
+// the path of segment 'segment' of the log under 'root'
+pub fn segment_path(root: &Path, segment: u64) -> PathBuf {
+    root.join(format!("{}.{}", WAL_FILENAME, segment))
+}
+
+// a new segment file with its header written
+fn create_segment(path: &Path) -> Result<BufWriter<fs::File>, Error> {
+    let mut writer = BufWriter::new(fs::File::create(path)?);
+    WAL_FORMAT.write_header(&mut writer)?;
+    writer.flush()?;
+    Ok(writer)
+}
+
+// a segment file positioned after its header and the format version of
+// its records
+fn open_segment(file: fs::File) -> Result<(BufReader<fs::File>, u8), Error> {
+    let mut reader = BufReader::new(file);
+    let version = WAL_FORMAT.read_header(&mut reader)?;
+    Ok((reader, version))
+}
+
+// the files of the log under 'root' in the order they are read
+pub fn log_files(root: &Path) -> Result<Vec<PathBuf>, Error> {
+    let mut segments = Vec::new();
+    for entry in fs::read_dir(root)? {
+        let name = entry?.file_name();
+        let segment = name.to_str()
+            .and_then(|name| name.strip_prefix(WAL_FILENAME))
+            .and_then(|suffix| suffix.strip_prefix('.'))
+            .and_then(|segment| segment.parse::<u64>().ok());
+        if let Some(segment) = segment {
+            segments.push(segment);
+        }
+    }
+    segments.sort_unstable();
+
+    let legacy = root.join(WAL_FILENAME);
+    let legacy = if legacy.exists() { Some(legacy) } else { None };
+    Ok(legacy.into_iter().chain(segments.into_iter().map(|segment| segment_path(root, segment))).collect())
+}
+
+// when records written by 'add_batch' are flushed to the file, a crash only
+// loses records that were not flushed yet
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SyncPolicy {
+    // after every record
+    SyncEvery,
+    // once after the whole batch
+    SyncOnBatch,
+}
+
+// a replayed PUT (with its value) or DELETE (None) record
+pub type ReplayEntry = (Duration, String, Option<Vec<u8>>);
+
+// why 'WALReader::replay' couldn't read the log to its end
+#[derive(Debug)]
+pub enum WalError {
+    Io(io::Error),
+    // the record at byte 'offset' of its file is cut short or has an unknown
+    // type, so nothing after it can be found, 'recovered' has the records
+    // before it
+    Corrupt { offset: u64, recovered: Vec<ReplayEntry> },
+}
+
+impl fmt::Display for WalError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            WalError::Io(e) => write!(f, "WAL read error: {}", e),
+            WalError::Corrupt { offset, .. } => write!(f, "WAL is unreadable from byte {}", offset),
+        }
+    }
+}
+
+impl StdError for WalError {
+    fn source(&self) -> Option<&(dyn StdError + 'static)> {
+        match self {
+            WalError::Io(e) => Some(e),
+            WalError::Corrupt { .. } => None,
+        }
+    }
+}
+
+impl From<io::Error> for WalError {
+    fn from(error: io::Error) -> Self {
+        WalError::Io(error)
+    }
+}
+
+impl From<Error> for WalError {
+    fn from(error: Error) -> Self {
+        WalError::Io(error.into())
+    }
+}
+
+// what is next in the log while replaying it
+enum NextRecord {
+    Record(WALRecord),
+    // a record that was read in full but fails its checksum or doesn't
+    // decode, the one after it can still be read
+    Invalid(Error),
+    // the log ends right before the next record
+    End,
+}
+
+pub enum WALRecord {
+    Put { seq: u64, timestamp: Duration, key: String, val: Vec<u8> },
+    Delete { seq: u64, timestamp: Duration, key: String },
+    Checkpoint { seq: u64 },
+}
 
 pub struct WALWriter {
     path: PathBuf,
     writer: BufWriter<fs::File>,
+    compress: bool,
+    sync: SyncPolicy,
+    next_seq: u64,
+    // the segment being written
+    segment: u64,
+    // bytes of the records in it
+    segment_bytes: usize,
+    // the segments written before it with the last sequence number and the
+    // bytes of the records in each
+    closed: Vec<(u64, u64, usize)>,
 }
 
 impl WALWriter {
-    pub fn new(path: &Path) -> io::Result<WALWriter> {
-        let walfile = fs::File::create(path.join(WAL_FILENAME))?;
-        let mut writer = BufWriter::new(walfile);
-        writer.flush()?;
+    pub fn new(path: &Path) -> Result<WALWriter, Error> {
+        Self::create(path, false)
+    }
+
+    // same as 'new', but every record's key and value are snappy compressed
+    pub fn new_compressed(path: &Path) -> Result<WALWriter, Error> {
+        Self::create(path, true)
+    }
+
+    // a new log replaces whatever log is under 'path'
+    fn create(path: &Path, compress: bool) -> Result<WALWriter, Error> {
+        for file in log_files(path)? {
+            fs::remove_file(file)?;
+        }
+        let writer = create_segment(&segment_path(path, 0))?;
         Ok(WALWriter {
             path: path.to_path_buf(),
-            writer: writer,
+            writer,
+            compress,
+            sync: SyncPolicy::SyncEvery,
+            next_seq: 1,
+            segment: 0,
+            segment_bytes: 0,
+            closed: Vec::new(),
         })
     }
 
-    pub fn reset(&mut self) -> io::Result<()> {
-        let walpath = &self.path.join(WAL_FILENAME);
-        if Path::exists(walpath) {
-            // remove the old wal file
-            fs::remove_file(walpath)?;
+    pub fn set_sync_policy(&mut self, sync: SyncPolicy) {
+        self.sync = sync;
+    }
+
+    // drop every record written so far and go on in a new segment
+    pub fn reset(&mut self) -> Result<(), Error> {
+        self.rotate()?;
+        let closed = mem::take(&mut self.closed);
+        for (segment, _, _) in closed {
+            fs::remove_file(segment_path(&self.path, segment))?;
         }
-        self.writer = BufWriter::new(fs::File::create(self.path.join(WAL_FILENAME))?);
+        Ok(())
+    }
+
+    // the sequence number of the last PUT or DELETE record, 0 if there is none
+    pub fn last_seq(&self) -> u64 {
+        self.next_seq - 1
+    }
+
+    // the segment records are written to
+    pub fn segment(&self) -> u64 {
+        self.segment
+    }
+
+    // bytes of the records in the segments not removed yet, which is what a
+    // recovery replays
+    pub fn bytes_written(&self) -> usize {
+        self.segment_bytes + self.closed.iter().map(|&(_, _, bytes)| bytes).sum::<usize>()
+    }
+
+    // close the current segment and write the records after it to a new one
+    pub fn rotate(&mut self) -> Result<(), Error> {
         self.writer.flush()?;
+        self.writer = create_segment(&segment_path(&self.path, self.segment + 1))?;
+        self.closed.push((self.segment, self.last_seq(), self.segment_bytes));
+        self.segment += 1;
+        self.segment_bytes = 0;
         Ok(())
     }
 
-    pub fn add(&mut self, timestamp: &Duration, key: &str, val: &str) -> io::Result<()> {
+    // every PUT and DELETE record up to and including 'seq' is durable
+    // elsewhere: checkpoint it and remove the closed segments holding only
+    // such records
+    pub fn truncate(&mut self, seq: u64) -> Result<(), Error> {
+        self.checkpoint(seq)?;
+        while let Some(&(segment, last_seq, _)) = self.closed.first() {
+            if last_seq > seq {
+                break;
+            }
+            fs::remove_file(segment_path(&self.path, segment))?;
+            self.closed.remove(0);
+        }
+        Ok(())
+    }
+
+    // append a PUT record and return its sequence number
+    pub fn add(&mut self, timestamp: &Duration, key: &str, val: &str) -> Result<u64, Error> {
+        self.add_bytes(timestamp, key, val.as_bytes())
+    }
+
+    // same as 'add' for a value that isn't necessarily valid utf8
+    pub fn add_bytes(&mut self, timestamp: &Duration, key: &str, val: &[u8]) -> Result<u64, Error> {
+        let seq = self.write_put(timestamp, key, val)?;
+
+        // each insertion will be flushed to disk immediately
+        self.writer.flush()?;
+        Ok(seq)
+    }
+
+    // append a PUT record for each (timestamp, key, val) and return the
+    // sequence number of the last one, flushed according to the sync policy
+    pub fn add_batch(&mut self, records: &[(Duration, &str, &str)]) -> Result<u64, Error> {
+        let mut seq = self.next_seq - 1;
+        for (timestamp, key, val) in records {
+            seq = self.write_put(timestamp, key, val.as_bytes())?;
+            if self.sync == SyncPolicy::SyncEvery {
+                self.writer.flush()?;
+            }
+        }
+        self.writer.flush()?;
+        Ok(seq)
+    }
+
+    // write a PUT record into the buffer without flushing it
+    fn write_put(&mut self, timestamp: &Duration, key: &str, val: &[u8]) -> Result<u64, Error> {
+        let (flag, keybytes, valbytes) = if self.compress {
+            let mut encoder = snap::raw::Encoder::new();
+            let keybytes = encoder.compress_vec(key.as_bytes()).map_err(|e| Error::InvalidArgument(e.to_string()))?;
+            let valbytes = encoder.compress_vec(val).map_err(|e| Error::InvalidArgument(e.to_string()))?;
+            (FLAG_SNAPPY, keybytes, valbytes)
+        } else {
+            (FLAG_PLAIN, key.as_bytes().to_vec(), val.to_vec())
+        };
+
+        let seq = self.next_seq;
+
+        // write record type, sequence number and flag
+        self.writer.write_u8(RECORD_PUT)?;
+        self.writer.write_u64::<LittleEndian>(seq)?;
+        self.writer.write_u8(flag)?;
+
+        // the checksummed part of the record
+        let mut body = Vec::with_capacity(20 + keybytes.len() + valbytes.len());
+
         // write timestamp
-        self.writer.write_u64::<LittleEndian>(timestamp.as_secs())?;
-        self.writer.write_u32::<LittleEndian>(timestamp.subsec_nanos())?;
+        body.write_u64::<LittleEndian>(timestamp.as_secs())?;
+        body.write_u32::<LittleEndian>(timestamp.subsec_nanos())?;
 
         // write key string
-        self.writer.write_u32::<LittleEndian>(key.as_bytes().len() as u32)?;
-        self.writer.write_all(key.as_bytes())?;
+        body.write_u32::<LittleEndian>(keybytes.len() as u32)?;
+        body.write_all(&keybytes)?;
 
         // write val string
-        self.writer.write_u32::<LittleEndian>(val.as_bytes().len() as u32)?;
-        self.writer.write_all(val.as_bytes())?;
+        body.write_u32::<LittleEndian>(valbytes.len() as u32)?;
+        body.write_all(&valbytes)?;
 
-        // each insertion will be flushed to disk immediately
+        self.writer.write_all(&body)?;
+        self.writer.write_u32::<LittleEndian>(crc32fast::hash(&body))?;
+
+        // type, sequence number, flag, body and crc
+        self.segment_bytes += 14 + body.len();
+        self.next_seq += 1;
+        Ok(seq)
+    }
+
+    // append a DELETE record for 'key' and return its sequence number
+    pub fn delete(&mut self, timestamp: &Duration, key: &str) -> Result<u64, Error> {
+        let seq = self.next_seq;
+        self.writer.write_u8(RECORD_DELETE)?;
+        self.writer.write_u64::<LittleEndian>(seq)?;
+
+        let mut body = Vec::with_capacity(16 + key.len());
+        body.write_u64::<LittleEndian>(timestamp.as_secs())?;
+        body.write_u32::<LittleEndian>(timestamp.subsec_nanos())?;
+        body.write_u32::<LittleEndian>(key.len() as u32)?;
+        body.write_all(key.as_bytes())?;
+
+        self.writer.write_all(&body)?;
+        self.writer.write_u32::<LittleEndian>(crc32fast::hash(&body))?;
+        self.writer.flush()?;
+
+        // type, sequence number, body and crc
+        self.segment_bytes += 13 + body.len();
+        self.next_seq += 1;
+        Ok(seq)
+    }
+
+    // mark every PUT and DELETE record up to and including 'seq' as flushed,
+    // recovery skips them
+    pub fn checkpoint(&mut self, seq: u64) -> Result<(), Error> {
+        self.writer.write_u8(RECORD_CHECKPOINT)?;
+        self.writer.write_u64::<LittleEndian>(seq)?;
         self.writer.flush()?;
+        self.segment_bytes += 9;
         Ok(())
     }
 }
 
 pub struct WALReader {
+    // the files of the log and the one being read
+    files: Vec<PathBuf>,
+    current: usize,
     reader: BufReader<fs::File>,
+    // the format version of the file being read
+    version: u8,
+    // records skipped by the last 'replay'
+    skipped: usize,
 }
 
 impl WALReader {
-    pub fn new(root: &Path) -> io::Result<Self> {
-        let walfpath = root.join(WAL_FILENAME);
-        if !walfpath.exists() {
+    pub fn new(root: &Path) -> Result<Self, Error> {
+        let mut files = log_files(root)?;
+        if files.is_empty() {
             // nothing to read
-            println!("No WAL records found, proceed")
+            println!("No WAL records found, proceed");
+            files.push(segment_path(root, 0));
         }
 
         // open the WAL file for R/W and create it if it doesn't exist
@@ -79,11 +394,51 @@ impl WALReader {
             .read(true)
             .write(true)
             .create(true)
-            .open(walfpath)?;
-        Ok(WALReader { reader: BufReader::new(walfile) })
+            .truncate(false)
+            .open(&files[0])?;
+        let (reader, version) = open_segment(walfile)?;
+        Ok(WALReader { files, current: 0, reader, version, skipped: 0 })
+    }
+
+    // move on to the next file once the current one is read to its end,
+    // false once every file is
+    fn fill_next(&mut self) -> Result<bool, Error> {
+        while self.reader.fill_buf()?.is_empty() {
+            if self.current + 1 >= self.files.len() {
+                return Ok(false);
+            }
+            self.current += 1;
+            (self.reader, self.version) = open_segment(fs::File::open(&self.files[self.current])?)?;
+        }
+        Ok(true)
     }
 
-    pub fn read_entry(&mut self) -> Result<(Duration, String, String), io::Error> {
+    // read the next PUT record, skipping the other records, its value must
+    // be valid utf8
+    pub fn read_entry(&mut self) -> Result<(Duration, String, String), Error> {
+        loop {
+            if let WALRecord::Put { timestamp, key, val, .. } = self.read_record()? {
+                let val = String::from_utf8(val).map_err(|_| Error::BadUtf8)?;
+                return Ok((timestamp, key, val));
+            }
+        }
+    }
+
+    pub fn read_record(&mut self) -> Result<WALRecord, Error> {
+        self.fill_next()?;
+        if self.version == LEGACY_VERSION {
+            return self.read_legacy();
+        }
+        let rtype = self.reader.read_u8()?;
+        let seq = self.reader.read_u64::<LittleEndian>()?;
+        match rtype {
+            RECORD_PUT => (),
+            RECORD_DELETE => return self.read_delete(seq),
+            RECORD_CHECKPOINT => return Ok(WALRecord::Checkpoint { seq }),
+            _ => return Err(Error::Corrupt(format!("unknown WAL record type {}", rtype))),
+        }
+
+        let flag = self.reader.read_u8()?;
         let secs = self.reader.read_u64::<LittleEndian>()?;
         let nsecs = self.reader.read_u32::<LittleEndian>()?;
 
@@ -91,16 +446,161 @@ impl WALReader {
         let keylen = self.reader.read_u32::<LittleEndian>()?;
         let mut keybuf = vec![0 as u8; keylen as usize];
         self.reader.read_exact(&mut keybuf)?;
-        let key = String::from_utf8(keybuf).unwrap();
 
         // read value
         let vallen = self.reader.read_u32::<LittleEndian>()?;
         let mut valbuf = vec![0 as u8; vallen as usize];
         self.reader.read_exact(&mut valbuf)?;
-        let val = String::from_utf8(valbuf).unwrap();
-        
-        Ok((Duration::new(secs, nsecs), key, val))
+
+        // verify the checksum before trusting any of it
+        let crc = self.reader.read_u32::<LittleEndian>()?;
+        let mut hasher = crc32fast::Hasher::new();
+        hasher.update(&secs.to_le_bytes());
+        hasher.update(&nsecs.to_le_bytes());
+        hasher.update(&keylen.to_le_bytes());
+        hasher.update(&keybuf);
+        hasher.update(&vallen.to_le_bytes());
+        hasher.update(&valbuf);
+        if hasher.finalize() != crc {
+            return Err(Error::Corrupt(format!("WAL record {} checksum mismatch", seq)));
+        }
+
+        if flag == FLAG_SNAPPY {
+            let mut decoder = snap::raw::Decoder::new();
+            keybuf = decoder.decompress_vec(&keybuf).map_err(snappy_error)?;
+            valbuf = decoder.decompress_vec(&valbuf).map_err(snappy_error)?;
+        }
+
+        let key = String::from_utf8(keybuf).map_err(|_| Error::BadUtf8)?;
+
+        Ok(WALRecord::Put { seq, timestamp: Duration::new(secs, nsecs), key, val: valbuf })
+    }
+
+    // a record of a file without a header
+    fn read_legacy(&mut self) -> Result<WALRecord, Error> {
+        let secs = self.reader.read_u64::<LittleEndian>()?;
+        let nsecs = self.reader.read_u32::<LittleEndian>()?;
+
+        let keylen = self.reader.read_u32::<LittleEndian>()?;
+        let mut keybuf = vec![0u8; keylen as usize];
+        self.reader.read_exact(&mut keybuf)?;
+
+        let vallen = self.reader.read_u32::<LittleEndian>()?;
+        let mut valbuf = vec![0u8; vallen as usize];
+        self.reader.read_exact(&mut valbuf)?;
+
+        let key = String::from_utf8(keybuf).map_err(|_| Error::BadUtf8)?;
+        Ok(WALRecord::Put { seq: 0, timestamp: Duration::new(secs, nsecs), key, val: valbuf })
+    }
+
+    // the rest of a DELETE record after its TYPE and SEQ
+    fn read_delete(&mut self, seq: u64) -> Result<WALRecord, Error> {
+        let secs = self.reader.read_u64::<LittleEndian>()?;
+        let nsecs = self.reader.read_u32::<LittleEndian>()?;
+        let keylen = self.reader.read_u32::<LittleEndian>()?;
+        let mut keybuf = vec![0u8; keylen as usize];
+        self.reader.read_exact(&mut keybuf)?;
+
+        let crc = self.reader.read_u32::<LittleEndian>()?;
+        let mut hasher = crc32fast::Hasher::new();
+        hasher.update(&secs.to_le_bytes());
+        hasher.update(&nsecs.to_le_bytes());
+        hasher.update(&keylen.to_le_bytes());
+        hasher.update(&keybuf);
+        if hasher.finalize() != crc {
+            return Err(Error::Corrupt(format!("WAL record {} checksum mismatch", seq)));
+        }
+
+        let key = String::from_utf8(keybuf).map_err(|_| Error::BadUtf8)?;
+        Ok(WALRecord::Delete { seq, timestamp: Duration::new(secs, nsecs), key })
+    }
+
+    // the next record, telling a clean end of the log apart from a record
+    // that can't be read
+    fn next_record(&mut self) -> Result<NextRecord, WalError> {
+        if !self.fill_next()? {
+            return Ok(NextRecord::End);
+        }
+        let offset = self.reader.stream_position()?;
+        let rtype = self.reader.fill_buf()?[0];
+        if self.version != LEGACY_VERSION && ![RECORD_PUT, RECORD_DELETE, RECORD_CHECKPOINT].contains(&rtype) {
+            return Err(WalError::Corrupt { offset, recovered: Vec::new() });
+        }
+
+        match self.read_record() {
+            Ok(record) => Ok(NextRecord::Record(record)),
+            Err(Error::Io(e)) if e.kind() == io::ErrorKind::UnexpectedEof => {
+                Err(WalError::Corrupt { offset, recovered: Vec::new() })
+            },
+            Err(Error::Io(e)) => Err(WalError::Io(e)),
+            // only checksum and decoding errors are left, both are
+            // raised once the whole record is read
+            Err(e) => Ok(NextRecord::Invalid(e)),
+        }
     }
+
+    // the sequence number of the most recent CHECKPOINT record in any file of
+    // the log, 0 if there is none, the read position is left unchanged
+    pub fn last_checkpoint_seq(&mut self) -> Result<u64, Error> {
+        let (reader, version) = open_segment(fs::File::open(&self.files[0])?)?;
+        let mut scan = WALReader { files: self.files.clone(), current: 0, reader, version, skipped: 0 };
+
+        let mut last_seq = 0;
+        loop {
+            match scan.next_record() {
+                Ok(NextRecord::Record(WALRecord::Checkpoint { seq })) => last_seq = seq,
+                Ok(NextRecord::Record(_)) | Ok(NextRecord::Invalid(_)) => (),
+                Ok(NextRecord::End) | Err(WalError::Corrupt { .. }) => break,
+                Err(WalError::Io(e)) => return Err(e.into()),
+            }
+        }
+        Ok(last_seq)
+    }
+
+    // the PUT and DELETE records that still need to be replayed on recovery,
+    // in the order they were written, i.e. the ones after the last checkpoint
+    // or all of them, records of a file without a header included, if there
+    // is none
+    // a DELETE record comes back with a None value
+    // a record that fails its checksum is skipped and counted in 'skipped',
+    // a record cut short or of an unknown type ends the replay with
+    // 'WalError::Corrupt'
+    pub fn replay(&mut self) -> Result<Vec<ReplayEntry>, WalError> {
+        let checkpoint = self.last_checkpoint_seq()?;
+        let mut entries = Vec::new();
+        self.skipped = 0;
+        loop {
+            match self.next_record() {
+                Ok(NextRecord::Record(record)) => match record {
+                    WALRecord::Put { seq, timestamp, key, val } if checkpoint == 0 || seq > checkpoint => {
+                        entries.push((timestamp, key, Some(val)));
+                    },
+                    WALRecord::Delete { seq, timestamp, key } if checkpoint == 0 || seq > checkpoint => {
+                        entries.push((timestamp, key, None));
+                    },
+                    _ => (),
+                },
+                Ok(NextRecord::Invalid(e)) => {
+                    println!("Skipping WAL record: {}", e);
+                    self.skipped += 1;
+                },
+                Ok(NextRecord::End) => return Ok(entries),
+                Err(WalError::Corrupt { offset, .. }) => {
+                    return Err(WalError::Corrupt { offset, recovered: entries });
+                },
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    // the number of records the last 'replay' skipped
+    pub fn skipped(&self) -> usize {
+        self.skipped
+    }
+}
+
+fn snappy_error(e: snap::Error) -> Error {
+    Error::Corrupt(format!("bad snappy data: {}", e))
 }
 
 impl Iterator for WALReader {
@@ -120,6 +620,7 @@ impl Iterator for WALReader {
 mod tests {
     use crate::storage::wal::*;
     use std::time::SystemTime;
+    use std::mem;
     use tempfile::Builder;
     use rand::prelude::*;
 
@@ -134,6 +635,13 @@ mod tests {
         let mut wal_reader = WALReader::new(walpath.path()).unwrap();
         let entry = wal_reader.read_entry().unwrap();
         assert_eq!(entry, (ts, String::from("foo"), String::from("bar")));
+
+        // a segment of a later format version isn't read
+        let walfile = segment_path(walpath.path(), 0);
+        let mut bytes = fs::read(&walfile).unwrap();
+        bytes[WAL_FORMAT.magic.len()] += 1;
+        fs::write(&walfile, &bytes).unwrap();
+        assert!(matches!(WALReader::new(walpath.path()), Err(Error::UnsupportedVersion { .. })));
     }
 
     #[test]
@@ -189,4 +697,399 @@ mod tests {
             assert_eq!((ts, key.as_str(), val.as_str()), (*timestamp, pkey.as_str(), pval.as_str()));
         }
     }
+
+    #[test]
+    fn wal_compressed_entries() {
+        let num = 100;
+        let plainpath = Builder::new().prefix("rustydb_wal_test").tempdir().unwrap();
+        let snappypath = Builder::new().prefix("rustydb_wal_test").tempdir().unwrap();
+        let mut plain_writer = WALWriter::new(plainpath.path()).unwrap();
+        let mut snappy_writer = WALWriter::new_compressed(snappypath.path()).unwrap();
+
+        // 1KB values that look like repetitive metric payloads
+        let mut timestamps: Vec<Duration> = Vec::new();
+        let mut pairs: Vec<(String, String)> = Vec::new();
+        for i in 0..num {
+            let ts = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap();
+            let key = format!("cpu,host=host_{}", i);
+            let val = format!("usage_user={},", i).repeat(128)[..1024].to_string();
+            plain_writer.add(&ts, &key, &val).unwrap();
+            snappy_writer.add(&ts, &key, &val).unwrap();
+            timestamps.push(ts);
+            pairs.push((key, val));
+        }
+
+        let plainsize = fs::metadata(segment_path(plainpath.path(), 0)).unwrap().len();
+        let snappysize = fs::metadata(segment_path(snappypath.path(), 0)).unwrap().len();
+        assert!(snappysize < plainsize);
+
+        // verify
+        let mut count = 0;
+        for (entry, (timestamp, pair)) in WALReader::new(snappypath.path()).unwrap()
+            .zip(timestamps.iter().zip(pairs.iter()))
+        {
+            let (ts, key, val) = entry;
+            let (pkey, pval) = pair;
+            assert_eq!((ts, key.as_str(), val.as_str()), (*timestamp, pkey.as_str(), pval.as_str()));
+            count += 1;
+        }
+        assert_eq!(count, num);
+    }
+
+    #[test]
+    fn wal_checkpoint() {
+        let walpath = Builder::new().prefix("rustydb_wal_test").tempdir().unwrap();
+        let mut wal_writer = WALWriter::new(walpath.path()).unwrap();
+
+        let mut timestamps: Vec<Duration> = Vec::new();
+        for i in 1..=10 {
+            let ts = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap();
+            let seq = wal_writer.add(&ts, &format!("key{}", i), &format!("val{}", i)).unwrap();
+            assert_eq!(seq, i);
+            timestamps.push(ts);
+        }
+        wal_writer.checkpoint(5).unwrap();
+
+        let mut wal_reader = WALReader::new(walpath.path()).unwrap();
+        assert_eq!(wal_reader.last_checkpoint_seq().unwrap(), 5);
+
+        // the read position is not moved by the scan
+        let entry = wal_reader.read_entry().unwrap();
+        assert_eq!(entry, (timestamps[0], String::from("key1"), String::from("val1")));
+
+        // only entries 6 to 10 are replayed
+        let replayed = WALReader::new(walpath.path()).unwrap().replay().unwrap();
+        assert_eq!(replayed.len(), 5);
+        for (i, (ts, key, val)) in (6..=10).zip(replayed) {
+            assert_eq!((ts, key, val), (timestamps[i - 1], format!("key{}", i), Some(format!("val{}", i).into_bytes())));
+        }
+
+        // without a checkpoint everything is replayed
+        let emptypath = Builder::new().prefix("rustydb_wal_test").tempdir().unwrap();
+        let mut empty_reader = WALReader::new(emptypath.path()).unwrap();
+        assert_eq!(empty_reader.last_checkpoint_seq().unwrap(), 0);
+    }
+
+    #[test]
+    fn wal_corrupt_tail() {
+        let walpath = Builder::new().prefix("rustydb_wal_test").tempdir().unwrap();
+        let mut wal_writer = WALWriter::new(walpath.path()).unwrap();
+
+        let ts = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap();
+        for i in 1..=3 {
+            wal_writer.add(&ts, &format!("key{}", i), &format!("val{}", i)).unwrap();
+        }
+
+        // flip the last bytes of the final value
+        let walfile = segment_path(walpath.path(), 0);
+        let mut bytes = fs::read(&walfile).unwrap();
+        let len = bytes.len();
+        for b in &mut bytes[len - 6..len - 4] {
+            *b ^= 0xff;
+        }
+        fs::write(&walfile, &bytes).unwrap();
+
+        let mut wal_reader = WALReader::new(walpath.path()).unwrap();
+        wal_reader.read_entry().unwrap();
+        wal_reader.read_entry().unwrap();
+        let err = wal_reader.read_entry().unwrap_err();
+        assert!(matches!(err, Error::Corrupt(_)));
+
+        // recovery skips the bad record
+        let mut wal_reader = WALReader::new(walpath.path()).unwrap();
+        let replayed = wal_reader.replay().unwrap();
+        assert_eq!(replayed, vec![
+            (ts, String::from("key1"), Some(b"val1".to_vec())),
+            (ts, String::from("key2"), Some(b"val2".to_vec())),
+        ]);
+        assert_eq!(wal_reader.skipped(), 1);
+
+        // and stops at a record cut short, keeping the ones before it
+        fs::write(&walfile, &bytes[..len - 10]).unwrap();
+        match WALReader::new(walpath.path()).unwrap().replay() {
+            Err(WalError::Corrupt { recovered, .. }) => assert_eq!(recovered.len(), 2),
+            _ => panic!("expected a corrupt WAL"),
+        }
+    }
+
+    #[test]
+    fn wal_corrupt_middle() {
+        let walpath = Builder::new().prefix("rustydb_wal_test").tempdir().unwrap();
+        let mut wal_writer = WALWriter::new(walpath.path()).unwrap();
+
+        let ts = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap();
+        wal_writer.add(&ts, "key1", "val1").unwrap();
+        let walfile = segment_path(walpath.path(), 0);
+        let first_len = fs::metadata(&walfile).unwrap().len() as usize;
+        wal_writer.add(&ts, "key2", "val2").unwrap();
+        let second_end = fs::metadata(&walfile).unwrap().len() as usize;
+        for i in 3..=5 {
+            wal_writer.add(&ts, &format!("key{}", i), &format!("val{}", i)).unwrap();
+        }
+        wal_writer.delete(&ts, "key1").unwrap();
+
+        // flip a byte in the value of the second record
+        let mut bytes = fs::read(&walfile).unwrap();
+        bytes[second_end - 5] ^= 0xff;
+        fs::write(&walfile, &bytes).unwrap();
+
+        // iterating stops at the bad record
+        assert_eq!(WALReader::new(walpath.path()).unwrap().count(), 1);
+
+        // replay only loses the bad record
+        let mut wal_reader = WALReader::new(walpath.path()).unwrap();
+        let replayed = wal_reader.replay().unwrap();
+        let keys: Vec<&str> = replayed.iter().map(|(_, key, _)| key.as_str()).collect();
+        assert_eq!(keys, vec!["key1", "key3", "key4", "key5", "key1"]);
+        assert_eq!(replayed[4].2, None);
+        assert_eq!(wal_reader.skipped(), 1);
+
+        // an unknown record type hides everything after it
+        bytes[first_len] = 0xff;
+        fs::write(&walfile, &bytes).unwrap();
+        match WALReader::new(walpath.path()).unwrap().replay() {
+            Err(WalError::Corrupt { offset, recovered }) => {
+                assert_eq!(offset, first_len as u64);
+                assert_eq!(recovered.len(), 1);
+            },
+            _ => panic!("expected a corrupt WAL"),
+        }
+    }
+
+    #[test]
+    fn wal_bad_utf8_key() {
+        let walpath = Builder::new().prefix("rustydb_wal_test").tempdir().unwrap();
+        let mut wal_writer = WALWriter::new(walpath.path()).unwrap();
+
+        let ts = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap();
+        wal_writer.add(&ts, "key1", "val1").unwrap();
+        let walfile = segment_path(walpath.path(), 0);
+        let second_start = fs::metadata(&walfile).unwrap().len() as usize;
+        wal_writer.add(&ts, "key2", "val2").unwrap();
+        let second_end = fs::metadata(&walfile).unwrap().len() as usize;
+        wal_writer.add(&ts, "key3", "val3").unwrap();
+
+        // make the key of the second record invalid utf8 under a valid checksum,
+        // the checksum covers everything after the TYPE, SEQ and FLAG
+        let mut bytes = fs::read(&walfile).unwrap();
+        let key_end = second_end - 4 - mem::size_of::<u32>() - "val2".len();
+        bytes[key_end - 1] = 0xff;
+        let crc = crc32fast::hash(&bytes[second_start + 10..second_end - 4]);
+        bytes[second_end - 4..second_end].copy_from_slice(&crc.to_le_bytes());
+        fs::write(&walfile, &bytes).unwrap();
+
+        let mut wal_reader = WALReader::new(walpath.path()).unwrap();
+        wal_reader.read_entry().unwrap();
+        assert!(matches!(wal_reader.read_entry(), Err(Error::BadUtf8)));
+
+        // replay skips the record like any other it can't decode
+        let mut wal_reader = WALReader::new(walpath.path()).unwrap();
+        let keys: Vec<String> = wal_reader.replay().unwrap().into_iter().map(|(_, key, _)| key).collect();
+        assert_eq!(keys, vec!["key1", "key3"]);
+        assert_eq!(wal_reader.skipped(), 1);
+    }
+
+    #[test]
+    fn wal_add_batch() {
+        let walpath = Builder::new().prefix("rustydb_wal_test").tempdir().unwrap();
+        let mut wal_writer = WALWriter::new(walpath.path()).unwrap();
+        wal_writer.set_sync_policy(SyncPolicy::SyncOnBatch);
+
+        let ts = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap();
+        assert_eq!(wal_writer.add(&ts, "key0", "val0").unwrap(), 1);
+        let keys: Vec<String> = (1..=5).map(|i| format!("key{}", i)).collect();
+        let vals: Vec<String> = (1..=5).map(|i| format!("val{}", i)).collect();
+        let batch: Vec<(Duration, &str, &str)> = keys.iter().zip(vals.iter())
+            .map(|(k, v)| (ts, k.as_str(), v.as_str()))
+            .collect();
+        assert_eq!(wal_writer.add_batch(&batch).unwrap(), 6);
+        assert_eq!(wal_writer.add_batch(&[]).unwrap(), 6);
+
+        // a flushed batch replays in full
+        let replayed = WALReader::new(walpath.path()).unwrap().replay().unwrap();
+        assert_eq!(replayed.len(), 6);
+        for (i, (_, key, val)) in replayed.into_iter().enumerate() {
+            assert_eq!((key, val), (format!("key{}", i), Some(format!("val{}", i).into_bytes())));
+        }
+
+        // a crash before the batch is flushed loses all of it
+        let walfile = segment_path(walpath.path(), 0);
+        let flushed_len = fs::metadata(&walfile).unwrap().len();
+        wal_writer.write_put(&ts, "key6", b"val6").unwrap();
+        wal_writer.write_put(&ts, "key7", b"val7").unwrap();
+        mem::forget(wal_writer);
+        assert_eq!(fs::metadata(&walfile).unwrap().len(), flushed_len);
+        assert_eq!(WALReader::new(walpath.path()).unwrap().replay().unwrap().len(), 6);
+
+        // and a crash while it is being written loses the record cut short
+        let mut bytes = fs::read(&walfile).unwrap();
+        bytes.truncate(bytes.len() - 3);
+        fs::write(&walfile, &bytes).unwrap();
+        match WALReader::new(walpath.path()).unwrap().replay() {
+            Err(WalError::Corrupt { recovered, .. }) => {
+                assert_eq!(recovered.len(), 5);
+                assert_eq!(recovered[4].1, "key4");
+            },
+            _ => panic!("expected a corrupt WAL"),
+        }
+    }
+
+    #[test]
+    fn wal_delete() {
+        let walpath = Builder::new().prefix("rustydb_wal_test").tempdir().unwrap();
+        let mut wal_writer = WALWriter::new(walpath.path()).unwrap();
+
+        let ts = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap();
+        assert_eq!(wal_writer.add(&ts, "foo", "bar").unwrap(), 1);
+        assert_eq!(wal_writer.delete(&ts, "foo").unwrap(), 2);
+        assert_eq!(wal_writer.add(&ts, "zoo", "kee").unwrap(), 3);
+
+        // replayed in order, deletes in between puts
+        let replayed = WALReader::new(walpath.path()).unwrap().replay().unwrap();
+        assert_eq!(replayed, vec![
+            (ts, String::from("foo"), Some(b"bar".to_vec())),
+            (ts, String::from("foo"), None),
+            (ts, String::from("zoo"), Some(b"kee".to_vec())),
+        ]);
+
+        // reading entries only yields the puts
+        let keys: Vec<String> = WALReader::new(walpath.path()).unwrap().map(|(_, key, _)| key).collect();
+        assert_eq!(keys, vec!["foo", "zoo"]);
+
+        // a checkpoint covers deletes too
+        wal_writer.checkpoint(2).unwrap();
+        let replayed = WALReader::new(walpath.path()).unwrap().replay().unwrap();
+        assert_eq!(replayed.len(), 1);
+    }
+
+    #[test]
+    fn wal_bytes_written() {
+        let walpath = Builder::new().prefix("rustydb_wal_test").tempdir().unwrap();
+        let root = walpath.path();
+        let mut wal_writer = WALWriter::new_compressed(root).unwrap();
+        // the records in the files, past the header of each
+        let on_disk = || -> usize {
+            log_files(root).unwrap().iter()
+                .map(|file| fs::metadata(file).unwrap().len() as usize - WAL_FORMAT.magic.len() - 1)
+                .sum()
+        };
+        assert_eq!(wal_writer.bytes_written(), 0);
+
+        let ts = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap();
+        wal_writer.add(&ts, "key1", &"v".repeat(100)).unwrap();
+        wal_writer.add_batch(&[(ts, "key2", "val"), (ts, "key3", "val")]).unwrap();
+        wal_writer.rotate().unwrap();
+        wal_writer.delete(&ts, "key1").unwrap();
+        assert!(wal_writer.bytes_written() > 0);
+        assert_eq!(wal_writer.bytes_written(), on_disk());
+
+        // removed segments no longer count, checkpoints do
+        let before = wal_writer.bytes_written();
+        wal_writer.truncate(3).unwrap();
+        assert!(wal_writer.bytes_written() < before);
+        assert_eq!(wal_writer.bytes_written(), on_disk());
+
+        wal_writer.reset().unwrap();
+        assert_eq!(wal_writer.bytes_written(), 0);
+    }
+
+    // a PUT record as written before records had a type: the timestamp, the
+    // key and the value, without a sequence number or checksum
+    fn legacy_record(ts: &Duration, key: &str, val: &str) -> Vec<u8> {
+        let mut buf = Vec::new();
+        buf.write_u64::<LittleEndian>(ts.as_secs()).unwrap();
+        buf.write_u32::<LittleEndian>(ts.subsec_nanos()).unwrap();
+        buf.write_u32::<LittleEndian>(key.len() as u32).unwrap();
+        buf.extend_from_slice(key.as_bytes());
+        buf.write_u32::<LittleEndian>(val.len() as u32).unwrap();
+        buf.extend_from_slice(val.as_bytes());
+        buf
+    }
+
+    #[test]
+    fn wal_legacy_records() {
+        let walpath = Builder::new().prefix("rustydb_wal_test").tempdir().unwrap();
+        let root = walpath.path();
+        let ts = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap();
+
+        let mut old = Vec::new();
+        for i in 0..10 {
+            old.extend(legacy_record(&ts, &format!("key{}", i), &format!("val{}", i)));
+        }
+        fs::write(root.join(WAL_FILENAME), &old).unwrap();
+
+        let replayed = WALReader::new(root).unwrap().replay().unwrap();
+        assert_eq!(replayed.len(), 10);
+        for (i, (timestamp, key, val)) in replayed.into_iter().enumerate() {
+            assert_eq!(timestamp, ts);
+            assert_eq!(key, format!("key{}", i));
+            assert_eq!(val, Some(format!("val{}", i).into_bytes()));
+        }
+        let entries: Vec<(Duration, String, String)> = WALReader::new(root).unwrap().collect();
+        assert_eq!(entries.len(), 10);
+
+        // a record cut short ends the replay with the ones before it
+        fs::write(root.join(WAL_FILENAME), &old[..old.len() - 2]).unwrap();
+        match WALReader::new(root).unwrap().replay() {
+            Err(WalError::Corrupt { recovered, .. }) => assert_eq!(recovered.len(), 9),
+            other => panic!("unexpected result {:?}", other.map(|entries| entries.len())),
+        }
+    }
+
+    #[test]
+    fn wal_segments() {
+        let walpath = Builder::new().prefix("rustydb_wal_test").tempdir().unwrap();
+        let root = walpath.path();
+        let mut wal_writer = WALWriter::new(root).unwrap();
+        let keys = |root: &Path| -> Vec<String> {
+            WALReader::new(root).unwrap().replay().unwrap().into_iter().map(|(_, key, _)| key).collect()
+        };
+
+        let ts = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap();
+        for i in 1..=3 {
+            wal_writer.add(&ts, &format!("key{}", i), "val").unwrap();
+        }
+        wal_writer.rotate().unwrap();
+        wal_writer.add(&ts, "key4", "val").unwrap();
+        wal_writer.rotate().unwrap();
+        assert_eq!(wal_writer.delete(&ts, "key1").unwrap(), 5);
+        assert_eq!(wal_writer.segment(), 2);
+        assert_eq!(log_files(root).unwrap(), (0..3).map(|i| segment_path(root, i)).collect::<Vec<_>>());
+
+        // the segments read as one log
+        assert_eq!(keys(root), vec!["key1", "key2", "key3", "key4", "key1"]);
+        assert_eq!(WALReader::new(root).unwrap().count(), 4);
+
+        // only the segments holding nothing but flushed records are removed
+        wal_writer.truncate(2).unwrap();
+        assert!(segment_path(root, 0).exists());
+        assert_eq!(keys(root), vec!["key3", "key4", "key1"]);
+        wal_writer.truncate(4).unwrap();
+        assert_eq!(log_files(root).unwrap(), vec![segment_path(root, 2)]);
+        assert_eq!(keys(root), vec!["key1"]);
+
+        // a reset drops every record written so far
+        wal_writer.reset().unwrap();
+        wal_writer.add(&ts, "key6", "val").unwrap();
+        assert_eq!(log_files(root).unwrap(), vec![segment_path(root, 3)]);
+        assert_eq!(keys(root), vec!["key6"]);
+
+        // a log written before it was segmented is read first, in the layout
+        // of its time
+        let oldpath = Builder::new().prefix("rustydb_wal_test").tempdir().unwrap();
+        let mut old = Vec::new();
+        for (key, val) in [("old1", "val1"), ("old2", "val2")] {
+            old.extend(legacy_record(&ts, key, val));
+        }
+        fs::write(oldpath.path().join(WAL_FILENAME), &old).unwrap();
+        fs::copy(segment_path(root, 3), segment_path(oldpath.path(), 0)).unwrap();
+        assert_eq!(keys(oldpath.path()), vec!["old1", "old2", "key6"]);
+        let (_, _, val) = WALReader::new(oldpath.path()).unwrap().replay().unwrap().remove(1);
+        assert_eq!(val, Some(b"val2".to_vec()));
+
+        // and replaced along with the segments by a new log
+        WALWriter::new(oldpath.path()).unwrap();
+        assert_eq!(log_files(oldpath.path()).unwrap(), vec![segment_path(oldpath.path(), 0)]);
+        assert!(keys(oldpath.path()).is_empty());
+    }
 }